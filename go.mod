@@ -1,6 +1,6 @@
 module github.com/lirios/ostree-upload
 
-go 1.14
+go 1.16
 
 require (
 	github.com/chilts/sid v0.0.0-20190607042430-660e94789ec9
@@ -8,5 +8,6 @@ require (
 	github.com/golang/gddo v0.0.0-20200604155040-845892271f91
 	github.com/hashicorp/go-memdb v1.2.1
 	github.com/spf13/cobra v1.0.0
+	golang.org/x/crypto v0.0.0-20190308221718-c2843e01d9a2
 	gopkg.in/yaml.v2 v2.3.0
 )
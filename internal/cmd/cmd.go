@@ -5,21 +5,63 @@
 package cmd
 
 import (
+	"errors"
+	"fmt"
+	"io/ioutil"
 	"os"
+	"os/signal"
+	"path/filepath"
+	"strings"
+	"syscall"
+	"time"
 
 	"github.com/spf13/cobra"
 
+	"github.com/lirios/ostree-upload/internal/common"
 	"github.com/lirios/ostree-upload/internal/logger"
 	"github.com/lirios/ostree-upload/internal/ostree"
 	"github.com/lirios/ostree-upload/internal/push"
 	"github.com/lirios/ostree-upload/internal/receiver"
 )
 
+// credentials returns the token, or the password when a user is specified;
+// the token is taken from the flag, the file or the environment in this order
+func credentials(token, tokenFile, user, passwordFile string) (string, string, error) {
+	if user != "" {
+		if passwordFile == "" {
+			return "", "", errors.New("Password file is mandatory with --user")
+		}
+		data, err := ioutil.ReadFile(passwordFile)
+		if err != nil {
+			return "", "", fmt.Errorf("Cannot read password file: %v", err)
+		}
+		return "", strings.TrimSpace(string(data)), nil
+	}
+
+	if len(token) == 0 && tokenFile != "" {
+		data, err := ioutil.ReadFile(tokenFile)
+		if err != nil {
+			return "", "", fmt.Errorf("Cannot read token file: %v", err)
+		}
+		token = strings.TrimSpace(string(data))
+	}
+	if len(token) == 0 {
+		token = os.Getenv("OSTREE_UPLOAD_TOKEN")
+	}
+
+	// Without a token the client looks it up in the keyring
+	return token, "", nil
+}
+
 // Generate token command
 func genTokenCmd() *cobra.Command {
 	var (
 		configPath string
 		verbose    bool
+		scopes     []string
+		refs       []string
+		subject    string
+		expiry     time.Duration
 	)
 
 	var cmd = &cobra.Command{
@@ -36,6 +78,11 @@ func genTokenCmd() *cobra.Command {
 				return
 			}
 
+			if err := receiver.ValidateScopes(scopes); err != nil {
+				logger.Fatal(err)
+				return
+			}
+
 			// Open configuration file
 			config, err := receiver.CreateConfig(configPath)
 			if err != nil {
@@ -51,6 +98,12 @@ func genTokenCmd() *cobra.Command {
 			}
 
 			// Save token to the configuration
+			token.Subject = subject
+			token.Scopes = scopes
+			token.Refs = refs
+			if expiry > 0 {
+				token.Expires = time.Now().UTC().Add(expiry).Format(time.RFC3339)
+			}
 			config.Tokens = append(config.Tokens, token)
 			if err := config.Save(); err != nil {
 				logger.Fatalf("Cannot save configuration file: %v", err)
@@ -59,6 +112,66 @@ func genTokenCmd() *cobra.Command {
 
 			// Print token
 			logger.Infof("Token: %s", token.Token)
+			if token.Expires != "" {
+				logger.Infof("Expires: %s", token.Expires)
+			}
+		},
+	}
+
+	cmd.Flags().StringVarP(&configPath, "config", "c", "ostree-upload.yaml", "path to configuration file")
+	cmd.Flags().StringVarP(&subject, "subject", "s", "", "who or what the token is for, used to identify it in logs")
+	cmd.Flags().DurationVarP(&expiry, "expiry", "", 0, "how long the token is valid (e.g. 720h), forever when not specified")
+	cmd.Flags().StringSliceVarP(&scopes, "scope", "", []string{}, "scope granted to the token (read, upload, publish, admin or force-push), all when not specified")
+	cmd.Flags().StringSliceVarP(&refs, "ref", "", []string{}, "branch pattern the token is allowed to update, all when not specified")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+
+	return cmd
+}
+
+// Generate signing key command
+func genKeyCmd() *cobra.Command {
+	var (
+		configPath string
+		verbose    bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "genkey",
+		Short: "Creates a new signing key",
+		Long:  "Generates an ed25519 key used to sign the server replies.",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Validate arguments
+			if len(configPath) == 0 {
+				logger.Fatal("Path to configuration file is mandatory")
+				return
+			}
+
+			// Open configuration file
+			config, err := receiver.CreateConfig(configPath)
+			if err != nil {
+				logger.Fatalf("Cannot open configuration file: %v", err)
+				return
+			}
+
+			// Generate key
+			privateKey, publicKey, err := receiver.GenerateSigningKey()
+			if err != nil {
+				logger.Fatalf("Failed to generate signing key: %v", err)
+				return
+			}
+
+			// Save key to the configuration
+			config.SigningKey = privateKey
+			if err := config.Save(); err != nil {
+				logger.Fatalf("Cannot save configuration file: %v", err)
+				return
+			}
+
+			// Print public key
+			logger.Infof("Public key: %s", publicKey)
 		},
 	}
 
@@ -75,6 +188,13 @@ func receiveCmd() *cobra.Command {
 		configPath  string
 		verbose     bool
 		repoPath    string
+		logFile     string
+		logMaxSize  int64
+		logMaxAge   time.Duration
+		logKeep     int
+		selfTest    bool
+		stdio       bool
+		stdioUser   string
 	)
 
 	var cmd = &cobra.Command{
@@ -84,52 +204,119 @@ func receiveCmd() *cobra.Command {
 			// Toggle debug output
 			logger.SetVerbose(verbose)
 
-			// Queue
-			queue, err := receiver.NewQueue()
-			if err != nil {
-				logger.Fatalf("Failed to create queue: %v", err)
+			// Push to a temporary server and exit, the configuration is not used
+			if selfTest {
+				if err := runSelfTest(); err != nil {
+					logger.Fatalf("Self-test failed: %v", err)
+					return
+				}
+				logger.Info("Self-test passed")
 				return
 			}
 
-			// Open repository
-			var repo *ostree.Repo
-			if _, err := os.Stat(repoPath); os.IsNotExist(err) {
-				repo, err = ostree.CreateRepo(repoPath)
+			// Write messages to a file, reopened on SIGUSR1 after an external rotation
+			var sandboxPaths []string
+			if logFile != "" {
+				file, err := logger.OpenRotatingFile(logFile, logMaxSize, logMaxAge, logKeep)
 				if err != nil {
-					logger.Fatalf("Failed to create OSTree repository: %v", err)
+					logger.Fatalf("Failed to open log file: %v", err)
 					return
 				}
-			} else {
-				repo, err = ostree.OpenRepo(repoPath)
-				if err != nil {
-					logger.Fatalf("Failed to open OSTree repository: %v", err)
+				logger.SetOutputFile(file)
+				sandboxPaths = append(sandboxPaths, filepath.Dir(logFile))
+
+				signals := make(chan os.Signal, 1)
+				signal.Notify(signals, syscall.SIGUSR1)
+				go func() {
+					for range signals {
+						if err := file.Reopen(); err != nil {
+							fmt.Fprintf(os.Stderr, "Failed to reopen %s: %v\n", logFile, err)
+						}
+					}
+				}()
+			}
+
+			// The configuration tells which repository to serve and how
+			config, err := receiver.OpenConfig(configPath)
+			if err != nil {
+				logger.Fatal(err)
+				return
+			}
+
+			// Requests over the standard input and output were already
+			// authenticated by SSH, find out who they belong to
+			var user *receiver.User
+			if stdio {
+				if config.EphemeralRepo {
+					logger.Fatal("An ephemeral repository cannot be served over the standard input and output")
+					return
+				}
+				if user, err = receiver.StdioUser(config, stdioUser); err != nil {
+					logger.Fatal(err)
+					return
+				}
+			}
+
+			// Serve a repository that only lives as long as the process
+			if config.EphemeralRepo {
+				if repoPath, err = receiver.CreateEphemeralRepo(configPath); err != nil {
+					logger.Fatalf("Failed to create the ephemeral repository: %v", err)
 					return
 				}
 			}
 
-			// Create temporary directory
-			if err = receiver.CreateTempDirectory(repo); err != nil {
-				logger.Fatalf("Failed to create temporary directory for OSTree repository: %v", err)
+			// Restrict the process before serving the repository
+			if err := receiver.EnterSandbox(config, repoPath, sandboxPaths...); err != nil {
+				logger.Fatalf("Failed to enter the sandbox: %v", err)
 				return
 			}
+			if config.EphemeralRepo {
+				signals := make(chan os.Signal, 1)
+				signal.Notify(signals, os.Interrupt, syscall.SIGTERM)
+				go func() {
+					<-signals
+					if err := receiver.RemoveEphemeralRepo(repoPath); err != nil {
+						logger.Errorf("Failed to remove the ephemeral repository: %v", err)
+					}
+					os.Exit(0)
+				}()
+			}
 
-			// Open configuration file
-			config, err := receiver.OpenConfig(configPath)
+			// Report what the OSTree library can do
+			logger.Infof("Using libostree %s with capabilities: %s", ostree.Version(), strings.Join(ostree.Capabilities(), ", "))
+
+			// Open repository and configuration
+			appState, err := receiver.NewAppState(repoPath, configPath)
 			if err != nil {
-				logger.Fatalf("Cannot open configuration file: %v", err)
+				logger.Fatal(err)
+				return
+			}
+
+			// Serve a single client, the repository is pruned by the server
+			if stdio {
+				if err := receiver.ServeStdio(appState, user, os.Stdin, os.Stdout); err != nil {
+					logger.Fatal(err)
+				}
 				return
 			}
 
 			// Prune the repository before we begin
 			logger.Infof("Pruning repository...")
-			total, pruned, size, err := repo.Prune(false, false)
+			total, pruned, size, err := appState.Repo.Prune(false, false)
 			if err != nil {
 				logger.Fatalf("Failed to prune repository: %v", err)
 				return
 			}
 			logger.Infof("Pruned %d/%d objects, %d bytes deleted", pruned, total, size)
 
-			appState := &receiver.AppState{Queue: queue, Repo: repo, Config: config}
+			// Deltas of pruned commits are useless
+			removed, err := receiver.PruneStaticDeltas(appState.Repo, appState.Config)
+			if err != nil {
+				logger.Fatalf("Failed to prune static deltas: %v", err)
+				return
+			}
+			logger.Infof("Removed %d stale static deltas", removed)
+
 			if err := receiver.StartServer(bindAddress, appState); err != nil {
 				logger.Fatal(err)
 				return
@@ -141,6 +328,218 @@ func receiveCmd() *cobra.Command {
 	cmd.Flags().StringVarP(&bindAddress, "address", "a", ":8080", "host name and port to bind")
 	cmd.Flags().StringVarP(&repoPath, "repo", "r", "repo", "path to OSTree repository")
 	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+	cmd.Flags().StringVarP(&logFile, "log-file", "", "", "write messages to this file instead of the standard error")
+	cmd.Flags().Int64VarP(&logMaxSize, "log-max-size", "", 100*1024*1024, "rotate the log file when it grows bigger than this many bytes (0 to disable)")
+	cmd.Flags().DurationVarP(&logMaxAge, "log-max-age", "", 24*time.Hour, "rotate the log file when it is older than this (0 to disable)")
+	cmd.Flags().IntVarP(&logKeep, "log-keep", "", 7, "number of rotated log files to keep (0 to keep all)")
+	cmd.Flags().BoolVarP(&selfTest, "self-test", "", false, "push a generated commit to a temporary server and exit with the result")
+	cmd.Flags().BoolVarP(&stdio, "stdio", "", false, "speak the protocol over the standard input and output, to be used as an SSH forced command")
+	cmd.Flags().StringVarP(&stdioUser, "stdio-user", "", "", "configured user the requests of --stdio are authenticated as, the current system user with all scopes when not specified")
+
+	return cmd
+}
+
+// Export history command
+func exportHistoryCmd() *cobra.Command {
+	var (
+		configPath string
+		since      string
+		format     string
+		output     string
+		verbose    bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "export-history",
+		Short: "Export the publish history",
+		Long:  "Writes the publishes recorded in history_file, including the rotated segments, as JSON lines or CSV.",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Open configuration file
+			config, err := receiver.OpenConfig(configPath)
+			if err != nil {
+				logger.Fatalf("Cannot open configuration file: %v", err)
+				return
+			}
+			if config.HistoryFile == "" {
+				logger.Fatal("History is disabled, set history_file in the configuration")
+				return
+			}
+
+			// Everything is exported by default
+			sinceTime := time.Time{}
+			if since != "" {
+				if sinceTime, err = receiver.ParseTime(since); err != nil {
+					logger.Fatal(err)
+					return
+				}
+			}
+
+			// Write to the standard output unless asked otherwise
+			writer := os.Stdout
+			if output != "" {
+				writer, err = os.Create(output)
+				if err != nil {
+					logger.Fatalf("Cannot create output file: %v", err)
+					return
+				}
+				defer writer.Close()
+			}
+
+			history := receiver.OpenHistory(config.HistoryFile)
+			count, err := receiver.ExportHistory(history, sinceTime, format, writer)
+			if err != nil {
+				logger.Fatalf("Failed to export history: %v", err)
+				return
+			}
+			logger.Debugf("Exported %d history entries", count)
+		},
+	}
+
+	cmd.Flags().StringVarP(&configPath, "config", "c", "ostree-upload.yaml", "path to configuration file")
+	cmd.Flags().StringVarP(&since, "since", "", "", "only export publishes after this time (RFC 3339 or seconds since the epoch)")
+	cmd.Flags().StringVarP(&format, "format", "f", receiver.HistoryExportJSON, "output format (json or csv)")
+	cmd.Flags().StringVarP(&output, "output", "o", "", "file to write, the standard output when not specified")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+
+	return cmd
+}
+
+// Add user command
+func addUserCmd() *cobra.Command {
+	var (
+		configPath   string
+		verbose      bool
+		name         string
+		passwordFile string
+		refs         []string
+		scopes       []string
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "adduser",
+		Short: "Adds a user for HTTP Basic authentication",
+		Long:  "Hashes the password and adds the user to the configuration file, replacing an existing user with the same name.",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Validate arguments
+			if len(configPath) == 0 {
+				logger.Fatal("Path to configuration file is mandatory")
+				return
+			}
+			if len(name) == 0 {
+				logger.Fatal("User name is mandatory")
+				return
+			}
+			if len(passwordFile) == 0 {
+				logger.Fatal("Password file is mandatory")
+				return
+			}
+			if err := receiver.ValidateScopes(scopes); err != nil {
+				logger.Fatal(err)
+				return
+			}
+
+			// Read password
+			data, err := ioutil.ReadFile(passwordFile)
+			if err != nil {
+				logger.Fatalf("Cannot read password file: %v", err)
+				return
+			}
+
+			// Open configuration file
+			config, err := receiver.CreateConfig(configPath)
+			if err != nil {
+				logger.Fatalf("Cannot open configuration file: %v", err)
+				return
+			}
+
+			// Hash password
+			hash, err := receiver.HashPassword(strings.TrimSpace(string(data)))
+			if err != nil {
+				logger.Fatalf("Failed to hash password: %v", err)
+				return
+			}
+
+			// Save user to the configuration
+			if user := config.FindUser(name); user != nil {
+				user.Password = hash
+				user.Refs = refs
+				user.Scopes = scopes
+			} else {
+				config.Users = append(config.Users, &receiver.User{Name: name, Password: hash, Refs: refs, Scopes: scopes})
+			}
+			if err := config.Save(); err != nil {
+				logger.Fatalf("Cannot save configuration file: %v", err)
+				return
+			}
+
+			logger.Infof("User \"%s\" saved", name)
+		},
+	}
+
+	cmd.Flags().StringVarP(&configPath, "config", "c", "ostree-upload.yaml", "path to configuration file")
+	cmd.Flags().StringVarP(&name, "name", "n", "", "user name")
+	cmd.Flags().StringVarP(&passwordFile, "password-file", "", "", "file containing the password")
+	cmd.Flags().StringSliceVarP(&refs, "ref", "", []string{}, "branch pattern the user is allowed to update, all when not specified")
+	cmd.Flags().StringSliceVarP(&scopes, "scope", "", []string{}, "scope granted to the user (read, upload, publish, admin or force-push), all when not specified")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+
+	return cmd
+}
+
+// Init command
+func initCmd() *cobra.Command {
+	var (
+		configPath         string
+		verbose            bool
+		repoPath           string
+		mode               string
+		collectionID       string
+		generateDeltas     bool
+		generateSigningKey bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "init",
+		Short: "Create a new repository",
+		Long:  "Creates a new OSTree repository ready to receive pushes, recording the options in its configuration.",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Create repository
+			opts := receiver.InitOptions{
+				Mode:               mode,
+				CollectionID:       collectionID,
+				GenerateDeltas:     generateDeltas,
+				GenerateSigningKey: generateSigningKey,
+			}
+			publicKey, err := receiver.InitRepository(repoPath, configPath, opts)
+			if err != nil {
+				logger.Fatal(err)
+				return
+			}
+
+			// Print public key
+			if publicKey != "" {
+				logger.Infof("Public key: %s", publicKey)
+			}
+			logger.Infof("Repository \"%s\" created", repoPath)
+		},
+	}
+
+	cmd.Flags().StringVarP(&configPath, "config", "c", "ostree-upload.yaml", "path to configuration file")
+	cmd.Flags().StringVarP(&repoPath, "repo", "r", "repo", "path to OSTree repository")
+	cmd.Flags().StringVar(&mode, "mode", "archive", "repository mode (archive, bare, bare-user or bare-user-only)")
+	cmd.Flags().StringVar(&collectionID, "collection-id", "", "collection ID of the repository")
+	cmd.Flags().BoolVar(&generateDeltas, "generate-deltas", false, "generate static deltas when branches are updated")
+	cmd.Flags().BoolVar(&generateSigningKey, "generate-signing-key", false, "generate a key to sign the server replies")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
 
 	return cmd
 }
@@ -148,12 +547,42 @@ func receiveCmd() *cobra.Command {
 // Push command
 func pushCmd() *cobra.Command {
 	var (
-		url      string
-		repoPath string
-		token    string
-		branches []string
-		verbose  bool
-		prune    bool
+		url          string
+		repoPath     string
+		token        string
+		tokenFile    string
+		user         string
+		passwordFile string
+		branches     []string
+		refFile      string
+		verbose      bool
+		prune        bool
+		serverKey    string
+		caCert       string
+		insecure     bool
+		signKey      string
+		signType     string
+		gpgHome      string
+		attempts     int
+		version      string
+		cachedOK     bool
+		cacheMaxAge  time.Duration
+		manifestKey  string
+		force        bool
+		sessionObjs  int
+		webSocket    bool
+		deferHash    int64
+		trackRemote  string
+		preUpload    string
+		inventory    string
+		fpRate       float64
+		chunkSize    int64
+		jobs         int
+		batchObjs    int
+		noPack       bool
+		deltas       bool
+		algorithm    string
+		ssh          string
 	)
 
 	var cmd = &cobra.Command{
@@ -163,16 +592,66 @@ func pushCmd() *cobra.Command {
 			// Toggle debug output
 			logger.SetVerbose(verbose)
 
-			// Check the token
-			if len(token) == 0 {
-				token = os.Getenv("OSTREE_UPLOAD_TOKEN")
-			}
-			if len(token) == 0 {
-				logger.Fatal("Token is mandatory")
+			// Check the credentials
+			token, password, err := credentials(token, tokenFile, user, passwordFile)
+			if err != nil {
+				logger.Fatal(err)
 				return
 			}
 
-			if err := push.StartClient(url, token, repoPath, branches, prune); err != nil {
+			// With ed25519 the secret key is read from a file
+			if signKey != "" && signType == push.SignTypeEd25519 {
+				data, err := ioutil.ReadFile(signKey)
+				if err != nil {
+					logger.Fatalf("Cannot read signing key: %v", err)
+					return
+				}
+				signKey = strings.TrimSpace(string(data))
+			}
+
+			// Run the receiver over SSH instead of connecting to it
+			if ssh != "" {
+				url = fmt.Sprintf("%s://%s", push.SSHScheme, ssh)
+			}
+
+			opts := push.Options{
+				URL:               url,
+				Token:             token,
+				User:              user,
+				Password:          password,
+				RepoPath:          repoPath,
+				Branches:          branches,
+				RefFile:           refFile,
+				Prune:             prune,
+				ServerKey:         serverKey,
+				CACert:            caCert,
+				Insecure:          insecure,
+				SignKey:           signKey,
+				SignType:          signType,
+				GPGHomedir:        gpgHome,
+				UploadAttempts:    attempts,
+				Jobs:              jobs,
+				BatchObjects:      batchObjs,
+				NoPack:            noPack,
+				StaticDeltas:      deltas,
+				ChecksumAlgorithm: algorithm,
+				ExpectVersion:     version,
+				CachedOK:          cachedOK,
+				CacheMaxAge:       cacheMaxAge,
+				ManifestKey:       manifestKey,
+				Force:             force,
+				SessionObjects:    sessionObjs,
+				WebSocket:         webSocket,
+				DeferHashSize:     deferHash,
+				TrackRemote:       trackRemote,
+				Inventory:         inventory,
+				InventoryFPRate:   fpRate,
+				ChunkSize:         chunkSize,
+			}
+			if preUpload != "" {
+				opts.PreUploadHooks = append(opts.PreUploadHooks, &push.CommandHook{Command: preUpload})
+			}
+			if err := push.StartClient(opts); err != nil {
 				logger.Fatal(err)
 				return
 			}
@@ -180,11 +659,736 @@ func pushCmd() *cobra.Command {
 	}
 
 	cmd.Flags().StringVarP(&url, "address", "a", "http://localhost:8080", "host name and port of the server")
+	cmd.Flags().StringVarP(&ssh, "ssh", "", "", "run \"ostree-upload receive --stdio\" on this user@host over SSH instead of connecting to --address")
 	cmd.Flags().StringVarP(&repoPath, "repo", "r", "repo", "path to OSTree repository")
 	cmd.Flags().StringVarP(&token, "token", "t", "", "token to authenticate with the server")
+	cmd.Flags().StringVarP(&tokenFile, "token-file", "", "", "file containing the token to authenticate with the server")
+	cmd.Flags().StringVarP(&user, "user", "u", "", "user name to authenticate with the server instead of the token")
+	cmd.Flags().StringVarP(&passwordFile, "password-file", "", "", "file containing the password of --user")
 	cmd.Flags().BoolVarP(&prune, "prune", "", false, "prune repository before the transfer happens")
 	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
 	cmd.Flags().StringSliceVarP(&branches, "branch", "b", []string{}, "branch to upload")
+	cmd.Flags().StringVarP(&refFile, "ref-file", "", "", "file listing the exact commit to push for each branch, as written by remote-refs")
+	cmd.Flags().StringVarP(&serverKey, "server-key", "", "", "public key to verify the server replies")
+	cmd.Flags().StringVarP(&caCert, "ca-cert", "", "", "file with PEM encoded CA certificates to trust besides the system ones")
+	cmd.Flags().BoolVarP(&insecure, "insecure", "k", false, "do not verify the TLS certificate of the server (dangerous)")
+	cmd.Flags().StringVarP(&signKey, "sign-before-push", "", "", "sign commits with this GPG key ID (or ed25519 secret key file) before pushing")
+	cmd.Flags().StringVarP(&signType, "sign-type", "", push.SignTypeGPG, "signature type for --sign-before-push (gpg or ed25519)")
+	cmd.Flags().StringVarP(&gpgHome, "gpg-homedir", "", "", "GPG home directory used to sign commits")
+	cmd.Flags().IntVarP(&attempts, "upload-attempts", "", 3, "how many times an object is sent before giving up")
+	cmd.Flags().IntVarP(&jobs, "jobs", "j", 1, "how many objects are uploaded at the same time")
+	cmd.Flags().IntVarP(&batchObjs, "batch-objects", "", 64, "how many small objects are sent in a single request (1 disables it)")
+	cmd.Flags().BoolVarP(&noPack, "no-pack", "", false, "never send all the objects as a single stream, even if the server supports it")
+	cmd.Flags().StringVarP(&algorithm, "checksum-algorithm", "", "", "algorithm of the checksums, either \"blake3\" or \"sha256\" (default the fastest the server supports)")
+	cmd.Flags().BoolVarP(&deltas, "static-deltas", "", false, "upload a static delta for each branch instead of its objects, if the server supports it")
+	cmd.Flags().StringVarP(&version, "expect-version", "", "", "refuse to push commits without this version")
+
+	cmd.Flags().BoolVarP(&cachedOK, "cached-ok", "", false, "trust the cached repository information when it's fresh, without contacting the server if there's nothing to push")
+	cmd.Flags().DurationVarP(&cacheMaxAge, "cache-max-age", "", time.Minute, "how long the cached repository information is fresh")
+	cmd.Flags().StringVarP(&manifestKey, "manifest-key", "", "", "file containing the ed25519 key to sign the push manifest")
+	cmd.Flags().IntVarP(&sessionObjs, "session-objects", "", 0, "split gigantic pushes into sessions of about this many objects, publishing the oldest commits first")
+	cmd.Flags().BoolVarP(&webSocket, "websocket", "", false, "send everything over a single WebSocket connection instead of a request per object")
+	cmd.Flags().Int64VarP(&chunkSize, "chunk-size", "", 64*1024*1024, "send objects bigger than this many bytes in chunks of this size, resuming interrupted uploads (0 disables it)")
+	cmd.Flags().Int64VarP(&deferHash, "defer-hash-size", "", 0, "hash objects bigger than this many bytes while uploading them instead of beforehand")
+	cmd.Flags().StringVarP(&trackRemote, "track-remote", "", "", "record the published commits as refs/remotes/<NAME>/<BRANCH> in the local repository")
+	cmd.Flags().StringVarP(&inventory, "inventory", "", "", "download the objects of the server as a \"list\" or a \"bloom\" filter instead of asking which are missing for each session")
+	cmd.Flags().Float64VarP(&fpRate, "inventory-fp-rate", "", 0.01, "false positive rate of the bloom filter of --inventory")
+	cmd.Flags().StringVarP(&preUpload, "pre-upload-cmd", "", "", "shell command run for each object before uploading anything, which can skip or refuse it")
+	cmd.Flags().BoolVarP(&force, "force", "f", false, "update branches even if the server commits are not in the local history (requires the force-push scope)")
+
+	return cmd
+}
+
+// Preflight command
+func preflightCmd() *cobra.Command {
+	var (
+		url          string
+		repoPath     string
+		token        string
+		tokenFile    string
+		user         string
+		passwordFile string
+		branches     []string
+		refFile      string
+		verbose      bool
+		serverKey    string
+		caCert       string
+		insecure     bool
+		force        bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "preflight",
+		Short: "Check whether the branches can be pushed, without uploading anything",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Check the credentials
+			token, password, err := credentials(token, tokenFile, user, passwordFile)
+			if err != nil {
+				logger.Fatal(err)
+				return
+			}
+
+			opts := push.Options{
+				URL:       url,
+				Token:     token,
+				User:      user,
+				Password:  password,
+				RepoPath:  repoPath,
+				Branches:  branches,
+				RefFile:   refFile,
+				ServerKey: serverKey,
+				CACert:    caCert,
+				Insecure:  insecure,
+				Force:     force,
+			}
+			if err := push.StartPreflight(opts); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	cmd.Flags().StringVarP(&url, "address", "a", "http://localhost:8080", "host name and port of the server")
+	cmd.Flags().StringVarP(&repoPath, "repo", "r", "repo", "path to OSTree repository")
+	cmd.Flags().StringVarP(&token, "token", "t", "", "token to authenticate with the server")
+	cmd.Flags().StringVarP(&tokenFile, "token-file", "", "", "file containing the token to authenticate with the server")
+	cmd.Flags().StringVarP(&user, "user", "u", "", "user name to authenticate with the server instead of the token")
+	cmd.Flags().StringVarP(&passwordFile, "password-file", "", "", "file containing the password of --user")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+	cmd.Flags().StringSliceVarP(&branches, "branch", "b", []string{}, "branch to check")
+	cmd.Flags().StringVarP(&refFile, "ref-file", "", "", "file listing the exact commit to check for each branch, as written by remote-refs")
+	cmd.Flags().StringVarP(&serverKey, "server-key", "", "", "public key to verify the server replies")
+	cmd.Flags().StringVarP(&caCert, "ca-cert", "", "", "file with PEM encoded CA certificates to trust besides the system ones")
+	cmd.Flags().BoolVarP(&insecure, "insecure", "k", false, "do not verify the TLS certificate of the server (dangerous)")
+	cmd.Flags().BoolVarP(&force, "force", "f", false, "check a forced update (requires the force-push scope)")
+
+	return cmd
+}
+
+// Doctor command
+func doctorCmd() *cobra.Command {
+	var (
+		url          string
+		repoPath     string
+		token        string
+		tokenFile    string
+		user         string
+		passwordFile string
+		verbose      bool
+		serverKey    string
+		caCert       string
+		insecure     bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "doctor",
+		Short: "Diagnose problems with the environment before pushing",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Check the credentials
+			token, password, err := credentials(token, tokenFile, user, passwordFile)
+			if err != nil {
+				logger.Fatal(err)
+				return
+			}
+
+			opts := push.Options{
+				URL:       url,
+				Token:     token,
+				User:      user,
+				Password:  password,
+				RepoPath:  repoPath,
+				ServerKey: serverKey,
+				CACert:    caCert,
+				Insecure:  insecure,
+			}
+			if err := push.Doctor(opts); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	cmd.Flags().StringVarP(&url, "address", "a", "http://localhost:8080", "host name and port of the server")
+	cmd.Flags().StringVarP(&repoPath, "repo", "r", "repo", "path to OSTree repository")
+	cmd.Flags().StringVarP(&token, "token", "t", "", "token to authenticate with the server")
+	cmd.Flags().StringVarP(&tokenFile, "token-file", "", "", "file containing the token to authenticate with the server")
+	cmd.Flags().StringVarP(&user, "user", "u", "", "user name to authenticate with the server instead of the token")
+	cmd.Flags().StringVarP(&passwordFile, "password-file", "", "", "file containing the password of --user")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+	cmd.Flags().StringVarP(&serverKey, "server-key", "", "", "public key to verify the server replies")
+	cmd.Flags().StringVarP(&caCert, "ca-cert", "", "", "file with PEM encoded CA certificates to trust besides the system ones")
+	cmd.Flags().BoolVarP(&insecure, "insecure", "k", false, "do not verify the TLS certificate of the server (dangerous)")
+
+	return cmd
+}
+
+// Log command
+func logCmd() *cobra.Command {
+	var (
+		url          string
+		repoPath     string
+		token        string
+		tokenFile    string
+		user         string
+		passwordFile string
+		branches     []string
+		refFile      string
+		verbose      bool
+		graph        bool
+		serverKey    string
+		caCert       string
+		insecure     bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "log",
+		Short: "Show the commits that would be pushed",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Check the credentials
+			token, password, err := credentials(token, tokenFile, user, passwordFile)
+			if err != nil {
+				logger.Fatal(err)
+				return
+			}
+
+			opts := push.Options{
+				URL:       url,
+				Token:     token,
+				User:      user,
+				Password:  password,
+				RepoPath:  repoPath,
+				Branches:  branches,
+				RefFile:   refFile,
+				ServerKey: serverKey,
+				CACert:    caCert,
+				Insecure:  insecure,
+			}
+			if err := push.LogBranches(opts, graph); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	cmd.Flags().StringVarP(&url, "address", "a", "http://localhost:8080", "host name and port of the server")
+	cmd.Flags().StringVarP(&repoPath, "repo", "r", "repo", "path to OSTree repository")
+	cmd.Flags().StringVarP(&token, "token", "t", "", "token to authenticate with the server")
+	cmd.Flags().StringVarP(&tokenFile, "token-file", "", "", "file containing the token to authenticate with the server")
+	cmd.Flags().StringVarP(&user, "user", "u", "", "user name to authenticate with the server instead of the token")
+	cmd.Flags().StringVarP(&passwordFile, "password-file", "", "", "file containing the password of --user")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+	cmd.Flags().BoolVarP(&graph, "graph", "g", false, "draw the commit chain as a graph")
+	cmd.Flags().StringSliceVarP(&branches, "branch", "b", []string{}, "branch to show")
+	cmd.Flags().StringVarP(&refFile, "ref-file", "", "", "file listing the exact commit to show for each branch, as written by remote-refs")
+	cmd.Flags().StringVarP(&serverKey, "server-key", "", "", "public key to verify the server replies")
+	cmd.Flags().StringVarP(&caCert, "ca-cert", "", "", "file with PEM encoded CA certificates to trust besides the system ones")
+	cmd.Flags().BoolVarP(&insecure, "insecure", "k", false, "do not verify the TLS certificate of the server (dangerous)")
+
+	return cmd
+}
+
+// Compare command
+func compareCmd() *cobra.Command {
+	var (
+		urls         []string
+		token        string
+		tokenFile    string
+		user         string
+		passwordFile string
+		verbose      bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "compare",
+		Short: "Compare the branches of two servers",
+		Long:  "Reports the branches that are missing or point to different commits on two servers, for example to verify replication.",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Check the credentials
+			token, password, err := credentials(token, tokenFile, user, passwordFile)
+			if err != nil {
+				logger.Fatal(err)
+				return
+			}
+
+			opts := push.Options{Token: token, User: user, Password: password}
+			if err := push.CompareServers(opts, urls); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	cmd.Flags().StringSliceVarP(&urls, "address", "a", []string{}, "host name and port of a server, pass it twice")
+	cmd.Flags().StringVarP(&token, "token", "t", "", "token to authenticate with the servers")
+	cmd.Flags().StringVarP(&tokenFile, "token-file", "", "", "file containing the token to authenticate with the servers")
+	cmd.Flags().StringVarP(&user, "user", "u", "", "user name to authenticate with the servers instead of the token")
+	cmd.Flags().StringVarP(&passwordFile, "password-file", "", "", "file containing the password of --user")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+
+	return cmd
+}
+
+// Remote refs command
+func remoteRefsCmd() *cobra.Command {
+	var (
+		url          string
+		token        string
+		tokenFile    string
+		user         string
+		passwordFile string
+		branches     []string
+		output       string
+		verbose      bool
+		serverKey    string
+		caCert       string
+		insecure     bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "remote-refs",
+		Short: "Write the branches published on the server",
+		Long:  "Writes the branches and commits published on the server in the format read by --ref-file, so that promotions can be reviewed and reproduced.",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Check the credentials
+			token, password, err := credentials(token, tokenFile, user, passwordFile)
+			if err != nil {
+				logger.Fatal(err)
+				return
+			}
+
+			// Write to the standard output unless asked otherwise
+			writer := os.Stdout
+			if output != "" {
+				writer, err = os.Create(output)
+				if err != nil {
+					logger.Fatalf("Cannot create output file: %v", err)
+					return
+				}
+				defer writer.Close()
+			}
+
+			opts := push.Options{
+				URL:       url,
+				Token:     token,
+				User:      user,
+				Password:  password,
+				Branches:  branches,
+				ServerKey: serverKey,
+				CACert:    caCert,
+				Insecure:  insecure,
+			}
+			if err := push.RemoteRefs(opts, writer); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	cmd.Flags().StringVarP(&url, "address", "a", "http://localhost:8080", "host name and port of the server")
+	cmd.Flags().StringVarP(&token, "token", "t", "", "token to authenticate with the server")
+	cmd.Flags().StringVarP(&tokenFile, "token-file", "", "", "file containing the token to authenticate with the server")
+	cmd.Flags().StringVarP(&user, "user", "u", "", "user name to authenticate with the server instead of the token")
+	cmd.Flags().StringVarP(&passwordFile, "password-file", "", "", "file containing the password of --user")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+	cmd.Flags().StringSliceVarP(&branches, "branch", "b", []string{}, "branch to write, all of them when not specified")
+	cmd.Flags().StringVarP(&output, "output", "o", "", "file to write, the standard output when not specified")
+	cmd.Flags().StringVarP(&serverKey, "server-key", "", "", "public key to verify the server replies")
+	cmd.Flags().StringVarP(&caCert, "ca-cert", "", "", "file with PEM encoded CA certificates to trust besides the system ones")
+	cmd.Flags().BoolVarP(&insecure, "insecure", "k", false, "do not verify the TLS certificate of the server (dangerous)")
+
+	return cmd
+}
+
+// Login command
+func loginCmd() *cobra.Command {
+	var (
+		token     string
+		tokenFile string
+		verbose   bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "login <ADDR>",
+		Short: "Save the token for a server in the keyring",
+		Long:  "Saves the token in the secret service, the client uses it when no token is passed.",
+		Args:  cobra.ExactArgs(1),
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Read the token from the standard input if not passed
+			token, _, err := credentials(token, tokenFile, "", "")
+			if err != nil {
+				logger.Fatal(err)
+				return
+			}
+			if len(token) == 0 {
+				data, err := ioutil.ReadAll(os.Stdin)
+				if err != nil {
+					logger.Fatalf("Cannot read token: %v", err)
+					return
+				}
+				token = strings.TrimSpace(string(data))
+			}
+			if len(token) == 0 {
+				logger.Fatal("Token is mandatory")
+				return
+			}
+
+			if err := push.StoreToken(args[0], token); err != nil {
+				logger.Fatalf("Failed to save token in the keyring: %v", err)
+				return
+			}
+			logger.Infof("Token for %s saved", args[0])
+		},
+	}
+
+	cmd.Flags().StringVarP(&token, "token", "t", "", "token to save, read from the standard input when not specified")
+	cmd.Flags().StringVarP(&tokenFile, "token-file", "", "", "file containing the token to save")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+
+	return cmd
+}
+
+// Logout command
+func logoutCmd() *cobra.Command {
+	var cmd = &cobra.Command{
+		Use:   "logout <ADDR>",
+		Short: "Remove the token for a server from the keyring",
+		Args:  cobra.ExactArgs(1),
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.ClearToken(args[0]); err != nil {
+				logger.Fatalf("Failed to remove token from the keyring: %v", err)
+				return
+			}
+			logger.Infof("Token for %s removed", args[0])
+		},
+	}
+
+	return cmd
+}
+
+// Generate manifest key command
+func manifestKeyCmd() *cobra.Command {
+	var (
+		output  string
+		verbose bool
+	)
+
+	var cmd = &cobra.Command{
+		Use:   "manifest-key",
+		Short: "Creates a new key to sign push manifests",
+		Long:  "Generates an ed25519 key used by the client to sign push manifests, the public key goes to manifest_keys on the server.",
+		Run: func(cmd *cobra.Command, args []string) {
+			// Toggle debug output
+			logger.SetVerbose(verbose)
+
+			// Generate key
+			publicKey, keyID, err := push.GenerateManifestKey(output)
+			if err != nil {
+				logger.Fatalf("Failed to generate manifest key: %v", err)
+				return
+			}
+
+			// Print public key
+			logger.Infof("Public key: %s", publicKey)
+			logger.Infof("Key ID: %s", keyID)
+		},
+	}
+
+	cmd.Flags().StringVarP(&output, "output", "o", "manifest.key", "file where the private key is saved")
+	cmd.Flags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+
+	return cmd
+}
+
+// Sessions command
+func sessionsCmd() *cobra.Command {
+	var (
+		url          string
+		token        string
+		tokenFile    string
+		user         string
+		passwordFile string
+		verbose      bool
+	)
+
+	options := func() push.Options {
+		// Toggle debug output
+		logger.SetVerbose(verbose)
+
+		// Check the credentials
+		token, password, err := credentials(token, tokenFile, user, passwordFile)
+		if err != nil {
+			logger.Fatal(err)
+		}
+
+		return push.Options{URL: url, Token: token, User: user, Password: password}
+	}
+
+	var listCmd = &cobra.Command{
+		Use:   "list",
+		Short: "List the active sessions",
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.ListSessions(options()); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var statusCmd = &cobra.Command{
+		Use:   "status",
+		Short: "Show the space left on the server for uploads",
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.ShowStatus(options()); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var cancelCmd = &cobra.Command{
+		Use:   "cancel <ID>",
+		Short: "Cancel a session",
+		Args:  cobra.ExactArgs(1),
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.CancelSession(options(), args[0]); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var approveCmd = &cobra.Command{
+		Use:   "approve <ID>",
+		Short: "Approve a session waiting for approval and publish it",
+		Args:  cobra.ExactArgs(1),
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.ApproveSession(options(), args[0]); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var cmd = &cobra.Command{
+		Use:   "sessions",
+		Short: "Manage the sessions on the server",
+	}
+
+	cmd.PersistentFlags().StringVarP(&url, "address", "a", "http://localhost:8080", "host name and port of the server")
+	cmd.PersistentFlags().StringVarP(&token, "token", "t", "", "token to authenticate with the server")
+	cmd.PersistentFlags().StringVarP(&tokenFile, "token-file", "", "", "file containing the token to authenticate with the server")
+	cmd.PersistentFlags().StringVarP(&user, "user", "u", "", "user name to authenticate with the server instead of the token")
+	cmd.PersistentFlags().StringVarP(&passwordFile, "password-file", "", "", "file containing the password of --user")
+	cmd.PersistentFlags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+
+	cmd.AddCommand(listCmd, statusCmd, cancelCmd, approveCmd)
+
+	return cmd
+}
+
+// Admin command
+func adminCmd() *cobra.Command {
+	var (
+		url          string
+		token        string
+		tokenFile    string
+		user         string
+		passwordFile string
+		caCert       string
+		insecure     bool
+		verbose      bool
+		noWait       bool
+		limit        int
+		follow       bool
+		last         string
+		format       string
+	)
+
+	options := func() push.Options {
+		// Toggle debug output
+		logger.SetVerbose(verbose)
+
+		// Check the credentials
+		token, password, err := credentials(token, tokenFile, user, passwordFile)
+		if err != nil {
+			logger.Fatal(err)
+		}
+
+		return push.Options{URL: url, Token: token, User: user, Password: password, CACert: caCert, Insecure: insecure}
+	}
+
+	var listCmd = &cobra.Command{
+		Use:   "sessions",
+		Short: "List the active sessions",
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.ListSessions(options()); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var cancelCmd = &cobra.Command{
+		Use:   "cancel <ID>",
+		Short: "Cancel a session",
+		Args:  cobra.ExactArgs(1),
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.CancelSession(options(), args[0]); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var frozenCmd = &cobra.Command{
+		Use:   "frozen",
+		Short: "List the frozen branches",
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.ShowFrozen(options()); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var freezeCmd = &cobra.Command{
+		Use:   "freeze <BRANCH>...",
+		Short: "Refuse pushes to the branches until they are unfrozen",
+		Args:  cobra.MinimumNArgs(1),
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.FreezeBranches(options(), args, true); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var unfreezeCmd = &cobra.Command{
+		Use:   "unfreeze <BRANCH>...",
+		Short: "Accept pushes to the branches again",
+		Args:  cobra.MinimumNArgs(1),
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.FreezeBranches(options(), args, false); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	jobCmd := func(kind, short string) *cobra.Command {
+		return &cobra.Command{
+			Use:   kind,
+			Short: short,
+			Run: func(cmd *cobra.Command, args []string) {
+				if err := push.RunJob(options(), kind, !noWait); err != nil {
+					logger.Fatal(err)
+					return
+				}
+			},
+		}
+	}
+
+	var jobsCmd = &cobra.Command{
+		Use:   "jobs",
+		Short: "List the maintenance jobs",
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.ListJobs(options()); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var revokeCmd = &cobra.Command{
+		Use:   "revoke <SUBJECT>",
+		Short: "Revoke the tokens issued to a subject",
+		Args:  cobra.ExactArgs(1),
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.RevokeTokens(options(), args[0]); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+
+	var historyCmd = &cobra.Command{
+		Use:   "history",
+		Short: "Show the last publishes",
+		Run: func(cmd *cobra.Command, args []string) {
+			if err := push.TailHistory(options(), limit, follow); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+	historyCmd.Flags().IntVarP(&limit, "lines", "n", 10, "how many publishes to show")
+	historyCmd.Flags().BoolVarP(&follow, "follow", "f", false, "keep showing new publishes")
+
+	var reportCmd = &cobra.Command{
+		Use:   "report",
+		Short: "Report how often and how much each branch was pushed",
+		Run: func(cmd *cobra.Command, args []string) {
+			period, err := common.ParseDuration(last)
+			if err != nil || period <= 0 {
+				logger.Fatalf("Invalid period \"%s\"", last)
+				return
+			}
+			if err := push.Report(options(), period, format, os.Stdout); err != nil {
+				logger.Fatal(err)
+				return
+			}
+		},
+	}
+	reportCmd.Flags().StringVarP(&last, "last", "", "30d", "period to report on (e.g. 30d or 12h)")
+	reportCmd.Flags().StringVarP(&format, "format", "", push.ReportTable, "output format (table or json)")
+
+	var cmd = &cobra.Command{
+		Use:   "admin",
+		Short: "Administer the server",
+	}
+
+	cmd.PersistentFlags().StringVarP(&url, "address", "a", "http://localhost:8080", "host name and port of the server")
+	cmd.PersistentFlags().StringVarP(&token, "token", "t", "", "token to authenticate with the server")
+	cmd.PersistentFlags().StringVarP(&tokenFile, "token-file", "", "", "file containing the token to authenticate with the server")
+	cmd.PersistentFlags().StringVarP(&user, "user", "u", "", "user name to authenticate with the server instead of the token")
+	cmd.PersistentFlags().StringVarP(&passwordFile, "password-file", "", "", "file containing the password of --user")
+	cmd.PersistentFlags().StringVarP(&caCert, "ca-cert", "", "", "file with PEM encoded CA certificates to trust besides the system ones")
+	cmd.PersistentFlags().BoolVarP(&insecure, "insecure", "k", false, "do not verify the TLS certificate of the server (dangerous)")
+	cmd.PersistentFlags().BoolVarP(&verbose, "verbose", "v", false, "more messages during the build")
+	cmd.PersistentFlags().BoolVarP(&noWait, "no-wait", "", false, "start maintenance jobs without waiting for them to finish")
+
+	cmd.AddCommand(
+		listCmd,
+		cancelCmd,
+		frozenCmd,
+		freezeCmd,
+		unfreezeCmd,
+		jobCmd(receiver.JobPrune, "Prune the repository"),
+		jobCmd(receiver.JobFsck, "Verify the objects of the published commits"),
+		jobCmd(receiver.JobSummary, "Regenerate the summary"),
+		jobsCmd,
+		revokeCmd,
+		historyCmd,
+		reportCmd,
+	)
 
 	return cmd
 }
@@ -193,14 +1397,29 @@ func pushCmd() *cobra.Command {
 func Execute() error {
 	// Root command
 	var rootCmd = &cobra.Command{
-		Use:   "ostree-upload",
-		Short: "Transfer local OSTree objects to a remote repository",
+		Use:     "ostree-upload",
+		Short:   "Transfer local OSTree objects to a remote repository",
+		Version: common.Version,
 	}
 
 	rootCmd.AddCommand(
 		genTokenCmd(),
+		genKeyCmd(),
+		addUserCmd(),
+		initCmd(),
 		receiveCmd(),
+		exportHistoryCmd(),
 		pushCmd(),
+		preflightCmd(),
+		doctorCmd(),
+		logCmd(),
+		compareCmd(),
+		remoteRefsCmd(),
+		sessionsCmd(),
+		adminCmd(),
+		loginCmd(),
+		logoutCmd(),
+		manifestKeyCmd(),
 	)
 
 	return rootCmd.Execute()
@@ -0,0 +1,144 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package cmd
+
+import (
+	"crypto/rand"
+	"fmt"
+	"io/ioutil"
+	"net"
+	"net/http"
+	"os"
+	"path/filepath"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+	"github.com/lirios/ostree-upload/internal/push"
+	"github.com/lirios/ostree-upload/internal/receiver"
+)
+
+// Branch pushed by the self-test
+const selfTestBranch = "ostree-upload/self-test"
+
+// runSelfTest pushes a generated commit, through the same code of the
+// push command, to a server listening on a random local port and serving
+// a temporary repository, then checks that the commit was published
+func runSelfTest() error {
+	dir, err := ioutil.TempDir("", "ostree-upload-self-test-")
+	if err != nil {
+		return err
+	}
+	defer os.RemoveAll(dir)
+
+	// Server repository, verifying everything it receives
+	logger.Action("Creating the server repository...")
+	serverRepoPath := filepath.Join(dir, "server")
+	configPath := filepath.Join(dir, "ostree-upload.yaml")
+	if _, err := receiver.InitRepository(serverRepoPath, configPath, receiver.InitOptions{}); err != nil {
+		return err
+	}
+	config, err := receiver.CreateConfig(configPath)
+	if err != nil {
+		return fmt.Errorf("cannot create configuration file: %v", err)
+	}
+	token, err := receiver.GenerateToken()
+	if err != nil {
+		return fmt.Errorf("failed to generate token: %v", err)
+	}
+	token.Subject = "self-test"
+	config.Tokens = append(config.Tokens, token)
+	config.VerificationLevel = receiver.VerifyFull
+	if err := config.Save(); err != nil {
+		return fmt.Errorf("cannot save configuration file: %v", err)
+	}
+
+	// Client repository with a commit of a couple of files
+	logger.Action("Creating the client repository...")
+	clientRepoPath := filepath.Join(dir, "client")
+	clientRepo, err := ostree.CreateRepo(clientRepoPath)
+	if err != nil {
+		return fmt.Errorf("failed to create OSTree repository: %v", err)
+	}
+	treePath := filepath.Join(dir, "tree")
+	if err := writeSelfTestTree(treePath); err != nil {
+		return fmt.Errorf("failed to create the files to commit: %v", err)
+	}
+	rev, err := clientRepo.CommitDirectory(selfTestBranch, treePath, "ostree-upload self-test")
+	if err != nil {
+		return fmt.Errorf("failed to commit: %v", err)
+	}
+	logger.Infof("Committed %s", rev)
+
+	// Server on a random local port
+	logger.Action("Starting the server...")
+	appState, err := receiver.NewAppState(serverRepoPath, configPath)
+	if err != nil {
+		return err
+	}
+	listener, err := net.Listen("tcp", "127.0.0.1:0")
+	if err != nil {
+		return err
+	}
+	server := &http.Server{Handler: receiver.NewHandler(appState)}
+	go server.Serve(listener)
+	defer server.Close()
+
+	// Push like the push command does
+	opts := push.Options{
+		URL:             fmt.Sprintf("http://%s", listener.Addr()),
+		Token:           token.Token,
+		RepoPath:        clientRepoPath,
+		Branches:        []string{selfTestBranch},
+		UploadAttempts:  1,
+		Jobs:            1,
+		BatchObjects:    64,
+		CacheMaxAge:     time.Minute,
+		ChunkSize:       64 * 1024 * 1024,
+		InventoryFPRate: 0.01,
+	}
+	if err := push.StartClient(opts); err != nil {
+		return fmt.Errorf("push failed: %v", err)
+	}
+
+	// The branch must point to the complete commit
+	logger.Action("Checking the published commit...")
+	published, err := appState.Repo.ResolveRev(selfTestBranch)
+	if err != nil {
+		return fmt.Errorf("branch was not published: %v", err)
+	}
+	if published != rev {
+		return fmt.Errorf("branch was published at %s instead of %s", published, rev)
+	}
+	objectNames, err := appState.Repo.TraverseCommit(rev, 0)
+	if err != nil {
+		return fmt.Errorf("published commit is incomplete: %v", err)
+	}
+	for _, objectName := range objectNames {
+		if err := appState.Repo.FsckObject(objectName); err != nil {
+			return fmt.Errorf("object %s is corrupted: %v", objectName, err)
+		}
+	}
+
+	return nil
+}
+
+// writeSelfTestTree creates the files committed by the self-test:
+// a small text file and a random one, so that each run pushes new objects
+func writeSelfTestTree(path string) error {
+	docPath := filepath.Join(path, "usr", "share", "doc", "ostree-upload")
+	if err := os.MkdirAll(docPath, 0755); err != nil {
+		return err
+	}
+	if err := ioutil.WriteFile(filepath.Join(docPath, "self-test.txt"), []byte("ostree-upload self-test\n"), 0644); err != nil {
+		return err
+	}
+
+	data := make([]byte, 256*1024)
+	if _, err := rand.Read(data); err != nil {
+		return err
+	}
+	return ioutil.WriteFile(filepath.Join(docPath, "random.bin"), data, 0644)
+}
@@ -0,0 +1,314 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package common
+
+import (
+	"encoding/binary"
+	"hash"
+	"math/bits"
+	"runtime"
+	"sync"
+)
+
+// BLAKE3 hashing, following the reference implementation: the input is split
+// in chunks that are hashed independently and then merged in a binary tree,
+// so that big inputs are hashed on all the CPUs
+
+const (
+	blake3OutLen   = 32
+	blake3BlockLen = 64
+	blake3ChunkLen = 1024
+
+	// Chunks hashed by each goroutine when the input is big enough
+	blake3ChunksPerWorker = 64
+)
+
+// Domain separation flags
+const (
+	blake3ChunkStart = 1 << 0
+	blake3ChunkEnd   = 1 << 1
+	blake3Parent     = 1 << 2
+	blake3Root       = 1 << 3
+)
+
+var blake3IV = [8]uint32{
+	0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A,
+	0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19,
+}
+
+var blake3MsgPermutation = [16]int{2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8}
+
+// blake3G is the quarter-round, mixing a column or a diagonal of the state
+func blake3G(state *[16]uint32, a, b, c, d int, mx, my uint32) {
+	state[a] = state[a] + state[b] + mx
+	state[d] = bits.RotateLeft32(state[d]^state[a], -16)
+	state[c] = state[c] + state[d]
+	state[b] = bits.RotateLeft32(state[b]^state[c], -12)
+	state[a] = state[a] + state[b] + my
+	state[d] = bits.RotateLeft32(state[d]^state[a], -8)
+	state[c] = state[c] + state[d]
+	state[b] = bits.RotateLeft32(state[b]^state[c], -7)
+}
+
+func blake3Round(state *[16]uint32, m *[16]uint32) {
+	// Mix the columns
+	blake3G(state, 0, 4, 8, 12, m[0], m[1])
+	blake3G(state, 1, 5, 9, 13, m[2], m[3])
+	blake3G(state, 2, 6, 10, 14, m[4], m[5])
+	blake3G(state, 3, 7, 11, 15, m[6], m[7])
+	// Mix the diagonals
+	blake3G(state, 0, 5, 10, 15, m[8], m[9])
+	blake3G(state, 1, 6, 11, 12, m[10], m[11])
+	blake3G(state, 2, 7, 8, 13, m[12], m[13])
+	blake3G(state, 3, 4, 9, 14, m[14], m[15])
+}
+
+func blake3Permute(m *[16]uint32) {
+	var permuted [16]uint32
+	for i := range permuted {
+		permuted[i] = m[blake3MsgPermutation[i]]
+	}
+	*m = permuted
+}
+
+func blake3Compress(cv *[8]uint32, block *[16]uint32, counter uint64, blockLen, flags uint32) [16]uint32 {
+	state := [16]uint32{
+		cv[0], cv[1], cv[2], cv[3], cv[4], cv[5], cv[6], cv[7],
+		blake3IV[0], blake3IV[1], blake3IV[2], blake3IV[3],
+		uint32(counter), uint32(counter >> 32), blockLen, flags,
+	}
+	m := *block
+
+	for i := 0; i < 7; i++ {
+		blake3Round(&state, &m)
+		if i < 6 {
+			blake3Permute(&m)
+		}
+	}
+
+	for i := 0; i < 8; i++ {
+		state[i] ^= state[i+8]
+		state[i+8] ^= cv[i]
+	}
+	return state
+}
+
+func blake3First8Words(state [16]uint32) [8]uint32 {
+	var words [8]uint32
+	copy(words[:], state[:8])
+	return words
+}
+
+func blake3BlockWords(block []byte) [16]uint32 {
+	var words [16]uint32
+	for i := range words {
+		words[i] = binary.LittleEndian.Uint32(block[4*i:])
+	}
+	return words
+}
+
+// blake3Output is a node of the tree that was not compressed yet,
+// because it might be the root
+type blake3Output struct {
+	inputCV    [8]uint32
+	blockWords [16]uint32
+	counter    uint64
+	blockLen   uint32
+	flags      uint32
+}
+
+func (o blake3Output) chainingValue() [8]uint32 {
+	return blake3First8Words(blake3Compress(&o.inputCV, &o.blockWords, o.counter, o.blockLen, o.flags))
+}
+
+func (o blake3Output) rootBytes() []byte {
+	words := blake3Compress(&o.inputCV, &o.blockWords, 0, o.blockLen, o.flags|blake3Root)
+	out := make([]byte, blake3OutLen)
+	for i := 0; i < blake3OutLen/4; i++ {
+		binary.LittleEndian.PutUint32(out[4*i:], words[i])
+	}
+	return out
+}
+
+func blake3ParentOutput(left, right [8]uint32, key [8]uint32, flags uint32) blake3Output {
+	o := blake3Output{inputCV: key, blockLen: blake3BlockLen, flags: blake3Parent | flags}
+	copy(o.blockWords[:8], left[:])
+	copy(o.blockWords[8:], right[:])
+	return o
+}
+
+// blake3ChunkState hashes the blocks of a chunk
+type blake3ChunkState struct {
+	cv               [8]uint32
+	chunkCounter     uint64
+	block            [blake3BlockLen]byte
+	blockLen         int
+	blocksCompressed int
+	flags            uint32
+}
+
+func newBlake3ChunkState(key [8]uint32, chunkCounter uint64, flags uint32) blake3ChunkState {
+	return blake3ChunkState{cv: key, chunkCounter: chunkCounter, flags: flags}
+}
+
+func (c *blake3ChunkState) len() int {
+	return blake3BlockLen*c.blocksCompressed + c.blockLen
+}
+
+func (c *blake3ChunkState) startFlag() uint32 {
+	if c.blocksCompressed == 0 {
+		return blake3ChunkStart
+	}
+	return 0
+}
+
+func (c *blake3ChunkState) update(input []byte) {
+	for len(input) > 0 {
+		// The last block is compressed by output(), with the end flag
+		if c.blockLen == blake3BlockLen {
+			words := blake3BlockWords(c.block[:])
+			c.cv = blake3First8Words(blake3Compress(&c.cv, &words, c.chunkCounter, blake3BlockLen, c.flags|c.startFlag()))
+			c.blocksCompressed++
+			c.block = [blake3BlockLen]byte{}
+			c.blockLen = 0
+		}
+
+		n := copy(c.block[c.blockLen:], input)
+		c.blockLen += n
+		input = input[n:]
+	}
+}
+
+func (c *blake3ChunkState) output() blake3Output {
+	return blake3Output{
+		inputCV:    c.cv,
+		blockWords: blake3BlockWords(c.block[:]),
+		counter:    c.chunkCounter,
+		blockLen:   uint32(c.blockLen),
+		flags:      c.flags | c.startFlag() | blake3ChunkEnd,
+	}
+}
+
+// blake3Hasher calculates the BLAKE3 digest, with a 32 bytes output
+type blake3Hasher struct {
+	chunk      blake3ChunkState
+	key        [8]uint32
+	cvStack    [54][8]uint32
+	cvStackLen int
+	flags      uint32
+}
+
+// NewBLAKE3 returns a hash.Hash calculating the BLAKE3 checksum
+func NewBLAKE3() hash.Hash {
+	h := &blake3Hasher{key: blake3IV}
+	h.Reset()
+	return h
+}
+
+// Reset resets the hasher to its initial state
+func (h *blake3Hasher) Reset() {
+	h.chunk = newBlake3ChunkState(h.key, 0, h.flags)
+	h.cvStackLen = 0
+}
+
+// Size returns the number of bytes of the checksum
+func (h *blake3Hasher) Size() int {
+	return blake3OutLen
+}
+
+// BlockSize returns the size of the blocks
+func (h *blake3Hasher) BlockSize() int {
+	return blake3BlockLen
+}
+
+// addChunkChainingValue pushes the chaining value of a chunk, merging
+// the complete subtrees: there are as many as the trailing zero bits
+// of the number of chunks so far
+func (h *blake3Hasher) addChunkChainingValue(cv [8]uint32, totalChunks uint64) {
+	for totalChunks&1 == 0 {
+		h.cvStackLen--
+		cv = blake3ParentOutput(h.cvStack[h.cvStackLen], cv, h.key, h.flags).chainingValue()
+		totalChunks >>= 1
+	}
+	h.cvStack[h.cvStackLen] = cv
+	h.cvStackLen++
+}
+
+// addChunks hashes whole chunks at the same time, the chunk state must be empty
+func (h *blake3Hasher) addChunks(input []byte) {
+	counter := h.chunk.chunkCounter
+	cvs := make([][8]uint32, len(input)/blake3ChunkLen)
+
+	var wg sync.WaitGroup
+	for first := 0; first < len(cvs); first += blake3ChunksPerWorker {
+		last := first + blake3ChunksPerWorker
+		if last > len(cvs) {
+			last = len(cvs)
+		}
+		wg.Add(1)
+		go func(first, last int) {
+			defer wg.Done()
+			for i := first; i < last; i++ {
+				chunk := newBlake3ChunkState(h.key, counter+uint64(i), h.flags)
+				chunk.update(input[i*blake3ChunkLen : (i+1)*blake3ChunkLen])
+				output := chunk.output()
+				cvs[i] = output.chainingValue()
+			}
+		}(first, last)
+	}
+	wg.Wait()
+
+	for i, cv := range cvs {
+		h.addChunkChainingValue(cv, counter+uint64(i)+1)
+	}
+	h.chunk = newBlake3ChunkState(h.key, counter+uint64(len(cvs)), h.flags)
+}
+
+// Write adds more data to the running hash, it never returns an error
+func (h *blake3Hasher) Write(p []byte) (int, error) {
+	written := len(p)
+	batch := blake3ChunksPerWorker * runtime.NumCPU() * blake3ChunkLen
+
+	for len(p) > 0 {
+		// A complete chunk is merged only when more input arrives,
+		// because the last one is the root when there is only one
+		if h.chunk.len() == blake3ChunkLen {
+			output := h.chunk.output()
+			totalChunks := h.chunk.chunkCounter + 1
+			h.addChunkChainingValue(output.chainingValue(), totalChunks)
+			h.chunk = newBlake3ChunkState(h.key, totalChunks, h.flags)
+		}
+
+		// Big inputs are split among goroutines, keeping at least a byte
+		// for the last chunk
+		if h.chunk.len() == 0 && len(p) > 2*blake3ChunksPerWorker*blake3ChunkLen {
+			n := (len(p) - 1) / blake3ChunkLen * blake3ChunkLen
+			if n > batch {
+				n = batch
+			}
+			h.addChunks(p[:n])
+			p = p[n:]
+			continue
+		}
+
+		n := blake3ChunkLen - h.chunk.len()
+		if n > len(p) {
+			n = len(p)
+		}
+		h.chunk.update(p[:n])
+		p = p[n:]
+	}
+
+	return written, nil
+}
+
+// Sum appends the checksum to b, without changing the state of the hasher
+func (h *blake3Hasher) Sum(b []byte) []byte {
+	output := h.chunk.output()
+	for i := h.cvStackLen - 1; i >= 0; i-- {
+		output = blake3ParentOutput(h.cvStack[i], output.chainingValue(), h.key, h.flags)
+	}
+	return append(b, output.rootBytes()...)
+}
@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package common
+
+import (
+	"encoding/hex"
+	"testing"
+)
+
+// blake3Vectors are the digests of inputs made of the sequence 0, 1, ...,
+// 250, 0, 1, ... of the given length: the official test vectors up to
+// 102400 bytes, around chunk boundaries, then longer ones calculated with
+// the reference implementation, which are hashed by several goroutines
+// when they are written at once
+var blake3Vectors = []struct {
+	length int
+	digest string
+}{
+	{0, "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"},
+	{1, "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"},
+	{1023, "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11"},
+	{1024, "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7"},
+	{1025, "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"},
+	{2048, "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a"},
+	{2049, "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030"},
+	{3072, "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2"},
+	{3073, "7124b49501012f81cc7f11ca069ec9226cecb8a2c850cfe644e327d22d3e1cd3"},
+	{4096, "015094013f57a5277b59d8475c0501042c0b642e531b0a1c8f58d2163229e969"},
+	{4097, "9b4052b38f1c5fc8b1f9ff7ac7b27cd242487b3d890d15c96a1c25b8aa0fb995"},
+	{5120, "9cadc15fed8b5d854562b26a9536d9707cadeda9b143978f319ab34230535833"},
+	{5121, "628bd2cb2004694adaab7bbd778a25df25c47b9d4155a55f8fbd79f2fe154cff"},
+	{6144, "3e2e5b74e048f3add6d21faab3f83aa44d3b2278afb83b80b3c35164ebeca205"},
+	{6145, "f1323a8631446cc50536a9f705ee5cb619424d46887f3c376c695b70e0f0507f"},
+	{7168, "61da957ec2499a95d6b8023e2b0e604ec7f6b50e80a9678b89d2628e99ada77a"},
+	{7169, "a003fc7a51754a9b3c7fae0367ab3d782dccf28855a03d435f8cfe74605e7817"},
+	{8192, "aae792484c8efe4f19e2ca7d371d8c467ffb10748d8a5a1ae579948f718a2a63"},
+	{8193, "bab6c09cb8ce8cf459261398d2e7aef35700bf488116ceb94a36d0f5f1b7bc3b"},
+	{16384, "f875d6646de28985646f34ee13be9a576fd515f76b5b0a26bb324735041ddde4"},
+	{31744, "62b6960e1a44bcc1eb1a611a8d6235b6b4b78f32e7abc4fb4c6cdcce94895c47"},
+	{102400, "bc3e3d41a1146b069abffad3c0d44860cf664390afce4d9661f7902e7943e085"},
+	{131072, "306baba93b1a393cbd35172837c98b0f59a41f64e1b2682ae102d8b2534b9e1c"},
+	{131073, "f837d4254d24ba3d50fe3743d46e4af6db5f5d6ab0469197d94e7ba1e906c4d8"},
+	{262145, "531c319935cf78f34869faebd865e5748266b1799039103bfb851a680d9ed30c"},
+	{1048577, "2f053cd7472cf0cd2f9adaf45c1180255b91b9a865404a63671a0ee5f792ed33"},
+}
+
+// blake3Input returns the input of the test vectors
+func blake3Input(length int) []byte {
+	input := make([]byte, length)
+	for i := range input {
+		input[i] = byte(i % 251)
+	}
+	return input
+}
+
+func TestBLAKE3KnownAnswers(t *testing.T) {
+	for _, vector := range blake3Vectors {
+		h := NewBLAKE3()
+		h.Write(blake3Input(vector.length))
+		if digest := hex.EncodeToString(h.Sum(nil)); digest != vector.digest {
+			t.Errorf("BLAKE3 of %d bytes is %s, want %s", vector.length, digest, vector.digest)
+		}
+	}
+}
+
+func TestBLAKE3Writes(t *testing.T) {
+	// Pieces smaller than a block, not aligned to blocks or chunks
+	// and bigger than the parallel threshold
+	for _, size := range []int{1, 63, 1000, 1025, 200000} {
+		for _, vector := range blake3Vectors {
+			input := blake3Input(vector.length)
+			h := NewBLAKE3()
+			for len(input) > 0 {
+				n := size
+				if n > len(input) {
+					n = len(input)
+				}
+				h.Write(input[:n])
+				input = input[n:]
+			}
+			if digest := hex.EncodeToString(h.Sum(nil)); digest != vector.digest {
+				t.Errorf("BLAKE3 of %d bytes written %d at a time is %s, want %s", vector.length, size, digest, vector.digest)
+			}
+		}
+	}
+}
+
+func TestBLAKE3SumKeepsState(t *testing.T) {
+	vector := blake3Vectors[len(blake3Vectors)-1]
+	input := blake3Input(vector.length)
+
+	h := NewBLAKE3()
+	h.Write(input[:vector.length/2])
+	prefix := []byte("prefix")
+	h.Sum(prefix)
+	h.Write(input[vector.length/2:])
+
+	sum := h.Sum(prefix)
+	if string(sum[:len(prefix)]) != "prefix" {
+		t.Errorf("Sum didn't append to its argument")
+	}
+	if digest := hex.EncodeToString(sum[len(prefix):]); digest != vector.digest {
+		t.Errorf("BLAKE3 after an intermediate Sum is %s, want %s", digest, vector.digest)
+	}
+}
+
+func TestBLAKE3Reset(t *testing.T) {
+	h := NewBLAKE3()
+	h.Write(blake3Input(4097))
+	h.Reset()
+	h.Write([]byte("abc"))
+
+	want := "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+	if digest := hex.EncodeToString(h.Sum(nil)); digest != want {
+		t.Errorf("BLAKE3 of \"abc\" after Reset is %s, want %s", digest, want)
+	}
+}
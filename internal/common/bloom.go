@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package common
+
+import (
+	"crypto/sha256"
+	"encoding/binary"
+	"math"
+)
+
+// BloomFilter tells whether an object name might be in a set: it never
+// misses a name that was added, but might report names that were not
+type BloomFilter struct {
+	Bits   []byte `json:"bits"`
+	Hashes int    `json:"hashes"`
+}
+
+// NewBloomFilter creates an empty filter sized for count names with
+// the false positive rate
+func NewBloomFilter(count int, fpRate float64) *BloomFilter {
+	if count < 1 {
+		count = 1
+	}
+	bits := math.Ceil(-float64(count) * math.Log(fpRate) / (math.Ln2 * math.Ln2))
+	hashes := int(math.Round(bits / float64(count) * math.Ln2))
+	if hashes < 1 {
+		hashes = 1
+	}
+
+	return &BloomFilter{Bits: make([]byte, (int(bits)+7)/8), Hashes: hashes}
+}
+
+// positions returns the bits of name, with double hashing
+func (f *BloomFilter) positions(name string) []uint64 {
+	sum := sha256.Sum256([]byte(name))
+	h1 := binary.BigEndian.Uint64(sum[0:8])
+	h2 := binary.BigEndian.Uint64(sum[8:16])
+	size := uint64(len(f.Bits)) * 8
+
+	positions := make([]uint64, f.Hashes)
+	for i := range positions {
+		positions[i] = (h1 + uint64(i)*h2) % size
+	}
+	return positions
+}
+
+// Add adds the name to the set
+func (f *BloomFilter) Add(name string) {
+	for _, pos := range f.positions(name) {
+		f.Bits[pos/8] |= 1 << (pos % 8)
+	}
+}
+
+// MayContain returns false if the name is surely not in the set
+func (f *BloomFilter) MayContain(name string) bool {
+	if len(f.Bits) == 0 {
+		return false
+	}
+	for _, pos := range f.positions(name) {
+		if f.Bits[pos/8]&(1<<(pos%8)) == 0 {
+			return false
+		}
+	}
+	return true
+}
@@ -4,6 +4,74 @@
 
 package common
 
+import (
+	"encoding/json"
+	"net/http"
+	"strings"
+	"time"
+)
+
+// SignatureHeader is the HTTP header carrying the signature of the reply
+const SignatureHeader = "X-Ostree-Upload-Signature"
+
+// SignatureTimeHeader is the HTTP header carrying the Unix time at which
+// the server signed the reply
+const SignatureTimeHeader = "X-Ostree-Upload-Signature-Time"
+
+// NonceHeader is the HTTP header carrying a random value chosen by the
+// client for each request, which the server signs along with the reply
+const NonceHeader = "X-Ostree-Upload-Nonce"
+
+// SignedReply returns what the server signs for a reply: the body bound
+// to the request it answers, the nonce of the client and the signing
+// time, so that a signed reply cannot be replayed for another request
+func SignedReply(method, path, nonce, timestamp string, body []byte) []byte {
+	payload := strings.Join([]string{"ostree-upload-reply-v1", method, path, nonce, timestamp}, "\n") + "\n"
+	return append([]byte(payload), body...)
+}
+
+// ChecksumHeader is the HTTP header carrying the checksum of an object
+// uploaded in chunks, calculated by the client
+const ChecksumHeader = "X-Ostree-Upload-Checksum"
+
+// IdempotencyKeyHeader is the HTTP header with the key of a request that
+// can be retried safely, the server replays the reply of the first one
+const IdempotencyKeyHeader = "Idempotency-Key"
+
+// IdempotentReplayHeader is set by the server on replayed replies
+const IdempotentReplayHeader = "Idempotent-Replayed"
+
+// FeatureUploadPack is advertised by servers that receive all the
+// objects of a push as a single tar stream
+const FeatureUploadPack = "upload-pack"
+
+// FeatureStaticDeltaUpload is advertised by servers that apply static
+// deltas generated by the client
+const FeatureStaticDeltaUpload = "static-delta-upload"
+
+// FeatureSessionAbort is advertised by servers that remove the staged
+// objects of a session aborted with DELETE /api/v1/session/{id}
+const FeatureSessionAbort = "session-abort"
+
+// FeatureUploadRedirect is advertised by servers that reply to
+// missing_objects with upload targets, where the objects are uploaded
+// before asking the server to fetch them
+const FeatureUploadRedirect = "upload-redirect"
+
+// Algorithms of the checksums of the objects, SHA-256 is assumed
+// when a peer doesn't tell which one it uses
+const (
+	ChecksumSHA256 = "sha256"
+	ChecksumBLAKE3 = "blake3"
+)
+
+// ChecksumAlgorithms are the supported algorithms, the preferred first
+var ChecksumAlgorithms = []string{ChecksumBLAKE3, ChecksumSHA256}
+
+// PackIndexName is the name of the last entry of an upload pack,
+// a PackIndex with the checksums of the objects before it
+const PackIndexName = "ostree-upload-index.json"
+
 // RevisionPair is a pair of revisions
 type RevisionPair struct {
 	Server string `json:"server"`
@@ -23,14 +91,68 @@ type Objects map[string]Object
 
 // InfoResponse contains OSTree repository information
 type InfoResponse struct {
-	Mode string            `json:"mode"`
-	Revs map[string]string `json:"revs"`
+	Mode               string            `json:"mode"`
+	Revs               map[string]string `json:"revs"`
+	Mirrors            map[string]string `json:"mirrors,omitempty"`
+	OstreeVersion      string            `json:"ostree_version"`
+	Capabilities       []string          `json:"capabilities"`
+	CollectionID       string            `json:"collection_id,omitempty"`
+	Options            map[string]string `json:"options,omitempty"`
+	ServerVersion      string            `json:"server_version"`
+	ProtocolVersion    int               `json:"protocol_version"`
+	Features           []string          `json:"features"`
+	VerificationLevel  string            `json:"verification_level,omitempty"`
+	Warnings           []string          `json:"warnings,omitempty"`
+	ChecksumAlgorithms []string          `json:"checksum_algorithms,omitempty"`
+}
+
+// AllRevs returns the revisions of both plain and mirrored refs
+func (i *InfoResponse) AllRevs() map[string]string {
+	revs := map[string]string{}
+	for branch, rev := range i.Revs {
+		revs[branch] = rev
+	}
+	for branch, rev := range i.Mirrors {
+		revs[branch] = rev
+	}
+	return revs
+}
+
+// HasCapability returns true if the server supports the capability
+func (i *InfoResponse) HasCapability(name string) bool {
+	for _, capability := range i.Capabilities {
+		if capability == name {
+			return true
+		}
+	}
+
+	return false
 }
 
 // QueueRequest contains local and remote branch revision
 type QueueRequest struct {
-	Refs    map[string]RevisionPair `json:"refs"`
-	Objects []string                `json:"objects"`
+	Refs              map[string]RevisionPair `json:"refs"`
+	Objects           []string                `json:"objects"`
+	Manifest          *PushManifest           `json:"manifest,omitempty"`
+	ManifestSignature string                  `json:"manifest_signature,omitempty"`
+	Force             bool                    `json:"force,omitempty"`
+	ChecksumAlgorithm string                  `json:"checksum_algorithm,omitempty"`
+}
+
+// PushManifest describes a push, signed by the client so that the server
+// can verify it independently of the transport and the token
+type PushManifest struct {
+	// Branches with the published and the pushed revisions
+	Refs map[string]RevisionPair `json:"refs"`
+	// Object names and their checksums
+	Objects map[string]string `json:"objects"`
+	// Algorithm of the checksums, SHA-256 when empty
+	ChecksumAlgorithm string `json:"checksum_algorithm,omitempty"`
+}
+
+// Payload returns what is signed, the JSON encoding with sorted keys
+func (m *PushManifest) Payload() ([]byte, error) {
+	return json.Marshal(m)
 }
 
 // UpdateResponse contains the update queue identifier
@@ -38,7 +160,223 @@ type UpdateResponse struct {
 	QueueID string `json:"id"`
 }
 
-// ObjectsResponse lists all missing objects
+// ObjectsRequest lists the objects of a session to check,
+// all of them when empty
+type ObjectsRequest struct {
+	Objects []string `json:"objects,omitempty"`
+}
+
+// ObjectsResponse lists all missing objects and, when the server redirects
+// uploads, where each of them is uploaded
 type ObjectsResponse struct {
-	Objects []string `json:"objects"`
+	Objects       []string                `json:"objects"`
+	UploadTargets map[string]UploadTarget `json:"upload_targets,omitempty"`
+}
+
+// UploadTarget is where an object is uploaded instead of the server,
+// such as a pre-signed URL of a bucket; no credentials are sent to it
+type UploadTarget struct {
+	URL    string            `json:"url"`
+	Method string            `json:"method"`
+	Header map[string]string `json:"header,omitempty"`
+}
+
+// FetchRequest asks the server to fetch the objects uploaded to their
+// targets, with the checksums calculated by the client
+type FetchRequest struct {
+	Checksums map[string]string `json:"checksums"`
+}
+
+// ObjectReceipt confirms that the server stored an object, the checksum
+// is calculated by the server and is empty if verification is disabled
+type ObjectReceipt struct {
+	ObjectName string `json:"object"`
+	Checksum   string `json:"checksum,omitempty"`
+	Size       int64  `json:"size"`
+	ReceiptID  string `json:"receipt"`
+}
+
+// UploadResponse contains the receipts of the objects that were uploaded
+type UploadResponse struct {
+	Receipts []ObjectReceipt `json:"receipts"`
+}
+
+// ChunkResponse tells how many bytes of an object uploaded in chunks
+// the server received and, once all of them are, the receipt
+type ChunkResponse struct {
+	Offset  int64          `json:"offset"`
+	Receipt *ObjectReceipt `json:"receipt,omitempty"`
+}
+
+// PackIndex lists the checksums, calculated by the client, of the objects of
+// an upload pack
+type PackIndex struct {
+	Checksums map[string]string `json:"checksums"`
+}
+
+// DoneRequest lists the receipts of the objects uploaded by the client,
+// publishing fails if they don't match what the server received or if
+// the branches are not at the expected revisions anymore
+type DoneRequest struct {
+	Receipts []ObjectReceipt   `json:"receipts,omitempty"`
+	Expected map[string]string `json:"expected,omitempty"`
+}
+
+// RefChange describes how a branch changed over a period of time
+type RefChange struct {
+	From string    `json:"from"`
+	To   string    `json:"to"`
+	Time time.Time `json:"time"`
+}
+
+// SummaryDiffResponse lists the branches changed since a point in time
+type SummaryDiffResponse struct {
+	Refs map[string]RefChange `json:"refs"`
+}
+
+// PreflightRequest contains the branch transitions to check
+type PreflightRequest struct {
+	Refs  map[string]RevisionPair `json:"refs"`
+	Force bool                    `json:"force,omitempty"`
+}
+
+// PreflightResult tells whether a branch can be updated and, if not, why
+type PreflightResult struct {
+	Pass    bool     `json:"pass"`
+	Reasons []string `json:"reasons,omitempty"`
+}
+
+// PreflightResponse contains the results of the preflight checks
+type PreflightResponse struct {
+	Pass      bool                       `json:"pass"`
+	Reasons   []string                   `json:"reasons,omitempty"`
+	Refs      map[string]PreflightResult `json:"refs"`
+	FreeSpace uint64                     `json:"free_space"`
+}
+
+// SessionInfo describes a queue entry
+type SessionInfo struct {
+	ID              string                  `json:"id"`
+	Refs            map[string]RevisionPair `json:"refs"`
+	Objects         int                     `json:"objects"`
+	Created         time.Time               `json:"created"`
+	Publishing      bool                    `json:"publishing"`
+	PendingApproval bool                    `json:"pending_approval"`
+}
+
+// SessionsResponse lists the queue entries
+type SessionsResponse struct {
+	Sessions []SessionInfo `json:"sessions"`
+}
+
+// TokenInfoResponse describes the credentials that authenticated the request
+type TokenInfoResponse struct {
+	Kind     string     `json:"kind"`
+	Subject  string     `json:"subject,omitempty"`
+	Scopes   []string   `json:"scopes,omitempty"`
+	NoScopes bool       `json:"no_scopes,omitempty"`
+	Refs     []string   `json:"refs,omitempty"`
+	Expires  *time.Time `json:"expires,omitempty"`
+}
+
+// Formats of the object inventory
+const (
+	InventoryList  = "list"
+	InventoryBloom = "bloom"
+)
+
+// InventoryResponse describes the objects the server already has, either
+// as the sorted list of their names or as a Bloom filter
+type InventoryResponse struct {
+	Count   int          `json:"count"`
+	Objects []string     `json:"objects,omitempty"`
+	Bloom   *BloomFilter `json:"bloom,omitempty"`
+}
+
+// StatusResponse describes the storage of the receiver
+type StatusResponse struct {
+	FreeSpace     uint64   `json:"free_space"`
+	ReservedSpace uint64   `json:"reserved_space"`
+	Headroom      int64    `json:"headroom"`
+	TempSize      int64    `json:"temp_size"`
+	Sessions      int      `json:"sessions"`
+	Warnings      []string `json:"warnings,omitempty"`
+}
+
+// DoneResponse is the receipt sent when the branches are published
+type DoneResponse struct {
+	QueueID           string            `json:"id"`
+	Revs              map[string]string `json:"revs"`
+	Pending           bool              `json:"pending,omitempty"`
+	Expires           *time.Time        `json:"expires,omitempty"`
+	Transfers         map[string]int    `json:"transfers,omitempty"`
+	VerificationLevel string            `json:"verification_level,omitempty"`
+}
+
+// FreezeRequest freezes or thaws branches, frozen branches cannot be pushed
+type FreezeRequest struct {
+	Branches []string `json:"branches"`
+	Frozen   bool     `json:"frozen"`
+}
+
+// FrozenResponse lists the frozen branches
+type FrozenResponse struct {
+	Branches []string `json:"branches"`
+}
+
+// RevokeRequest removes the tokens issued to a subject
+type RevokeRequest struct {
+	Subject string `json:"subject"`
+}
+
+// RevokeResponse tells how many tokens were revoked
+type RevokeResponse struct {
+	Revoked int `json:"revoked"`
+}
+
+// JobInfo describes a maintenance job running in the background
+type JobInfo struct {
+	ID       string     `json:"id"`
+	Kind     string     `json:"kind"`
+	Started  time.Time  `json:"started"`
+	Finished *time.Time `json:"finished,omitempty"`
+	Result   string     `json:"result,omitempty"`
+	Error    string     `json:"error,omitempty"`
+}
+
+// JobsResponse lists the last maintenance jobs
+type JobsResponse struct {
+	Jobs []JobInfo `json:"jobs"`
+}
+
+// HistoryRecord is a publish recorded in the history
+type HistoryRecord struct {
+	Time    time.Time               `json:"time"`
+	ID      string                  `json:"id"`
+	Refs    map[string]RevisionPair `json:"refs"`
+	KeyID   string                  `json:"key_id,omitempty"`
+	Objects int                     `json:"objects,omitempty"`
+	Bytes   int64                   `json:"bytes,omitempty"`
+}
+
+// HistoryResponse lists the last publishes
+type HistoryResponse struct {
+	Entries []HistoryRecord `json:"entries"`
+}
+
+// SocketRequest is an API request sent over the push socket,
+// its body follows in a binary message
+type SocketRequest struct {
+	ID     uint64      `json:"id"`
+	Method string      `json:"method"`
+	Path   string      `json:"path"`
+	Header http.Header `json:"header,omitempty"`
+}
+
+// SocketResponse is the reply to a SocketRequest,
+// its body follows in a binary message
+type SocketResponse struct {
+	ID     uint64      `json:"id"`
+	Status int         `json:"status"`
+	Header http.Header `json:"header,omitempty"`
 }
@@ -0,0 +1,100 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package common
+
+import (
+	"io"
+	"net"
+	"sync"
+	"time"
+)
+
+// streamAddr is the address of both ends of a StreamConn
+type streamAddr string
+
+// Network returns the name of the network
+func (a streamAddr) Network() string {
+	return "stdio"
+}
+
+// String returns the address
+func (a streamAddr) String() string {
+	return string(a)
+}
+
+// StreamConn is a connection over a pair of streams, such as the standard
+// input and output of a process, so that HTTP can be spoken over SSH
+type StreamConn struct {
+	reader  io.ReadCloser
+	writer  io.WriteCloser
+	name    string
+	onClose func() error
+
+	closeOnce sync.Once
+	closeErr  error
+	closed    chan struct{}
+}
+
+// NewStreamConn returns a connection reading from reader and writing to
+// writer, named after the other end; onClose, if not nil, is called once
+// both streams are closed
+func NewStreamConn(reader io.ReadCloser, writer io.WriteCloser, name string, onClose func() error) *StreamConn {
+	return &StreamConn{reader: reader, writer: writer, name: name, onClose: onClose, closed: make(chan struct{})}
+}
+
+// Read reads from the input stream
+func (c *StreamConn) Read(b []byte) (int, error) {
+	return c.reader.Read(b)
+}
+
+// Write writes to the output stream
+func (c *StreamConn) Write(b []byte) (int, error) {
+	return c.writer.Write(b)
+}
+
+// Close closes both streams, only the first call has an effect
+func (c *StreamConn) Close() error {
+	c.closeOnce.Do(func() {
+		c.closeErr = c.writer.Close()
+		c.reader.Close()
+		if c.onClose != nil {
+			if err := c.onClose(); err != nil && c.closeErr == nil {
+				c.closeErr = err
+			}
+		}
+		close(c.closed)
+	})
+	return c.closeErr
+}
+
+// Closed returns a channel that is closed with the connection
+func (c *StreamConn) Closed() <-chan struct{} {
+	return c.closed
+}
+
+// LocalAddr returns the address of this end
+func (c *StreamConn) LocalAddr() net.Addr {
+	return streamAddr("stdio")
+}
+
+// RemoteAddr returns the address of the other end
+func (c *StreamConn) RemoteAddr() net.Addr {
+	return streamAddr(c.name)
+}
+
+// SetDeadline does nothing, streams have no deadlines
+func (c *StreamConn) SetDeadline(t time.Time) error {
+	return nil
+}
+
+// SetReadDeadline does nothing, streams have no deadlines
+func (c *StreamConn) SetReadDeadline(t time.Time) error {
+	return nil
+}
+
+// SetWriteDeadline does nothing, streams have no deadlines
+func (c *StreamConn) SetWriteDeadline(t time.Time) error {
+	return nil
+}
@@ -7,23 +7,219 @@ package common
 import (
 	"crypto/sha256"
 	"fmt"
+	"hash"
 	"io"
 	"os"
+	"regexp"
+	"sort"
+	"strconv"
+	"strings"
+	"syscall"
+	"time"
 )
 
+// Files at least this big are memory-mapped to calculate their checksum,
+// when the caller allows it
+const mmapChecksumThreshold = 4 * 1024 * 1024
+
+// Size of the reads when a file is not memory-mapped
+const checksumBufferSize = 1024 * 1024
+
+// NewChecksumHasher returns the hasher of a checksum algorithm,
+// SHA-256 when the algorithm is empty
+func NewChecksumHasher(algorithm string) (hash.Hash, error) {
+	switch algorithm {
+	case "", ChecksumSHA256:
+		return sha256.New(), nil
+	case ChecksumBLAKE3:
+		return NewBLAKE3(), nil
+	}
+	return nil, fmt.Errorf("unsupported checksum algorithm %q", algorithm)
+}
+
 // CalculateChecksum calculates the SHA-256 checksum of the file and
 // returns the hex value
 func CalculateChecksum(path string) (string, error) {
+	return CalculateChecksumWith(path, ChecksumSHA256)
+}
+
+// CalculateChecksumWith calculates the checksum of the file with the
+// algorithm and returns the hex value, reading the file so that it's
+// safe even if somebody else truncates it meanwhile
+func CalculateChecksumWith(path, algorithm string) (string, error) {
+	return calculateChecksum(path, algorithm, false)
+}
+
+// CalculateMappedChecksumWith is like CalculateChecksumWith, but memory-maps
+// big files because read syscalls dominate the hashing time: it's only for
+// files that nobody else truncates, which would crash the process
+func CalculateMappedChecksumWith(path, algorithm string) (string, error) {
+	return calculateChecksum(path, algorithm, true)
+}
+
+// calculateChecksum calculates the checksum of the file, memory-mapping
+// it if mapped and it's big enough
+func calculateChecksum(path, algorithm string, mapped bool) (string, error) {
+	h, err := NewChecksumHasher(algorithm)
+	if err != nil {
+		return "", err
+	}
+
 	f, err := os.Open(path)
-	defer f.Close()
 	if err != nil {
 		return "", err
 	}
+	defer f.Close()
 
-	h := sha256.New()
-	if _, err := io.Copy(h, f); err != nil {
+	if mapped && mapFile(h, f) {
+		return fmt.Sprintf("%x", h.Sum(nil)), nil
+	}
+	if _, err := io.CopyBuffer(h, f, make([]byte, checksumBufferSize)); err != nil {
 		return "", err
 	}
 
 	return fmt.Sprintf("%x", h.Sum(nil)), nil
 }
+
+// mapFile writes the content of a big file to h through a memory mapping,
+// it returns false if the file is small or cannot be mapped
+func mapFile(h hash.Hash, f *os.File) bool {
+	info, err := f.Stat()
+	if err != nil || info.Size() < mmapChecksumThreshold || int64(int(info.Size())) != info.Size() {
+		return false
+	}
+
+	data, err := syscall.Mmap(int(f.Fd()), 0, int(info.Size()), syscall.PROT_READ, syscall.MAP_SHARED)
+	if err != nil {
+		return false
+	}
+	defer syscall.Munmap(data)
+	h.Write(data)
+
+	return true
+}
+
+// KeyID returns a short fingerprint of a public key, to tell keys apart in logs
+func KeyID(publicKey []byte) string {
+	sum := sha256.Sum256(publicKey)
+	return fmt.Sprintf("%x", sum[:8])
+}
+
+// Object names are a SHA-256 checksum followed by the object type
+var objectNameRegexp = regexp.MustCompile(`^[0-9a-f]{64}\.(commit|commitmeta|dirtree|dirmeta|file|filez)$`)
+
+// Revisions and checksums are hex encoded SHA-256 digests
+var checksumRegexp = regexp.MustCompile(`^[0-9a-f]{64}$`)
+
+// ValidateObjectName returns an error if name is not a valid OSTree object name,
+// names are used to build paths so they must never contain separators
+func ValidateObjectName(name string) error {
+	if !objectNameRegexp.MatchString(name) {
+		return fmt.Errorf("invalid object name %q", name)
+	}
+	return nil
+}
+
+// ValidateChecksum returns an error if value is not a hex encoded SHA-256 digest
+func ValidateChecksum(value string) error {
+	if !checksumRegexp.MatchString(value) {
+		return fmt.Errorf("invalid checksum %q", value)
+	}
+	return nil
+}
+
+// SortedBranches returns the branches of refs in lexical order, so that
+// they are always processed in the same order
+func SortedBranches(refs map[string]RevisionPair) []string {
+	branches := make([]string, 0, len(refs))
+	for branch := range refs {
+		branches = append(branches, branch)
+	}
+	sort.Strings(branches)
+	return branches
+}
+
+// SortedBranchNames returns the branches of a map of revisions in lexical order
+func SortedBranchNames(revs map[string]string) []string {
+	branches := make([]string, 0, len(revs))
+	for branch := range revs {
+		branches = append(branches, branch)
+	}
+	sort.Strings(branches)
+	return branches
+}
+
+// SortedObjectNames returns the object names in lexical order
+func SortedObjectNames(objects Objects) []string {
+	objectNames := make([]string, 0, len(objects))
+	for objectName := range objects {
+		objectNames = append(objectNames, objectName)
+	}
+	sort.Strings(objectNames)
+	return objectNames
+}
+
+// IsContentObject returns true if the object is a file rather than metadata
+func IsContentObject(objectName string) bool {
+	return strings.HasSuffix(objectName, ".file") || strings.HasSuffix(objectName, ".filez")
+}
+
+// UploadOrder returns the object names with commits and trees before the
+// files, each in lexical order, as servers only accept files referenced by
+// the metadata they already received
+func UploadOrder(objects Objects) []string {
+	objectNames := SortedObjectNames(objects)
+	sort.SliceStable(objectNames, func(i, j int) bool {
+		return !IsContentObject(objectNames[i]) && IsContentObject(objectNames[j])
+	})
+	return objectNames
+}
+
+// CompareVersions compares two version strings such as "2.1.0" part by part,
+// numerically when both parts are numbers, and returns -1, 0 or 1 when a is
+// respectively lower, equal or greater than b
+func CompareVersions(a, b string) int {
+	split := func(r rune) bool { return r == '.' || r == '-' || r == '+' || r == '~' }
+	partsA := strings.FieldsFunc(a, split)
+	partsB := strings.FieldsFunc(b, split)
+
+	for i := 0; i < len(partsA) || i < len(partsB); i++ {
+		// Missing parts are lower: 2.1 < 2.1.0
+		if i >= len(partsA) {
+			return -1
+		}
+		if i >= len(partsB) {
+			return 1
+		}
+
+		numA, errA := strconv.ParseUint(partsA[i], 10, 64)
+		numB, errB := strconv.ParseUint(partsB[i], 10, 64)
+		if errA == nil && errB == nil {
+			if numA < numB {
+				return -1
+			} else if numA > numB {
+				return 1
+			}
+			continue
+		}
+
+		if c := strings.Compare(partsA[i], partsB[i]); c != 0 {
+			return c
+		}
+	}
+
+	return 0
+}
+
+// ParseDuration is like time.ParseDuration but also accepts a number
+// of days, such as "30d"
+func ParseDuration(value string) (time.Duration, error) {
+	if strings.HasSuffix(value, "d") {
+		days, err := strconv.Atoi(strings.TrimSuffix(value, "d"))
+		if err != nil || days < 0 {
+			return 0, fmt.Errorf("invalid duration \"%s\"", value)
+		}
+		return time.Duration(days) * 24 * time.Hour, nil
+	}
+	return time.ParseDuration(value)
+}
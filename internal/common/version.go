@@ -0,0 +1,26 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package common
+
+import "strings"
+
+// Version of ostree-upload, set at build time with:
+// -ldflags "-X github.com/lirios/ostree-upload/internal/common.Version=x.y.z"
+var Version = "0.0.0"
+
+// ProtocolVersion is the version of the HTTP API spoken by client and server
+const ProtocolVersion = 1
+
+// ProtocolHeader is the HTTP header with the ProtocolVersion of the client,
+// uploads without it come from clients that expect them to publish the branches
+const ProtocolHeader = "X-Ostree-Upload-Protocol"
+
+// ServerHeader is the HTTP header identifying the server build
+const ServerHeader = "X-Ostree-Upload-Server"
+
+// MajorVersion returns the major component of a version string
+func MajorVersion(version string) string {
+	return strings.SplitN(strings.TrimPrefix(version, "v"), ".", 2)[0]
+}
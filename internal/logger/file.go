@@ -0,0 +1,123 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package logger
+
+import (
+	"fmt"
+	"log"
+	"os"
+	"sync"
+	"time"
+)
+
+// RotatingFile is a log file that is rotated when it grows too big or
+// too old, the rotated files are named after it with a number appended,
+// ".1" being the newest, and only the newest ones are kept
+type RotatingFile struct {
+	path    string
+	maxSize int64
+	maxAge  time.Duration
+	keep    int
+
+	mutex  sync.Mutex
+	file   *os.File
+	size   int64
+	opened time.Time
+}
+
+// OpenRotatingFile opens the log file, appending to it. A zero maxSize
+// or maxAge disables the rotation by size or age, a zero keep keeps all
+// the rotated files.
+func OpenRotatingFile(path string, maxSize int64, maxAge time.Duration, keep int) (*RotatingFile, error) {
+	f := &RotatingFile{path: path, maxSize: maxSize, maxAge: maxAge, keep: keep}
+	if err := f.open(); err != nil {
+		return nil, err
+	}
+	return f, nil
+}
+
+// SetOutputFile sends the messages to the file, without colors
+func SetOutputFile(f *RotatingFile) {
+	colorOff = nil
+	colorRed = nil
+	colorGreen = nil
+	colorOrange = nil
+	colorBlue = nil
+	colorPurple = nil
+	colorCyan = nil
+	colorGray = nil
+
+	log.SetOutput(f)
+}
+
+func (f *RotatingFile) open() error {
+	file, err := os.OpenFile(f.path, os.O_WRONLY|os.O_CREATE|os.O_APPEND, 0640)
+	if err != nil {
+		return err
+	}
+	info, err := file.Stat()
+	if err != nil {
+		file.Close()
+		return err
+	}
+
+	f.file = file
+	f.size = info.Size()
+	f.opened = time.Now()
+	return nil
+}
+
+// Write writes a message, rotating the file first if needed
+func (f *RotatingFile) Write(p []byte) (int, error) {
+	f.mutex.Lock()
+	defer f.mutex.Unlock()
+
+	tooBig := f.maxSize > 0 && f.size > 0 && f.size+int64(len(p)) > f.maxSize
+	tooOld := f.maxAge > 0 && time.Since(f.opened) > f.maxAge
+	if tooBig || tooOld {
+		if err := f.rotate(); err != nil {
+			fmt.Fprintf(os.Stderr, "Failed to rotate %s: %v\n", f.path, err)
+		}
+	}
+
+	n, err := f.file.Write(p)
+	f.size += int64(n)
+	return n, err
+}
+
+// Reopen closes and opens the file again, for when an external tool
+// rotated it
+func (f *RotatingFile) Reopen() error {
+	f.mutex.Lock()
+	defer f.mutex.Unlock()
+
+	f.file.Close()
+	return f.open()
+}
+
+// rotate shifts the rotated files, removing the oldest, and starts a new file
+func (f *RotatingFile) rotate() error {
+	if f.keep > 0 {
+		os.Remove(fmt.Sprintf("%s.%d", f.path, f.keep))
+	}
+	last := f.keep
+	if last == 0 {
+		for last = 1; ; last++ {
+			if _, err := os.Stat(fmt.Sprintf("%s.%d", f.path, last)); os.IsNotExist(err) {
+				break
+			}
+		}
+	}
+	for i := last - 1; i >= 1; i-- {
+		os.Rename(fmt.Sprintf("%s.%d", f.path, i), fmt.Sprintf("%s.%d", f.path, i+1))
+	}
+
+	f.file.Close()
+	if err := os.Rename(f.path, f.path+".1"); err != nil {
+		f.open()
+		return err
+	}
+	return f.open()
+}
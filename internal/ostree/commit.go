@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package ostree
+
+import (
+	"errors"
+	"unsafe"
+)
+
+// #cgo pkg-config: ostree-1
+// #include <stdlib.h>
+// #include <glib.h>
+// #include <ostree.h>
+//
+// static gboolean _ostree_repo_commit_directory(OstreeRepo *repo,
+//                                               const char *branch,
+//                                               const char *path,
+//                                               const char *subject,
+//                                               char **out_rev,
+//                                               GError **error) {
+//   g_autoptr(GFile) dir = g_file_new_for_path(path);
+//   g_autoptr(OstreeMutableTree) mtree = ostree_mutable_tree_new();
+//   g_autoptr(GFile) root = NULL;
+//   g_autofree char *parent = NULL;
+//
+//   if (!ostree_repo_resolve_rev(repo, branch, TRUE, &parent, error))
+//     return FALSE;
+//   if (!ostree_repo_prepare_transaction(repo, NULL, NULL, error))
+//     return FALSE;
+//   if (!ostree_repo_write_directory_to_mtree(repo, dir, mtree, NULL, NULL,
+//                                             error) ||
+//       !ostree_repo_write_mtree(repo, mtree, &root, NULL, error) ||
+//       !ostree_repo_write_commit(repo, parent, subject, NULL, NULL,
+//                                 OSTREE_REPO_FILE(root), out_rev, NULL,
+//                                 error)) {
+//     ostree_repo_abort_transaction(repo, NULL, NULL);
+//     return FALSE;
+//   }
+//   ostree_repo_transaction_set_ref(repo, NULL, branch, *out_rev);
+//   return ostree_repo_commit_transaction(repo, NULL, NULL, error);
+// }
+import "C"
+
+// CommitDirectory commits the content of the directory at path on top of
+// the branch, creating it if it doesn't exist, and returns the new revision
+func (r *Repo) CommitDirectory(branch, path, subject string) (string, error) {
+	if r.ptr == nil {
+		return "", errors.New("repo not initialized")
+	}
+
+	branchC := C.CString(branch)
+	defer C.free(unsafe.Pointer(branchC))
+	pathC := C.CString(path)
+	defer C.free(unsafe.Pointer(pathC))
+	subjectC := C.CString(subject)
+	defer C.free(unsafe.Pointer(subjectC))
+
+	var revC *C.char
+	var errC *C.GError
+	if C._ostree_repo_commit_directory(r.native(), branchC, pathC, subjectC, &revC, &errC) == C.FALSE {
+		return "", convertGError(errC)
+	}
+
+	return takeChecksum(revC), nil
+}
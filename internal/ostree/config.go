@@ -0,0 +1,204 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package ostree
+
+import (
+	"errors"
+	"unsafe"
+)
+
+// #cgo pkg-config: ostree-1
+// #include <stdlib.h>
+// #include <glib.h>
+// #include <ostree.h>
+//
+// static gboolean _ostree_repo_set_config_value(OstreeRepo *repo,
+//                                               const char *group,
+//                                               const char *key,
+//                                               const char *value,
+//                                               GError **error) {
+//   g_autoptr(GKeyFile) config = ostree_repo_copy_config(repo);
+//   g_key_file_set_string(config, group, key, value);
+//   return ostree_repo_write_config(repo, config, error);
+// }
+//
+// static char *_ostree_repo_get_config_value(OstreeRepo *repo,
+//                                            const char *group,
+//                                            const char *key) {
+//   return g_key_file_get_string(ostree_repo_get_config(repo), group, key,
+//                                NULL);
+// }
+//
+// static char **_ostree_repo_get_config_keys(OstreeRepo *repo,
+//                                            const char *group) {
+//   return g_key_file_get_keys(ostree_repo_get_config(repo), group, NULL, NULL);
+// }
+//
+// static gboolean _ostree_repo_set_collection_id(OstreeRepo *repo,
+//                                                const char *collection_id,
+//                                                GError **error) {
+// #if OSTREE_CHECK_VERSION(2018, 6)
+//   if (!ostree_repo_set_collection_id(repo, collection_id, error))
+//     return FALSE;
+//   return ostree_repo_write_config(repo, ostree_repo_get_config(repo), error);
+// #else
+//   g_set_error_literal(error, G_IO_ERROR, G_IO_ERROR_NOT_SUPPORTED,
+//                       "collection IDs require libostree 2018.6");
+//   return FALSE;
+// #endif
+// }
+//
+// static const char *_ostree_repo_get_collection_id(OstreeRepo *repo) {
+// #if OSTREE_CHECK_VERSION(2018, 6)
+//   return ostree_repo_get_collection_id(repo);
+// #else
+//   return NULL;
+// #endif
+// }
+import "C"
+
+// Group of the repository configuration where ostree-upload keeps its settings
+const ConfigGroup = "ostree-upload"
+
+// CreateRepoWithMode creates the repository from path with the specified
+// mode ("archive", "bare", "bare-user" or "bare-user-only") and opens it.
+func CreateRepoWithMode(path, mode string) (*Repo, error) {
+	if path == "" {
+		return nil, errors.New("empty path")
+	}
+
+	modeC := C.CString(mode)
+	defer C.free(unsafe.Pointer(modeC))
+
+	var repoMode C.OstreeRepoMode
+	var errC *C.GError
+	if C.ostree_repo_mode_from_string(modeC, &repoMode, &errC) == C.FALSE {
+		return nil, convertGError(errC)
+	}
+
+	return createRepo(path, repoMode)
+}
+
+// CreateStagingRepo creates the repository from path, with the same mode as
+// parent, and opens it; objects missing from it are looked up in parent,
+// so that objects can be checked with libostree before they are added to parent.
+func CreateStagingRepo(path string, parent *Repo) (*Repo, error) {
+	mode, err := parent.GetMode()
+	if err != nil {
+		return nil, err
+	}
+
+	repo, err := CreateRepoWithMode(path, mode)
+	if err != nil {
+		return nil, err
+	}
+	if err := repo.SetConfigValue("core", "parent", parent.Path()); err != nil {
+		return nil, err
+	}
+
+	// The parent is only looked up when the repository is opened
+	return OpenRepo(path)
+}
+
+// GetConfigValue returns the value of key from the repository configuration,
+// or an empty string if it's not set
+func (r *Repo) GetConfigValue(group, key string) string {
+	if r.ptr == nil {
+		return ""
+	}
+
+	groupC := C.CString(group)
+	defer C.free(unsafe.Pointer(groupC))
+	keyC := C.CString(key)
+	defer C.free(unsafe.Pointer(keyC))
+
+	valueC := C._ostree_repo_get_config_value(r.native(), groupC, keyC)
+	if valueC == nil {
+		return ""
+	}
+	defer C.g_free(C.gpointer(valueC))
+
+	return C.GoString(valueC)
+}
+
+// GetConfigValues returns all the keys of a group of the repository
+// configuration with their values
+func (r *Repo) GetConfigValues(group string) map[string]string {
+	values := map[string]string{}
+	if r.ptr == nil {
+		return values
+	}
+
+	groupC := C.CString(group)
+	defer C.free(unsafe.Pointer(groupC))
+
+	keysC := C._ostree_repo_get_config_keys(r.native(), groupC)
+	if keysC == nil {
+		return values
+	}
+	defer C.g_strfreev(keysC)
+
+	for i := 0; ; i++ {
+		keyC := *(**C.char)(unsafe.Pointer(uintptr(unsafe.Pointer(keysC)) + uintptr(i)*unsafe.Sizeof(*keysC)))
+		if keyC == nil {
+			break
+		}
+		key := C.GoString(keyC)
+		values[key] = r.GetConfigValue(group, key)
+	}
+
+	return values
+}
+
+// SetConfigValue sets key in the repository configuration and saves it
+func (r *Repo) SetConfigValue(group, key, value string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+
+	groupC := C.CString(group)
+	defer C.free(unsafe.Pointer(groupC))
+	keyC := C.CString(key)
+	defer C.free(unsafe.Pointer(keyC))
+	valueC := C.CString(value)
+	defer C.free(unsafe.Pointer(valueC))
+
+	var errC *C.GError
+	if C._ostree_repo_set_config_value(r.native(), groupC, keyC, valueC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
+
+// GetCollectionID returns the collection ID of the repository, or an empty
+// string if it doesn't have one
+func (r *Repo) GetCollectionID() string {
+	if r.ptr == nil {
+		return ""
+	}
+
+	return C.GoString(C._ostree_repo_get_collection_id(r.native()))
+}
+
+// SetCollectionID sets the collection ID of the repository and saves the configuration
+func (r *Repo) SetCollectionID(collectionID string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+	if !HasCapability(CapabilityCollectionIDs) {
+		return errors.New("collection IDs require libostree 2018.6 or later")
+	}
+
+	collectionIDC := C.CString(collectionID)
+	defer C.free(unsafe.Pointer(collectionIDC))
+
+	var errC *C.GError
+	if C._ostree_repo_set_collection_id(r.native(), collectionIDC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
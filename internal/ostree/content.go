@@ -0,0 +1,73 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package ostree
+
+import (
+	"unsafe"
+)
+
+// #cgo pkg-config: ostree-1
+// #include <stdlib.h>
+// #include <glib.h>
+// #include <ostree.h>
+//
+// static gboolean _ostree_validate_archive_object(const char *path,
+//                                                 GError **error) {
+//   g_autoptr(GFile) file = g_file_new_for_path(path);
+//   g_autoptr(GFileInfo) file_info = g_file_query_info(
+//       file, G_FILE_ATTRIBUTE_STANDARD_SIZE, G_FILE_QUERY_INFO_NONE, NULL,
+//       error);
+//   if (file_info == NULL)
+//     return FALSE;
+//   g_autoptr(GFileInputStream) input = g_file_read(file, NULL, error);
+//   if (input == NULL)
+//     return FALSE;
+//
+//   // Parse the file header and set up the zlib decompressor
+//   g_autoptr(GInputStream) content = NULL;
+//   g_autoptr(GFileInfo) info = NULL;
+//   g_autoptr(GVariant) xattrs = NULL;
+//   if (!ostree_content_stream_parse(TRUE, (GInputStream *)input,
+//                                    g_file_info_get_size(file_info), FALSE,
+//                                    &content, &info, &xattrs, NULL, error))
+//     return FALSE;
+//
+//   // Symbolic links don't have any content
+//   if (content == NULL)
+//     return TRUE;
+//
+//   // Decompress everything to catch truncated or corrupt streams
+//   guint64 expected = g_file_info_get_size(info);
+//   guint64 total = 0;
+//   char buf[8192];
+//   gssize n;
+//   while ((n = g_input_stream_read(content, buf, sizeof(buf), NULL, error)) > 0)
+//     total += n;
+//   if (n < 0)
+//     return FALSE;
+//   if (total != expected) {
+//     g_set_error(error, G_IO_ERROR, G_IO_ERROR_INVALID_DATA,
+//                 "content is %" G_GUINT64_FORMAT " bytes instead of %" G_GUINT64_FORMAT,
+//                 total, expected);
+//     return FALSE;
+//   }
+//   return TRUE;
+// }
+import "C"
+
+// ValidateArchiveObject parses the header and decompresses the content
+// of the archive file object (.filez) at path, returning an error
+// if it is truncated or corrupt
+func ValidateArchiveObject(path string) error {
+	pathC := C.CString(path)
+	defer C.free(unsafe.Pointer(pathC))
+
+	var errC *C.GError
+	if C._ostree_validate_archive_object(pathC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
@@ -0,0 +1,225 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package ostree
+
+import (
+	"encoding/base64"
+	"encoding/hex"
+	"errors"
+	"io/ioutil"
+	"os"
+	"path/filepath"
+	"strings"
+	"unsafe"
+)
+
+// #cgo pkg-config: ostree-1
+// #include <stdlib.h>
+// #include <glib.h>
+// #include <ostree.h>
+//
+// static gboolean _ostree_repo_static_delta_reindex(OstreeRepo *repo,
+//                                                   GError **error) {
+// #if OSTREE_CHECK_VERSION(2020, 8)
+//   return ostree_repo_static_delta_reindex(repo, 0, NULL, NULL, error);
+// #else
+//   return TRUE;
+// #endif
+// }
+//
+// static GVariant *_static_delta_file_params(const char *filename) {
+//   GVariantBuilder builder;
+//   g_variant_builder_init(&builder, G_VARIANT_TYPE("a{sv}"));
+//   g_variant_builder_add(&builder, "{sv}", "filename",
+//                         g_variant_new_bytestring(filename));
+//   g_variant_builder_add(&builder, "{sv}", "inline-parts",
+//                         g_variant_new_boolean(TRUE));
+//   return g_variant_ref_sink(g_variant_builder_end(&builder));
+// }
+//
+// static gboolean _ostree_repo_static_delta_apply_file(OstreeRepo *repo,
+//                                                      const char *path,
+//                                                      GError **error) {
+//   g_autoptr(GFile) file = g_file_new_for_path(path);
+//   if (!ostree_repo_prepare_transaction(repo, NULL, NULL, error))
+//     return FALSE;
+//   if (!ostree_repo_static_delta_execute_offline(repo, file, FALSE, NULL,
+//                                                  error)) {
+//     ostree_repo_abort_transaction(repo, NULL, NULL);
+//     return FALSE;
+//   }
+//   return ostree_repo_commit_transaction(repo, NULL, NULL, error);
+// }
+import "C"
+
+// StaticDelta represents a static delta stored in the repository
+type StaticDelta struct {
+	// Commit the delta starts from, empty for deltas from scratch
+	From string
+	// Commit the delta leads to
+	To string
+	// Path of the delta directory
+	Path string
+}
+
+// Name returns the name of the delta, as used by the ostree command
+func (d StaticDelta) Name() string {
+	if d.From == "" {
+		return d.To
+	}
+	return d.From + "-" + d.To
+}
+
+// checksumFromModifiedBase64 converts a checksum encoded with the
+// modified base64 used for delta paths to hexadecimal
+func checksumFromModifiedBase64(value string) (string, error) {
+	data, err := base64.RawStdEncoding.DecodeString(strings.Replace(value, "_", "/", -1))
+	if err != nil {
+		return "", err
+	}
+	if len(data) != 32 {
+		return "", errors.New("invalid checksum length")
+	}
+
+	return hex.EncodeToString(data), nil
+}
+
+// ListStaticDeltas returns the static deltas of the repository
+func (r *Repo) ListStaticDeltas() ([]StaticDelta, error) {
+	deltasPath := filepath.Join(r.path, "deltas")
+
+	prefixes, err := ioutil.ReadDir(deltasPath)
+	if os.IsNotExist(err) {
+		return nil, nil
+	} else if err != nil {
+		return nil, err
+	}
+
+	var deltas []StaticDelta
+	for _, prefix := range prefixes {
+		if !prefix.IsDir() {
+			continue
+		}
+
+		entries, err := ioutil.ReadDir(filepath.Join(deltasPath, prefix.Name()))
+		if err != nil {
+			return nil, err
+		}
+
+		for _, entry := range entries {
+			if !entry.IsDir() {
+				continue
+			}
+
+			// Directories are named after the modified base64 encoding
+			// of either "FROM-TO" or "TO", split after two characters
+			name := prefix.Name() + entry.Name()
+			delta := StaticDelta{Path: filepath.Join(deltasPath, prefix.Name(), entry.Name())}
+			parts := strings.SplitN(name, "-", 2)
+			if len(parts) == 2 {
+				if delta.From, err = checksumFromModifiedBase64(parts[0]); err != nil {
+					continue
+				}
+				parts = parts[1:]
+			}
+			if delta.To, err = checksumFromModifiedBase64(parts[0]); err != nil {
+				continue
+			}
+
+			deltas = append(deltas, delta)
+		}
+	}
+
+	return deltas, nil
+}
+
+// DeleteStaticDelta deletes the static delta and updates the deltas index
+func (r *Repo) DeleteStaticDelta(delta StaticDelta) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+
+	if err := os.RemoveAll(delta.Path); err != nil {
+		return err
+	}
+
+	// Remove the prefix directory when it's empty
+	os.Remove(filepath.Dir(delta.Path))
+
+	var errC *C.GError
+	if C._ostree_repo_static_delta_reindex(r.native(), &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
+
+// GenerateStaticDelta generates a static delta between two commits,
+// or from scratch if from is empty
+func (r *Repo) GenerateStaticDelta(from, to string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+
+	var fromC *C.char
+	if from != "" {
+		fromC = C.CString(from)
+		defer C.free(unsafe.Pointer(fromC))
+	}
+	toC := C.CString(to)
+	defer C.free(unsafe.Pointer(toC))
+
+	var errC *C.GError
+	if C.ostree_repo_static_delta_generate(r.native(), C.OSTREE_STATIC_DELTA_GENERATE_OPT_MAJOR, fromC, toC, nil, nil, nil, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
+
+// GenerateStaticDeltaFile generates a static delta between two commits
+// as a single file at path, with all the parts inlined, instead of
+// storing it in the repository
+func (r *Repo) GenerateStaticDeltaFile(from, to, path string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+
+	fromC := C.CString(from)
+	defer C.free(unsafe.Pointer(fromC))
+	toC := C.CString(to)
+	defer C.free(unsafe.Pointer(toC))
+	pathC := C.CString(path)
+	defer C.free(unsafe.Pointer(pathC))
+
+	paramsC := C._static_delta_file_params(pathC)
+	defer C.g_variant_unref(paramsC)
+
+	var errC *C.GError
+	if C.ostree_repo_static_delta_generate(r.native(), C.OSTREE_STATIC_DELTA_GENERATE_OPT_MAJOR, fromC, toC, nil, paramsC, nil, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
+
+// ApplyStaticDeltaFile imports the objects of the static delta file at
+// path, verifying them, the commit the delta starts from must be in the
+// repository; refs are not changed
+func (r *Repo) ApplyStaticDeltaFile(path string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+
+	pathC := C.CString(path)
+	defer C.free(unsafe.Pointer(pathC))
+
+	var errC *C.GError
+	if C._ostree_repo_static_delta_apply_file(r.native(), pathC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
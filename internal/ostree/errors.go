@@ -0,0 +1,68 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package ostree
+
+import (
+	"errors"
+	"fmt"
+	"strings"
+)
+
+// #include <gio/gio.h>
+import "C"
+
+// Domain of the errors of GIO, which libostree uses for most of its errors
+const ioErrorDomain = "g-io-error-quark"
+
+// Error is an error reported by libostree, with the domain and the
+// code of the GLib error so that callers can tell failures apart
+type Error struct {
+	Domain  string
+	Code    int
+	Message string
+}
+
+// Error returns the message
+func (e *Error) Error() string {
+	return e.Message
+}
+
+// NotFound returns true if something, such as an object or a ref, doesn't exist
+func (e *Error) NotFound() bool {
+	return e.Domain == ioErrorDomain && e.Code == int(C.G_IO_ERROR_NOT_FOUND)
+}
+
+// Corrupted returns true if an object is not what it should be
+func (e *Error) Corrupted() bool {
+	if e.Domain == ioErrorDomain && e.Code == int(C.G_IO_ERROR_INVALID_DATA) {
+		return true
+	}
+	// libostree reports bad checksums and malformed objects as generic failures
+	return strings.Contains(strings.ToLower(e.Message), "corrupted")
+}
+
+// IsNotFound returns true if err, or an error it wraps, is an Error
+// telling that something doesn't exist
+func IsNotFound(err error) bool {
+	var ostreeErr *Error
+	return errors.As(err, &ostreeErr) && ostreeErr.NotFound()
+}
+
+// IsCorrupted returns true if err, or an error it wraps, is an Error
+// telling that an object is corrupted
+func IsCorrupted(err error) bool {
+	var ostreeErr *Error
+	return errors.As(err, &ostreeErr) && ostreeErr.Corrupted()
+}
+
+// notFoundError creates an Error like those of libostree for something missing
+func notFoundError(format string, a ...interface{}) error {
+	return &Error{Domain: ioErrorDomain, Code: int(C.G_IO_ERROR_NOT_FOUND), Message: fmt.Sprintf(format, a...)}
+}
+
+// corruptedError creates an Error like those of libostree for invalid data
+func corruptedError(format string, a ...interface{}) error {
+	return &Error{Domain: ioErrorDomain, Code: int(C.G_IO_ERROR_INVALID_DATA), Message: fmt.Sprintf(format, a...)}
+}
@@ -0,0 +1,114 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package ostree
+
+// #cgo pkg-config: ostree-1
+// #include <ostree.h>
+//
+// static const char *_ostree_version_s(void) { return OSTREE_VERSION_S; }
+// static guint _ostree_year_version(void) { return OSTREE_YEAR_VERSION; }
+import "C"
+
+import (
+	"fmt"
+	"sync"
+)
+
+// First year of the libostree versions
+const firstVersionYear = 2012
+
+// Highest release probed in a year
+const maxVersionRelease = 100
+
+var (
+	versionOnce    sync.Once
+	runtimeVersion string
+)
+
+// Capabilities that depend on the libostree version
+const (
+	// CapabilityStaticDeltas is the static delta API
+	CapabilityStaticDeltas = "static-deltas"
+
+	// CapabilityCollectionIDs is the collection ID support
+	CapabilityCollectionIDs = "collection-ids"
+
+	// CapabilitySign is the signing API with ed25519 support
+	CapabilitySign = "sign"
+)
+
+// Minimum libostree version for each capability
+var capabilityVersions = []struct {
+	name    string
+	year    uint
+	release uint
+}{
+	{CapabilityStaticDeltas, 2016, 1},
+	{CapabilityCollectionIDs, 2018, 6},
+	{CapabilitySign, 2020, 2},
+}
+
+// Version returns the version of the libostree loaded at runtime, which
+// can differ from the one the program was built against
+func Version() string {
+	versionOnce.Do(func() {
+		// Start from the headers, the library is usually close to them
+		year := uint(C._ostree_year_version())
+		if CheckVersion(year, 0) {
+			for CheckVersion(year+1, 0) {
+				year++
+			}
+		} else {
+			for year > firstVersionYear && !CheckVersion(year, 0) {
+				year--
+			}
+		}
+
+		release := uint(0)
+		for release < maxVersionRelease && CheckVersion(year, release+1) {
+			release++
+		}
+
+		runtimeVersion = fmt.Sprintf("%d.%d", year, release)
+	})
+	return runtimeVersion
+}
+
+// BuildVersion returns the libostree version the program was built against
+func BuildVersion() string {
+	return C.GoString(C._ostree_version_s())
+}
+
+// CheckVersion returns true if the libostree loaded at runtime is at
+// least the specified version
+func CheckVersion(year, release uint) bool {
+	return C.ostree_check_version(C.guint(year), C.guint(release)) == C.TRUE
+}
+
+// Capabilities returns the list of capabilities supported by the
+// libostree loaded at runtime
+func Capabilities() []string {
+	capabilities := []string{}
+
+	for _, capability := range capabilityVersions {
+		if CheckVersion(capability.year, capability.release) {
+			capabilities = append(capabilities, capability.name)
+		}
+	}
+
+	return capabilities
+}
+
+// HasCapability returns true if the libostree loaded at runtime
+// supports the capability
+func HasCapability(name string) bool {
+	for _, capability := range Capabilities() {
+		if capability == name {
+			return true
+		}
+	}
+
+	return false
+}
@@ -26,6 +26,26 @@ static void _g_variant_get_su(GVariant *v, const char **checksum,
 
 static const char *_g_strdup(gpointer string) { return g_strdup(string); }
 
+static char *_ostree_commit_get_version(GVariant *commit) {
+  g_autoptr(GVariant) metadata = g_variant_get_child_value(commit, 0);
+  const char *version = NULL;
+  if (!g_variant_lookup(metadata, "version", "&s", &version))
+    return NULL;
+  return g_strdup(version);
+}
+
+static char *_ostree_commit_get_subject(GVariant *commit) {
+  const char *subject = NULL;
+  g_variant_get_child(commit, 3, "&s", &subject);
+  return g_strdup(subject);
+}
+
+static char *_ostree_commit_get_body(GVariant *commit) {
+  const char *body = NULL;
+  g_variant_get_child(commit, 4, "&s", &body);
+  return g_strdup(body);
+}
+
 static gboolean _ostree_repo_file_ensure_resolved(GFile *file) {
   return ostree_repo_file_ensure_resolved((OstreeRepoFile *)file, NULL);
 }
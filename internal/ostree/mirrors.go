@@ -0,0 +1,182 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package ostree
+
+import (
+	"errors"
+	"fmt"
+	"strings"
+	"unsafe"
+)
+
+// #cgo pkg-config: ostree-1
+// #include <stdlib.h>
+// #include <glib.h>
+// #include <ostree.h>
+//
+// static GHashTable *_ostree_repo_list_mirror_refs(OstreeRepo *repo,
+//                                                  GError **error) {
+//   GHashTable *mirrors =
+//       g_hash_table_new_full(g_str_hash, g_str_equal, g_free, g_free);
+// #if OSTREE_CHECK_VERSION(2018, 6)
+//   g_autoptr(GHashTable) refs = NULL;
+//   if (!ostree_repo_list_collection_refs(
+//           repo, NULL, &refs, OSTREE_REPO_LIST_REFS_EXT_EXCLUDE_REMOTES, NULL,
+//           error)) {
+//     g_hash_table_unref(mirrors);
+//     return NULL;
+//   }
+//   // Refs of the collection of the repository are the plain heads
+//   const char *main_collection_id = ostree_repo_get_collection_id(repo);
+//   GHashTableIter iter;
+//   gpointer key, value;
+//   g_hash_table_iter_init(&iter, refs);
+//   while (g_hash_table_iter_next(&iter, &key, &value)) {
+//     const OstreeCollectionRef *ref = key;
+//     if (g_strcmp0(ref->collection_id, main_collection_id) == 0)
+//       continue;
+//     g_hash_table_insert(mirrors,
+//                         g_strdup_printf("refs/mirrors/%s/%s",
+//                                         ref->collection_id, ref->ref_name),
+//                         g_strdup(value));
+//   }
+// #endif
+//   return mirrors;
+// }
+//
+// static gboolean _ostree_repo_set_mirror_ref_immediate(
+//     OstreeRepo *repo, const char *collection_id, const char *ref_name,
+//     const char *checksum, GError **error) {
+// #if OSTREE_CHECK_VERSION(2018, 6)
+//   const OstreeCollectionRef ref = {(gchar *)collection_id, (gchar *)ref_name};
+//   return ostree_repo_set_collection_ref_immediate(repo, &ref, checksum, NULL,
+//                                                   error);
+// #else
+//   g_set_error_literal(error, G_IO_ERROR, G_IO_ERROR_NOT_SUPPORTED,
+//                       "mirror refs require libostree 2018.6");
+//   return FALSE;
+// #endif
+// }
+//
+// static gboolean _ostree_validate_collection_id(const char *collection_id,
+//                                                GError **error) {
+// #if OSTREE_CHECK_VERSION(2018, 6)
+//   return ostree_validate_collection_id(collection_id, error);
+// #else
+//   g_set_error_literal(error, G_IO_ERROR, G_IO_ERROR_NOT_SUPPORTED,
+//                       "collection IDs require libostree 2018.6");
+//   return FALSE;
+// #endif
+// }
+import "C"
+
+// MirrorRefPrefix is the namespace of the refs mirrored from other collections,
+// the full name is refs/mirrors/<collection ID>/<ref>
+const MirrorRefPrefix = "refs/mirrors/"
+
+// MirrorRef returns the full name of ref mirrored from collectionID
+func MirrorRef(collectionID, ref string) string {
+	return MirrorRefPrefix + collectionID + "/" + ref
+}
+
+// ParseMirrorRef splits the full name of a mirrored ref into collection ID
+// and ref, ok is false for the other branches
+func ParseMirrorRef(branch string) (collectionID, ref string, ok bool) {
+	if !strings.HasPrefix(branch, MirrorRefPrefix) {
+		return "", "", false
+	}
+
+	// Collection IDs can't contain slashes, refs can
+	parts := strings.SplitN(strings.TrimPrefix(branch, MirrorRefPrefix), "/", 2)
+	if len(parts) != 2 || parts[0] == "" || parts[1] == "" {
+		return "", "", false
+	}
+
+	return parts[0], parts[1], true
+}
+
+// ValidateCollectionID returns an error if the collection ID is not valid
+func ValidateCollectionID(collectionID string) error {
+	collectionIDC := C.CString(collectionID)
+	defer C.free(unsafe.Pointer(collectionIDC))
+
+	var errC *C.GError
+	if C._ostree_validate_collection_id(collectionIDC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
+
+// ValidateBranch returns an error if the branch is not valid, either
+// a plain branch or one mirrored from another collection
+func ValidateBranch(branch string) error {
+	if strings.HasPrefix(branch, MirrorRefPrefix) {
+		collectionID, ref, ok := ParseMirrorRef(branch)
+		if !ok {
+			return fmt.Errorf("mirrored ref %q must be %s<collection ID>/<ref>", branch, MirrorRefPrefix)
+		}
+		if err := ValidateCollectionID(collectionID); err != nil {
+			return err
+		}
+		return ValidateRev(ref)
+	}
+
+	return ValidateRev(branch)
+}
+
+// ListMirrorRevisions returns a dictionary whose keys are the full names of
+// the refs mirrored from other collections and values are the corresponding revisions
+func (r *Repo) ListMirrorRevisions() (map[string]string, error) {
+	if r.ptr == nil {
+		return nil, errors.New("repo not initialized")
+	}
+
+	var errC *C.GError
+	refsC := C._ostree_repo_list_mirror_refs(r.native(), &errC)
+	if refsC == nil {
+		return nil, convertGError(errC)
+	}
+	defer C.g_hash_table_unref(refsC)
+
+	var iter C.GHashTableIter
+	C.g_hash_table_iter_init(&iter, refsC)
+
+	revs := map[string]string{}
+
+	var hkey C.gpointer
+	var hvalue C.gpointer
+	for C.g_hash_table_iter_next(&iter, &hkey, &hvalue) == C.TRUE {
+		revs[C.GoString((*C.char)(hkey))] = C.GoString((*C.char)(hvalue))
+	}
+
+	return revs, nil
+}
+
+// SetMirrorRefImmediate points the ref mirrored from collectionID to checksum
+func (r *Repo) SetMirrorRefImmediate(collectionID, ref, checksum string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+
+	// The refs of our own collection are stored as plain heads
+	if collectionID == r.GetCollectionID() {
+		return fmt.Errorf("cannot mirror ref %s from collection %s, it's the collection of the repository", ref, collectionID)
+	}
+
+	collectionIDC := C.CString(collectionID)
+	defer C.free(unsafe.Pointer(collectionIDC))
+	refC := C.CString(ref)
+	defer C.free(unsafe.Pointer(refC))
+	checksumC := C.CString(checksum)
+	defer C.free(unsafe.Pointer(checksumC))
+
+	var errC *C.GError
+	if C._ostree_repo_set_mirror_ref_immediate(r.native(), collectionIDC, refC, checksumC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
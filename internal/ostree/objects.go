@@ -0,0 +1,268 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package ostree
+
+import (
+	"errors"
+	"fmt"
+	"io/ioutil"
+	"os"
+	"path/filepath"
+	"strings"
+	"unsafe"
+)
+
+// #cgo pkg-config: ostree-1
+// #include <stdlib.h>
+// #include <glib.h>
+// #include <ostree.h>
+//
+// static GVariant *_g_variant_new_from_data(const char *type, gconstpointer data,
+//                                           gsize size) {
+//   g_autoptr(GBytes) bytes = g_bytes_new(data, size);
+//   return g_variant_ref_sink(
+//       g_variant_new_from_bytes(G_VARIANT_TYPE(type), bytes, FALSE));
+// }
+//
+// static char *_checksum_from_child(GVariant *v, gsize index) {
+//   g_autoptr(GVariant) csum = g_variant_get_child_value(v, index);
+//   if (g_variant_n_children(csum) != OSTREE_SHA256_DIGEST_LEN)
+//     return NULL;
+//   return ostree_checksum_from_bytes_v(csum);
+// }
+//
+// static gsize _ostree_dirtree_n_children(GVariant *tree, gsize index) {
+//   g_autoptr(GVariant) children = g_variant_get_child_value(tree, index);
+//   return g_variant_n_children(children);
+// }
+//
+// static char *_ostree_dirtree_get_file(GVariant *tree, gsize i) {
+//   g_autoptr(GVariant) files = g_variant_get_child_value(tree, 0);
+//   g_autoptr(GVariant) file = g_variant_get_child_value(files, i);
+//   return _checksum_from_child(file, 1);
+// }
+//
+// static GVariant *_ostree_dirtree_get_dir(GVariant *tree, gsize i) {
+//   g_autoptr(GVariant) dirs = g_variant_get_child_value(tree, 1);
+//   return g_variant_get_child_value(dirs, i);
+// }
+//
+// static char *_variant_dict_get_key(GVariant *dict, gsize i) {
+//   g_autoptr(GVariant) entry = g_variant_get_child_value(dict, i);
+//   g_autoptr(GVariant) key = g_variant_get_child_value(entry, 0);
+//   return g_variant_dup_string(key, NULL);
+// }
+import "C"
+
+// GVariant types of the metadata objects
+var metadataObjectTypes = map[string]string{
+	".commit":     "(a{sv}aya(say)sstayay)",
+	".commitmeta": "a{sv}",
+	".dirtree":    "(a(say)a(sayay))",
+	".dirmeta":    "(uuua(ayay))",
+}
+
+// CommitObject holds what a commit object references
+type CommitObject struct {
+	Parent   string
+	RootTree string
+	RootMeta string
+}
+
+// DirTreeEntry is a subdirectory of a directory tree
+type DirTreeEntry struct {
+	Tree string
+	Meta string
+}
+
+// DirTreeObject holds what a directory tree object references
+type DirTreeObject struct {
+	Files []string
+	Dirs  []DirTreeEntry
+}
+
+// loadVariantFile reads a metadata object that is not in the repository yet,
+// such as the objects just received, refusing anything that is not in normal form
+func loadVariantFile(path, typeString string) (*C.GVariant, error) {
+	data, err := ioutil.ReadFile(path)
+	if err != nil {
+		return nil, err
+	}
+	if len(data) == 0 {
+		return nil, corruptedError("object %s is empty", path)
+	}
+
+	typeC := C.CString(typeString)
+	defer C.free(unsafe.Pointer(typeC))
+
+	variantC := C._g_variant_new_from_data(typeC, C.gconstpointer(unsafe.Pointer(&data[0])), C.gsize(len(data)))
+	if C.g_variant_is_normal_form(variantC) == C.FALSE {
+		C.g_variant_unref(variantC)
+		return nil, corruptedError("object %s is corrupted", path)
+	}
+
+	return variantC, nil
+}
+
+// takeChecksum converts a checksum allocated by libostree, an empty string means invalid
+func takeChecksum(checksumC *C.char) string {
+	if checksumC == nil {
+		return ""
+	}
+	defer C.g_free(C.gpointer(checksumC))
+	return C.GoString(checksumC)
+}
+
+// ReadCommitObject parses the commit object at path
+func ReadCommitObject(path string) (*CommitObject, error) {
+	variantC, err := loadVariantFile(path, "(a{sv}aya(say)sstayay)")
+	if err != nil {
+		return nil, err
+	}
+	defer C.g_variant_unref(variantC)
+
+	commit := &CommitObject{
+		Parent:   takeChecksum(C.ostree_commit_get_parent(variantC)),
+		RootTree: takeChecksum(C._checksum_from_child(variantC, 6)),
+		RootMeta: takeChecksum(C._checksum_from_child(variantC, 7)),
+	}
+	if commit.RootTree == "" || commit.RootMeta == "" {
+		return nil, corruptedError("commit object %s has an invalid root", path)
+	}
+
+	return commit, nil
+}
+
+// ReadDirTreeObject parses the directory tree object at path
+func ReadDirTreeObject(path string) (*DirTreeObject, error) {
+	variantC, err := loadVariantFile(path, "(a(say)a(sayay))")
+	if err != nil {
+		return nil, err
+	}
+	defer C.g_variant_unref(variantC)
+
+	tree := &DirTreeObject{}
+
+	nFiles := C._ostree_dirtree_n_children(variantC, 0)
+	for i := C.gsize(0); i < nFiles; i++ {
+		checksum := takeChecksum(C._ostree_dirtree_get_file(variantC, i))
+		if checksum == "" {
+			return nil, corruptedError("directory tree object %s has an invalid file checksum", path)
+		}
+		tree.Files = append(tree.Files, checksum)
+	}
+
+	nDirs := C._ostree_dirtree_n_children(variantC, 1)
+	for i := C.gsize(0); i < nDirs; i++ {
+		dirC := C._ostree_dirtree_get_dir(variantC, i)
+		dir := DirTreeEntry{
+			Tree: takeChecksum(C._checksum_from_child(dirC, 1)),
+			Meta: takeChecksum(C._checksum_from_child(dirC, 2)),
+		}
+		C.g_variant_unref(dirC)
+		if dir.Tree == "" || dir.Meta == "" {
+			return nil, corruptedError("directory tree object %s has an invalid subdirectory checksum", path)
+		}
+		tree.Dirs = append(tree.Dirs, dir)
+	}
+
+	return tree, nil
+}
+
+// ReadCommitMetaKeys returns the keys of the detached metadata object at path
+func ReadCommitMetaKeys(path string) ([]string, error) {
+	variantC, err := loadVariantFile(path, "a{sv}")
+	if err != nil {
+		return nil, err
+	}
+	defer C.g_variant_unref(variantC)
+
+	keys := []string{}
+	n := C.g_variant_n_children(variantC)
+	for i := C.gsize(0); i < n; i++ {
+		keyC := C._variant_dict_get_key(variantC, i)
+		keys = append(keys, C.GoString(keyC))
+		C.g_free(C.gpointer(keyC))
+	}
+
+	return keys, nil
+}
+
+// ValidateMetadataObject makes sure the metadata object at path, not yet
+// in the repository, is well formed, other objects are ignored
+func ValidateMetadataObject(path string) error {
+	typeString, ok := metadataObjectTypes[filepath.Ext(path)]
+	if !ok {
+		return nil
+	}
+
+	variantC, err := loadVariantFile(path, typeString)
+	if err != nil {
+		return err
+	}
+	C.g_variant_unref(variantC)
+
+	return nil
+}
+
+// FsckObject verifies the checksum of an object in the repository
+func (r *Repo) FsckObject(objectName string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+
+	var objectType C.OstreeObjectType
+	switch filepath.Ext(objectName) {
+	case ".commit":
+		objectType = C.OSTREE_OBJECT_TYPE_COMMIT
+	case ".commitmeta":
+		objectType = C.OSTREE_OBJECT_TYPE_COMMIT_META
+	case ".dirtree":
+		objectType = C.OSTREE_OBJECT_TYPE_DIR_TREE
+	case ".dirmeta":
+		objectType = C.OSTREE_OBJECT_TYPE_DIR_META
+	case ".file", ".filez":
+		objectType = C.OSTREE_OBJECT_TYPE_FILE
+	default:
+		return fmt.Errorf("unknown object type for %s", objectName)
+	}
+
+	checksumC := C.CString(strings.TrimSuffix(objectName, filepath.Ext(objectName)))
+	defer C.free(unsafe.Pointer(checksumC))
+
+	var errC *C.GError
+	if C.ostree_repo_fsck_object(r.native(), objectType, checksumC, nil, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
+
+// ListObjects returns the names of the objects in the repository, sorted
+func (r *Repo) ListObjects() ([]string, error) {
+	objectsPath := filepath.Join(r.path, "objects")
+	dirs, err := ioutil.ReadDir(objectsPath)
+	if err != nil {
+		return nil, err
+	}
+
+	objectNames := []string{}
+	for _, dir := range dirs {
+		if !dir.IsDir() || len(dir.Name()) != 2 {
+			continue
+		}
+		files, err := ioutil.ReadDir(filepath.Join(objectsPath, dir.Name()))
+		if err != nil {
+			return nil, err
+		}
+		for _, file := range files {
+			if file.Mode().IsRegular() || file.Mode()&os.ModeSymlink != 0 {
+				objectNames = append(objectNames, dir.Name()+file.Name())
+			}
+		}
+	}
+
+	return objectNames, nil
+}
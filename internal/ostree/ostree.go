@@ -9,6 +9,9 @@ import (
 	"fmt"
 	"os"
 	"path/filepath"
+	"sort"
+	"time"
+	"unicode/utf8"
 	"unsafe"
 )
 
@@ -18,12 +21,17 @@ import (
 // #include "glibsupport.h"
 import "C"
 
+// convertGError converts a GLib error to an Error and frees it
 func convertGError(errC *C.GError) error {
 	if errC == nil {
 		return errors.New("nil GError")
 	}
 
-	err := errors.New(C.GoString((*C.char)(C._g_error_get_message(errC))))
+	err := &Error{
+		Domain:  C.GoString(C.g_quark_to_string(errC.domain)),
+		Code:    int(errC.code),
+		Message: C.GoString((*C.char)(C._g_error_get_message(errC))),
+	}
 	defer C.g_error_free(errC)
 	return err
 }
@@ -64,12 +72,16 @@ func OpenRepo(path string) (*Repo, error) {
 	return repo, nil
 }
 
-// CreateRepo creates the repository from path and opens it.
+// CreateRepo creates an archive repository from path and opens it.
 func CreateRepo(path string) (*Repo, error) {
 	if path == "" {
 		return nil, errors.New("empty path")
 	}
 
+	return createRepo(path, C.OSTREE_REPO_MODE_ARCHIVE)
+}
+
+func createRepo(path string, mode C.OstreeRepoMode) (*Repo, error) {
 	// Create path if it doesn't exist
 	if _, err := os.Stat(path); os.IsNotExist(err) {
 		os.Mkdir(path, 0755)
@@ -90,7 +102,7 @@ func CreateRepo(path string) (*Repo, error) {
 
 	var errC *C.GError
 
-	if C.ostree_repo_create(repoC, C.OSTREE_REPO_MODE_ARCHIVE, nil, &errC) == C.FALSE {
+	if C.ostree_repo_create(repoC, mode, nil, &errC) == C.FALSE {
 		return nil, convertGError(errC)
 	}
 
@@ -164,6 +176,9 @@ func (r *Repo) ListRefs() ([]string, error) {
 		refs = append(refs, C.GoString(C._g_strdup(hkey)))
 	}
 
+	// Hash table order is random
+	sort.Strings(refs)
+
 	return refs, nil
 }
 
@@ -203,11 +218,122 @@ func (r *Repo) GetParentRev(rev string) (string, error) {
 		return "", convertGError(errC)
 	}
 	if variantC == nil {
-		return "", fmt.Errorf("commit %s doesn't exist", rev)
+		return "", notFoundError("commit %s doesn't exist", rev)
 	}
 	return C.GoString(C.ostree_commit_get_parent(variantC)), nil
 }
 
+// HasCommit returns true if the commit is in the repository
+func (r *Repo) HasCommit(rev string) bool {
+	if r.ptr == nil {
+		return false
+	}
+
+	revC := C.CString(rev)
+	defer C.free(unsafe.Pointer(revC))
+
+	var hasObject C.gboolean
+	if C.ostree_repo_has_object(r.native(), C.OSTREE_OBJECT_TYPE_COMMIT, revC, &hasObject, nil, nil) == C.FALSE {
+		return false
+	}
+
+	return hasObject == C.TRUE
+}
+
+// GetCommitVersion returns the "version" metadata of the commit, or an empty string if it doesn't have one
+func (r *Repo) GetCommitVersion(rev string) (string, error) {
+	if r.ptr == nil {
+		return "", errors.New("repo not initialized")
+	}
+
+	revC := C.CString(rev)
+	defer C.free(unsafe.Pointer(revC))
+
+	var variantC *C.GVariant
+	var errC *C.GError
+	if C.ostree_repo_load_variant_if_exists(r.native(), C.OSTREE_OBJECT_TYPE_COMMIT, revC, &variantC, &errC) == C.FALSE {
+		return "", convertGError(errC)
+	}
+	if variantC == nil {
+		return "", notFoundError("commit %s doesn't exist", rev)
+	}
+	defer C.g_variant_unref(variantC)
+
+	versionC := C._ostree_commit_get_version(variantC)
+	if versionC == nil {
+		return "", nil
+	}
+	defer C.g_free(C.gpointer(versionC))
+
+	return C.GoString(versionC), nil
+}
+
+// GetCommitTimestamp returns the time the commit was created
+func (r *Repo) GetCommitTimestamp(rev string) (time.Time, error) {
+	if r.ptr == nil {
+		return time.Time{}, errors.New("repo not initialized")
+	}
+
+	revC := C.CString(rev)
+	defer C.free(unsafe.Pointer(revC))
+
+	var variantC *C.GVariant
+	var errC *C.GError
+	if C.ostree_repo_load_variant_if_exists(r.native(), C.OSTREE_OBJECT_TYPE_COMMIT, revC, &variantC, &errC) == C.FALSE {
+		return time.Time{}, convertGError(errC)
+	}
+	if variantC == nil {
+		return time.Time{}, notFoundError("commit %s doesn't exist", rev)
+	}
+	defer C.g_variant_unref(variantC)
+
+	return time.Unix(int64(C.ostree_commit_get_timestamp(variantC)), 0).UTC(), nil
+}
+
+// GetCommitMessage returns subject and body of the commit message
+func (r *Repo) GetCommitMessage(rev string) (string, string, error) {
+	if r.ptr == nil {
+		return "", "", errors.New("repo not initialized")
+	}
+
+	revC := C.CString(rev)
+	defer C.free(unsafe.Pointer(revC))
+
+	var variantC *C.GVariant
+	var errC *C.GError
+	if C.ostree_repo_load_variant_if_exists(r.native(), C.OSTREE_OBJECT_TYPE_COMMIT, revC, &variantC, &errC) == C.FALSE {
+		return "", "", convertGError(errC)
+	}
+	if variantC == nil {
+		return "", "", notFoundError("commit %s doesn't exist", rev)
+	}
+	defer C.g_variant_unref(variantC)
+
+	subjectC := C._ostree_commit_get_subject(variantC)
+	defer C.g_free(C.gpointer(subjectC))
+	bodyC := C._ostree_commit_get_body(variantC)
+	defer C.g_free(C.gpointer(bodyC))
+
+	return C.GoString(subjectC), C.GoString(bodyC), nil
+}
+
+// ValidateRev returns an error if the branch name is not valid
+func ValidateRev(branch string) error {
+	if !utf8.ValidString(branch) {
+		return fmt.Errorf("branch name %q is not valid UTF-8", branch)
+	}
+
+	branchC := C.CString(branch)
+	defer C.free(unsafe.Pointer(branchC))
+
+	var errC *C.GError
+	if C.ostree_validate_rev(branchC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
+
 // ResolveRev returns the revision corresponding to the specified branch
 func (r *Repo) ResolveRev(branch string) (string, error) {
 	if r.ptr == nil {
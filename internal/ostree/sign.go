@@ -0,0 +1,133 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package ostree
+
+import (
+	"errors"
+	"unsafe"
+)
+
+// #cgo pkg-config: ostree-1
+// #include <stdlib.h>
+// #include <glib.h>
+// #include <ostree.h>
+//
+// static gboolean _ostree_repo_sign_commit_gpg(OstreeRepo *repo,
+//                                              const char *commit,
+//                                              const char *key_id,
+//                                              const char *homedir,
+//                                              GError **error) {
+//   g_autoptr(GError) local_error = NULL;
+//   if (!ostree_repo_sign_commit(repo, commit, key_id, homedir, NULL,
+//                                &local_error)) {
+//     // Signing twice with the same key is not an error for us
+//     if (g_error_matches(local_error, G_IO_ERROR, G_IO_ERROR_EXISTS))
+//       return TRUE;
+//     g_propagate_error(error, g_steal_pointer(&local_error));
+//     return FALSE;
+//   }
+//   return TRUE;
+// }
+//
+// static gboolean _ostree_repo_sign_commit_ed25519(OstreeRepo *repo,
+//                                                  const char *commit,
+//                                                  const char *secret_key,
+//                                                  GError **error) {
+// #if OSTREE_CHECK_VERSION(2020, 2)
+//   g_autoptr(OstreeSign) sign = ostree_sign_get_by_name("ed25519", error);
+//   if (sign == NULL)
+//     return FALSE;
+//   g_autoptr(GVariant) sk = g_variant_ref_sink(g_variant_new_string(secret_key));
+//   if (!ostree_sign_set_sk(sign, sk, error))
+//     return FALSE;
+//   return ostree_sign_commit(sign, repo, commit, NULL, error);
+// #else
+//   g_set_error_literal(error, G_IO_ERROR, G_IO_ERROR_NOT_SUPPORTED,
+//                       "ed25519 signing requires libostree 2020.2");
+//   return FALSE;
+// #endif
+// }
+//
+// static gboolean _ostree_repo_sign_summary_gpg(OstreeRepo *repo,
+//                                               const char *key_id,
+//                                               const char *homedir,
+//                                               GError **error) {
+//   const char *key_ids[] = { key_id, NULL };
+//   return ostree_repo_add_gpg_signature_summary(repo, key_ids, homedir, NULL,
+//                                                error);
+// }
+import "C"
+
+// SignCommitGPG signs the commit with the GPG key keyID, homedir can be
+// empty to use the default GPG home directory
+func (r *Repo) SignCommitGPG(rev, keyID, homedir string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+
+	revC := C.CString(rev)
+	defer C.free(unsafe.Pointer(revC))
+	keyIDC := C.CString(keyID)
+	defer C.free(unsafe.Pointer(keyIDC))
+
+	var homedirC *C.char
+	if homedir != "" {
+		homedirC = C.CString(homedir)
+		defer C.free(unsafe.Pointer(homedirC))
+	}
+
+	var errC *C.GError
+	if C._ostree_repo_sign_commit_gpg(r.native(), revC, keyIDC, homedirC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
+
+// SignCommitEd25519 signs the commit with the base64 encoded ed25519 secret key
+func (r *Repo) SignCommitEd25519(rev, secretKey string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+	if !HasCapability(CapabilitySign) {
+		return errors.New("ed25519 signing requires libostree 2020.2 or later")
+	}
+
+	revC := C.CString(rev)
+	defer C.free(unsafe.Pointer(revC))
+	secretKeyC := C.CString(secretKey)
+	defer C.free(unsafe.Pointer(secretKeyC))
+
+	var errC *C.GError
+	if C._ostree_repo_sign_commit_ed25519(r.native(), revC, secretKeyC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
+
+// SignSummaryGPG signs the summary with the GPG key keyID, homedir can be
+// empty to use the default GPG home directory
+func (r *Repo) SignSummaryGPG(keyID, homedir string) error {
+	if r.ptr == nil {
+		return errors.New("repo not initialized")
+	}
+
+	keyIDC := C.CString(keyID)
+	defer C.free(unsafe.Pointer(keyIDC))
+
+	var homedirC *C.char
+	if homedir != "" {
+		homedirC = C.CString(homedir)
+		defer C.free(unsafe.Pointer(homedirC))
+	}
+
+	var errC *C.GError
+	if C._ostree_repo_sign_summary_gpg(r.native(), keyIDC, homedirC, &errC) == C.FALSE {
+		return convertGError(errC)
+	}
+
+	return nil
+}
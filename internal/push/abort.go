@@ -0,0 +1,91 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"os"
+	"os/signal"
+	"sync"
+	"syscall"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// Exit status after an interruption, like shells do for SIGINT
+const interruptedExitStatus = 130
+
+// sessionAborter removes the session in progress from the server when the
+// push fails or is interrupted, so that the server doesn't keep the objects
+// it staged forever
+type sessionAborter struct {
+	client    *Client
+	supported bool
+	mutex     sync.Mutex
+	queueID   string
+	signals   chan os.Signal
+}
+
+// newSessionAborter returns an aborter for the sessions created with client,
+// Ctrl+C and SIGTERM abort the session in progress until Stop is called
+func newSessionAborter(client *Client, info *common.InfoResponse) *sessionAborter {
+	a := &sessionAborter{client: client, signals: make(chan os.Signal, 1)}
+	for _, feature := range info.Features {
+		if feature == common.FeatureSessionAbort {
+			a.supported = true
+		}
+	}
+
+	signal.Notify(a.signals, os.Interrupt, syscall.SIGTERM)
+	go func() {
+		if _, ok := <-a.signals; !ok {
+			return
+		}
+		logger.Warn("Interrupted, aborting the session...")
+		a.Abort()
+		os.Exit(interruptedExitStatus)
+	}()
+
+	return a
+}
+
+// Start remembers the session in progress
+func (a *sessionAborter) Start(queueID string) {
+	a.mutex.Lock()
+	defer a.mutex.Unlock()
+	a.queueID = queueID
+}
+
+// Finish forgets the session, which was published or awaits approval
+func (a *sessionAborter) Finish() {
+	a.Start("")
+}
+
+// Abort removes the session in progress from the server, if any
+func (a *sessionAborter) Abort() {
+	a.mutex.Lock()
+	defer a.mutex.Unlock()
+
+	if a.queueID == "" {
+		return
+	}
+
+	var err error
+	if a.supported {
+		err = a.client.AbortSession(a.queueID)
+	} else {
+		err = a.client.DeleteQueueEntry(a.queueID)
+	}
+	if err != nil {
+		logger.Errorf("Failed to delete entry \"%s\" from queue: %v", a.queueID, err)
+	}
+	a.queueID = ""
+}
+
+// Stop restores the default behavior of Ctrl+C and SIGTERM
+func (a *sessionAborter) Stop() {
+	signal.Stop(a.signals)
+	close(a.signals)
+}
@@ -0,0 +1,191 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"errors"
+	"fmt"
+	"strings"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// How often jobs and history are polled
+const adminPollInterval = 2 * time.Second
+
+// ShowFrozen prints the branches frozen on the server
+func ShowFrozen(opts Options) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	branches, err := client.FrozenBranches()
+	if err != nil {
+		return fmt.Errorf("Failed to list frozen branches: %v", err)
+	}
+
+	printFrozen(branches)
+
+	return nil
+}
+
+// FreezeBranches freezes or thaws the branches on the server
+func FreezeBranches(opts Options, branches []string, frozen bool) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	frozenBranches, err := client.Freeze(branches, frozen)
+	if err != nil {
+		return fmt.Errorf("Failed to freeze branches: %v", err)
+	}
+
+	printFrozen(frozenBranches)
+
+	return nil
+}
+
+// printFrozen prints the list of frozen branches
+func printFrozen(branches []string) {
+	if len(branches) == 0 {
+		logger.Info("No frozen branches")
+		return
+	}
+
+	logger.Infof("Frozen branches: %s", strings.Join(branches, ", "))
+}
+
+// RevokeTokens removes the tokens of subject from the server configuration
+func RevokeTokens(opts Options, subject string) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	revoked, err := client.RevokeTokens(subject)
+	if err != nil {
+		return fmt.Errorf("Failed to revoke tokens: %v", err)
+	}
+	if revoked == 0 {
+		return fmt.Errorf("No tokens issued to \"%s\"", subject)
+	}
+
+	logger.Infof("Revoked %d tokens of \"%s\"", revoked, subject)
+
+	return nil
+}
+
+// RunJob starts a maintenance job on the server and, if wait is true,
+// waits until it's finished
+func RunJob(opts Options, kind string, wait bool) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	job, err := client.StartJob(kind)
+	if err != nil {
+		return fmt.Errorf("Failed to start %s: %v", kind, err)
+	}
+	logger.Infof("Job %s: %s started", job.ID, kind)
+	if !wait {
+		return nil
+	}
+
+	for {
+		time.Sleep(adminPollInterval)
+
+		jobs, err := client.ListJobs()
+		if err != nil {
+			return fmt.Errorf("Failed to list jobs: %v", err)
+		}
+
+		var current *common.JobInfo
+		for i := range jobs {
+			if jobs[i].ID == job.ID {
+				current = &jobs[i]
+			}
+		}
+		if current == nil {
+			return errors.New("Job disappeared from the server")
+		}
+		if current.Finished == nil {
+			continue
+		}
+
+		if current.Error != "" {
+			return fmt.Errorf("Job %s failed: %s", current.ID, current.Error)
+		}
+		logger.Infof("Job %s: %s", current.ID, current.Result)
+		return nil
+	}
+}
+
+// ListJobs prints the maintenance jobs of the server
+func ListJobs(opts Options) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	jobs, err := client.ListJobs()
+	if err != nil {
+		return fmt.Errorf("Failed to list jobs: %v", err)
+	}
+	if len(jobs) == 0 {
+		logger.Info("No jobs")
+		return nil
+	}
+
+	for _, job := range jobs {
+		state := "running"
+		if job.Error != "" {
+			state = "failed: " + job.Error
+		} else if job.Finished != nil {
+			state = job.Result
+		}
+		logger.Infof("%s\t%s\t%s\t%s", job.ID, job.Started.Local().Format("2006-01-02 15:04:05"), job.Kind, state)
+	}
+
+	return nil
+}
+
+// TailHistory prints the last limit publishes and, if follow
+// is true, keeps printing new ones as they happen
+func TailHistory(opts Options, limit int, follow bool) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	var since time.Time
+	for {
+		entries, err := client.GetHistory(since, limit)
+		if err != nil {
+			return fmt.Errorf("Failed to get history: %v", err)
+		}
+
+		for _, entry := range entries {
+			logger.Infof("%s\t%s\t%s", entry.Time.Local().Format("2006-01-02 15:04:05"), entry.ID, entry.KeyID)
+			for _, branch := range common.SortedBranches(entry.Refs) {
+				revPair := entry.Refs[branch]
+				logger.Infof("\t%s: %s -> %s", branch, revPair.Server, revPair.Client)
+			}
+			since = entry.Time
+		}
+
+		if !follow {
+			return nil
+		}
+		if since.IsZero() {
+			since = time.Now()
+		}
+		time.Sleep(adminPollInterval)
+	}
+}
@@ -5,16 +5,29 @@
 package push
 
 import (
+	"archive/tar"
 	"bytes"
+	"compress/gzip"
+	"context"
+	"crypto/ed25519"
+	"crypto/rand"
+	"crypto/sha256"
+	"crypto/tls"
+	"crypto/x509"
+	"encoding/base64"
+	"encoding/hex"
 	"encoding/json"
 	"errors"
 	"fmt"
+	"hash"
 	"io"
 	"io/ioutil"
 	"mime/multipart"
+	"net"
 	"net/http"
 	"net/url"
 	"os"
+	"strconv"
 	"strings"
 	"time"
 
@@ -22,17 +35,67 @@ import (
 	"github.com/lirios/ostree-upload/internal/logger"
 )
 
+// ErrUnauthorized is returned when the server refuses the credentials
+var ErrUnauthorized = errors.New("credentials refused by the server")
+
+// ErrChunksUnsupported is returned when the server can't receive objects in chunks
+var ErrChunksUnsupported = errors.New("server doesn't support uploads in chunks")
+
+// ErrPackUnsupported is returned when the server can't receive objects in a
+// single stream, even though it might say so when it forwards to another one
+var ErrPackUnsupported = errors.New("server doesn't support uploads in a single stream")
+
+// BusyError is returned when the server is too busy, and says when to retry
+type BusyError struct {
+	Message    string
+	RetryAfter time.Duration
+}
+
+func (e *BusyError) Error() string {
+	return fmt.Sprintf("%s, retry in %s", e.Message, e.RetryAfter)
+}
+
 // Client is used to upload objects to a receiver
 type Client struct {
 	endpoint   string
 	userAgent  string
 	httpClient *http.Client
 	token      string
+	serverKey  ed25519.PublicKey
+	user       string
+	password   string
+	tlsConfig  *tls.Config
+	algorithm  string
+
+	// Upload targets are reached directly, whatever the transport of the server
+	targetClient *http.Client
+}
+
+// UnixScheme is the scheme of the addresses of unix sockets, such as
+// unix:///run/ostree-upload.sock
+const UnixScheme = "unix"
+
+// hasScheme returns true if address is a URL with scheme
+func hasScheme(address, scheme string) bool {
+	u, err := url.Parse(address)
+	return err == nil && u.Scheme == scheme
+}
+
+// Attempts to publish when the reply is lost
+const doneAttempts = 3
+
+// How far the signing time of a reply can be from the local time
+const maxReplySkew = 15 * time.Minute
+
+// idempotencyKey returns the key of an operation that can be retried
+// safely, the same for all its attempts
+func idempotencyKey(parts ...string) string {
+	return fmt.Sprintf("%x", sha256.Sum256([]byte(strings.Join(parts, "\x00"))))
 }
 
 // NewClient creates a new upload client connecting to the specified receiver endpoint
 func NewClient(endpoint, token string) (*Client, error) {
-	_, err := url.Parse(endpoint)
+	u, err := url.Parse(endpoint)
 	if err != nil {
 		return nil, err
 	}
@@ -40,9 +103,133 @@ func NewClient(endpoint, token string) (*Client, error) {
 	transport := &http.Transport{
 		DisableCompression: false,
 	}
+
+	// Requests to a unix socket still need a host name
+	if u.Scheme == UnixScheme {
+		socketPath := u.Path
+		transport.DialContext = func(ctx context.Context, network, addr string) (net.Conn, error) {
+			var dialer net.Dialer
+			return dialer.DialContext(ctx, "unix", socketPath)
+		}
+		endpoint = "http://localhost"
+	}
 	httpClient := &http.Client{Transport: transport, Timeout: 60 * time.Minute}
 
-	return &Client{endpoint, "ostree-upload", httpClient, token}, nil
+	return &Client{
+		endpoint:     endpoint,
+		userAgent:    "ostree-upload/" + common.Version,
+		httpClient:   httpClient,
+		token:        token,
+		targetClient: &http.Client{Timeout: 60 * time.Minute},
+	}, nil
+}
+
+// SetBasicAuth authenticates with user and password instead of the token
+func (c *Client) SetBasicAuth(user, password string) {
+	c.user = user
+	c.password = password
+}
+
+// setAuthorization adds the credentials to the request
+func (c *Client) setAuthorization(request *http.Request) {
+	if c.user != "" {
+		request.SetBasicAuth(c.user, c.password)
+	} else {
+		request.Header.Set("Authorization", fmt.Sprintf("BEARER %s", c.token))
+	}
+}
+
+// SetTLS trusts the PEM encoded CA certificates in caFile in addition to
+// the system ones, insecure disables the verification of the certificate
+func (c *Client) SetTLS(caFile string, insecure bool) error {
+	config, err := newTLSConfig(caFile, insecure)
+	if err != nil {
+		return err
+	}
+
+	c.tlsConfig = config
+	if transport, ok := c.httpClient.Transport.(*http.Transport); ok {
+		transport.TLSClientConfig = config
+	}
+
+	return nil
+}
+
+// newTLSConfig creates the TLS configuration for SetTLS
+func newTLSConfig(caFile string, insecure bool) (*tls.Config, error) {
+	config := &tls.Config{InsecureSkipVerify: insecure}
+	if caFile == "" {
+		return config, nil
+	}
+
+	data, err := ioutil.ReadFile(caFile)
+	if err != nil {
+		return nil, err
+	}
+	pool, err := x509.SystemCertPool()
+	if err != nil {
+		pool = x509.NewCertPool()
+	}
+	if !pool.AppendCertsFromPEM(data) {
+		return nil, fmt.Errorf("no certificates found in \"%s\"", caFile)
+	}
+	config.RootCAs = pool
+
+	return config, nil
+}
+
+// SetServerKey pins the base64 encoded ed25519 public key of the server,
+// signed replies will be verified against it
+func (c *Client) SetServerKey(value string) error {
+	key, err := parseServerKey(value)
+	if err != nil {
+		return err
+	}
+
+	c.serverKey = key
+
+	return nil
+}
+
+// serverKeyID returns the identifier of the pinned server key,
+// or an empty string when replies are not verified
+func (c *Client) serverKeyID() string {
+	if c.serverKey == nil {
+		return ""
+	}
+	return common.KeyID(c.serverKey)
+}
+
+// parseServerKey decodes a base64 encoded ed25519 public key
+func parseServerKey(value string) (ed25519.PublicKey, error) {
+	key, err := base64.StdEncoding.DecodeString(value)
+	if err != nil {
+		return nil, err
+	}
+	if len(key) != ed25519.PublicKeySize {
+		return nil, fmt.Errorf("bad server key size %d", len(key))
+	}
+
+	return ed25519.PublicKey(key), nil
+}
+
+// SetChecksumAlgorithm sets the algorithm of the checksums calculated
+// while objects are uploaded, SHA-256 when empty
+func (c *Client) SetChecksumAlgorithm(algorithm string) error {
+	if _, err := common.NewChecksumHasher(algorithm); err != nil {
+		return err
+	}
+
+	c.algorithm = algorithm
+
+	return nil
+}
+
+// newHasher returns the hasher of the checksum algorithm,
+// which was validated by SetChecksumAlgorithm
+func (c *Client) newHasher() hash.Hash {
+	hasher, _ := common.NewChecksumHasher(c.algorithm)
+	return hasher
 }
 
 func (c *Client) newRequest(method, path string, body interface{}) (*http.Request, error) {
@@ -69,11 +256,52 @@ func (c *Client) newRequest(method, path string, body interface{}) (*http.Reques
 	}
 	request.Header.Set("Accept", "application/json")
 	request.Header.Set("User-Agent", c.userAgent)
-	request.Header.Set("Authorization", fmt.Sprintf("BEARER %s", c.token))
+	c.setAuthorization(request)
+	return request, nil
+}
+
+// newCompressedRequest is like newRequest, but the body is gzip compressed
+func (c *Client) newCompressedRequest(method, path string, body interface{}) (*http.Request, error) {
+	buf := new(bytes.Buffer)
+	writer := gzip.NewWriter(buf)
+	if err := json.NewEncoder(writer).Encode(body); err != nil {
+		return nil, err
+	}
+	if err := writer.Close(); err != nil {
+		return nil, err
+	}
+
+	request, err := c.newRequest(method, path, nil)
+	if err != nil {
+		return nil, err
+	}
+	data := buf.Bytes()
+	request.Body = ioutil.NopCloser(bytes.NewReader(data))
+	request.ContentLength = int64(len(data))
+	request.GetBody = func() (io.ReadCloser, error) {
+		return ioutil.NopCloser(bytes.NewReader(data)), nil
+	}
+	request.Header.Set("Content-Type", "application/json")
+	request.Header.Set("Content-Encoding", "gzip")
 	return request, nil
 }
 
 func (c *Client) do(request *http.Request, v interface{}) (*http.Response, error) {
+	return c.doRequest(request, v, false)
+}
+
+// doSigned works like do but also verifies the signature of the reply,
+// if a server key was pinned
+func (c *Client) doSigned(request *http.Request, v interface{}) (*http.Response, error) {
+	return c.doRequest(request, v, true)
+}
+
+func (c *Client) doRequest(request *http.Request, v interface{}, signed bool) (*http.Response, error) {
+	// The signature of the reply must cover this very request
+	if signed && c.serverKey != nil && request.Header.Get(common.NonceHeader) == "" {
+		request.Header.Set(common.NonceHeader, newNonce())
+	}
+
 	response, err := c.httpClient.Do(request)
 	if err != nil {
 		return nil, err
@@ -88,10 +316,28 @@ func (c *Client) do(request *http.Request, v interface{}) (*http.Response, error
 
 	bodyString := strings.TrimSuffix(string(body), "\n")
 
+	// Conditional requests have no body when nothing changed
+	if response.StatusCode == http.StatusNotModified {
+		return response, nil
+	}
+
+	// The server might tell us when to try again
+	if response.StatusCode == http.StatusServiceUnavailable || response.StatusCode == http.StatusTooManyRequests {
+		if seconds, err := strconv.Atoi(response.Header.Get("Retry-After")); err == nil {
+			return response, &BusyError{Message: bodyString, RetryAfter: time.Duration(seconds) * time.Second}
+		}
+	}
+
 	if response.StatusCode != http.StatusOK {
 		return response, errors.New(bodyString)
 	}
 
+	if signed && c.serverKey != nil {
+		if err := c.verifySignature(request, response, body); err != nil {
+			return nil, err
+		}
+	}
+
 	if v != nil {
 		err = json.Unmarshal(body, v)
 		if err != nil {
@@ -107,6 +353,45 @@ func (c *Client) do(request *http.Request, v interface{}) (*http.Response, error
 	return response, nil
 }
 
+// verifySignature checks that the reply was signed by the server for
+// the request, with its nonce, and recently
+func (c *Client) verifySignature(request *http.Request, response *http.Response, body []byte) error {
+	value := response.Header.Get(common.SignatureHeader)
+	if value == "" {
+		return errors.New("reply is not signed")
+	}
+
+	signature, err := base64.StdEncoding.DecodeString(value)
+	if err != nil {
+		return fmt.Errorf("bad reply signature: %v", err)
+	}
+
+	timestamp := response.Header.Get(common.SignatureTimeHeader)
+	seconds, err := strconv.ParseInt(timestamp, 10, 64)
+	if err != nil {
+		return errors.New("reply has no signature time")
+	}
+	if skew := time.Since(time.Unix(seconds, 0)); skew > maxReplySkew || skew < -maxReplySkew {
+		return fmt.Errorf("reply was signed at %s, too far from the local time", time.Unix(seconds, 0).Format(time.RFC3339))
+	}
+
+	payload := common.SignedReply(request.Method, request.URL.Path, request.Header.Get(common.NonceHeader), timestamp, body)
+	if !ed25519.Verify(c.serverKey, payload, signature) {
+		return errors.New("reply signature verification failed")
+	}
+
+	return nil
+}
+
+// newNonce returns a random value identifying a request
+func newNonce() string {
+	nonce := make([]byte, 16)
+	if _, err := rand.Read(nonce); err != nil {
+		panic(err)
+	}
+	return hex.EncodeToString(nonce)
+}
+
 // GetInfo retries remote repository information
 func (c *Client) GetInfo() (*common.InfoResponse, error) {
 	request, err := c.newRequest("GET", "/api/v1/info", nil)
@@ -115,7 +400,7 @@ func (c *Client) GetInfo() (*common.InfoResponse, error) {
 	}
 
 	var info common.InfoResponse
-	_, err = c.do(request, &info)
+	_, err = c.doSigned(request, &info)
 	if err != nil {
 		return nil, err
 	}
@@ -123,9 +408,71 @@ func (c *Client) GetInfo() (*common.InfoResponse, error) {
 	return &info, err
 }
 
+// GetInfoIfNoneMatch retrieves remote repository information unless its
+// entity tag matches etag, in which case a nil response is returned
+func (c *Client) GetInfoIfNoneMatch(etag string) (*common.InfoResponse, string, error) {
+	request, err := c.newRequest("GET", "/api/v1/info", nil)
+	if err != nil {
+		return nil, "", err
+	}
+	if etag != "" {
+		request.Header.Set("If-None-Match", etag)
+	}
+
+	var info common.InfoResponse
+	response, err := c.doSigned(request, &info)
+	if err != nil {
+		return nil, "", err
+	}
+	if response.StatusCode == http.StatusNotModified {
+		return nil, etag, nil
+	}
+
+	return &info, response.Header.Get("ETag"), nil
+}
+
+// CheckToken returns what the server knows about the credentials
+// along with the time of the server
+func (c *Client) CheckToken() (*common.TokenInfoResponse, time.Time, error) {
+	request, err := c.newRequest("GET", "/api/v1/token", nil)
+	if err != nil {
+		return nil, time.Time{}, err
+	}
+
+	var result common.TokenInfoResponse
+	response, err := c.do(request, &result)
+	if err != nil {
+		if response != nil && response.StatusCode == http.StatusUnauthorized {
+			return nil, time.Time{}, ErrUnauthorized
+		}
+		return nil, time.Time{}, err
+	}
+
+	serverTime, _ := http.ParseTime(response.Header.Get("Date"))
+
+	return &result, serverTime, nil
+}
+
+// Preflight asks the server whether the branches can be updated
+func (c *Client) Preflight(updateRefs map[string]common.RevisionPair, force bool) (*common.PreflightResponse, error) {
+	req := common.PreflightRequest{Refs: updateRefs, Force: force}
+	request, err := c.newRequest("POST", "/api/v1/preflight", req)
+	if err != nil {
+		return nil, err
+	}
+
+	var result common.PreflightResponse
+	_, err = c.do(request, &result)
+	if err != nil {
+		return nil, err
+	}
+
+	return &result, nil
+}
+
 // NewQueueEntry tells the server which branches need to be updated
-func (c *Client) NewQueueEntry(updateRefs map[string]common.RevisionPair, objects []string) (string, error) {
-	req := common.QueueRequest{Refs: updateRefs, Objects: objects}
+// and which objects are going to be uploaded
+func (c *Client) NewQueueEntry(req *common.QueueRequest) (string, error) {
 	request, err := c.newRequest("POST", "/api/v1/queue", req)
 	if err != nil {
 		return "", err
@@ -155,90 +502,627 @@ func (c *Client) DeleteQueueEntry(queueID string) error {
 	return nil
 }
 
-// SendObjectsList sends the list of missing objects to the server which will reply
-// with the list of objects that were not already submitted by a previous upload
-func (c *Client) SendObjectsList(queueID string) ([]string, error) {
-	request, err := c.newRequest("GET", fmt.Sprintf("/api/v1/queue/%s", queueID), nil)
+// AbortSession removes the session from the server together with the
+// objects it staged, servers without common.FeatureSessionAbort only
+// support DeleteQueueEntry
+func (c *Client) AbortSession(queueID string) error {
+	request, err := c.newRequest("DELETE", fmt.Sprintf("/api/v1/session/%s", queueID), nil)
+	if err != nil {
+		return err
+	}
+
+	_, err = c.do(request, nil)
+	return err
+}
+
+// ListSessions returns the queue entries on the server
+func (c *Client) ListSessions() ([]common.SessionInfo, error) {
+	request, err := c.newRequest("GET", "/api/v1/sessions", nil)
 	if err != nil {
 		return nil, err
 	}
 
-	var result common.ObjectsResponse
+	var result common.SessionsResponse
 	_, err = c.do(request, &result)
 	if err != nil {
 		return nil, err
 	}
 
-	return result.Objects, nil
+	return result.Sessions, nil
+}
+
+// GetStatus returns the storage status of the server
+func (c *Client) GetStatus() (*common.StatusResponse, error) {
+	request, err := c.newRequest("GET", "/api/v1/status", nil)
+	if err != nil {
+		return nil, err
+	}
+
+	var result common.StatusResponse
+	_, err = c.do(request, &result)
+	if err != nil {
+		return nil, err
+	}
+
+	return &result, nil
 }
 
-// Upload uploads an object
-func (c *Client) Upload(queueID string, objects common.Objects) error {
+// CancelSession removes the entry from the queue on the server
+func (c *Client) CancelSession(sessionID string) error {
+	request, err := c.newRequest("DELETE", fmt.Sprintf("/api/v1/sessions/%s", sessionID), nil)
+	if err != nil {
+		return err
+	}
+
+	_, err = c.do(request, nil)
+	return err
+}
+
+// FrozenBranches returns the branches frozen on the server
+func (c *Client) FrozenBranches() ([]string, error) {
+	request, err := c.newRequest("GET", "/api/v1/admin/frozen", nil)
+	if err != nil {
+		return nil, err
+	}
+
+	var result common.FrozenResponse
+	_, err = c.do(request, &result)
+	if err != nil {
+		return nil, err
+	}
+
+	return result.Branches, nil
+}
+
+// Freeze freezes or thaws the branches and returns the frozen ones
+func (c *Client) Freeze(branches []string, frozen bool) ([]string, error) {
+	request, err := c.newRequest("POST", "/api/v1/admin/frozen", &common.FreezeRequest{Branches: branches, Frozen: frozen})
+	if err != nil {
+		return nil, err
+	}
+
+	var result common.FrozenResponse
+	_, err = c.do(request, &result)
+	if err != nil {
+		return nil, err
+	}
+
+	return result.Branches, nil
+}
+
+// RevokeTokens removes the tokens of the subject from the server
+// configuration and returns how many were revoked
+func (c *Client) RevokeTokens(subject string) (int, error) {
+	request, err := c.newRequest("POST", "/api/v1/admin/revoke", &common.RevokeRequest{Subject: subject})
+	if err != nil {
+		return 0, err
+	}
+
+	var result common.RevokeResponse
+	_, err = c.do(request, &result)
+	if err != nil {
+		return 0, err
+	}
+
+	return result.Revoked, nil
+}
+
+// StartJob starts a maintenance job on the server
+func (c *Client) StartJob(kind string) (*common.JobInfo, error) {
+	request, err := c.newRequest("POST", fmt.Sprintf("/api/v1/admin/jobs/%s", kind), nil)
+	if err != nil {
+		return nil, err
+	}
+
+	var result common.JobInfo
+	_, err = c.do(request, &result)
+	if err != nil {
+		return nil, err
+	}
+
+	return &result, nil
+}
+
+// ListJobs returns the maintenance jobs on the server
+func (c *Client) ListJobs() ([]common.JobInfo, error) {
+	request, err := c.newRequest("GET", "/api/v1/admin/jobs", nil)
+	if err != nil {
+		return nil, err
+	}
+
+	var result common.JobsResponse
+	_, err = c.do(request, &result)
+	if err != nil {
+		return nil, err
+	}
+
+	return result.Jobs, nil
+}
+
+// GetHistory returns up to limit publishes recorded after since, the last ones
+func (c *Client) GetHistory(since time.Time, limit int) ([]common.HistoryRecord, error) {
+	path := fmt.Sprintf("/api/v1/admin/history?limit=%d", limit)
+	if !since.IsZero() {
+		path += fmt.Sprintf("&since=%s", url.QueryEscape(since.Format(time.RFC3339Nano)))
+	}
+	request, err := c.newRequest("GET", path, nil)
+	if err != nil {
+		return nil, err
+	}
+
+	var result common.HistoryResponse
+	_, err = c.do(request, &result)
+	if err != nil {
+		return nil, err
+	}
+
+	return result.Entries, nil
+}
+
+// GetInventory returns the objects the server has, in the format
+// common.InventoryList or common.InventoryBloom with the false positive rate
+func (c *Client) GetInventory(format string, fpRate float64) (*common.InventoryResponse, error) {
+	path := fmt.Sprintf("/api/v1/objects?format=%s", url.QueryEscape(format))
+	if format == common.InventoryBloom && fpRate > 0 {
+		path += fmt.Sprintf("&fp_rate=%g", fpRate)
+	}
+	request, err := c.newRequest("GET", path, nil)
+	if err != nil {
+		return nil, err
+	}
+
+	var result common.InventoryResponse
+	_, err = c.do(request, &result)
+	if err != nil {
+		return nil, err
+	}
+
+	return &result, nil
+}
+
+// SendObjectsList sends the list of missing objects to the server which will reply
+// with the list of objects that were not already submitted by a previous upload,
+// and where to upload them when the server redirects uploads.
+// The list is compressed, servers that don't know about it are asked
+// for all the missing objects of the session.
+func (c *Client) SendObjectsList(queueID string, objectNames []string) ([]string, map[string]common.UploadTarget, error) {
+	request, err := c.newCompressedRequest("POST", fmt.Sprintf("/api/v1/queue/%s/missing_objects", queueID), common.ObjectsRequest{Objects: objectNames})
+	if err != nil {
+		return nil, nil, err
+	}
+
+	var result common.ObjectsResponse
+	response, err := c.do(request, &result)
+	if err != nil {
+		if response == nil || (response.StatusCode != http.StatusNotFound && response.StatusCode != http.StatusMethodNotAllowed) {
+			return nil, nil, err
+		}
+
+		request, err = c.newRequest("GET", fmt.Sprintf("/api/v1/queue/%s", queueID), nil)
+		if err != nil {
+			return nil, nil, err
+		}
+		if _, err := c.do(request, &result); err != nil {
+			return nil, nil, err
+		}
+	}
+
+	return result.Objects, result.UploadTargets, nil
+}
+
+// UploadToTarget uploads an object where the server redirected it, without
+// the credentials of the server; objects without a checksum are hashed
+// while they are sent and updated with the checksum
+func (c *Client) UploadToTarget(target common.UploadTarget, object *common.Object) error {
+	file, err := os.Open(object.ObjectPath)
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+	info, err := file.Stat()
+	if err != nil {
+		return err
+	}
+
+	var body io.Reader = file
+	var hasher hash.Hash
+	if object.Checksum == "" {
+		hasher = c.newHasher()
+		body = io.TeeReader(file, hasher)
+	}
+
+	method := target.Method
+	if method == "" {
+		method = "PUT"
+	}
+	request, err := http.NewRequest(method, target.URL, body)
+	if err != nil {
+		return err
+	}
+
+	// Buckets refuse uploads without a length
+	request.ContentLength = info.Size()
+	for name, value := range target.Header {
+		request.Header.Set(name, value)
+	}
+	request.Header.Set("User-Agent", c.userAgent)
+
+	response, err := c.targetClient.Do(request)
+	if err != nil {
+		return err
+	}
+	defer response.Body.Close()
+	io.Copy(ioutil.Discard, response.Body)
+	if response.StatusCode < 200 || response.StatusCode >= 300 {
+		return fmt.Errorf("Upload target replied %s", response.Status)
+	}
+
+	if hasher != nil {
+		object.Checksum = fmt.Sprintf("%x", hasher.Sum(nil))
+	}
+
+	return nil
+}
+
+// FetchObjects asks the server to fetch the objects uploaded to their
+// targets, telling their checksums, and returns the receipts
+func (c *Client) FetchObjects(queueID string, objects common.Objects) ([]common.ObjectReceipt, error) {
+	checksums := map[string]string{}
+	for objectName, object := range objects {
+		checksums[objectName] = object.Checksum
+	}
+
+	request, err := c.newRequest("POST", fmt.Sprintf("/api/v1/queue/%s/fetch", queueID), common.FetchRequest{Checksums: checksums})
+	if err != nil {
+		return nil, err
+	}
+	request.Header.Set(common.IdempotencyKeyHeader, idempotencyKey(append([]string{"fetch", queueID}, common.SortedObjectNames(objects)...)...))
+
+	var result common.UploadResponse
+	if _, err := c.do(request, &result); err != nil {
+		return nil, err
+	}
+
+	return result.Receipts, nil
+}
+
+// Upload uploads the objects and returns their receipts, the branches
+// are not published until Done() is called. Objects without a checksum
+// are hashed while they are sent and updated with the checksum.
+func (c *Client) Upload(queueID string, objects common.Objects) ([]common.ObjectReceipt, error) {
 	r, w := io.Pipe()
 	writer := multipart.NewWriter(w)
 
-	errChan := make(chan error)
+	// Buffered so that the goroutine never blocks if the request fails early
+	errChan := make(chan error, 1)
 
 	go func() {
-		defer func() {
-			writer.Close()
-			w.Close()
-			errChan <- nil
-		}()
+		errChan <- func() error {
+			for _, objectName := range common.UploadOrder(objects) {
+				object := objects[objectName]
 
-		for _, object := range objects {
-			// Upload each object independently
-			part, err := writer.CreateFormFile("file", object.ObjectName)
-			if err != nil {
-				errChan <- err
-				return
-			}
+				// Upload each object independently
+				part, err := writer.CreateFormFile("file", object.ObjectName)
+				if err != nil {
+					return err
+				}
 
-			file, err := os.Open(object.ObjectPath)
-			if err != nil {
-				errChan <- err
-				return
-			}
+				file, err := os.Open(object.ObjectPath)
+				if err != nil {
+					return err
+				}
+
+				// Hash objects without a checksum while they are sent
+				var destination io.Writer = part
+				var hasher hash.Hash
+				if object.Checksum == "" {
+					hasher = c.newHasher()
+					destination = io.MultiWriter(part, hasher)
+				}
+
+				if _, err = io.Copy(destination, file); err != nil {
+					file.Close()
+					return err
+				}
 
-			if _, err = io.Copy(part, file); err != nil {
 				file.Close()
-				errChan <- err
-				return
-			}
 
-			file.Close()
+				if hasher != nil {
+					object.Checksum = fmt.Sprintf("%x", hasher.Sum(nil))
+					objects[objectName] = object
+				}
 
-			// Let the server verify the checksum
-			if err := writer.WriteField("checksum", fmt.Sprintf("%s:%s", object.ObjectName, object.Checksum)); err != nil {
-				errChan <- err
-				return
+				// Let the server verify the checksum
+				if err := writer.WriteField("checksum", fmt.Sprintf("%s:%s", object.ObjectName, object.Checksum)); err != nil {
+					return err
+				}
 			}
-		}
+
+			return nil
+		}()
+
+		writer.Close()
+		w.Close()
 	}()
 
 	u, err := url.Parse(fmt.Sprintf("%s/api/v1/queue/%s", c.endpoint, queueID))
 	if err != nil {
-		return err
+		return nil, err
 	}
 
 	request, err := http.NewRequest("PUT", u.String(), r)
 	if err != nil {
-		return err
+		return nil, err
 	}
 
 	request.Header.Set("Content-Type", writer.FormDataContentType())
 	request.Header.Set("Accept", "application/json")
 	request.Header.Set("User-Agent", c.userAgent)
-	request.Header.Set("Authorization", fmt.Sprintf("BEARER %s", c.token))
+	request.Header.Set(common.ProtocolHeader, strconv.Itoa(common.ProtocolVersion))
+	request.Header.Set(common.IdempotencyKeyHeader, idempotencyKey(append([]string{"upload", queueID}, common.SortedObjectNames(objects)...)...))
+	c.setAuthorization(request)
 
-	if _, err := c.httpClient.Do(request); err != nil {
-		return err
+	// The body is closed even on failure, so the goroutine stops writing to objects
+	var result common.UploadResponse
+	if _, err := c.do(request, &result); err != nil {
+		<-errChan
+		return nil, err
 	}
 
 	err = <-errChan
 	if err != nil {
-		return err
+		return nil, err
 	}
 
-	return nil
+	return result.Receipts, nil
+}
+
+// UploadPack uploads the objects as a single tar stream, followed by
+// their checksums, and returns the receipts; the checksums calculated
+// while sending are stored in objects. The error is ErrPackUnsupported
+// if the server can't receive the stream.
+func (c *Client) UploadPack(queueID string, objects common.Objects) ([]common.ObjectReceipt, error) {
+	r, w := io.Pipe()
+	writer := tar.NewWriter(w)
+
+	// Buffered so that the goroutine never blocks if the request fails early
+	errChan := make(chan error, 1)
+
+	go func() {
+		err := func() error {
+			index := common.PackIndex{Checksums: map[string]string{}}
+			for _, objectName := range common.UploadOrder(objects) {
+				object := objects[objectName]
+
+				file, err := os.Open(object.ObjectPath)
+				if err != nil {
+					return err
+				}
+				info, err := file.Stat()
+				if err != nil {
+					file.Close()
+					return err
+				}
+				header := &tar.Header{Typeflag: tar.TypeReg, Name: objectName, Mode: 0644, Size: info.Size(), ModTime: info.ModTime()}
+				if err := writer.WriteHeader(header); err != nil {
+					file.Close()
+					return err
+				}
+
+				// Hash objects without a checksum while they are sent
+				var destination io.Writer = writer
+				var hasher hash.Hash
+				if object.Checksum == "" {
+					hasher = c.newHasher()
+					destination = io.MultiWriter(writer, hasher)
+				}
+
+				if _, err = io.Copy(destination, file); err != nil {
+					file.Close()
+					return err
+				}
+
+				file.Close()
+
+				if hasher != nil {
+					object.Checksum = fmt.Sprintf("%x", hasher.Sum(nil))
+					objects[objectName] = object
+				}
+				index.Checksums[objectName] = object.Checksum
+			}
+
+			// Let the server verify the checksums
+			data, err := json.Marshal(index)
+			if err != nil {
+				return err
+			}
+			header := &tar.Header{Typeflag: tar.TypeReg, Name: common.PackIndexName, Mode: 0644, Size: int64(len(data)), ModTime: time.Now()}
+			if err := writer.WriteHeader(header); err != nil {
+				return err
+			}
+			if _, err := writer.Write(data); err != nil {
+				return err
+			}
+
+			return writer.Close()
+		}()
+
+		w.CloseWithError(err)
+		errChan <- err
+	}()
+
+	u, err := url.Parse(fmt.Sprintf("%s/api/v1/queue/%s/upload_pack", c.endpoint, queueID))
+	if err != nil {
+		r.Close()
+		<-errChan
+		return nil, err
+	}
+
+	request, err := http.NewRequest("PUT", u.String(), r)
+	if err != nil {
+		r.Close()
+		<-errChan
+		return nil, err
+	}
+
+	request.Header.Set("Content-Type", "application/x-tar")
+	request.Header.Set("Accept", "application/json")
+	request.Header.Set("User-Agent", c.userAgent)
+	request.Header.Set(common.IdempotencyKeyHeader, idempotencyKey(append([]string{"upload_pack", queueID}, common.SortedObjectNames(objects)...)...))
+	c.setAuthorization(request)
+
+	// The body is closed even on failure, so the goroutine stops writing to objects
+	var result common.UploadResponse
+	if response, err := c.do(request, &result); err != nil {
+		<-errChan
+		if response != nil && (response.StatusCode == http.StatusNotFound || response.StatusCode == http.StatusMethodNotAllowed) {
+			return nil, ErrPackUnsupported
+		}
+		return nil, err
+	}
+
+	if err := <-errChan; err != nil {
+		return nil, err
+	}
+
+	return result.Receipts, nil
+}
+
+// UploadStaticDelta uploads the static delta file between two commits
+// and returns the objects the server still misses
+func (c *Client) UploadStaticDelta(queueID, from, to, path string) ([]string, error) {
+	file, err := os.Open(path)
+	if err != nil {
+		return nil, err
+	}
+	defer file.Close()
+
+	u, err := url.Parse(fmt.Sprintf("%s/api/v1/queue/%s/delta?from=%s&to=%s", c.endpoint, queueID, from, to))
+	if err != nil {
+		return nil, err
+	}
+
+	request, err := http.NewRequest("PUT", u.String(), file)
+	if err != nil {
+		return nil, err
+	}
+
+	request.Header.Set("Content-Type", "application/octet-stream")
+	request.Header.Set("Accept", "application/json")
+	request.Header.Set("User-Agent", c.userAgent)
+	c.setAuthorization(request)
+
+	var result common.ObjectsResponse
+	if _, err := c.do(request, &result); err != nil {
+		return nil, err
+	}
+
+	return result.Objects, nil
+}
+
+// UploadOffset returns how many bytes of an object uploaded in chunks
+// the server holds and, if it has all of them, the receipt; the error
+// is ErrChunksUnsupported if the server can't receive chunks
+func (c *Client) UploadOffset(queueID, objectName string) (*common.ChunkResponse, error) {
+	request, err := c.newRequest("GET", fmt.Sprintf("/api/v1/queue/%s/objects/%s", queueID, objectName), nil)
+	if err != nil {
+		return nil, err
+	}
+
+	var result common.ChunkResponse
+	response, err := c.do(request, &result)
+	if err != nil {
+		if response != nil && (response.StatusCode == http.StatusNotFound || response.StatusCode == http.StatusMethodNotAllowed) {
+			return nil, ErrChunksUnsupported
+		}
+		return nil, err
+	}
+
+	return &result, nil
+}
+
+// UploadChunk sends length bytes of the object from offset, size is the
+// size of the whole object, and returns what the server holds afterwards
+func (c *Client) UploadChunk(queueID string, object common.Object, offset, length, size int64) (*common.ChunkResponse, error) {
+	file, err := os.Open(object.ObjectPath)
+	if err != nil {
+		return nil, err
+	}
+	defer file.Close()
+
+	u, err := url.Parse(fmt.Sprintf("%s/api/v1/queue/%s/objects/%s", c.endpoint, queueID, object.ObjectName))
+	if err != nil {
+		return nil, err
+	}
+
+	request, err := http.NewRequest("PUT", u.String(), io.NewSectionReader(file, offset, length))
+	if err != nil {
+		return nil, err
+	}
+	request.ContentLength = length
+
+	request.Header.Set("Content-Type", "application/octet-stream")
+	request.Header.Set("Content-Range", fmt.Sprintf("bytes %d-%d/%d", offset, offset+length-1, size))
+	request.Header.Set(common.ChecksumHeader, object.Checksum)
+	request.Header.Set("Accept", "application/json")
+	request.Header.Set("User-Agent", c.userAgent)
+	request.Header.Set(common.IdempotencyKeyHeader, idempotencyKey("chunk", queueID, object.ObjectName, strconv.FormatInt(offset, 10), strconv.FormatInt(length, 10)))
+	c.setAuthorization(request)
+
+	var result common.ChunkResponse
+	if _, err := c.do(request, &result); err != nil {
+		return nil, err
+	}
+
+	return &result, nil
+}
+
+// Done publishes the branches and returns the receipt, the server
+// refuses to publish if the object receipts don't match.
+// When the reply is lost the request is sent again with the same
+// idempotency key, so that the server replies with the same receipt
+// instead of failing because the session was already published.
+func (c *Client) Done(queueID string, receipts []common.ObjectReceipt, expected map[string]string) (*common.DoneResponse, error) {
+	req := common.DoneRequest{Receipts: receipts, Expected: expected}
+	key := idempotencyKey("done", queueID)
+
+	// Replayed replies are signed for the first attempt, keep its nonce
+	nonce := newNonce()
+
+	for attempt := 1; ; attempt++ {
+		request, err := c.newRequest("POST", fmt.Sprintf("/api/v1/queue/%s/done", queueID), req)
+		if err != nil {
+			return nil, err
+		}
+		request.Header.Set(common.IdempotencyKeyHeader, key)
+		request.Header.Set(common.NonceHeader, nonce)
+
+		var receipt common.DoneResponse
+		_, err = c.doSigned(request, &receipt)
+		if err == nil {
+			return &receipt, nil
+		}
+
+		// Only the errors without a reply are worth retrying
+		if _, ok := err.(*url.Error); !ok || attempt >= doneAttempts {
+			return nil, err
+		}
+		logger.Warnf("No reply to the publish request, sending it again: %v", err)
+	}
+}
+
+// Approve publishes a queue entry that is waiting for approval
+func (c *Client) Approve(queueID string) (*common.DoneResponse, error) {
+	request, err := c.newRequest("POST", fmt.Sprintf("/api/v1/queue/%s/approve", queueID), nil)
+	if err != nil {
+		return nil, err
+	}
+
+	var receipt common.DoneResponse
+	_, err = c.doSigned(request, &receipt)
+	if err != nil {
+		return nil, err
+	}
+
+	return &receipt, nil
 }
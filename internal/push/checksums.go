@@ -0,0 +1,210 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"encoding"
+	"encoding/json"
+	"fmt"
+	"io"
+	"io/ioutil"
+	"os"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// Objects bigger than this are hashed in steps of this size, the state of
+// the hash is saved after each step so that an interrupted push resumes it
+const checksumCheckpointSize = 256 * 1024 * 1024
+
+// negotiateChecksumAlgorithm returns the requested checksum algorithm, or the
+// preferred one when empty, as long as the server supports it; servers that
+// don't list the algorithms only support SHA-256, which is returned empty so
+// that requests and manifests are those that old servers expect
+func negotiateChecksumAlgorithm(requested string, supported []string) (string, error) {
+	if len(supported) == 0 {
+		supported = []string{common.ChecksumSHA256}
+	}
+	candidates := common.ChecksumAlgorithms
+	if requested != "" {
+		candidates = []string{requested}
+	}
+
+	for _, algorithm := range candidates {
+		for _, supportedAlgorithm := range supported {
+			if algorithm != supportedAlgorithm {
+				continue
+			}
+			if algorithm == common.ChecksumSHA256 {
+				return "", nil
+			}
+			return algorithm, nil
+		}
+	}
+
+	if requested != "" {
+		return "", fmt.Errorf("Server doesn't support %s checksums", requested)
+	}
+	return "", nil
+}
+
+ the checksum of an object along with the size and
+// modification time of the file when it was calculated, or the state of
+// the hash after the first offset bytes while it's being calculated
+type checksumCacheEntry struct {
+	Size      int64  `json:"size"`
+	ModTime   int64  `json:"mtime"`
+	Algorithm string `json:"algorithm,omitempty"`
+	Checksum  string `json:"checksum,omitempty"`
+	Offset    int64  `json:"offset,omitempty"`
+	State     []byte `json:"state,omitempty"`
+}
+
+// ChecksumCache remembers the checksums of the objects, so that they are
+// calculated again only if the file size or modification time changed
+// or if they were calculated with another algorithm
+type ChecksumCache struct {
+	path      string
+	algorithm string
+	entries   map[string]checksumCacheEntry
+}
+
+// OpenChecksumCache loads the cache from path, starting with an empty
+// cache if it doesn't exist or cannot be read; checksums are calculated
+// with algorithm, SHA-256 when empty
+func OpenChecksumCache(path, algorithm string) *ChecksumCache {
+	cache := &ChecksumCache{path: path, algorithm: algorithm, entries: map[string]checksumCacheEntry{}}
+
+	data, err := ioutil.ReadFile(path)
+	if err != nil {
+		if !os.IsNotExist(err) {
+			logger.Warnf("Cannot read checksum cache: %v", err)
+		}
+		return cache
+	}
+	if err := json.Unmarshal(data, &cache.entries); err != nil {
+		logger.Warnf("Ignoring corrupt checksum cache: %v", err)
+		cache.entries = map[string]checksumCacheEntry{}
+	}
+
+	return cache
+}
+
+// Checksum returns the checksum of the object, calculating it only when needed
+func (c *ChecksumCache) Checksum(objectName, objectPath string) (string, error) {
+	info, err := os.Stat(objectPath)
+	if err != nil {
+		return "", err
+	}
+
+	entry, ok := c.entries[objectName]
+	fresh := ok && entry.Size == info.Size() && entry.ModTime == info.ModTime().UnixNano() && entry.Algorithm == c.algorithm
+	if fresh && entry.Checksum != "" {
+		return entry.Checksum, nil
+	}
+
+	var checksum string
+	if info.Size() > checksumCheckpointSize {
+		if !fresh {
+			entry = checksumCacheEntry{Size: info.Size(), ModTime: info.ModTime().UnixNano(), Algorithm: c.algorithm}
+		}
+		checksum, err = c.hashWithCheckpoints(objectName, objectPath, entry)
+	} else {
+		checksum, err = common.CalculateMappedChecksumWith(objectPath, c.algorithm)
+	}
+	if err != nil {
+		return "", err
+	}
+	c.entries[objectName] = checksumCacheEntry{Size: info.Size(), ModTime: info.ModTime().UnixNano(), Algorithm: c.algorithm, Checksum: checksum}
+
+	return checksum, nil
+}
+
+// Store remembers a checksum calculated elsewhere, for example while uploading
+func (c *ChecksumCache) Store(objectName, objectPath, checksum string) error {
+	info, err := os.Stat(objectPath)
+	if err != nil {
+		return err
+	}
+
+	c.entries[objectName] = checksumCacheEntry{Size: info.Size(), ModTime: info.ModTime().UnixNano(), Algorithm: c.algorithm, Checksum: checksum}
+
+	return nil
+}
+
+// hashWithCheckpoints hashes a big object starting from the checkpoint of
+// the entry, if any, and saves a new checkpoint after each step when the
+// state of the hash can be saved
+func (c *ChecksumCache) hashWithCheckpoints(objectName, objectPath string, entry checksumCacheEntry) (string, error) {
+	hasher, err := common.NewChecksumHasher(c.algorithm)
+	if err != nil {
+		return "", err
+	}
+	marshaler, checkpoints := hasher.(encoding.BinaryMarshaler)
+	if !checkpoints {
+		entry.Offset = 0
+	}
+
+	file, err := os.Open(objectPath)
+	if err != nil {
+		return "", err
+	}
+	defer file.Close()
+
+	// Only the tail after the checkpoint is read again
+	if entry.Offset > 0 {
+		if err := hasher.(encoding.BinaryUnmarshaler).UnmarshalBinary(entry.State); err != nil {
+			logger.Warnf("Ignoring bad checksum checkpoint of \"%s\": %v", objectName, err)
+			hasher.Reset()
+			entry.Offset = 0
+		}
+	}
+	if entry.Offset > 0 {
+		logger.Debugf("Resuming checksum of \"%s\" after %d/%d bytes", objectName, entry.Offset, entry.Size)
+		if _, err := file.Seek(entry.Offset, io.SeekStart); err != nil {
+			return "", err
+		}
+	}
+
+	buf := make([]byte, 1024*1024)
+	for entry.Offset < entry.Size {
+		n, err := io.CopyBuffer(hasher, io.LimitReader(file, checksumCheckpointSize), buf)
+		if err != nil {
+			return "", err
+		}
+		if n == 0 {
+			return "", io.ErrUnexpectedEOF
+		}
+		entry.Offset += n
+
+		if checkpoints && entry.Offset < entry.Size {
+			if entry.State, err = marshaler.MarshalBinary(); err != nil {
+				return "", err
+			}
+			c.entries[objectName] = entry
+			if err := c.Save(); err != nil {
+				logger.Warnf("Cannot save checksum checkpoint: %v", err)
+			}
+		}
+	}
+
+	return fmt.Sprintf("%x", hasher.Sum(nil)), nil
+}
+
+// Save writes the cache to disk
+func (c *ChecksumCache) Save() error {
+	data, err := json.Marshal(c.entries)
+	if err != nil {
+		return err
+	}
+
+	// Write to a temporary file first, so that the cache is never truncated
+	tempPath := c.path + ".tmp"
+	if err := ioutil.WriteFile(tempPath, data, 0644); err != nil {
+		return err
+	}
+	return os.Rename(tempPath, c.path)
+}
@@ -5,22 +5,500 @@
 package push
 
 import (
+	"crypto/ed25519"
+	"errors"
 	"fmt"
+	"io/ioutil"
+	"os"
+	"path/filepath"
+	"strings"
+	"sync"
+	"time"
 
 	"github.com/lirios/ostree-upload/internal/common"
 	"github.com/lirios/ostree-upload/internal/logger"
 )
 
-// StartClient starts the client
-func StartClient(url, token, path string, refs []string, prune bool) error {
+// How many times a busy server is asked to start a session
+const busyAttempts = 10
+
+// Objects smaller than this are uploaded together with others
+const batchObjectSize = 1024 * 1024
+
+// How many bytes of objects are sent in a single request at most
+const batchBytes = 16 * 1024 * 1024
+
+// Options contains the client settings
+type Options struct {
+	// URL of the receiver, ssh://[user@]host[:port] runs it over SSH
+	URL string
+	// Token used to authenticate with the receiver
+	Token string
+	// User name for HTTP Basic authentication, used instead of the token
+	User string
+	// Password for HTTP Basic authentication
+	Password string
+	// Path to the local OSTree repository
+	RepoPath string
+	// Branches to push, all of them when empty
+	Branches []string
+	// Path to a file pinning the commit to push for each branch
+	RefFile string
+	// Prune the local repository before the transfer
+	Prune bool
+	// Base64 encoded ed25519 public key of the receiver
+	ServerKey string
+	// Path to PEM encoded CA certificates trusted in addition to the system ones
+	CACert string
+	// Don't verify the TLS certificate of the receiver
+	Insecure bool
+	// Sign commits with this key before pushing them
+	SignKey string
+	// Type of signature, either SignTypeGPG or SignTypeEd25519
+	SignType string
+	// GPG home directory used to sign commits
+	GPGHomedir string
+	// How many times an object is sent before giving up
+	UploadAttempts int
+	// How many objects are uploaded at the same time
+	Jobs int
+	// How many small objects are sent in a single request, one disables it
+	BatchObjects int
+	// Never send all the objects of a session as a single stream
+	NoPack bool
+	// Algorithm of the checksums, the preferred one the server supports when empty
+	ChecksumAlgorithm string
+	// Upload static deltas of the branches instead of their objects
+	StaticDeltas bool
+	// Version the commits must have, not checked when empty
+	ExpectVersion string
+	// Trust the cached repository information if it's fresh enough
+	CachedOK bool
+	// How long the cached repository information is fresh
+	CacheMaxAge time.Duration
+	// Path to the ed25519 key used to sign the push manifest
+	ManifestKey string
+	// Update branches even if the commits don't descend from the published ones
+	Force bool
+	// Split the push into sessions of about this many objects, zero disables it
+	SessionObjects int
+	// Send everything over a single WebSocket connection
+	WebSocket bool
+	// Hash objects bigger than this while they are uploaded, zero disables it
+	DeferHashSize int64
+	// Record the published commits under this remote in the local repository
+	TrackRemote string
+	// Send objects bigger than this in chunks of this size, zero disables it
+	ChunkSize int64
+	// Invoked for each object before anything is uploaded
+	PreUploadHooks []PreUploadHook
+	// Download the objects of the server, either common.InventoryList or
+	// common.InventoryBloom, instead of asking which are missing
+	Inventory string
+	// False positive rate of the Bloom filter
+	InventoryFPRate float64
+}
+
+// batchObjects groups the objects in requests: those smaller than
+// batchObjectSize are sent together, up to batchSize of them and batchBytes
+// in total, the others alone
+func batchObjects(objects common.Objects, batchSize int) []common.Objects {
+	batches := []common.Objects{}
+	small := common.Objects{}
+	var smallSize int64
+	for _, objectName := range common.UploadOrder(objects) {
+		object := objects[objectName]
+		if info, err := os.Stat(object.ObjectPath); err == nil && batchSize > 1 && info.Size() < batchObjectSize {
+			if len(small) > 0 && smallSize+info.Size() > batchBytes {
+				batches = append(batches, small)
+				small = common.Objects{}
+				smallSize = 0
+			}
+			small[objectName] = object
+			smallSize += info.Size()
+			if len(small) >= batchSize {
+				batches = append(batches, small)
+				small = common.Objects{}
+				smallSize = 0
+			}
+			continue
+		}
+		batches = append(batches, common.Objects{objectName: object})
+	}
+	if len(small) > 0 {
+		batches = append(batches, small)
+	}
+	return batches
+}
+
+// uploadObjects uploads objects, jobs requests at the same time, with up
+// to batchSize small objects in each of them. Those that failed are retried
+// at the end until attempts is reached, the receipts sent by the server are
+// verified and recorded in the journal and the checksums calculated while
+// sending are stored in objects.
+// Objects bigger than chunkSize are sent in chunks, so that retrying
+// resumes from what the server received.
+func uploadObjects(client *Client, queueID string, objects common.Objects, attempts, jobs, batchSize int, chunkSize int64, journal *ReceiptJournal) error {
+	if jobs < 1 {
+		jobs = 1
+	}
+	pending := objects
+	total := len(objects)
+	sent := 0
+
+	for attempt := 1; ; attempt++ {
+		failed := common.Objects{}
+		var mutex sync.Mutex
+
+		// Workers write to objects, which might be pending
+		batches := batchObjects(pending, batchSize)
+
+		queue := make(chan common.Objects)
+		var wg sync.WaitGroup
+		for i := 0; i < jobs; i++ {
+			wg.Add(1)
+			go func() {
+				defer wg.Done()
+				for batch := range queue {
+					// Chunks are disabled for everyone when the server doesn't support them
+					mutex.Lock()
+					size := chunkSize
+					mutex.Unlock()
+					receipts, err := uploadBatch(client, queueID, batch, &size)
+
+					mutex.Lock()
+					if size == 0 {
+						chunkSize = 0
+					}
+					if err != nil {
+						for objectName, object := range batch {
+							logger.Warnf("Failed to upload \"%s\": %v", objectName, err)
+							failed[objectName] = object
+						}
+						mutex.Unlock()
+						continue
+					}
+					received := map[string]bool{}
+					for _, receipt := range receipts {
+						object, ok := batch[receipt.ObjectName]
+						if !ok {
+							logger.Warnf("Unexpected receipt for \"%s\"", receipt.ObjectName)
+							continue
+						}
+						if err := journal.Record(object, receipt); err != nil {
+							logger.Warnf("Bad receipt for \"%s\": %v", receipt.ObjectName, err)
+							failed[receipt.ObjectName] = object
+							continue
+						}
+						received[receipt.ObjectName] = true
+						logger.Debugf("Received receipt %s for \"%s\"", receipt.ReceiptID, receipt.ObjectName)
+					}
+					for objectName, object := range batch {
+						objects[objectName] = object
+						if _, ok := failed[objectName]; ok {
+							continue
+						}
+						if !received[objectName] {
+							logger.Warnf("No receipt for \"%s\"", objectName)
+							failed[objectName] = object
+							continue
+						}
+						sent++
+						if sent == total || sent*10/total != (sent-1)*10/total {
+							logger.Infof("Sent %d/%d objects", sent, total)
+						}
+					}
+					mutex.Unlock()
+				}
+			}()
+		}
+		for _, batch := range batches {
+			queue <- batch
+		}
+		close(queue)
+		wg.Wait()
+
+		if len(failed) == 0 {
+			return nil
+		}
+		if attempt >= attempts {
+			return fmt.Errorf("%d objects could not be uploaded", len(failed))
+		}
+
+		logger.Actionf("Retrying %d failed objects (attempt %d/%d)...", len(failed), attempt+1, attempts)
+		pending = failed
+	}
+}
+
+// uploadMetadataFirst uploads the objects with uploadObjects, the commits
+// and trees before the files, which the server refuses until the metadata
+// referencing them is received
+func uploadMetadataFirst(client *Client, queueID string, objects common.Objects, opts Options, journal *ReceiptJournal) error {
+	metadata := common.Objects{}
+	content := common.Objects{}
+	for objectName, object := range objects {
+		if common.IsContentObject(objectName) {
+			content[objectName] = object
+		} else {
+			metadata[objectName] = object
+		}
+	}
+
+	err := uploadObjects(client, queueID, metadata, opts.UploadAttempts, opts.Jobs, opts.BatchObjects, opts.ChunkSize, journal)
+	if err == nil {
+		err = uploadObjects(client, queueID, content, opts.UploadAttempts, opts.Jobs, opts.BatchObjects, opts.ChunkSize, journal)
+	}
+	for objectName, object := range metadata {
+		objects[objectName] = object
+	}
+	for objectName, object := range content {
+		objects[objectName] = object
+	}
+
+	return err
+}
+
+// uploadBatch uploads the objects in a single request, or the only
+// one with uploadObject, and stores the checksums calculated while
+// sending in batch
+func uploadBatch(client *Client, queueID string, batch common.Objects, chunkSize *int64) ([]common.ObjectReceipt, error) {
+	if len(batch) > 1 {
+		logger.Debugf("Sending %d small objects...", len(batch))
+		return client.Upload(queueID, batch)
+	}
+
+	for objectName, object := range batch {
+		logger.Debugf("Sending \"%s\"...", objectName)
+		receipts, err := uploadObject(client, queueID, &object, chunkSize)
+		batch[objectName] = object
+		return receipts, err
+	}
+	return nil, nil
+}
+
+// uploadPack uploads the objects as a single stream, the receipts sent
+// by the server are verified and recorded in the journal and the checksums
+// calculated while sending are stored in objects; it returns the objects
+// without a valid receipt
+func uploadPack(client *Client, queueID string, objects common.Objects, journal *ReceiptJournal) (common.Objects, error) {
+	receipts, err := client.UploadPack(queueID, objects)
+	if err != nil {
+		return nil, err
+	}
+
+	pending := common.Objects{}
+	for objectName, object := range objects {
+		pending[objectName] = object
+	}
+	for _, receipt := range receipts {
+		object, ok := pending[receipt.ObjectName]
+		if !ok {
+			logger.Warnf("Unexpected receipt for \"%s\"", receipt.ObjectName)
+			continue
+		}
+		if err := journal.Record(object, receipt); err != nil {
+			logger.Warnf("Bad receipt for \"%s\": %v", receipt.ObjectName, err)
+			continue
+		}
+		logger.Debugf("Received receipt %s for \"%s\"", receipt.ReceiptID, receipt.ObjectName)
+		delete(pending, receipt.ObjectName)
+	}
+	return pending, nil
+}
+
+// uploadStaticDeltas generates a static delta for each branch that moves
+// forward from the published commit and uploads it in place of the objects;
+// it returns the objects the server still misses, and false if no delta
+// was applied and the objects must be sent as usual
+func uploadStaticDeltas(client *Client, pusher *Pusher, queueID, repoPath string, updateRefs map[string]common.RevisionPair) ([]string, bool) {
+	var missing []string
+	applied := false
+
+	for _, branch := range common.SortedBranches(updateRefs) {
+		revPair := updateRefs[branch]
+		if revPair.Server == "" {
+			continue
+		}
+
+		// Without a common ancestor the server can't apply the delta
+		needed, err := pusher.FindNeededCommits(revPair.Server, revPair.Client)
+		if err != nil || needed.Shallow || needed.CommonAncestor != revPair.Server {
+			logger.Debugf("No common ancestor for %s, sending its objects", branch)
+			continue
+		}
+
+		deltaPath, err := generateStaticDelta(pusher, repoPath, revPair)
+		if err != nil {
+			logger.Warnf("Failed to generate the static delta for %s: %v", branch, err)
+			continue
+		}
+		logger.Actionf("Sending static delta for %s...", branch)
+		result, err := client.UploadStaticDelta(queueID, revPair.Server, revPair.Client, deltaPath)
+		os.Remove(deltaPath)
+		if err != nil {
+			logger.Warnf("Failed to upload the static delta for %s: %v", branch, err)
+			continue
+		}
+		missing = result
+		applied = true
+	}
+
+	return missing, applied
+}
+
+// generateStaticDelta writes the static delta between the published and the
+// new commit in the temporary directory of the repository and returns its path
+func generateStaticDelta(pusher *Pusher, repoPath string, revPair common.RevisionPair) (string, error) {
+	file, err := ioutil.TempFile(filepath.Join(repoPath, "tmp"), "ostree-upload-delta-")
+	if err != nil {
+		return "", err
+	}
+	file.Close()
+
+	if err := pusher.GenerateStaticDelta(revPair.Server, revPair.Client, file.Name()); err != nil {
+		os.Remove(file.Name())
+		return "", err
+	}
+	return file.Name(), nil
+}
+
+// uploadObject uploads an object, in chunks if it's bigger than chunkSize,
+// which is set to zero if the server doesn't support them
+func uploadObject(client *Client, queueID string, object *common.Object, chunkSize *int64) ([]common.ObjectReceipt, error) {
+	if info, err := os.Stat(object.ObjectPath); err == nil && *chunkSize > 0 && info.Size() > *chunkSize {
+		receipt, err := uploadInChunks(client, queueID, object, info.Size(), *chunkSize)
+		if err != ErrChunksUnsupported {
+			if err != nil {
+				return nil, err
+			}
+			return []common.ObjectReceipt{*receipt}, nil
+		}
+		logger.Debug("Server doesn't support uploads in chunks")
+		*chunkSize = 0
+	}
+
+	batch := common.Objects{object.ObjectName: *object}
+	receipts, err := client.Upload(queueID, batch)
+	*object = batch[object.ObjectName]
+	return receipts, err
+}
+
+// uploadInChunks sends a big object in chunks, starting from what the
+// server already holds, and returns the receipt
+func uploadInChunks(client *Client, queueID string, object *common.Object, size, chunkSize int64) (*common.ObjectReceipt, error) {
+	// The server needs the checksum with the last chunk
+	if object.Checksum == "" {
+		checksum, err := common.CalculateMappedChecksumWith(object.ObjectPath, client.algorithm)
+		if err != nil {
+			return nil, err
+		}
+		object.Checksum = checksum
+	}
+
+	status, err := client.UploadOffset(queueID, object.ObjectName)
+	if err != nil {
+		return nil, err
+	}
+	if status.Offset > 0 && status.Receipt == nil {
+		logger.Debugf("Resuming \"%s\" from byte %d", object.ObjectName, status.Offset)
+	}
+
+	for status.Receipt == nil {
+		length := size - status.Offset
+		if length > chunkSize {
+			length = chunkSize
+		}
+		if status, err = client.UploadChunk(queueID, *object, status.Offset, length, size); err != nil {
+			return nil, err
+		}
+	}
+
+	return status.Receipt, nil
+}
+
+// newQueueEntry starts a session, waiting as long as the server
+// asks when it's too busy
+func newQueueEntry(client *Client, req *common.QueueRequest) (string, error) {
+	for attempt := 1; ; attempt++ {
+		queueID, err := client.NewQueueEntry(req)
+		var busyErr *BusyError
+		if !errors.As(err, &busyErr) || attempt >= busyAttempts {
+			return queueID, err
+		}
+
+		logger.Warnf("Server busy: %s (attempt %d/%d)", busyErr.Message, attempt, busyAttempts)
+		time.Sleep(busyErr.RetryAfter)
+	}
+}
+
+// newClient creates the client connecting to the receiver specified by the options,
+// the token is looked up in the keyring when neither token nor user are given
+func newClient(opts Options) (*Client, error) {
+	if hasScheme(opts.URL, SSHScheme) {
+		return newSSHClient(opts.URL)
+	}
+
+	token := opts.Token
+	if token == "" && opts.User == "" {
+		token = LookupToken(opts.URL)
+		if token == "" {
+			return nil, fmt.Errorf("Token is mandatory, pass it or save it with \"ostree-upload login %s\"", opts.URL)
+		}
+		logger.Debugf("Using token from the keyring for %s", opts.URL)
+	}
+
+	client, err := NewClient(opts.URL, token)
+	if err != nil {
+		return nil, err
+	}
+	if opts.User != "" {
+		client.SetBasicAuth(opts.User, opts.Password)
+	}
+	if opts.ServerKey != "" {
+		if err := client.SetServerKey(opts.ServerKey); err != nil {
+			return nil, fmt.Errorf("Invalid server key: %v", err)
+		}
+	}
+	if opts.CACert != "" || opts.Insecure {
+		if opts.Insecure {
+			logger.Warn("TLS certificate verification is disabled, the connection is not protected")
+		}
+		if err := client.SetTLS(opts.CACert, opts.Insecure); err != nil {
+			return nil, fmt.Errorf("Invalid CA certificates: %v", err)
+		}
+	}
+
+	return client, nil
+}
+
+// checkServerVersion warns when the server has a different major version
+func checkServerVersion(info *common.InfoResponse) {
+	if info.ServerVersion == "" {
+		logger.Warn("Server didn't report its version, it might be too old")
+		return
+	}
+
+	logger.Debugf("Server is ostree-upload %s (protocol %d)", info.ServerVersion, info.ProtocolVersion)
+	if common.MajorVersion(info.ServerVersion) != common.MajorVersion(common.Version) {
+		logger.Warnf("Server version %s doesn't match client version %s", info.ServerVersion, common.Version)
+	}
+	if info.ProtocolVersion != common.ProtocolVersion {
+		logger.Warnf("Server speaks protocol %d, client speaks %d", info.ProtocolVersion, common.ProtocolVersion)
+	}
+}
+
+// StartPreflight asks the server whether the branches can be updated,
+// without uploading anything
+func StartPreflight(opts Options) error {
 	// Pusher
-	pusher, err := NewPusher(path, refs)
+	pusher, err := newPusher(opts)
 	if err != nil {
 		return err
 	}
+	pusher.SetForce(opts.Force)
 
 	// Client
-	client, err := NewClient(url, token)
+	client, err := newClient(opts)
 	if err != nil {
 		return err
 	}
@@ -31,10 +509,126 @@ func StartClient(url, token, path string, refs []string, prune bool) error {
 	if err != nil {
 		return fmt.Errorf("Failed to retrieve repository information: %v", err)
 	}
+	checkServerVersion(info)
+
+	// See if there's something to update
+	updateRefs, err := pusher.CheckUpdate(info.AllRevs())
+	if err != nil {
+		return fmt.Errorf("Failed to determine the branches to update: %v", err)
+	}
+	if len(updateRefs) == 0 {
+		logger.Info("Nothing to update!")
+		return nil
+	}
+
+	logger.Action("Running preflight checks...")
+	result, err := client.Preflight(updateRefs, opts.Force)
+	if err != nil {
+		return fmt.Errorf("Failed to run preflight checks: %v", err)
+	}
+
+	// Report
+	for _, reason := range result.Reasons {
+		logger.Errorf("\t%s", reason)
+	}
+	for _, branch := range common.SortedBranches(updateRefs) {
+		branchResult := result.Refs[branch]
+		if branchResult.Pass {
+			logger.Infof("\tBranch \"%s\": pass", branch)
+		} else {
+			logger.Errorf("\tBranch \"%s\": fail", branch)
+			for _, reason := range branchResult.Reasons {
+				logger.Errorf("\t\t%s", reason)
+			}
+		}
+	}
+	logger.Infof("Free space on the server: %d bytes", result.FreeSpace)
+
+	if !result.Pass {
+		return errors.New("Preflight checks failed")
+	}
+
+	return nil
+}
+
+// StartClient starts the client
+func StartClient(opts Options) error {
+	// Pusher
+	pusher, err := newPusher(opts)
+	if err != nil {
+		return err
+	}
+	if opts.SignKey != "" {
+		if err := pusher.SetSigning(opts.SignType, opts.SignKey, opts.GPGHomedir); err != nil {
+			return err
+		}
+	}
+	pusher.SetForce(opts.Force)
+	var manifestKey ed25519.PrivateKey
+	if opts.ManifestKey != "" {
+		if manifestKey, err = LoadManifestKey(opts.ManifestKey); err != nil {
+			return fmt.Errorf("Cannot read manifest key: %v", err)
+		}
+	}
+
+	// Avoid asking the server when the cache says there's nothing to do
+	infoCache := OpenInfoCache(filepath.Join(opts.RepoPath, infoCacheFileName))
+	if opts.CachedOK {
+		keyID := ""
+		if opts.ServerKey != "" {
+			serverKey, err := parseServerKey(opts.ServerKey)
+			if err != nil {
+				return fmt.Errorf("Invalid server key: %v", err)
+			}
+			keyID = common.KeyID(serverKey)
+		}
+		if cached := infoCache.Fresh(opts.URL, keyID, opts.CacheMaxAge); cached != nil {
+			updateRefs, err := pusher.CheckUpdate(cached.AllRevs())
+			if err == nil && len(updateRefs) == 0 {
+				logger.Info("Nothing to update! (cached)")
+				return nil
+			}
+		}
+	}
+
+	// Client
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+	defer client.Close()
+
+	// Single connection for the whole push
+	if opts.WebSocket {
+		if hasScheme(opts.URL, SSHScheme) {
+			return errors.New("WebSocket cannot be used over SSH, which is a single connection already")
+		}
+		if hasScheme(opts.URL, UnixScheme) {
+			return errors.New("WebSocket cannot be used over a unix socket")
+		}
+		logger.Action("Opening push socket...")
+		if err := client.UseSocket(); err != nil {
+			return fmt.Errorf("Failed to open push socket: %v", err)
+		}
+	}
+
+	// Repository information
+	logger.Action("Receiving repository information...")
+	info, err := infoCache.GetInfo(client, opts.URL)
+	if err != nil {
+		return fmt.Errorf("Failed to retrieve repository information: %v", err)
+	}
+	if err := infoCache.Save(); err != nil {
+		logger.Warnf("Cannot save repository information cache: %v", err)
+	}
+	checkServerVersion(info)
+	if info.OstreeVersion != "" {
+		logger.Debugf("Server uses libostree %s with capabilities: %s", info.OstreeVersion, strings.Join(info.Capabilities, ", "))
+	}
 
 	// See if there's something to update
 	logger.Action("Looking for branches to update...")
-	updateRefs, err := pusher.CheckUpdate(info.Revs)
+	updateRefs, err := pusher.CheckUpdate(info.AllRevs())
 	if err != nil {
 		return fmt.Errorf("Failed to determine the branches to update: %v", err)
 	}
@@ -45,7 +639,8 @@ func StartClient(url, token, path string, refs []string, prune bool) error {
 
 	// Update branches
 	logger.Action("About to update the following branches:")
-	for branch, revPair := range updateRefs {
+	for _, branch := range common.SortedBranches(updateRefs) {
+		revPair := updateRefs[branch]
 		if revPair.Server == "" {
 			logger.Infof("\tNew branch \"%s\"\n\t\t  to: %s", branch, revPair.Client)
 		} else {
@@ -53,7 +648,13 @@ func StartClient(url, token, path string, refs []string, prune bool) error {
 		}
 	}
 
-	if prune {
+	if opts.ExpectVersion != "" {
+		if err := pusher.CheckVersion(updateRefs, opts.ExpectVersion); err != nil {
+			return err
+		}
+	}
+
+	if opts.Prune {
 		// Prune the repository before sending any object
 		logger.Action("Pruning repository (this might take a while)...")
 		if err = pusher.Prune(); err != nil {
@@ -61,53 +662,257 @@ func StartClient(url, token, path string, refs []string, prune bool) error {
 		}
 	}
 
+	// Gigantic pushes are split into sessions, oldest commits first,
+	// so that an interruption leaves consistent commits on the server
+	sessions := []map[string]common.RevisionPair{updateRefs}
+	if opts.SessionObjects > 0 {
+		if sessions, err = pusher.SplitUpdate(updateRefs, opts.SessionObjects); err != nil {
+			return fmt.Errorf("Failed to split the push: %v", err)
+		}
+	}
+
+	// Knowing what the server has saves asking it for each session
+	var inventory *Inventory
+	if opts.Inventory != "" {
+		logger.Action("Receiving the objects of the server...")
+		response, err := client.GetInventory(opts.Inventory, opts.InventoryFPRate)
+		if err != nil {
+			logger.Warnf("Cannot receive the objects of the server, asking for the missing ones instead: %v", err)
+		} else {
+			logger.Debugf("Server has %d objects", response.Count)
+			inventory = NewInventory(response)
+		}
+	}
+
+	// The whole session is a single request when the server can receive it,
+	// unless objects are sent at the same time or over the socket
+	pack := false
+	if !opts.NoPack && opts.Jobs <= 1 && !opts.WebSocket {
+		for _, feature := range info.Features {
+			if feature == common.FeatureUploadPack {
+				pack = true
+			}
+		}
+	}
+
+	// Checksums are calculated with the preferred algorithm the server verifies
+	algorithm, err := negotiateChecksumAlgorithm(opts.ChecksumAlgorithm, info.ChecksumAlgorithms)
+	if err != nil {
+		return err
+	}
+	if err := client.SetChecksumAlgorithm(algorithm); err != nil {
+		return err
+	}
+	pusher.SetChecksumAlgorithm(algorithm)
+	if algorithm != "" {
+		logger.Debugf("Using %s checksums", algorithm)
+	}
+
+	// Static deltas replace the objects only when the server can apply them
+	// and they don't need to match a signed manifest
+	deltas := false
+	if opts.StaticDeltas && manifestKey == nil {
+		for _, feature := range info.Features {
+			if feature == common.FeatureStaticDeltaUpload {
+				deltas = true
+			}
+		}
+	}
+
+	// An interrupted or failed session is removed from the server
+	aborter := newSessionAborter(client, info)
+	defer aborter.Stop()
+
+	for i, sessionRefs := range sessions {
+		if len(sessions) > 1 {
+			logger.Actionf("Session %d/%d", i+1, len(sessions))
+		}
+		pending, err := pushSession(client, pusher, aborter, opts, manifestKey, inventory, pack, deltas, sessionRefs)
+		if err != nil {
+			return err
+		}
+		if pending {
+			if i+1 < len(sessions) {
+				logger.Infof("Push again once approved to send the remaining %d sessions", len(sessions)-i-1)
+			}
+			return nil
+		}
+	}
+
+	logger.Info("Done!")
+
+	return nil
+}
+
+// pushSession uploads the objects needed to update the branches in a single
+// session and publishes them, it returns true if the publish awaits approval
+func pushSession(client *Client, pusher *Pusher, aborter *sessionAborter, opts Options, manifestKey ed25519.PrivateKey, inventory *Inventory, pack, deltas bool, updateRefs map[string]common.RevisionPair) (bool, error) {
 	// Collect commits and objects to upload
 	objects, err := pusher.FindObjectsToPush(updateRefs)
 	if err != nil {
-		return fmt.Errorf("Failed to enumerate objects to upload: %v", err)
+		return false, fmt.Errorf("Failed to enumerate objects to upload: %v", err)
+	}
+
+	// Let the hooks filter what leaves the build machine
+	objects, err = runPreUploadHooks(opts.PreUploadHooks, objects)
+	if err != nil {
+		return false, err
 	}
 
 	// Now extract the list object names
-	objectNames := []string{}
-	for objectName := range objects {
-		objectNames = append(objectNames, objectName)
+	objectNames := common.SortedObjectNames(objects)
+
+	// Sign what we are going to send, the server needs all the checksums
+	req := &common.QueueRequest{Refs: updateRefs, Objects: objectNames, Force: opts.Force, ChecksumAlgorithm: client.algorithm}
+	if manifestKey != nil {
+		logger.Action("Signing push manifest...")
+		if err := pusher.CalculateChecksums(objects, 0); err != nil {
+			return false, fmt.Errorf("Failed to calculate checksums: %v", err)
+		}
+		if req.Manifest, req.ManifestSignature, err = signManifest(manifestKey, updateRefs, objects, client.algorithm); err != nil {
+			return false, fmt.Errorf("Failed to sign push manifest: %v", err)
+		}
 	}
 
 	// Start the process
-	queueID, err := client.NewQueueEntry(updateRefs, objectNames)
+	queueID, err := newQueueEntry(client, req)
 	if err != nil {
-		return fmt.Errorf("Failed to check which branches need to be updated: %v", err)
+		return false, fmt.Errorf("Failed to check which branches need to be updated: %v", err)
 	}
+	aborter.Start(queueID)
 
-	// Check which objects we still need to upload
-	wantedObjectNames, err := client.SendObjectsList(queueID)
-	if err != nil {
-		client.DeleteQueueEntry(queueID)
-		return fmt.Errorf("Failed to retrieve the list of objects to upload: %v", err)
+	// Check which objects we still need to upload, after the static deltas
+	// the server already told us; with the inventory the server is only
+	// asked about those it might have
+	var wantedObjectNames []string
+	var targets map[string]common.UploadTarget
+	uncertain := objectNames
+	if deltas {
+		if missing, ok := uploadStaticDeltas(client, pusher, queueID, opts.RepoPath, updateRefs); ok {
+			wantedObjectNames, uncertain = missing, nil
+		}
+	}
+	if inventory != nil && len(uncertain) > 0 {
+		wantedObjectNames, uncertain = inventory.Split(objectNames)
+	}
+	if len(uncertain) > 0 {
+		missing, redirected, err := client.SendObjectsList(queueID, uncertain)
+		if err != nil {
+			aborter.Abort()
+			return false, fmt.Errorf("Failed to retrieve the list of objects to upload: %v", err)
+		}
+		wantedObjectNames = append(wantedObjectNames, missing...)
+		targets = redirected
 	}
 
 	// List of objects to upload
 	wantedObjects := common.Objects{}
-	for objectName, object := range objects {
-		for _, wantedObjectName := range wantedObjectNames {
-			if objectName == wantedObjectName {
-				wantedObjects[objectName] = object
-				break
-			}
+	for _, wantedObjectName := range wantedObjectNames {
+		if object, ok := objects[wantedObjectName]; ok {
+			wantedObjects[wantedObjectName] = object
 		}
 	}
 
-	// Send objects and update refs
+	// The server verifies what we send
+	logger.Action("Calculating checksums...")
+	if err := pusher.CalculateChecksums(wantedObjects, opts.DeferHashSize); err != nil {
+		aborter.Abort()
+		return false, fmt.Errorf("Failed to calculate checksums: %v", err)
+	}
+
+	// Keep track of what the server says it received
+	journal, err := OpenReceiptJournal(opts.RepoPath, queueID)
+	if err != nil {
+		aborter.Abort()
+		return false, fmt.Errorf("Failed to open the receipts journal: %v", err)
+	}
+
+	// Send objects to where the server redirected them, then in a single
+	// stream if possible, what didn't make it is sent again one by one
 	logger.Actionf("Sending %d/%d objects...", len(wantedObjects), len(objects))
-	if err := client.Upload(queueID, wantedObjects); err != nil {
-		logger.Errorf("Failed to upload: %v", err)
-		if err := client.DeleteQueueEntry(queueID); err != nil {
-			logger.Errorf("Failed to delete entry \"%s\" from queue: %v", queueID, err)
+	pending := wantedObjects
+	if len(targets) > 0 {
+		pending = uploadRedirected(client, queueID, wantedObjects, targets, opts.Jobs, journal)
+	}
+	if pack && len(pending) > 0 {
+		packed := pending
+		if pending, err = uploadPack(client, queueID, packed, journal); err == ErrPackUnsupported {
+			logger.Debug("Server doesn't support uploads in a single stream, sending the objects one by one")
+			pending = packed
+		} else if err != nil {
+			logger.Warnf("Failed to upload the pack, sending the objects one by one: %v", err)
+			pending = packed
+		}
+		for objectName, object := range packed {
+			wantedObjects[objectName] = object
+		}
+	}
+	err = uploadMetadataFirst(client, queueID, pending, opts, journal)
+	for objectName, object := range pending {
+		wantedObjects[objectName] = object
+	}
+	if opts.DeferHashSize > 0 {
+		pusher.RememberChecksums(wantedObjects)
+	}
+	if err != nil {
+		aborter.Abort()
+		return false, fmt.Errorf("Failed to upload: %v", err)
+	}
+	if inventory != nil {
+		for objectName := range wantedObjects {
+			inventory.Add(objectName)
 		}
-		return nil
 	}
 
-	logger.Info("Done!")
+	// Update refs
+	logger.Action("Publishing branches...")
+	expected := map[string]string{}
+	for branch, revPair := range updateRefs {
+		expected[branch] = revPair.Server
+	}
+	receipt, err := client.Done(queueID, journal.Receipts(), expected)
+	if err != nil {
+		aborter.Abort()
+		return false, fmt.Errorf("Failed to publish branches: %v", err)
+	}
+	aborter.Finish()
 
-	return nil
+	// The server publishes anyway, but something needs attention
+	for _, warning := range receipt.Warnings {
+		logger.Warnf("Server warning: %s", warning)
+	}
+
+	// Somebody else has to approve the publish
+	if receipt.Pending {
+		logger.Infof("Publishing requires approval, ask somebody else to run \"ostree-upload sessions approve %s\"", queueID)
+		if receipt.Expires != nil {
+			logger.Infof("The request expires on %s", receipt.Expires.Local().Format("2006-01-02 15:04:05"))
+		}
+		return true, nil
+	}
+
+	// Make sure the server published what we asked for
+	for _, branch := range common.SortedBranches(updateRefs) {
+		revPair := updateRefs[branch]
+		if receipt.Revs[branch] != revPair.Client {
+			return false, fmt.Errorf("Server published %s for branch \"%s\" instead of %s", receipt.Revs[branch], branch, revPair.Client)
+		}
+	}
+
+	for strategy, count := range receipt.Transfers {
+		logger.Debugf("Server published %d objects with %s", count, strategy)
+	}
+
+	// Remember what was published without asking the server
+	if opts.TrackRemote != "" {
+		if err := pusher.TrackPublished(opts.TrackRemote, updateRefs); err != nil {
+			logger.Warnf("Failed to track the published commits under \"%s\": %v", opts.TrackRemote, err)
+		}
+	}
+
+	if err := journal.Remove(); err != nil {
+		logger.Warnf("Failed to remove the receipts journal: %v", err)
+	}
+
+	return false, nil
 }
@@ -0,0 +1,79 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"fmt"
+	"sort"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// CompareServers compares the branches of the receivers at urls and
+// returns an error if they diverge
+func CompareServers(opts Options, urls []string) error {
+	if len(urls) != 2 {
+		return fmt.Errorf("Exactly two servers are required, %d given", len(urls))
+	}
+
+	// Repository information from both servers
+	revs := make([]map[string]string, len(urls))
+	for i, url := range urls {
+		serverOpts := opts
+		serverOpts.URL = url
+		client, err := newClient(serverOpts)
+		if err != nil {
+			return err
+		}
+
+		logger.Actionf("Receiving repository information from %s...", url)
+		info, err := client.GetInfo()
+		if err != nil {
+			return fmt.Errorf("Failed to retrieve repository information from %s: %v", url, err)
+		}
+		revs[i] = info.AllRevs()
+	}
+
+	// Union of the branches
+	branchSet := map[string]bool{}
+	for _, serverRevs := range revs {
+		for branch := range serverRevs {
+			branchSet[branch] = true
+		}
+	}
+	branches := make([]string, 0, len(branchSet))
+	for branch := range branchSet {
+		branches = append(branches, branch)
+	}
+	sort.Strings(branches)
+
+	// Report the differences
+	divergences := 0
+	for _, branch := range branches {
+		revA, okA := revs[0][branch]
+		revB, okB := revs[1][branch]
+
+		switch {
+		case !okB:
+			logger.Errorf("\tBranch \"%s\" only on %s: %s", branch, urls[0], revA)
+		case !okA:
+			logger.Errorf("\tBranch \"%s\" only on %s: %s", branch, urls[1], revB)
+		case revA != revB:
+			logger.Errorf("\tBranch \"%s\" differs\n\t\t%s: %s\n\t\t%s: %s", branch, urls[0], revA, urls[1], revB)
+		default:
+			logger.Debugf("\tBranch \"%s\": %s", branch, revA)
+			continue
+		}
+		divergences++
+	}
+
+	if divergences > 0 {
+		return fmt.Errorf("%d of %d branches diverge", divergences, len(branches))
+	}
+
+	logger.Infof("All %d branches match", len(branches))
+
+	return nil
+}
@@ -0,0 +1,252 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"crypto/tls"
+	"fmt"
+	"net"
+	"net/url"
+	"os"
+	"path/filepath"
+	"strings"
+	"syscall"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// How long network checks wait for the server
+const doctorTimeout = 10 * time.Second
+
+// Clock difference with the server above which token expiry becomes unreliable
+const maxClockSkew = 30 * time.Second
+
+// Warn about credentials and certificates expiring sooner than this
+const expiryWarning = 14 * 24 * time.Hour
+
+// doctor collects the problems found by the checks
+type doctor struct {
+	problems int
+}
+
+func (d *doctor) ok(format string, v ...interface{}) {
+	logger.Infof("\tOK: "+format, v...)
+}
+
+func (d *doctor) warn(fix, format string, v ...interface{}) {
+	logger.Warnf("\tWARNING: "+format, v...)
+	logger.Warnf("\t\t%s", fix)
+}
+
+func (d *doctor) fail(fix, format string, v ...interface{}) {
+	d.problems++
+	logger.Errorf("\tERROR: "+format, v...)
+	logger.Errorf("\t\t%s", fix)
+}
+
+// isRepoLocked returns true if another process holds the lock of the
+// repository, libostree takes it during transactions and pruning
+func isRepoLocked(repoPath string) (bool, error) {
+	file, err := os.Open(filepath.Join(repoPath, ".lock"))
+	if os.IsNotExist(err) {
+		return false, nil
+	} else if err != nil {
+		return false, err
+	}
+	defer file.Close()
+
+	lock := syscall.Flock_t{Type: syscall.F_WRLCK}
+	if err := syscall.FcntlFlock(file.Fd(), syscall.F_GETLK, &lock); err != nil {
+		return false, err
+	}
+
+	return lock.Type != syscall.F_UNLCK, nil
+}
+
+// checkLocal checks libostree and the local repository
+func (d *doctor) checkLocal(opts Options) {
+	logger.Action("Checking the local environment...")
+
+	d.ok("libostree %s with capabilities: %s", ostree.Version(), strings.Join(ostree.Capabilities(), ", "))
+	if ostree.Version() != ostree.BuildVersion() {
+		d.ok("libostree %s loaded, built against %s", ostree.Version(), ostree.BuildVersion())
+	}
+
+	repo, err := ostree.OpenRepo(opts.RepoPath)
+	if err != nil {
+		d.fail("Pass the path to the repository with --repo.", "cannot open repository \"%s\": %v", opts.RepoPath, err)
+		return
+	}
+
+	mode, err := repo.GetMode()
+	if err != nil {
+		d.fail("Make sure the repository configuration is not corrupted.", "cannot determine the repository mode: %v", err)
+	} else if mode != "archive" {
+		d.warn("Push from an archive repository, for example with \"ostree pull-local\".", "repository mode is %s, the server usually expects archive objects", mode)
+	} else {
+		d.ok("repository \"%s\" in %s mode", opts.RepoPath, mode)
+	}
+
+	locked, err := isRepoLocked(opts.RepoPath)
+	if err != nil {
+		d.warn("Check the permissions of the repository.", "cannot check the repository lock: %v", err)
+	} else if locked {
+		d.fail("Wait for the other ostree process, such as a commit or a prune, to finish.", "repository is locked by another process")
+	} else {
+		d.ok("repository is not locked")
+	}
+}
+
+// checkNetwork checks that the server can be reached, with TLS if needed
+func (d *doctor) checkNetwork(opts Options) bool {
+	logger.Action("Checking the connection to the server...")
+
+	u, err := url.Parse(opts.URL)
+	if err == nil && u.Scheme == UnixScheme {
+		conn, err := net.DialTimeout("unix", u.Path, doctorTimeout)
+		if err != nil {
+			d.fail("Make sure the receiver is running and the socket is writable by this user.", "cannot connect to %s: %v", u.Path, err)
+			return false
+		}
+		conn.Close()
+		d.ok("%s is reachable", u.Path)
+		return true
+	}
+	if err != nil || u.Host == "" {
+		d.fail("Pass the address with --address, for example https://ostree.example.com.", "invalid server address \"%s\"", opts.URL)
+		return false
+	}
+
+	host := u.Host
+	if u.Port() == "" {
+		if u.Scheme == "https" {
+			host = net.JoinHostPort(u.Hostname(), "443")
+		} else {
+			host = net.JoinHostPort(u.Hostname(), "80")
+		}
+	}
+
+	conn, err := net.DialTimeout("tcp", host, doctorTimeout)
+	if err != nil {
+		d.fail("Check the address, the DNS resolution and that no firewall blocks the connection.", "cannot connect to %s: %v", host, err)
+		return false
+	}
+	conn.Close()
+	d.ok("%s is reachable", host)
+
+	if u.Scheme != "https" {
+		d.warn("Serve HTTPS with tls_cert and tls_key or behind a reverse proxy.", "the connection is not encrypted, the token is sent in clear text")
+		return true
+	}
+
+	tlsConfig, err := newTLSConfig(opts.CACert, opts.Insecure)
+	if err != nil {
+		d.fail("Pass a file with PEM encoded certificates to --ca-cert.", "cannot load the CA certificates: %v", err)
+		return false
+	}
+	tlsConfig.ServerName = u.Hostname()
+	if opts.Insecure {
+		d.warn("Trust the CA of the server with --ca-cert instead of --insecure.", "the certificate of the server is not verified")
+	}
+
+	dialer := &net.Dialer{Timeout: doctorTimeout}
+	tlsConn, err := tls.DialWithDialer(dialer, "tcp", host, tlsConfig)
+	if err != nil {
+		d.fail("Make sure the certificate is valid for the host name and its CA is trusted by the system.", "TLS handshake with %s failed: %v", host, err)
+		return false
+	}
+	defer tlsConn.Close()
+
+	certs := tlsConn.ConnectionState().PeerCertificates
+	if len(certs) > 0 {
+		expires := certs[0].NotAfter
+		if time.Until(expires) < expiryWarning {
+			d.warn("Renew the certificate of the server.", "the certificate expires on %s", expires.Local().Format("2006-01-02 15:04:05"))
+		} else {
+			d.ok("certificate valid until %s", expires.Local().Format("2006-01-02"))
+		}
+	}
+
+	return true
+}
+
+// checkServer checks the server version, the credentials and the clock
+func (d *doctor) checkServer(opts Options) {
+	logger.Action("Checking the server...")
+
+	client, err := newClient(opts)
+	if err != nil {
+		d.fail("Pass the token with --token or --token-file, or save it with \"ostree-upload login\".", "%v", err)
+		return
+	}
+
+	info, err := client.GetInfo()
+	if err != nil {
+		d.fail("Make sure the address points to ostree-upload receive and, with --server-key, that the key is right.", "cannot retrieve repository information: %v", err)
+	} else {
+		d.ok("ostree-upload %s with libostree %s, repository in %s mode", info.ServerVersion, info.OstreeVersion, info.Mode)
+		checkServerVersion(info)
+	}
+
+	tokenInfo, serverTime, err := client.CheckToken()
+	if err == ErrUnauthorized {
+		d.fail("Generate a new token with \"ostree-upload gentoken\" on the server or check the user name and password.", "the server refused the credentials")
+		return
+	} else if err != nil {
+		d.fail("Upgrade the server, older versions can't check the credentials.", "cannot check the credentials: %v", err)
+		return
+	}
+
+	name := tokenInfo.Subject
+	if name == "" {
+		name = "without subject"
+	}
+	scopes := "all"
+	if tokenInfo.NoScopes {
+		scopes = "none"
+	} else if len(tokenInfo.Scopes) > 0 {
+		scopes = strings.Join(tokenInfo.Scopes, ", ")
+	}
+	d.ok("authenticated with %s %s, scopes: %s", tokenInfo.Kind, name, scopes)
+	if len(tokenInfo.Refs) > 0 {
+		d.ok("allowed to update: %s", strings.Join(tokenInfo.Refs, ", "))
+	}
+	if tokenInfo.Expires != nil && time.Until(*tokenInfo.Expires) < expiryWarning {
+		d.warn("Ask for a new token before it expires.", "the token expires on %s", tokenInfo.Expires.Local().Format("2006-01-02 15:04:05"))
+	}
+
+	if !serverTime.IsZero() {
+		skew := time.Since(serverTime)
+		if skew < 0 {
+			skew = -skew
+		}
+		if skew > maxClockSkew {
+			d.fail("Synchronize the clocks with NTP, for example with systemd-timesyncd.", "the clock differs from the server by %s", skew.Round(time.Second))
+		} else {
+			d.ok("clock in sync with the server")
+		}
+	}
+}
+
+// Doctor diagnoses the most common problems of the environment, printing
+// what to do about them, and returns an error if any check failed
+func Doctor(opts Options) error {
+	d := &doctor{}
+
+	d.checkLocal(opts)
+	if d.checkNetwork(opts) {
+		d.checkServer(opts)
+	}
+
+	if d.problems > 0 {
+		return fmt.Errorf("Found %d problems", d.problems)
+	}
+
+	logger.Info("No problems found!")
+
+	return nil
+}
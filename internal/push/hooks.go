@@ -0,0 +1,141 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"bytes"
+	"errors"
+	"fmt"
+	"os"
+	"os/exec"
+	"strings"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// PreUploadAction tells what to do with an object before it's uploaded
+type PreUploadAction int
+
+// Actions of a PreUploadHook
+const (
+	// PreUploadAccept uploads the object
+	PreUploadAccept PreUploadAction = iota
+
+	// PreUploadSkip leaves the object out of the push
+	PreUploadSkip
+
+	// PreUploadVeto aborts the push
+	PreUploadVeto
+)
+
+// Exit status of --pre-upload-cmd that skips the object
+const preUploadSkipStatus = 10
+
+// PreUploadResult is the decision of a PreUploadHook about an object
+type PreUploadResult struct {
+	Action PreUploadAction
+	// Why the object is skipped or vetoed, or a note about it
+	Message string
+}
+
+// PreUploadHook is invoked for each object that is going to be uploaded,
+// before anything is sent to the server
+type PreUploadHook interface {
+	PreUpload(object common.Object, size int64) (PreUploadResult, error)
+}
+
+// CommandHook runs a shell command for each object: the object is described
+// by environment variables, the first line written to standard output is
+// the message and the exit status is the action
+type CommandHook struct {
+	Command string
+}
+
+// PreUpload runs the command
+func (h *CommandHook) PreUpload(object common.Object, size int64) (PreUploadResult, error) {
+	cmd := exec.Command("/bin/sh", "-c", h.Command)
+	cmd.Env = append(os.Environ(),
+		"OSTREE_UPLOAD_OBJECT="+object.ObjectName,
+		"OSTREE_UPLOAD_OBJECT_PATH="+object.ObjectPath,
+		"OSTREE_UPLOAD_OBJECT_SIZE="+fmt.Sprint(size),
+		"OSTREE_UPLOAD_REV="+object.Rev)
+	var stdout bytes.Buffer
+	cmd.Stdout = &stdout
+	cmd.Stderr = os.Stderr
+
+	err := cmd.Run()
+	message := strings.TrimSpace(strings.SplitN(stdout.String(), "\n", 2)[0])
+	if err == nil {
+		return PreUploadResult{Action: PreUploadAccept, Message: message}, nil
+	}
+
+	var exitErr *exec.ExitError
+	if !errors.As(err, &exitErr) {
+		return PreUploadResult{}, err
+	}
+	if exitErr.ExitCode() == preUploadSkipStatus {
+		return PreUploadResult{Action: PreUploadSkip, Message: message}, nil
+	}
+	if message == "" {
+		message = fmt.Sprintf("refused by %s (%v)", h.Command, err)
+	}
+	return PreUploadResult{Action: PreUploadVeto, Message: message}, nil
+}
+
+// runPreUploadHooks passes the objects through the hooks, returning those
+// that are still going to be uploaded or an error if any of them is vetoed
+func runPreUploadHooks(hooks []PreUploadHook, objects common.Objects) (common.Objects, error) {
+	if len(hooks) == 0 {
+		return objects, nil
+	}
+
+	accepted := common.Objects{}
+	vetoed := 0
+	for _, objectName := range common.SortedObjectNames(objects) {
+		object := objects[objectName]
+
+		var size int64
+		if info, err := os.Stat(object.ObjectPath); err == nil {
+			size = info.Size()
+		}
+
+		action := PreUploadAccept
+		for _, hook := range hooks {
+			result, err := hook.PreUpload(object, size)
+			if err != nil {
+				return nil, fmt.Errorf("Pre-upload hook failed on %s: %v", objectName, err)
+			}
+
+			switch result.Action {
+			case PreUploadAccept:
+				if result.Message != "" {
+					logger.Infof("%s: %s", objectName, result.Message)
+				}
+			case PreUploadSkip:
+				logger.Warnf("Skipping %s: %s", objectName, result.Message)
+			case PreUploadVeto:
+				logger.Errorf("Refusing %s: %s", objectName, result.Message)
+			}
+			if result.Action != PreUploadAccept {
+				action = result.Action
+				break
+			}
+		}
+
+		switch action {
+		case PreUploadAccept:
+			accepted[objectName] = object
+		case PreUploadVeto:
+			vetoed++
+		}
+	}
+
+	if vetoed > 0 {
+		return nil, fmt.Errorf("Pre-upload hooks refused %d objects", vetoed)
+	}
+
+	return accepted, nil
+}
@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"encoding/json"
+	"errors"
+	"io/ioutil"
+	"os"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// Name of the repository information cache inside the OSTree repository
+const infoCacheFileName = "tmp/ostree-upload-info.json"
+
+// How long the cached information of a server with a pinned key is trusted
+// after its signature was verified, a reply that it didn't change is not signed
+const infoCacheMaxAge = time.Hour
+
+// infoCacheEntry is the repository information of a server along
+// with its entity tag and when it was retrieved
+type infoCacheEntry struct {
+	ETag    string              `json:"etag"`
+	Fetched time.Time           `json:"fetched"`
+	Info    common.InfoResponse `json:"info"`
+
+	// Key that signed the information and when the signature was verified
+	KeyID    string    `json:"key_id,omitempty"`
+	Verified time.Time `json:"verified,omitempty"`
+}
+
+// usable returns true if the entry can be trusted when the server key
+// identified by keyID is pinned, which requires a recent signature by it
+func (e *infoCacheEntry) usable(keyID string) bool {
+	if keyID == "" {
+		return true
+	}
+	return e.KeyID == keyID && time.Since(e.Verified) < infoCacheMaxAge
+}
+
+// InfoCache remembers the repository information of the servers, so that
+// it's retrieved again only when it changed
+type InfoCache struct {
+	path    string
+	entries map[string]*infoCacheEntry
+}
+
+// OpenInfoCache loads the cache from path, starting with an empty
+// cache if it doesn't exist or cannot be read
+func OpenInfoCache(path string) *InfoCache {
+	cache := &InfoCache{path: path, entries: map[string]*infoCacheEntry{}}
+
+	data, err := ioutil.ReadFile(path)
+	if err != nil {
+		if !os.IsNotExist(err) {
+			logger.Warnf("Cannot read repository information cache: %v", err)
+		}
+		return cache
+	}
+	if err := json.Unmarshal(data, &cache.entries); err != nil {
+		logger.Warnf("Ignoring corrupt repository information cache: %v", err)
+		cache.entries = map[string]*infoCacheEntry{}
+	}
+
+	return cache
+}
+
+// Fresh returns the cached information of the server if it was
+// retrieved less than maxAge ago, or nil; keyID identifies the pinned
+// server key, if any
+func (c *InfoCache) Fresh(url, keyID string, maxAge time.Duration) *common.InfoResponse {
+	entry, ok := c.entries[url]
+	if !ok || time.Since(entry.Fetched) > maxAge || !entry.usable(keyID) {
+		return nil
+	}
+	return &entry.Info
+}
+
+// GetInfo retrieves the repository information from the server, with a
+// conditional request when the cache has it
+func (c *InfoCache) GetInfo(client *Client, url string) (*common.InfoResponse, error) {
+	keyID := client.serverKeyID()
+
+	// Without a recent signature the information is retrieved again
+	etag := ""
+	entry, ok := c.entries[url]
+	if ok && entry.usable(keyID) {
+		etag = entry.ETag
+	}
+
+	info, newETag, err := client.GetInfoIfNoneMatch(etag)
+	if err != nil {
+		return nil, err
+	}
+
+	if info == nil {
+		// Not modified, the reply is not signed so it doesn't count as a verification
+		if etag == "" {
+			return nil, errors.New("Server replied not modified to an unconditional request")
+		}
+		logger.Debug("Repository information didn't change")
+		entry.Fetched = time.Now().UTC()
+		return &entry.Info, nil
+	}
+
+	now := time.Now().UTC()
+	entry = &infoCacheEntry{ETag: newETag, Fetched: now, Info: *info}
+	if keyID != "" {
+		entry.KeyID = keyID
+		entry.Verified = now
+	}
+	c.entries[url] = entry
+	return info, nil
+}
+
+// Save writes the cache to disk
+func (c *InfoCache) Save() error {
+	data, err := json.Marshal(c.entries)
+	if err != nil {
+		return err
+	}
+
+	// Write to a temporary file first, so that the cache is never truncated
+	tempPath := c.path + ".tmp"
+	if err := ioutil.WriteFile(tempPath, data, 0644); err != nil {
+		return err
+	}
+	return os.Rename(tempPath, c.path)
+}
@@ -0,0 +1,48 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"github.com/lirios/ostree-upload/internal/common"
+)
+
+// Inventory is what the server had when the push started, plus what
+// was uploaded since
+type Inventory struct {
+	known map[string]bool
+	bloom *common.BloomFilter
+}
+
+// NewInventory creates the inventory from the reply of the server
+func NewInventory(response *common.InventoryResponse) *Inventory {
+	inventory := &Inventory{known: map[string]bool{}, bloom: response.Bloom}
+	for _, objectName := range response.Objects {
+		inventory.known[objectName] = true
+	}
+	return inventory
+}
+
+// Add records that the server has the object
+func (i *Inventory) Add(objectName string) {
+	i.known[objectName] = true
+}
+
+// Split returns the objects the server surely doesn't have and those
+// it might have, according to the Bloom filter, leaving out the others
+func (i *Inventory) Split(objectNames []string) ([]string, []string) {
+	missing := []string{}
+	uncertain := []string{}
+	for _, objectName := range objectNames {
+		if i.known[objectName] {
+			continue
+		}
+		if i.bloom != nil && i.bloom.MayContain(objectName) {
+			uncertain = append(uncertain, objectName)
+		} else {
+			missing = append(missing, objectName)
+		}
+	}
+	return missing, uncertain
+}
@@ -0,0 +1,60 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"bytes"
+	"fmt"
+	"os/exec"
+	"strings"
+)
+
+// Attributes identifying the tokens in the secret service
+const (
+	keyringService   = "ostree-upload"
+	keyringAttribute = "server"
+)
+
+// secretTool runs secret-tool from libsecret, which talks to
+// the secret service of the desktop (GNOME Keyring, KWallet...)
+func secretTool(stdin string, args ...string) (string, error) {
+	cmd := exec.Command("secret-tool", args...)
+	cmd.Stdin = strings.NewReader(stdin)
+	var stdout, stderr bytes.Buffer
+	cmd.Stdout = &stdout
+	cmd.Stderr = &stderr
+
+	if err := cmd.Run(); err != nil {
+		if msg := strings.TrimSpace(stderr.String()); msg != "" {
+			return "", fmt.Errorf("%v: %s", err, msg)
+		}
+		return "", err
+	}
+
+	return strings.TrimSpace(stdout.String()), nil
+}
+
+// StoreToken saves the token for the server in the keyring
+func StoreToken(url, token string) error {
+	label := fmt.Sprintf("ostree-upload token for %s", url)
+	_, err := secretTool(token, "store", "--label="+label, "service", keyringService, keyringAttribute, url)
+	return err
+}
+
+// LookupToken returns the token saved for the server, or an
+// empty string if there is none or the keyring is not available
+func LookupToken(url string) string {
+	token, err := secretTool("", "lookup", "service", keyringService, keyringAttribute, url)
+	if err != nil {
+		return ""
+	}
+	return token
+}
+
+// ClearToken removes the token for the server from the keyring
+func ClearToken(url string) error {
+	_, err := secretTool("", "clear", "service", keyringService, keyringAttribute, url)
+	return err
+}
@@ -0,0 +1,100 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"fmt"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// How many published commits are shown below the ones to push
+const logPublishedContext = 3
+
+// logLine prints a commit, as a graph node when graph is true
+func (p *Pusher) logLine(rev, marker, label string, graph bool) {
+	subject, _, err := p.repo.GetCommitMessage(rev)
+	if err != nil {
+		subject = fmt.Sprintf("(%v)", err)
+	}
+
+	if graph {
+		logger.Infof("%s %s %s %s", marker, rev[:12], label, subject)
+		logger.Info("|")
+	} else {
+		logger.Infof("%s\t%s\t%s", label, rev, subject)
+	}
+}
+
+// LogBranches shows which commits of the branches would be pushed and
+// which ones the server already has
+func LogBranches(opts Options, graph bool) error {
+	// Pusher
+	pusher, err := newPusher(opts)
+	if err != nil {
+		return err
+	}
+
+	// Client
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	// Repository information
+	info, err := client.GetInfo()
+	if err != nil {
+		return fmt.Errorf("Failed to retrieve repository information: %v", err)
+	}
+
+	remoteRevs := info.AllRevs()
+	for _, branch := range common.SortedBranchNames(pusher.branches) {
+		localRev := pusher.branches[branch]
+		remoteRev := remoteRevs[branch]
+
+		logger.Actionf("Branch \"%s\"", branch)
+
+		needed, err := pusher.FindNeededCommits(remoteRev, localRev)
+		if err != nil {
+			return err
+		}
+
+		for _, pushRev := range needed.Commits {
+			pusher.logLine(pushRev, "*", "[push]", graph)
+		}
+
+		switch {
+		case needed.Diverged:
+			// The remote commit is not in the local history
+			if graph {
+				logger.Infof("x %s [server] not in local history", remoteRev[:12])
+			}
+			logger.Errorf("Diverged: the server commit %s is not an ancestor of %s", remoteRev, localRev)
+		case remoteRev == "":
+			if needed.Shallow {
+				logger.Infof("New branch, %d commits to push (shallow history)", len(needed.Commits))
+			} else {
+				logger.Infof("New branch, %d commits to push", len(needed.Commits))
+			}
+		default:
+			// Show some context below the commits to push
+			rev := needed.CommonAncestor
+			for i := 0; rev != "" && i < logPublishedContext && pusher.repo.HasCommit(rev); i++ {
+				label := "[published]"
+				if rev == remoteRev {
+					label = "[server]"
+				}
+				pusher.logLine(rev, "o", label, graph)
+				if rev, err = pusher.repo.GetParentRev(rev); err != nil {
+					break
+				}
+			}
+			logger.Infof("%d commits to push", len(needed.Commits))
+		}
+	}
+
+	return nil
+}
@@ -0,0 +1,66 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"crypto/ed25519"
+	"crypto/rand"
+	"encoding/base64"
+	"fmt"
+	"io/ioutil"
+	"strings"
+
+	"github.com/lirios/ostree-upload/internal/common"
+)
+
+// GenerateManifestKey saves a new ed25519 private key seed, base64 encoded,
+// to path and returns the base64 encoded public key for the server and its ID
+func GenerateManifestKey(path string) (string, string, error) {
+	publicKey, privateKey, err := ed25519.GenerateKey(rand.Reader)
+	if err != nil {
+		return "", "", err
+	}
+
+	seed := base64.StdEncoding.EncodeToString(privateKey.Seed())
+	if err := ioutil.WriteFile(path, []byte(seed+"\n"), 0600); err != nil {
+		return "", "", err
+	}
+
+	return base64.StdEncoding.EncodeToString(publicKey), common.KeyID(publicKey), nil
+}
+
+// LoadManifestKey reads the base64 encoded ed25519 private key seed from path
+func LoadManifestKey(path string) (ed25519.PrivateKey, error) {
+	data, err := ioutil.ReadFile(path)
+	if err != nil {
+		return nil, err
+	}
+
+	seed, err := base64.StdEncoding.DecodeString(strings.TrimSpace(string(data)))
+	if err != nil {
+		return nil, err
+	}
+	if len(seed) != ed25519.SeedSize {
+		return nil, fmt.Errorf("bad manifest key size %d", len(seed))
+	}
+
+	return ed25519.NewKeyFromSeed(seed), nil
+}
+
+// signManifest describes the push and signs it with the key, objects
+// must have their checksums already calculated with algorithm
+func signManifest(key ed25519.PrivateKey, updateRefs map[string]common.RevisionPair, objects common.Objects, algorithm string) (*common.PushManifest, string, error) {
+	manifest := &common.PushManifest{Refs: updateRefs, Objects: map[string]string{}, ChecksumAlgorithm: algorithm}
+	for objectName, object := range objects {
+		manifest.Objects[objectName] = object.Checksum
+	}
+
+	payload, err := manifest.Payload()
+	if err != nil {
+		return nil, "", err
+	}
+
+	return manifest, base64.StdEncoding.EncodeToString(ed25519.Sign(key, payload)), nil
+}
@@ -7,16 +7,31 @@ package push
 import (
 	"fmt"
 	"os"
+	"path/filepath"
 
 	"github.com/lirios/ostree-upload/internal/common"
 	"github.com/lirios/ostree-upload/internal/logger"
 	"github.com/lirios/ostree-upload/internal/ostree"
 )
 
+// Name of the checksum cache inside the OSTree repository
+const checksumCacheFileName = "tmp/ostree-upload-checksums.json"
+
+// Signature types supported by SetSigning()
+const (
+	SignTypeGPG     = "gpg"
+	SignTypeEd25519 = "ed25519"
+)
+
 // Pusher allows you to push missing objects to an OSTree repository
 type Pusher struct {
-	repo     *ostree.Repo
-	branches map[string]string
+	repo       *ostree.Repo
+	branches   map[string]string
+	signType   string
+	signKey    string
+	gpgHomedir string
+	force      bool
+	algorithm  string
 }
 
 // NewPusher creates a new Pusher object
@@ -39,9 +54,32 @@ func NewPusher(repoPath string, refs []string) (*Pusher, error) {
 			branches[branch] = rev
 		}
 	} else {
+		// Refs mirrored from other collections are only pushed when asked
+		mirrors, err := repo.ListMirrorRevisions()
+		if err != nil {
+			return nil, err
+		}
+
 		for _, ref := range refs {
+			// Branches use forward slashes on every platform
+			ref = filepath.ToSlash(ref)
+			if err := ostree.ValidateBranch(ref); err != nil {
+				return nil, fmt.Errorf("invalid branch name %q: %v", ref, err)
+			}
+
+			if _, _, ok := ostree.ParseMirrorRef(ref); ok {
+				rev, found := mirrors[ref]
+				if !found {
+					return nil, fmt.Errorf("mirrored ref %q not found", ref)
+				}
+				branches[ref] = rev
+				continue
+			}
+
 			rev, err := repo.ResolveRev(ref)
-			if err != nil {
+			if ostree.IsNotFound(err) {
+				return nil, fmt.Errorf("branch %q not found in the local repository", ref)
+			} else if err != nil {
 				return nil, err
 			}
 
@@ -49,36 +87,200 @@ func NewPusher(repoPath string, refs []string) (*Pusher, error) {
 		}
 	}
 
-	return &Pusher{repo, branches}, nil
+	return &Pusher{repo: repo, branches: branches}, nil
+}
+
+// NewPinnedPusher creates a Pusher that pushes exactly the commits
+// in pins, keyed by branch, rather than the local tips
+func NewPinnedPusher(repoPath string, pins map[string]string) (*Pusher, error) {
+	if len(pins) == 0 {
+		return nil, fmt.Errorf("no branches to push")
+	}
+
+	// Check if the repository path exist
+	repo, err := ostree.OpenRepo(repoPath)
+	if err != nil {
+		return nil, err
+	}
+
+	// The pinned commits must be available locally
+	branches := map[string]string{}
+	for branch, rev := range pins {
+		if !repo.HasCommit(rev) {
+			return nil, fmt.Errorf("commit %s of branch %q not found in %s", rev, branch, repoPath)
+		}
+		branches[branch] = rev
+	}
+
+	return &Pusher{repo: repo, branches: branches}, nil
+}
+
+// SetSigning signs the commits before they are pushed: key is the GPG key ID
+// for SignTypeGPG and the base64 encoded secret key for SignTypeEd25519
+func (p *Pusher) SetSigning(signType, key, gpgHomedir string) error {
+	if signType != SignTypeGPG && signType != SignTypeEd25519 {
+		return fmt.Errorf("unsupported signature type \"%s\"", signType)
+	}
+
+	p.signType = signType
+	p.signKey = key
+	p.gpgHomedir = gpgHomedir
+
+	return nil
 }
 
-// FindNeededCommits finds the commits of the local repository that the remove one doesn't have
-func (p *Pusher) FindNeededCommits(remoteRev, localRev string) ([]string, error) {
-	commits := []string{}
+// SetForce allows pushing commits that don't descend from the published ones
+func (p *Pusher) SetForce(force bool) {
+	p.force = force
+}
 
-	parent := localRev
+// SetChecksumAlgorithm sets the algorithm of the checksums, SHA-256 when empty
+func (p *Pusher) SetChecksumAlgorithm(algorithm string) {
+	p.algorithm = algorithm
+}
 
-	for parent != remoteRev {
-		logger.Debugf("Parent commit %s", parent)
-		commits = append(commits, parent)
+// TrackPublished records the published commits as refs/remotes/<remote>/<branch>
+// in the local repository, refs mirrored from other collections are skipped
+func (p *Pusher) TrackPublished(remote string, updateRefs map[string]common.RevisionPair) error {
+	for _, branch := range common.SortedBranches(updateRefs) {
+		if _, _, ok := ostree.ParseMirrorRef(branch); ok {
+			logger.Debugf("Not tracking mirrored ref \"%s\"", branch)
+			continue
+		}
+		if err := p.repo.SetRefImmediate(remote, branch, updateRefs[branch].Client); err != nil {
+			return err
+		}
+	}
 
-		newParent, err := p.repo.GetParentRev(parent)
+	return nil
+}
+
+// SignCommits signs the commits, the detached metadata objects that are
+// created will be pushed along with the commits
+func (p *Pusher) SignCommits(revs []string) error {
+	for _, rev := range revs {
+		logger.Debugf("Signing commit %s", rev)
+
+		var err error
+		switch p.signType {
+		case SignTypeGPG:
+			err = p.repo.SignCommitGPG(rev, p.signKey, p.gpgHomedir)
+		case SignTypeEd25519:
+			err = p.repo.SignCommitEd25519(rev, p.signKey)
+		}
 		if err != nil {
-			return nil, err
+			return fmt.Errorf("failed to sign commit %s: %v", rev, err)
+		}
+	}
+
+	return nil
+}
+
+// NeededCommits describes how the local history of a branch relates to the remote one
+type NeededCommits struct {
+	// Commits the remote repository doesn't have, newest first
+	Commits []string
+	// Remote commit the local history descends from, empty for new
+	// branches and when the histories diverged
+	CommonAncestor string
+	// The remote commit is not in the local history
+	Diverged bool
+	// The local history ends with a commit whose parent is not in the repository
+	Shallow bool
+}
+
+// FindNeededCommits walks the local history back from localRev until remoteRev
+// to find the commits of the local repository that the remote one doesn't have
+func (p *Pusher) FindNeededCommits(remoteRev, localRev string) (*NeededCommits, error) {
+	result := &NeededCommits{}
+
+	rev := localRev
+	for rev != "" && rev != remoteRev {
+		if !p.repo.HasCommit(rev) {
+			result.Shallow = true
+			break
 		}
 
-		if newParent == "" {
+		logger.Debugf("Parent commit %s", rev)
+		result.Commits = append(result.Commits, rev)
+
+		// A missing commit ends the local history, a corrupted one stops the push
+		parent, err := p.repo.GetParentRev(rev)
+		if ostree.IsNotFound(err) {
+			result.Shallow = true
 			break
+		} else if ostree.IsCorrupted(err) {
+			return nil, fmt.Errorf("commit %s is corrupted, run ostree fsck: %v", rev, err)
+		} else if err != nil {
+			return nil, err
+		}
+		rev = parent
+	}
+
+	if remoteRev != "" {
+		if rev == remoteRev {
+			result.CommonAncestor = remoteRev
 		} else {
-			parent = newParent
+			result.Diverged = true
 		}
 	}
 
-	if remoteRev != "" && parent != remoteRev {
-		return nil, fmt.Errorf("remote commit %v not descendent of commit %v", remoteRev, localRev)
+	return result, nil
+}
+
+// GenerateStaticDelta writes the static delta between two commits to path
+func (p *Pusher) GenerateStaticDelta(from, to, path string) error {
+	return p.repo.GenerateStaticDeltaFile(from, to, path)
+}
+
+// SplitUpdate splits the update of the branches into updates of about
+// maxObjects objects each, one branch at a time, that move the branches
+// a few commits at a time from the oldest to the newest; a single commit
+// with more objects than maxObjects is never split
+func (p *Pusher) SplitUpdate(updateRefs map[string]common.RevisionPair, maxObjects int) ([]map[string]common.RevisionPair, error) {
+	var sessions []map[string]common.RevisionPair
+
+	for _, branch := range common.SortedBranches(updateRefs) {
+		revs := updateRefs[branch]
+		neededCommits, err := p.FindNeededCommits(revs.Server, revs.Client)
+		if err != nil {
+			return nil, err
+		}
+
+		// Objects shared by commits are sent only once
+		seen := map[string]bool{}
+		from := revs.Server
+		count := 0
+		for i := len(neededCommits.Commits) - 1; i >= 0; i-- {
+			rev := neededCommits.Commits[i]
+			objects, err := p.repo.TraverseCommit(rev, 0)
+			if err != nil {
+				return nil, err
+			}
+
+			newObjects := 0
+			for _, objectName := range objects {
+				if !seen[objectName] {
+					seen[objectName] = true
+					newObjects++
+				}
+			}
+
+			// Close the session at the previous commit when this one doesn't fit
+			if count > 0 && count+newObjects > maxObjects {
+				to := neededCommits.Commits[i+1]
+				sessions = append(sessions, map[string]common.RevisionPair{branch: {Server: from, Client: to}})
+				from = to
+				count = 0
+			}
+			count += newObjects
+		}
+		sessions = append(sessions, map[string]common.RevisionPair{branch: {Server: from, Client: revs.Client}})
 	}
 
-	return commits, nil
+	logger.Debugf("Push split into %d sessions", len(sessions))
+
+	return sessions, nil
 }
 
 // FindObjectsForCommits finds the objects corresponding to the revisions that needs to be pushed to the receiver
@@ -91,18 +293,24 @@ func (p *Pusher) FindObjectsForCommits(revs []string) (common.Objects, error) {
 			return nil, err
 		}
 
+		// Include detached metadata, such as signatures
+		commitMetaName := fmt.Sprintf("%s.commitmeta", rev)
+		if _, err := os.Stat(p.repo.GetObjectPath(commitMetaName)); err == nil {
+			revObjects = append(revObjects, commitMetaName)
+		}
+
 		for _, objectName := range revObjects {
-			path := p.repo.GetObjectPath(objectName)
-			if _, err := os.Stat(path); err != nil {
+			if err := common.ValidateObjectName(objectName); err != nil {
 				return nil, err
 			}
 
-			checksum, err := common.CalculateChecksum(path)
-			if err != nil {
+			path := p.repo.GetObjectPath(objectName)
+			if _, err := os.Stat(path); err != nil {
 				return nil, err
 			}
 
-			object := common.Object{Rev: rev, ObjectName: objectName, ObjectPath: path, Checksum: checksum}
+			// Checksums are calculated later, only for the objects the server wants
+			object := common.Object{Rev: rev, ObjectName: objectName, ObjectPath: path}
 			objects[objectName] = object
 		}
 
@@ -111,6 +319,53 @@ func (p *Pusher) FindObjectsForCommits(revs []string) (common.Objects, error) {
 	return objects, nil
 }
 
+// CalculateChecksums calculates the checksums of the objects, reusing
+// those from the cache when the files didn't change; objects bigger than
+// deferSize, if it's not zero, are left to be hashed while they are uploaded
+func (p *Pusher) CalculateChecksums(objects common.Objects, deferSize int64) error {
+	cache := OpenChecksumCache(filepath.Join(p.repo.Path(), checksumCacheFileName), p.algorithm)
+
+	for objectName, object := range objects {
+		if deferSize > 0 {
+			if info, err := os.Stat(object.ObjectPath); err == nil && info.Size() > deferSize {
+				continue
+			}
+		}
+
+		checksum, err := cache.Checksum(objectName, object.ObjectPath)
+		if err != nil {
+			return err
+		}
+		object.Checksum = checksum
+		objects[objectName] = object
+	}
+
+	if err := cache.Save(); err != nil {
+		logger.Warnf("Cannot save checksum cache: %v", err)
+	}
+
+	return nil
+}
+
+// RememberChecksums saves the checksums calculated while uploading, so
+// that pushing again after an interruption doesn't calculate them again
+func (p *Pusher) RememberChecksums(objects common.Objects) {
+	cache := OpenChecksumCache(filepath.Join(p.repo.Path(), checksumCacheFileName), p.algorithm)
+
+	for objectName, object := range objects {
+		if object.Checksum == "" {
+			continue
+		}
+		if err := cache.Store(objectName, object.ObjectPath, object.Checksum); err != nil {
+			logger.Warnf("Cannot remember checksum of \"%s\": %v", objectName, err)
+		}
+	}
+
+	if err := cache.Save(); err != nil {
+		logger.Warnf("Cannot save checksum cache: %v", err)
+	}
+}
+
 // CheckUpdate returns a map whose key is a branch and the value contains the corresponding
 // revision in the remote and local repositories
 func (p *Pusher) CheckUpdate(remoteRefs map[string]string) (map[string]common.RevisionPair, error) {
@@ -126,6 +381,23 @@ func (p *Pusher) CheckUpdate(remoteRefs map[string]string) (map[string]common.Re
 	return updateRefs, nil
 }
 
+// CheckVersion makes sure the commits that are going to be pushed
+// have the expected "version" metadata
+func (p *Pusher) CheckVersion(updateRefs map[string]common.RevisionPair, expectedVersion string) error {
+	for _, branch := range common.SortedBranches(updateRefs) {
+		rev := updateRefs[branch].Client
+		version, err := p.repo.GetCommitVersion(rev)
+		if err != nil {
+			return err
+		}
+		if version != expectedVersion {
+			return fmt.Errorf("commit %s on branch \"%s\" has version \"%s\" instead of \"%s\"", rev, branch, version, expectedVersion)
+		}
+	}
+
+	return nil
+}
+
 // Prune prunes the repository
 func (p *Pusher) Prune() error {
 	total, pruned, size, err := p.repo.Prune(false, false)
@@ -142,13 +414,27 @@ func (p *Pusher) Prune() error {
 func (p *Pusher) FindObjectsToPush(updateRefs map[string]common.RevisionPair) (common.Objects, error) {
 	var commits []string
 
-	for branch, revs := range updateRefs {
+	for _, branch := range common.SortedBranches(updateRefs) {
+		revs := updateRefs[branch]
 		logger.Actionf("Finding commits on branch \"%s\"...", branch)
 		neededCommits, err := p.FindNeededCommits(revs.Server, revs.Client)
 		if err != nil {
 			return nil, err
 		}
-		commits = append(commits, neededCommits...)
+		if neededCommits.Diverged {
+			if !p.force {
+				return nil, fmt.Errorf("remote commit %v not descendent of commit %v, use --force to replace it", revs.Server, revs.Client)
+			}
+			logger.Warnf("Forcing branch \"%s\" from %s to %s", branch, revs.Server, revs.Client)
+		}
+		commits = append(commits, neededCommits.Commits...)
+	}
+
+	if p.signType != "" {
+		logger.Action("Signing commits...")
+		if err := p.SignCommits(commits); err != nil {
+			return nil, err
+		}
 	}
 
 	logger.Action("Enumerating objects to send (this might take a while)...")
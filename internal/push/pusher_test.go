@@ -0,0 +1,122 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"io/ioutil"
+	"os"
+	"path/filepath"
+	"reflect"
+	"testing"
+
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// commitTestBranch commits to branch once for each subject, on top of what
+// the branch points to, and returns the new revisions, oldest first
+func commitTestBranch(t *testing.T, repo *ostree.Repo, branch string, subjects ...string) []string {
+	t.Helper()
+
+	revs := []string{}
+	for _, subject := range subjects {
+		// Each commit has a different content
+		dir := t.TempDir()
+		if err := ioutil.WriteFile(filepath.Join(dir, "subject"), []byte(subject), 0644); err != nil {
+			t.Fatal(err)
+		}
+		rev, err := repo.CommitDirectory(branch, dir, subject)
+		if err != nil {
+			t.Fatalf("failed to commit \"%s\" to %s: %v", subject, branch, err)
+		}
+		revs = append(revs, rev)
+	}
+
+	return revs
+}
+
+func TestFindNeededCommits(t *testing.T) {
+	repo, err := ostree.CreateRepo(filepath.Join(t.TempDir(), "repo"))
+	if err != nil {
+		t.Fatalf("failed to create the repository: %v", err)
+	}
+
+	// main: m1 <- m2 <- m3
+	mainRevs := commitTestBranch(t, repo, "main", "main 1", "main 2", "main 3")
+
+	// fork: m1 <- f2, diverging from main after m1
+	if err := repo.SetRefImmediate("", "fork", mainRevs[0]); err != nil {
+		t.Fatal(err)
+	}
+	forkRevs := commitTestBranch(t, repo, "fork", "fork 2")
+
+	// shallow: s2 <- s3, the parent s1 is not in the repository
+	shallowRevs := commitTestBranch(t, repo, "shallow", "shallow 1", "shallow 2", "shallow 3")
+	if err := os.Remove(repo.GetObjectPath(shallowRevs[0] + ".commit")); err != nil {
+		t.Fatal(err)
+	}
+
+	tests := []struct {
+		name   string
+		remote string
+		local  string
+		want   NeededCommits
+	}{
+		{
+			name:   "fast-forward",
+			remote: mainRevs[0],
+			local:  mainRevs[2],
+			want:   NeededCommits{Commits: []string{mainRevs[2], mainRevs[1]}, CommonAncestor: mainRevs[0]},
+		},
+		{
+			name:   "divergent",
+			remote: mainRevs[2],
+			local:  forkRevs[0],
+			want:   NeededCommits{Commits: []string{forkRevs[0], mainRevs[0]}, Diverged: true},
+		},
+		{
+			name:   "shallow",
+			remote: "",
+			local:  shallowRevs[2],
+			want:   NeededCommits{Commits: []string{shallowRevs[2], shallowRevs[1]}, Shallow: true},
+		},
+		{
+			name:   "new branch",
+			remote: "",
+			local:  mainRevs[2],
+			want:   NeededCommits{Commits: []string{mainRevs[2], mainRevs[1], mainRevs[0]}},
+		},
+	}
+
+	pusher := &Pusher{repo: repo}
+	for _, test := range tests {
+		t.Run(test.name, func(t *testing.T) {
+			got, err := pusher.FindNeededCommits(test.remote, test.local)
+			if err != nil {
+				t.Fatalf("got error %v, want none", err)
+			}
+			if !reflect.DeepEqual(got.Commits, test.want.Commits) {
+				t.Errorf("got commits %v, want %v", got.Commits, test.want.Commits)
+			}
+			if got.CommonAncestor != test.want.CommonAncestor {
+				t.Errorf("got common ancestor %q, want %q", got.CommonAncestor, test.want.CommonAncestor)
+			}
+			if got.Diverged != test.want.Diverged {
+				t.Errorf("got diverged %v, want %v", got.Diverged, test.want.Diverged)
+			}
+			if got.Shallow != test.want.Shallow {
+				t.Errorf("got shallow %v, want %v", got.Shallow, test.want.Shallow)
+			}
+		})
+	}
+
+	// Nothing is needed when the branch didn't move
+	got, err := pusher.FindNeededCommits(mainRevs[2], mainRevs[2])
+	if err != nil {
+		t.Fatalf("got error %v, want none", err)
+	}
+	if want := (NeededCommits{CommonAncestor: mainRevs[2]}); !reflect.DeepEqual(*got, want) {
+		t.Errorf("got %+v for an unchanged branch, want %+v", *got, want)
+	}
+}
@@ -0,0 +1,81 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"encoding/json"
+	"fmt"
+	"os"
+	"path/filepath"
+
+	"github.com/lirios/ostree-upload/internal/common"
+)
+
+// Directory inside the OSTree repository where the receipts are journaled
+const receiptsDirName = "tmp/ostree-upload-receipts"
+
+// ReceiptJournal records the receipts of the objects uploaded for a queue entry,
+// so that they survive a failed publish and can be inspected afterwards
+type ReceiptJournal struct {
+	path     string
+	receipts []common.ObjectReceipt
+}
+
+// OpenReceiptJournal creates the journal of the queue entry in the repository
+func OpenReceiptJournal(repoPath, queueID string) (*ReceiptJournal, error) {
+	path := filepath.Join(repoPath, receiptsDirName)
+	if err := os.MkdirAll(path, 0755); err != nil {
+		return nil, err
+	}
+
+	return &ReceiptJournal{path: filepath.Join(path, queueID+".json")}, nil
+}
+
+// Record verifies the receipt against the object that was sent and appends it
+func (j *ReceiptJournal) Record(object common.Object, receipt common.ObjectReceipt) error {
+	info, err := os.Stat(object.ObjectPath)
+	if err != nil {
+		return err
+	}
+	if receipt.Size != info.Size() {
+		return fmt.Errorf("Server stored %d bytes for \"%s\" instead of %d", receipt.Size, object.ObjectName, info.Size())
+	}
+	if receipt.Checksum != "" && receipt.Checksum != object.Checksum {
+		return fmt.Errorf("Server calculated checksum %s for \"%s\" instead of %s", receipt.Checksum, object.ObjectName, object.Checksum)
+	}
+
+	data, err := json.Marshal(receipt)
+	if err != nil {
+		return err
+	}
+
+	file, err := os.OpenFile(j.path, os.O_WRONLY|os.O_CREATE|os.O_APPEND, 0644)
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+
+	if _, err := file.Write(append(data, '\n')); err != nil {
+		return err
+	}
+
+	j.receipts = append(j.receipts, receipt)
+
+	return nil
+}
+
+// Receipts returns the receipts recorded so far
+func (j *ReceiptJournal) Receipts() []common.ObjectReceipt {
+	return j.receipts
+}
+
+// Remove deletes the journal once the branches are published
+func (j *ReceiptJournal) Remove() error {
+	err := os.Remove(j.path)
+	if os.IsNotExist(err) {
+		return nil
+	}
+	return err
+}
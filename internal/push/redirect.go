@@ -0,0 +1,118 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"sync"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// How many objects the server is asked to fetch in each request
+const fetchBatchSize = 100
+
+// uploadRedirected uploads the objects with a target there, jobs at the same
+// time, and asks the server to fetch them; the receipts sent by the server
+// are verified and recorded in the journal and the checksums calculated while
+// sending are stored in objects. It returns the objects without a valid
+// receipt, which are sent to the server instead.
+func uploadRedirected(client *Client, queueID string, objects common.Objects, targets map[string]common.UploadTarget, jobs int, journal *ReceiptJournal) common.Objects {
+	if jobs < 1 {
+		jobs = 1
+	}
+
+	pending := common.Objects{}
+	redirected := []common.Object{}
+	for _, objectName := range common.SortedObjectNames(objects) {
+		if _, ok := targets[objectName]; ok {
+			redirected = append(redirected, objects[objectName])
+		} else {
+			pending[objectName] = objects[objectName]
+		}
+	}
+	if len(redirected) == 0 {
+		return pending
+	}
+	logger.Debugf("Uploading %d objects to their targets...", len(redirected))
+
+	// Objects go straight to their targets
+	uploaded := common.Objects{}
+	var mutex sync.Mutex
+	queue := make(chan common.Object)
+	var wg sync.WaitGroup
+	for i := 0; i < jobs; i++ {
+		wg.Add(1)
+		go func() {
+			defer wg.Done()
+			for object := range queue {
+				err := client.UploadToTarget(targets[object.ObjectName], &object)
+
+				mutex.Lock()
+				if err != nil {
+					logger.Warnf("Failed to upload \"%s\" to its target: %v", object.ObjectName, err)
+					pending[object.ObjectName] = object
+				} else {
+					uploaded[object.ObjectName] = object
+				}
+				mutex.Unlock()
+			}
+		}()
+	}
+	for _, object := range redirected {
+		queue <- object
+	}
+	close(queue)
+	wg.Wait()
+
+	for objectName, object := range uploaded {
+		objects[objectName] = object
+	}
+
+	// Then the server fetches them and sends the receipts
+	objectNames := common.UploadOrder(uploaded)
+	for start := 0; start < len(objectNames); start += fetchBatchSize {
+		end := start + fetchBatchSize
+		if end > len(objectNames) {
+			end = len(objectNames)
+		}
+		batch := common.Objects{}
+		for _, objectName := range objectNames[start:end] {
+			batch[objectName] = uploaded[objectName]
+		}
+
+		receipts, err := client.FetchObjects(queueID, batch)
+		if err != nil {
+			logger.Warnf("Server failed to fetch %d objects: %v", len(batch), err)
+			for objectName, object := range batch {
+				pending[objectName] = object
+			}
+			continue
+		}
+		for _, receipt := range receipts {
+			object, ok := batch[receipt.ObjectName]
+			if !ok {
+				logger.Warnf("Unexpected receipt for \"%s\"", receipt.ObjectName)
+				continue
+			}
+			if err := journal.Record(object, receipt); err != nil {
+				logger.Warnf("Bad receipt for \"%s\": %v", receipt.ObjectName, err)
+				continue
+			}
+			logger.Debugf("Received receipt %s for \"%s\"", receipt.ReceiptID, receipt.ObjectName)
+			delete(batch, receipt.ObjectName)
+		}
+		for objectName, object := range batch {
+			logger.Warnf("No receipt for \"%s\"", objectName)
+			pending[objectName] = object
+		}
+	}
+
+	if len(pending) < len(objects) {
+		logger.Infof("Sent %d/%d objects through their targets", len(objects)-len(pending), len(redirected))
+	}
+
+	return pending
+}
@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"bufio"
+	"fmt"
+	"io"
+	"os"
+	"strings"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// ReadRefFile reads a ref file: one "<branch> <commit>" pair per line,
+// empty lines and lines starting with # are ignored
+func ReadRefFile(path string) (map[string]string, error) {
+	file, err := os.Open(path)
+	if err != nil {
+		return nil, err
+	}
+	defer file.Close()
+
+	pins := map[string]string{}
+	scanner := bufio.NewScanner(file)
+	for lineNumber := 1; scanner.Scan(); lineNumber++ {
+		line := strings.TrimSpace(scanner.Text())
+		if line == "" || strings.HasPrefix(line, "#") {
+			continue
+		}
+
+		fields := strings.Fields(line)
+		if len(fields) != 2 {
+			return nil, fmt.Errorf("%s:%d: expected \"<branch> <commit>\"", path, lineNumber)
+		}
+		branch, rev := fields[0], fields[1]
+		if err := ostree.ValidateBranch(branch); err != nil {
+			return nil, fmt.Errorf("%s:%d: invalid branch name %q: %v", path, lineNumber, branch, err)
+		}
+		if err := common.ValidateChecksum(rev); err != nil {
+			return nil, fmt.Errorf("%s:%d: invalid commit for %s: %v", path, lineNumber, branch, err)
+		}
+		if _, found := pins[branch]; found {
+			return nil, fmt.Errorf("%s:%d: branch %s is listed twice", path, lineNumber, branch)
+		}
+		pins[branch] = rev
+	}
+	if err := scanner.Err(); err != nil {
+		return nil, err
+	}
+
+	return pins, nil
+}
+
+// WriteRefFile writes the revisions in the format read by ReadRefFile,
+// sorted by branch so that changes are easy to review
+func WriteRefFile(w io.Writer, revs map[string]string) error {
+	for _, branch := range common.SortedBranchNames(revs) {
+		if _, err := fmt.Fprintf(w, "%s %s\n", branch, revs[branch]); err != nil {
+			return err
+		}
+	}
+
+	return nil
+}
+
+// newPusher creates the Pusher for the options, pinned to the
+// commits of the ref file if there is one
+func newPusher(opts Options) (*Pusher, error) {
+	if opts.RefFile == "" {
+		return NewPusher(opts.RepoPath, opts.Branches)
+	}
+	if len(opts.Branches) > 0 {
+		return nil, fmt.Errorf("Branches and ref file cannot be used together")
+	}
+
+	pins, err := ReadRefFile(opts.RefFile)
+	if err != nil {
+		return nil, fmt.Errorf("Cannot read ref file: %v", err)
+	}
+
+	return NewPinnedPusher(opts.RepoPath, pins)
+}
+
+// RemoteRefs writes the branches published by the receiver in the
+// ref file format, only the requested ones when branches is not empty
+func RemoteRefs(opts Options, w io.Writer) error {
+	// Client
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	// Repository information
+	info, err := client.GetInfo()
+	if err != nil {
+		return fmt.Errorf("Failed to retrieve repository information: %v", err)
+	}
+
+	revs := info.AllRevs()
+	if len(opts.Branches) > 0 {
+		selected := map[string]string{}
+		for _, branch := range opts.Branches {
+			rev, found := revs[branch]
+			if !found {
+				return fmt.Errorf("Branch \"%s\" not found on the server", branch)
+			}
+			selected[branch] = rev
+		}
+		revs = selected
+	}
+	logger.Debugf("Writing %d branches", len(revs))
+
+	return WriteRefFile(w, revs)
+}
@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"encoding/json"
+	"fmt"
+	"io"
+	"sort"
+	"text/tabwriter"
+	"time"
+)
+
+// Formats supported by Report()
+const (
+	ReportTable = "table"
+	ReportJSON  = "json"
+)
+
+// Most publishes the report asks the server for
+const reportHistoryLimit = 100000
+
+// BranchReport sums up the publishes of a branch; the bytes of a
+// publish are split evenly between its branches
+type BranchReport struct {
+	Branch       string   `json:"branch"`
+	Pushes       int      `json:"pushes"`
+	PushesPerDay float64  `json:"pushes_per_day"`
+	Objects      int      `json:"objects"`
+	Bytes        int64    `json:"bytes"`
+	AverageBytes int64    `json:"average_bytes"`
+	SizeTrend    *float64 `json:"size_trend,omitempty"`
+
+	// Bytes and pushes of each half of the period, for the trend
+	halves [2]struct {
+		bytes  int64
+		pushes int
+	}
+}
+
+// Report prints, for each branch published in the last period, how often
+// it was pushed and how big the pushes were, the size trend is the change
+// in percent of the average size from the first half of the period to the second
+func Report(opts Options, last time.Duration, format string, w io.Writer) error {
+	if format != ReportTable && format != ReportJSON {
+		return fmt.Errorf("Unknown report format \"%s\"", format)
+	}
+
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	now := time.Now()
+	since := now.Add(-last)
+	entries, err := client.GetHistory(since, reportHistoryLimit)
+	if err != nil {
+		return fmt.Errorf("Failed to get history: %v", err)
+	}
+
+	branches := map[string]*BranchReport{}
+	middle := since.Add(last / 2)
+	for _, entry := range entries {
+		half := 0
+		if entry.Time.After(middle) {
+			half = 1
+		}
+		for branch := range entry.Refs {
+			report, ok := branches[branch]
+			if !ok {
+				report = &BranchReport{Branch: branch}
+				branches[branch] = report
+			}
+			bytes := entry.Bytes / int64(len(entry.Refs))
+			report.Pushes++
+			report.Objects += entry.Objects / len(entry.Refs)
+			report.Bytes += bytes
+			report.halves[half].bytes += bytes
+			report.halves[half].pushes++
+		}
+	}
+
+	reports := []*BranchReport{}
+	for _, report := range branches {
+		report.PushesPerDay = float64(report.Pushes) / last.Hours() * 24
+		report.AverageBytes = report.Bytes / int64(report.Pushes)
+		first, second := report.halves[0], report.halves[1]
+		if first.pushes > 0 && second.pushes > 0 && first.bytes > 0 {
+			firstAverage := float64(first.bytes) / float64(first.pushes)
+			secondAverage := float64(second.bytes) / float64(second.pushes)
+			trend := (secondAverage - firstAverage) / firstAverage * 100
+			report.SizeTrend = &trend
+		}
+		reports = append(reports, report)
+	}
+	sort.Slice(reports, func(i, j int) bool {
+		return reports[i].Branch < reports[j].Branch
+	})
+
+	if format == ReportJSON {
+		encoder := json.NewEncoder(w)
+		encoder.SetIndent("", "  ")
+		return encoder.Encode(reports)
+	}
+
+	table := tabwriter.NewWriter(w, 0, 4, 2, ' ', 0)
+	fmt.Fprintln(table, "BRANCH\tPUSHES\tPER DAY\tOBJECTS\tBYTES\tAVERAGE\tTREND")
+	for _, report := range reports {
+		trend := "-"
+		if report.SizeTrend != nil {
+			trend = fmt.Sprintf("%+.1f%%", *report.SizeTrend)
+		}
+		fmt.Fprintf(table, "%s\t%d\t%.2f\t%d\t%d\t%d\t%s\n", report.Branch, report.Pushes, report.PushesPerDay,
+			report.Objects, report.Bytes, report.AverageBytes, trend)
+	}
+	return table.Flush()
+}
@@ -0,0 +1,104 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"fmt"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// ListSessions prints the queue entries on the server
+func ListSessions(opts Options) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	sessions, err := client.ListSessions()
+	if err != nil {
+		return fmt.Errorf("Failed to list sessions: %v", err)
+	}
+	if len(sessions) == 0 {
+		logger.Info("No active sessions")
+		return nil
+	}
+
+	for _, session := range sessions {
+		state := "uploading"
+		if session.Publishing {
+			state = "publishing"
+		} else if session.PendingApproval {
+			state = "pending approval"
+		}
+		logger.Infof("%s\t%s\t%s\t%d objects", session.ID, session.Created.Local().Format("2006-01-02 15:04:05"), state, session.Objects)
+		for _, branch := range common.SortedBranches(session.Refs) {
+			logger.Infof("\t%s: %s", branch, session.Refs[branch].Client)
+		}
+	}
+
+	return nil
+}
+
+// ShowStatus prints the storage status of the server
+func ShowStatus(opts Options) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	status, err := client.GetStatus()
+	if err != nil {
+		return fmt.Errorf("Failed to get status: %v", err)
+	}
+
+	logger.Infof("Free space:      %d bytes", status.FreeSpace)
+	logger.Infof("Reserved space:  %d bytes", status.ReservedSpace)
+	logger.Infof("Headroom:        %d bytes", status.Headroom)
+	logger.Infof("Temporary files: %d bytes", status.TempSize)
+	logger.Infof("Sessions:        %d", status.Sessions)
+	for _, warning := range status.Warnings {
+		logger.Warnf("Warning: %s", warning)
+	}
+
+	return nil
+}
+
+// CancelSession removes the queue entry from the server
+func CancelSession(opts Options, sessionID string) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	if err := client.CancelSession(sessionID); err != nil {
+		return fmt.Errorf("Failed to cancel session %s: %v", sessionID, err)
+	}
+
+	logger.Infof("Session %s cancelled", sessionID)
+
+	return nil
+}
+
+// ApproveSession publishes a queue entry that is waiting for approval
+func ApproveSession(opts Options, sessionID string) error {
+	client, err := newClient(opts)
+	if err != nil {
+		return err
+	}
+
+	receipt, err := client.Approve(sessionID)
+	if err != nil {
+		return fmt.Errorf("Failed to approve session %s: %v", sessionID, err)
+	}
+
+	for _, branch := range common.SortedBranchNames(receipt.Revs) {
+		logger.Infof("\t%s: %s", branch, receipt.Revs[branch])
+	}
+	logger.Infof("Session %s approved and published", sessionID)
+
+	return nil
+}
@@ -0,0 +1,154 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"bytes"
+	"encoding/json"
+	"fmt"
+	"io"
+	"io/ioutil"
+	"net/http"
+	"net/url"
+	"strings"
+	"sync"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/websocket"
+)
+
+// Largest reply header and body accepted from the push socket
+const (
+	maxSocketHeaderSize = 64 * 1024
+	maxSocketBodySize   = 256 * 1024 * 1024
+)
+
+// socketTransport sends the requests over the push socket, one at a time
+type socketTransport struct {
+	conn   *websocket.Conn
+	mutex  sync.Mutex
+	nextID uint64
+}
+
+// RoundTrip sends the request and its body, then reads the reply
+func (t *socketTransport) RoundTrip(request *http.Request) (*http.Response, error) {
+	if request.Body != nil {
+		defer request.Body.Close()
+	}
+
+	t.mutex.Lock()
+	defer t.mutex.Unlock()
+
+	// Paths are relative to the API, whatever the endpoint prefix
+	path := request.URL.Path
+	if i := strings.Index(path, "/api/v1"); i >= 0 {
+		path = path[i+len("/api/v1"):]
+	}
+	if request.URL.RawQuery != "" {
+		path += "?" + request.URL.RawQuery
+	}
+
+	// The socket is already authenticated
+	header := request.Header.Clone()
+	header.Del("Authorization")
+
+	t.nextID++
+	data, err := json.Marshal(common.SocketRequest{ID: t.nextID, Method: request.Method, Path: path, Header: header})
+	if err != nil {
+		return nil, err
+	}
+	if err := t.conn.WriteMessage(websocket.TextMessage, data); err != nil {
+		return nil, err
+	}
+	writer := t.conn.NextWriter(websocket.BinaryMessage)
+	if request.Body != nil {
+		if _, err := io.Copy(writer, request.Body); err != nil {
+			return nil, err
+		}
+	}
+	if err := writer.Close(); err != nil {
+		return nil, err
+	}
+
+	// Reply
+	messageType, data, err := t.conn.ReadMessage(maxSocketHeaderSize)
+	if err != nil {
+		return nil, err
+	}
+	if messageType != websocket.TextMessage {
+		return nil, fmt.Errorf("expected a reply header")
+	}
+	var reply common.SocketResponse
+	if err := json.Unmarshal(data, &reply); err != nil {
+		return nil, fmt.Errorf("bad reply header: %v", err)
+	}
+	if reply.ID != t.nextID {
+		return nil, fmt.Errorf("reply to request %d received instead of %d", reply.ID, t.nextID)
+	}
+	_, body, err := t.conn.ReadMessage(maxSocketBodySize)
+	if err != nil {
+		return nil, err
+	}
+
+	response := &http.Response{
+		Status:        fmt.Sprintf("%d %s", reply.Status, http.StatusText(reply.Status)),
+		StatusCode:    reply.Status,
+		Proto:         "HTTP/1.1",
+		ProtoMajor:    1,
+		ProtoMinor:    1,
+		Header:        reply.Header,
+		Body:          ioutil.NopCloser(bytes.NewReader(body)),
+		ContentLength: int64(len(body)),
+		Request:       request,
+	}
+	if response.Header == nil {
+		response.Header = http.Header{}
+	}
+
+	return response, nil
+}
+
+// UseSocket sends the following requests over a single WebSocket connection
+// to the receiver, avoiding the overhead of a request per object and
+// the body limits of proxies
+func (c *Client) UseSocket() error {
+	u, err := url.Parse(fmt.Sprintf("%s/api/v1/push-socket", c.endpoint))
+	if err != nil {
+		return err
+	}
+	switch u.Scheme {
+	case "http":
+		u.Scheme = "ws"
+	case "https":
+		u.Scheme = "wss"
+	}
+
+	request, err := http.NewRequest("GET", u.String(), nil)
+	if err != nil {
+		return err
+	}
+	request.Header.Set("User-Agent", c.userAgent)
+	c.setAuthorization(request)
+
+	conn, err := websocket.Dial(u, request.Header, c.tlsConfig)
+	if err != nil {
+		return err
+	}
+
+	c.httpClient = &http.Client{Transport: &socketTransport{conn: conn}, Timeout: c.httpClient.Timeout}
+
+	return nil
+}
+
+// Close closes the push socket, if UseSocket was called, otherwise the
+// idle connections, ending the SSH sessions
+func (c *Client) Close() error {
+	if transport, ok := c.httpClient.Transport.(*socketTransport); ok {
+		return transport.conn.Close()
+	}
+	c.httpClient.CloseIdleConnections()
+
+	return nil
+}
@@ -0,0 +1,91 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package push
+
+import (
+	"context"
+	"fmt"
+	"net"
+	"net/http"
+	"net/url"
+	"os"
+	"os/exec"
+
+	"github.com/lirios/ostree-upload/internal/common"
+)
+
+// SSHScheme is the scheme of the addresses reached over SSH
+const SSHScheme = "ssh"
+
+// Command run on the server, ignored when the key has a forced command
+const sshReceiveCommand = "ostree-upload receive --stdio"
+
+// sshArgs returns the arguments of ssh to run the receiver at address,
+// written as ssh://[user@]host[:port]
+func sshArgs(address string) ([]string, string, error) {
+	u, err := url.Parse(address)
+	if err != nil {
+		return nil, "", err
+	}
+	if u.Hostname() == "" {
+		return nil, "", fmt.Errorf("No host in \"%s\"", address)
+	}
+
+	destination := u.Hostname()
+	if u.User != nil {
+		destination = u.User.Username() + "@" + destination
+	}
+	args := []string{}
+	if u.Port() != "" {
+		args = append(args, "-p", u.Port())
+	}
+	args = append(args, "--", destination, sshReceiveCommand)
+
+	return args, u.Hostname(), nil
+}
+
+// dialSSH runs the receiver over SSH and returns a connection to its
+// standard input and output, ssh messages go to the standard error
+func dialSSH(args []string, host string) (net.Conn, error) {
+	cmd := exec.Command("ssh", args...)
+	cmd.Stderr = os.Stderr
+	stdin, err := cmd.StdinPipe()
+	if err != nil {
+		return nil, err
+	}
+	stdout, err := cmd.StdoutPipe()
+	if err != nil {
+		return nil, err
+	}
+	if err := cmd.Start(); err != nil {
+		return nil, fmt.Errorf("Cannot run ssh: %v", err)
+	}
+
+	return common.NewStreamConn(stdout, stdin, host, cmd.Wait), nil
+}
+
+// newSSHClient creates a client speaking to "ostree-upload receive --stdio"
+// over SSH: each connection is an SSH session, and there is only one at
+// a time because the receiver serves a single connection. SSH already
+// authenticated the user, so no token is needed.
+func newSSHClient(address string) (*Client, error) {
+	args, host, err := sshArgs(address)
+	if err != nil {
+		return nil, err
+	}
+
+	client, err := NewClient("http://"+host, "")
+	if err != nil {
+		return nil, err
+	}
+	client.httpClient.Transport = &http.Transport{
+		DialContext: func(ctx context.Context, network, addr string) (net.Conn, error) {
+			return dialSSH(args, host)
+		},
+		MaxConnsPerHost: 1,
+	}
+
+	return client, nil
+}
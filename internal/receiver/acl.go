@@ -0,0 +1,108 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"context"
+	"errors"
+	"fmt"
+
+	"github.com/lirios/ostree-upload/internal/common"
+)
+
+// Operations on branches that can be granted by the ACL
+const (
+	ACLCreate = "create"
+	ACLUpdate = "update"
+	ACLDelete = "delete"
+)
+
+// ACLRule grants operations on the matching branches to a subject,
+// which is either a user name or the subject of a token
+type ACLRule struct {
+	Subject    string   `yaml:"subject"`
+	Refs       []string `yaml:"refs"`
+	Operations []string `yaml:"operations"`
+}
+
+// validate returns an error if the rule is incomplete or has unknown operations
+func (r *ACLRule) validate() error {
+	if r.Subject == "" {
+		return errors.New("acl rule without subject")
+	}
+	if len(r.Refs) == 0 {
+		return fmt.Errorf("acl rule for \"%s\" without refs", r.Subject)
+	}
+	for _, operation := range r.Operations {
+		switch operation {
+		case ACLCreate, ACLUpdate, ACLDelete:
+		default:
+			return fmt.Errorf("acl rule for \"%s\": unknown operation \"%s\"", r.Subject, operation)
+		}
+	}
+
+	return nil
+}
+
+// allows returns true if the rule grants operation on branch to subject,
+// the "*" subject matches everybody
+func (r *ACLRule) allows(subject, branch, operation string) bool {
+	if r.Subject != "*" && r.Subject != subject {
+		return false
+	}
+	if !matchRefs(r.Refs, branch) {
+		return false
+	}
+	for _, op := range r.Operations {
+		if op == operation {
+			return true
+		}
+	}
+
+	return false
+}
+
+// refOperation returns the operation needed to move the branch
+func refOperation(revPair common.RevisionPair) string {
+	switch {
+	case revPair.Server == "":
+		return ACLCreate
+	case revPair.Client == "":
+		return ACLDelete
+	default:
+		return ACLUpdate
+	}
+}
+
+// subject returns the user name or the token subject of the request
+func subject(ctx context.Context) string {
+	if user, ok := ctx.Value(KeyUser).(*User); ok {
+		return user.Name
+	}
+	if token, ok := ctx.Value(KeyToken).(*Token); ok {
+		return token.Subject
+	}
+
+	return ""
+}
+
+// checkACL returns an error if the ACL doesn't grant the operation on the
+// branch to whoever authenticated the request, everything is allowed
+// when there is no ACL
+func checkACL(ctx context.Context, config *Config, branch string, revPair common.RevisionPair) error {
+	if len(config.ACL) == 0 {
+		return nil
+	}
+
+	operation := refOperation(revPair)
+	who := subject(ctx)
+	for _, rule := range config.ACL {
+		if rule.allows(who, branch, operation) {
+			return nil
+		}
+	}
+
+	return fmt.Errorf("\"%s\" is not allowed to %s branch \"%s\"", who, operation, redactRef(branch))
+}
@@ -0,0 +1,457 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"crypto/subtle"
+	"errors"
+	"fmt"
+	"net/http"
+	"sort"
+	"strconv"
+	"sync"
+	"time"
+
+	"github.com/go-chi/chi"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// Maintenance jobs administrators can start
+const (
+	JobPrune   = "prune"
+	JobFsck    = "fsck"
+	JobSummary = "summary"
+)
+
+// How many finished jobs are remembered
+const maxFinishedJobs = 20
+
+// How many history entries are returned when no limit is passed
+const defaultHistoryLimit = 50
+
+var (
+	// ErrUnknownJob is returned when the kind of job doesn't exist
+	ErrUnknownJob = errors.New("unknown job")
+
+	// ErrJobRunning is returned when a job of the same kind is running
+	ErrJobRunning = errors.New("a job of the same kind is already running")
+)
+
+// Jobs runs the maintenance jobs in the background, one of each
+// kind at a time, and remembers the last ones
+type Jobs struct {
+	repo   *ostree.Repo
+	config *Config
+	queue  *Queue
+	mutex  sync.Mutex
+	jobs   []*common.JobInfo
+}
+
+// NewJobs creates the jobs runner
+func NewJobs(repo *ostree.Repo, config *Config, queue *Queue) *Jobs {
+	return &Jobs{repo: repo, config: config, queue: queue}
+}
+
+// Start starts a job of the kind in the background
+func (j *Jobs) Start(kind string) (common.JobInfo, error) {
+	var runFn func() (string, error)
+	switch kind {
+	case JobPrune:
+		runFn = j.prune
+	case JobFsck:
+		runFn = j.fsck
+	case JobSummary:
+		runFn = j.summary
+	default:
+		return common.JobInfo{}, ErrUnknownJob
+	}
+
+	j.mutex.Lock()
+	defer j.mutex.Unlock()
+
+	for _, job := range j.jobs {
+		if job.Kind == kind && job.Finished == nil {
+			return common.JobInfo{}, ErrJobRunning
+		}
+	}
+
+	job := &common.JobInfo{ID: strconv.FormatInt(time.Now().UnixNano(), 36), Kind: kind, Started: time.Now().UTC()}
+	j.jobs = append(j.jobs, job)
+	if len(j.jobs) > maxFinishedJobs {
+		j.jobs = j.jobs[1:]
+	}
+
+	go func() {
+		logger.Infof("Job %s: %s started", job.ID, kind)
+		result, err := runFn()
+
+		j.mutex.Lock()
+		defer j.mutex.Unlock()
+
+		finished := time.Now().UTC()
+		job.Finished = &finished
+		job.Result = result
+		if err != nil {
+			job.Error = err.Error()
+			logger.Errorf("Job %s: %s failed: %v", job.ID, kind, err)
+		} else {
+			logger.Infof("Job %s: %s", job.ID, result)
+		}
+	}()
+
+	return *job, nil
+}
+
+// List returns the running jobs and the last finished ones, oldest first
+func (j *Jobs) List() []common.JobInfo {
+	j.mutex.Lock()
+	defer j.mutex.Unlock()
+
+	jobs := []common.JobInfo{}
+	for _, job := range j.jobs {
+		jobs = append(jobs, *job)
+	}
+
+	return jobs
+}
+
+// prune prunes the repository and the static deltas of pruned commits
+func (j *Jobs) prune() (string, error) {
+	// Objects being published are not referenced yet
+	publishing := false
+	j.queue.Walk(func(entry *QueueEntry) error {
+		if j.queue.IsPublishing(entry.ID) {
+			publishing = true
+		}
+		return nil
+	})
+	if publishing {
+		return "", errors.New("a session is being published, try again later")
+	}
+
+	total, pruned, size, err := j.repo.Prune(false, false)
+	if err != nil {
+		return "", err
+	}
+	removed, err := PruneStaticDeltas(j.repo, j.config)
+	if err != nil {
+		return "", err
+	}
+
+	return fmt.Sprintf("pruned %d/%d objects, %d bytes deleted, %d stale static deltas removed", pruned, total, size, removed), nil
+}
+
+// fsck verifies the objects of the commits the refs point to
+func (j *Jobs) fsck() (string, error) {
+	revs, err := j.repo.ListRevisions()
+	if err != nil {
+		return "", err
+	}
+	mirrors, err := j.repo.ListMirrorRevisions()
+	if err != nil {
+		return "", err
+	}
+	for ref, rev := range mirrors {
+		revs[ref] = rev
+	}
+
+	checked := map[string]bool{}
+	corrupted := 0
+	for _, ref := range common.SortedBranchNames(revs) {
+		objects, err := j.repo.TraverseCommit(revs[ref], 0)
+		if err != nil {
+			return "", fmt.Errorf("failed to traverse %s: %v", redactRef(ref), err)
+		}
+		for _, objectName := range objects {
+			if checked[objectName] {
+				continue
+			}
+			checked[objectName] = true
+			if err := j.repo.FsckObject(objectName); err != nil {
+				logger.Errorf("Object %s of %s is corrupted: %v", redact(objectName), redactRef(ref), err)
+				corrupted++
+			}
+		}
+	}
+
+	result := fmt.Sprintf("checked %d objects of %d refs", len(checked), len(revs))
+	if corrupted > 0 {
+		return result, fmt.Errorf("%d objects are corrupted", corrupted)
+	}
+	return result, nil
+}
+
+// summary regenerates the summary
+func (j *Jobs) summary() (string, error) {
+	if err := updateSummary(j.repo, j.config); err != nil {
+		return "", err
+	}
+	return "summary regenerated", nil
+}
+
+// IsFrozen returns true if an administrator froze the branch
+func (c *Config) IsFrozen(branch string) bool {
+	c.mutex.Lock()
+	defer c.mutex.Unlock()
+
+	for _, frozen := range c.FrozenRefs {
+		if frozen == branch {
+			return true
+		}
+	}
+
+	return false
+}
+
+// FrozenBranches returns the frozen branches
+func (c *Config) FrozenBranches() []string {
+	c.mutex.Lock()
+	defer c.mutex.Unlock()
+
+	return append([]string{}, c.FrozenRefs...)
+}
+
+// SetFrozen freezes or thaws the branches and saves the configuration,
+// so that they stay frozen when the receiver is restarted
+func (c *Config) SetFrozen(branches []string, frozen bool) ([]string, error) {
+	c.mutex.Lock()
+	defer c.mutex.Unlock()
+
+	set := map[string]bool{}
+	for _, branch := range c.FrozenRefs {
+		set[branch] = true
+	}
+	for _, branch := range branches {
+		set[branch] = frozen
+	}
+
+	c.FrozenRefs = []string{}
+	for branch, isFrozen := range set {
+		if isFrozen {
+			c.FrozenRefs = append(c.FrozenRefs, branch)
+		}
+	}
+	sort.Strings(c.FrozenRefs)
+
+	return append([]string{}, c.FrozenRefs...), c.Save()
+}
+
+// FindToken returns the configured token, nil if it doesn't exist
+func (c *Config) FindToken(tokenString string) *Token {
+	c.mutex.Lock()
+	defer c.mutex.Unlock()
+
+	// Look at every token, so that the time doesn't tell how much matched
+	var found *Token
+	for _, token := range c.Tokens {
+		if subtle.ConstantTimeCompare([]byte(token.Token), []byte(tokenString)) == 1 {
+			found = token
+		}
+	}
+
+	return found
+}
+
+// RevokeTokens removes the tokens issued to the subject and saves the
+// configuration, it returns how many were removed
+func (c *Config) RevokeTokens(subject string) (int, error) {
+	c.mutex.Lock()
+	defer c.mutex.Unlock()
+
+	tokens := []*Token{}
+	for _, token := range c.Tokens {
+		if token.Subject != subject {
+			tokens = append(tokens, token)
+		}
+	}
+
+	revoked := len(c.Tokens) - len(tokens)
+	if revoked == 0 {
+		return 0, nil
+	}
+	c.Tokens = tokens
+
+	return revoked, c.Save()
+}
+
+// FrozenHandler lists the frozen branches
+func FrozenHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	config, ok := ctx.Value(KeyConfig).(*Config)
+	if !ok {
+		logger.Error("Unable to retrieve configuration from context")
+		http.Error(w, "no configuration found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	object := common.FrozenResponse{Branches: config.FrozenBranches()}
+	EncodeJSONReply(w, r, object)
+}
+
+// FreezeHandler freezes or thaws branches
+func FreezeHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	config, ok := ctx.Value(KeyConfig).(*Config)
+	if !ok {
+		logger.Error("Unable to retrieve configuration from context")
+		http.Error(w, "no configuration found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	// Decode request
+	var req common.FreezeRequest
+	if err := DecodeJSONBody(w, r, &req); err != nil {
+		HandleDecodeError(w, err)
+		return
+	}
+	if len(req.Branches) == 0 {
+		http.Error(w, "no branches", http.StatusUnprocessableEntity)
+		return
+	}
+	for _, branch := range req.Branches {
+		if err := ostree.ValidateBranch(branch); err != nil {
+			http.Error(w, err.Error(), http.StatusBadRequest)
+			return
+		}
+	}
+
+	frozen, err := config.SetFrozen(req.Branches, req.Frozen)
+	if err != nil {
+		logger.Errorf("Failed to save the frozen branches: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+	logger.Infof("%s set frozen=%v for %v", subject(ctx), req.Frozen, redactRefs(req.Branches))
+
+	object := common.FrozenResponse{Branches: frozen}
+	EncodeJSONReply(w, r, object)
+}
+
+// RevokeHandler removes the tokens of a subject from the configuration
+func RevokeHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	config, ok := ctx.Value(KeyConfig).(*Config)
+	if !ok {
+		logger.Error("Unable to retrieve configuration from context")
+		http.Error(w, "no configuration found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	// Decode request
+	var req common.RevokeRequest
+	if err := DecodeJSONBody(w, r, &req); err != nil {
+		HandleDecodeError(w, err)
+		return
+	}
+	if req.Subject == "" {
+		http.Error(w, "no subject", http.StatusUnprocessableEntity)
+		return
+	}
+
+	revoked, err := config.RevokeTokens(req.Subject)
+	if err != nil {
+		logger.Errorf("Failed to save the configuration: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+	logger.Infof("%s revoked %d tokens of \"%s\"", subject(ctx), revoked, req.Subject)
+
+	object := common.RevokeResponse{Revoked: revoked}
+	EncodeJSONReply(w, r, object)
+}
+
+// ListJobsHandler lists the maintenance jobs
+func ListJobsHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	jobs, ok := ctx.Value(KeyJobs).(*Jobs)
+	if !ok {
+		logger.Error("Unable to retrieve jobs object from context")
+		http.Error(w, "no jobs found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	object := common.JobsResponse{Jobs: jobs.List()}
+	EncodeJSONReply(w, r, object)
+}
+
+// StartJobHandler starts a maintenance job in the background
+func StartJobHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	jobs, ok := ctx.Value(KeyJobs).(*Jobs)
+	if !ok {
+		logger.Error("Unable to retrieve jobs object from context")
+		http.Error(w, "no jobs found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	job, err := jobs.Start(chi.URLParam(r, "kind"))
+	if err == ErrUnknownJob {
+		http.Error(w, err.Error(), http.StatusNotFound)
+		return
+	} else if err == ErrJobRunning {
+		http.Error(w, err.Error(), http.StatusConflict)
+		return
+	}
+
+	EncodeJSONReply(w, r, job)
+}
+
+// HistoryHandler returns the last publishes, optionally only
+// those after the time passed with the "since" parameter
+func HistoryHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	history, ok := ctx.Value(KeyHistory).(*History)
+	if !ok || history == nil {
+		logger.Error("Unable to retrieve history object from context")
+		http.Error(w, "history is disabled", http.StatusNotFound)
+		return
+	}
+
+	var since time.Time
+	if value := r.URL.Query().Get("since"); value != "" {
+		var err error
+		if since, err = ParseTime(value); err != nil {
+			http.Error(w, err.Error(), http.StatusBadRequest)
+			return
+		}
+	}
+	limit := defaultHistoryLimit
+	if value := r.URL.Query().Get("limit"); value != "" {
+		var err error
+		if limit, err = strconv.Atoi(value); err != nil || limit <= 0 {
+			http.Error(w, "invalid limit", http.StatusBadRequest)
+			return
+		}
+	}
+
+	entries := []common.HistoryRecord{}
+	err := history.Walk(func(entry *HistoryEntry) error {
+		if !entry.Time.After(since) {
+			return nil
+		}
+		entries = append(entries, common.HistoryRecord{Time: entry.Time, ID: entry.QueueID, Refs: entry.Refs, KeyID: entry.KeyID, Objects: entry.Objects, Bytes: entry.Bytes})
+		if len(entries) > limit {
+			entries = entries[1:]
+		}
+		return nil
+	})
+	if err != nil {
+		logger.Errorf("Failed to read history: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+
+	object := common.HistoryResponse{Entries: entries}
+	EncodeJSONReply(w, r, object)
+}
@@ -0,0 +1,175 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"regexp"
+	"sort"
+	"strings"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// AliasRule maintains a convenience ref pointing to the newest commit
+// among the branches matching a regular expression: the alias can
+// refer to the groups of the expression like $1 or ${name}
+type AliasRule struct {
+	Pattern string `yaml:"pattern"`
+	Alias   string `yaml:"alias"`
+
+	patternRe *regexp.Regexp
+}
+
+// compile compiles the regular expression of the rule
+func (r *AliasRule) compile() error {
+	if r.Alias == "" {
+		return fmt.Errorf("alias rule \"%s\" has no alias", r.Pattern)
+	}
+
+	var err error
+	if r.patternRe, err = regexp.Compile("^(?:" + r.Pattern + ")$"); err != nil {
+		return fmt.Errorf("invalid alias pattern: %v", err)
+	}
+
+	return nil
+}
+
+// validateAliases makes sure that aliases without groups are not
+// themselves matched by a pattern, which would make them branches
+func validateAliases(rules []*AliasRule) error {
+	for _, rule := range rules {
+		if strings.Contains(rule.Alias, "$") {
+			continue
+		}
+		for _, other := range rules {
+			if other.patternRe.MatchString(rule.Alias) {
+				return fmt.Errorf("alias \"%s\" is matched by the alias pattern \"%s\"", rule.Alias, other.Pattern)
+			}
+		}
+	}
+
+	return nil
+}
+
+// aliasCandidates expands the aliases of the branches, returning the
+// branches that each alias can follow
+func aliasCandidates(rules []*AliasRule, revs map[string]string) (map[string][]string, error) {
+	candidates := map[string][]string{}
+	for _, rule := range rules {
+		for branch := range revs {
+			match := rule.patternRe.FindStringSubmatchIndex(branch)
+			if match == nil {
+				continue
+			}
+			alias := string(rule.patternRe.ExpandString(nil, rule.Alias, branch, match))
+			if err := ostree.ValidateRev(alias); err != nil {
+				return nil, fmt.Errorf("invalid alias %q for branch %s: %v", redactRef(alias), redactRef(branch), err)
+			}
+			candidates[alias] = append(candidates[alias], branch)
+		}
+	}
+
+	return candidates, nil
+}
+
+// aliasTargets returns the commit each alias should point to, the
+// newest among those of the matching branches
+func aliasTargets(r *ostree.Repo, rules []*AliasRule, revs map[string]string) (map[string]string, error) {
+	// Expand the aliases first, aliases are never followed by other aliases
+	candidates, err := aliasCandidates(rules, revs)
+	if err != nil {
+		return nil, err
+	}
+
+	targets := map[string]string{}
+	for alias, branches := range candidates {
+		sort.Strings(branches)
+
+		var newestBranch string
+		var newestTimestamp time.Time
+		for _, branch := range branches {
+			if _, isAlias := candidates[branch]; isAlias {
+				continue
+			}
+
+			timestamp, err := r.GetCommitTimestamp(revs[branch])
+			if err != nil {
+				return nil, err
+			}
+			if newestBranch == "" || !timestamp.Before(newestTimestamp) {
+				newestBranch, newestTimestamp = branch, timestamp
+			}
+		}
+		if newestBranch != "" {
+			logger.Debugf("Alias \"%s\" follows branch \"%s\"", redactRef(alias), redactRef(newestBranch))
+			targets[alias] = revs[newestBranch]
+		}
+	}
+
+	return targets, nil
+}
+
+// checkRefsNotAliases refuses to update branches that are aliases of the
+// published branches or of the updated ones, they would be overwritten
+func checkRefsNotAliases(r *ostree.Repo, config *Config, refs map[string]common.RevisionPair) error {
+	if len(config.Aliases) == 0 {
+		return nil
+	}
+
+	revs, err := r.ListRevisions()
+	if err != nil {
+		return err
+	}
+	for branch, revPair := range refs {
+		revs[branch] = revPair.Client
+	}
+
+	candidates, err := aliasCandidates(config.Aliases, revs)
+	if err != nil {
+		return err
+	}
+	for _, branch := range common.SortedBranches(refs) {
+		if _, isAlias := candidates[branch]; isAlias {
+			return fmt.Errorf("branch \"%s\" is an alias maintained by the server and cannot be pushed", redactRef(branch))
+		}
+	}
+
+	return nil
+}
+
+// updateAliases moves the aliases to the newest commits of the branches
+// they follow, it's called after the branches are updated
+func updateAliases(r *ostree.Repo, config *Config) error {
+	if len(config.Aliases) == 0 {
+		return nil
+	}
+
+	revs, err := r.ListRevisions()
+	if err != nil {
+		return err
+	}
+
+	targets, err := aliasTargets(r, config.Aliases, revs)
+	if err != nil {
+		return err
+	}
+
+	for _, alias := range common.SortedBranchNames(targets) {
+		rev := targets[alias]
+		if revs[alias] == rev {
+			continue
+		}
+		logger.Infof("Moving alias \"%s\" to %s", redactRef(alias), rev)
+		if err := r.SetRefImmediate("", alias, rev); err != nil {
+			return fmt.Errorf("Failed to set alias %s to %s: %v", redactRef(alias), rev, err)
+		}
+	}
+
+	return nil
+}
@@ -0,0 +1,189 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"context"
+	"crypto/sha256"
+	"encoding/hex"
+	"fmt"
+	"net/http"
+	"strings"
+	"time"
+
+	"github.com/go-chi/chi"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// How long an approval request is valid when the configuration doesn't say
+const defaultApprovalExpiry = 24 * time.Hour
+
+// Approval is a request to publish an entry that somebody else must approve
+type Approval struct {
+	RequestedBy string
+	Requested   time.Time
+}
+
+// RequestApproval marks the entry as waiting for approval, an existing
+// request is returned as is
+func (q *Queue) RequestApproval(ID, requestedBy string) *Approval {
+	q.mutex.Lock()
+	defer q.mutex.Unlock()
+
+	if approval, ok := q.approvals[ID]; ok {
+		return approval
+	}
+
+	approval := &Approval{RequestedBy: requestedBy, Requested: time.Now().UTC()}
+	q.approvals[ID] = approval
+	return approval
+}
+
+// GetApproval returns the approval request of the entry, or nil
+func (q *Queue) GetApproval(ID string) *Approval {
+	q.mutex.Lock()
+	defer q.mutex.Unlock()
+
+	return q.approvals[ID]
+}
+
+// restoreApproval sets the approval request of the entry recovered from the journal
+func (q *Queue) restoreApproval(ID string, approval *Approval) {
+	q.mutex.Lock()
+	defer q.mutex.Unlock()
+
+	q.approvals[ID] = approval
+}
+
+// RemoveApproval forgets the approval request of the entry
+func (q *Queue) RemoveApproval(ID string) {
+	q.mutex.Lock()
+	defer q.mutex.Unlock()
+
+	delete(q.approvals, ID)
+}
+
+// identity returns who authenticated the request, tokens without a subject
+// are identified by a short hash so that they don't end up in logs
+func identity(ctx context.Context) string {
+	if user, ok := ctx.Value(KeyUser).(*User); ok {
+		return "user:" + user.Name
+	}
+	if token, ok := ctx.Value(KeyToken).(*Token); ok {
+		if token.Subject != "" {
+			return "token:" + token.Subject
+		}
+		sum := sha256.Sum256([]byte(token.Token))
+		return "token:" + hex.EncodeToString(sum[:4])
+	}
+
+	return ""
+}
+
+// identitySubject returns who an identity belongs to, without the kind of
+// credentials, so that the user and the token of a person are the same
+func identitySubject(id string) string {
+	for _, prefix := range []string{"user:", "token:"} {
+		id = strings.TrimPrefix(id, prefix)
+	}
+
+	return strings.ToLower(strings.TrimSpace(id))
+}
+
+// requiresApproval returns true if any branch of the entry matches
+// one of the patterns that need approval
+func requiresApproval(config *Config, entry *QueueEntry) bool {
+	for branch := range entry.UpdateRefs {
+		if matchRefs(config.ApprovalRefs, branch) {
+			return true
+		}
+	}
+
+	return false
+}
+
+// requestApproval puts the entry on hold until somebody else approves it
+func requestApproval(w http.ResponseWriter, r *http.Request, service *ReceiveService, entry *QueueEntry) {
+	// All objects must be uploaded before asking for approval
+	if missing := countMissingObjects(service.Repo, entry); missing > 0 {
+		logger.Errorf("Queue %s: cannot request approval, %d objects were not uploaded", entry.ID, missing)
+		http.Error(w, fmt.Sprintf("%d objects were not uploaded", missing), http.StatusConflict)
+		return
+	}
+
+	approval := service.Queue.RequestApproval(entry.ID, identity(r.Context()))
+	logger.Infof("Queue %s: waiting for approval, requested by %s", entry.ID, approval.RequestedBy)
+
+	// Approvers can come after a restart
+	if err := service.Journal.RecordApproval(entry.ID, approval); err != nil {
+		logger.Errorf("Queue %s: failed to journal the approval request: %v", entry.ID, err)
+	}
+
+	expires := approval.Requested.Add(service.Config.ApprovalExpiryDuration())
+	object := common.DoneResponse{QueueID: entry.ID, Pending: true, Expires: &expires}
+	EncodeSignedJSONReply(w, r, object)
+}
+
+// ApproveHandler publishes an entry that is waiting for approval,
+// the request must come from somebody else than who asked for it
+func ApproveHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	service, err := serviceFromContext(ctx)
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+	queue := service.Queue
+
+	// Get the entry from the queue
+	queueID := chi.URLParam(r, "queueID")
+	entry, err := service.getEntry(queueID)
+	if err != nil {
+		logger.Errorf("Unable to retrieve queue entry: %v", err)
+		replyServiceError(w, err)
+		return
+	}
+
+	// Decode request
+	err = DecodeJSONBody(w, r, nil)
+	if err != nil {
+		HandleDecodeError(w, err)
+		return
+	}
+
+	// Check the approval request
+	approval := queue.GetApproval(queueID)
+	if approval == nil {
+		http.Error(w, "queue entry is not waiting for approval", http.StatusConflict)
+		return
+	}
+	if time.Since(approval.Requested) > service.Config.ApprovalExpiryDuration() {
+		logger.Errorf("Queue %s: approval request expired", queueID)
+		queue.RemoveApproval(queueID)
+		service.Journal.RecordApproval(queueID, nil)
+		http.Error(w, "approval request expired, publish again", http.StatusGone)
+		return
+	}
+	approver := identity(ctx)
+	approverSubject := identitySubject(approver)
+	if approverSubject == identitySubject(approval.RequestedBy) || approverSubject == strings.ToLower(strings.TrimSpace(entry.Subject)) {
+		logger.Errorf("Queue %s: %s cannot approve its own request", queueID, approver)
+		http.Error(w, "the approval must come from somebody else", http.StatusForbidden)
+		return
+	}
+
+	if err := checkRefsAllowed(ctx, entry.UpdateRefs); err != nil {
+		logger.Errorf("Queue %s: %s cannot approve: %v", queueID, approver, err)
+		http.Error(w, err.Error(), http.StatusForbidden)
+		return
+	}
+
+	logger.Infof("Queue %s: approved by %s", queueID, approver)
+	publishEntry(w, r, service, entry)
+}
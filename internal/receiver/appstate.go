@@ -4,11 +4,132 @@
 
 package receiver
 
-import "github.com/lirios/ostree-upload/internal/ostree"
+import (
+	"crypto/ed25519"
+	"fmt"
+	"os"
+
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
 
 // AppState represents the ostree-receiver context
 type AppState struct {
-	Queue  *Queue
-	Repo   *ostree.Repo
-	Config *Config
+	Queue       *Queue
+	Repo        *ostree.Repo
+	Config      *Config
+	SigningKey  ed25519.PrivateKey
+	History     *History
+	Journal     *Journal
+	OIDC        *OIDCVerifier
+	Upstream    *Upstream
+	RateLimiter *RateLimiter
+	Idempotency *IdempotencyCache
+	Redirect    *UploadRedirect
+	Jobs        *Jobs
+
+	// Authentication state
+	Passwords    *PasswordCache
+	AuthFailures *AuthFailures
+}
+
+// NewAppState opens the repository, creating it if it doesn't exist,
+// and the configuration file and sets up the receiver context
+func NewAppState(repoPath, configPath string) (*AppState, error) {
+	// Queue
+	queue, err := NewQueue()
+	if err != nil {
+		return nil, fmt.Errorf("failed to create queue: %v", err)
+	}
+
+	// Open repository
+	var repo *ostree.Repo
+	if _, err := os.Stat(repoPath); os.IsNotExist(err) {
+		repo, err = ostree.CreateRepo(repoPath)
+		if err != nil {
+			return nil, fmt.Errorf("failed to create OSTree repository: %v", err)
+		}
+	} else {
+		repo, err = ostree.OpenRepo(repoPath)
+		if err != nil {
+			return nil, fmt.Errorf("failed to open OSTree repository: %v", err)
+		}
+	}
+
+	// Create temporary directory
+	if err = CreateTempDirectory(repo); err != nil {
+		return nil, fmt.Errorf("failed to create temporary directory for OSTree repository: %v", err)
+	}
+
+	// Open configuration file
+	config, err := OpenConfig(configPath)
+	if err != nil {
+		return nil, fmt.Errorf("cannot open configuration file: %v", err)
+	}
+
+	// Keep names of branches and objects out of the logs if requested
+	SetLogRedaction(config.LogRedaction)
+
+	appState := &AppState{Queue: queue, Repo: repo, Config: config, Jobs: NewJobs(repo, config, queue), AuthFailures: NewAuthFailures()}
+
+	// Clients send the password with every request
+	appState.Passwords, err = NewPasswordCache()
+	if err != nil {
+		return nil, fmt.Errorf("failed to create password cache: %v", err)
+	}
+
+	// Rebuild the queue from the journal, in case we crashed
+	appState.Journal, err = OpenJournal(repo)
+	if err != nil {
+		return nil, fmt.Errorf("failed to open journal: %v", err)
+	}
+	if err := appState.Journal.Recover(queue, repo); err != nil {
+		return nil, fmt.Errorf("failed to recover queue from journal: %v", err)
+	}
+
+	// Validate unknown tokens with the OpenID Connect provider
+	if config.OIDC != nil {
+		appState.OIDC = NewOIDCVerifier(config.OIDC)
+	}
+
+	// Forward the API to another receiver
+	if config.Upstream != nil {
+		appState.Upstream, err = NewUpstream(config.Upstream)
+		if err != nil {
+			return nil, fmt.Errorf("invalid upstream: %v", err)
+		}
+	}
+
+	// Objects are uploaded to a bucket instead
+	if config.UploadRedirect != nil {
+		appState.Redirect, err = NewUploadRedirect(config.UploadRedirect)
+		if err != nil {
+			return nil, fmt.Errorf("invalid upload redirect: %v", err)
+		}
+	}
+
+	// Limit what each client can do
+	if config.RateLimit != nil {
+		appState.RateLimiter = NewRateLimiter(config.RateLimit)
+	}
+
+	// Answer retried requests with the reply of the first one
+	if window := config.IdempotencyWindowDuration(); window > 0 {
+		appState.Idempotency = NewIdempotencyCache(window)
+	}
+
+	// Keep track of what is published
+	if config.HistoryFile != "" {
+		appState.History = OpenHistory(config.HistoryFile)
+		appState.History.SetRotation(config.HistoryRotateSize, config.HistoryMaxAgeDuration(), config.HistoryCompression)
+	}
+
+	// Load the key used to sign replies
+	if config.SigningKey != "" {
+		appState.SigningKey, err = ParseSigningKey(config.SigningKey)
+		if err != nil {
+			return nil, fmt.Errorf("invalid signing key: %v", err)
+		}
+	}
+
+	return appState, nil
 }
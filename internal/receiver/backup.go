@@ -0,0 +1,106 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"io/ioutil"
+	"os"
+	"path/filepath"
+	"sort"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// Refs directories that are saved
+var backupRefsDirs = []string{"heads", "mirrors"}
+
+// Files saved along with the refs
+var backupSummaryFiles = []string{"summary", "summary.sig"}
+
+// BackupRefs copies refs/heads, refs/mirrors and the summary to a timestamped directory
+// inside backupDir, keeping only the latest retention backups
+func BackupRefs(r *ostree.Repo, backupDir string, retention int) error {
+	destPath := filepath.Join(backupDir, time.Now().UTC().Format("20060102T150405.000000000Z"))
+	if err := os.MkdirAll(destPath, 0755); err != nil {
+		return err
+	}
+
+	// Copy refs, a repository might not have mirrors
+	for _, dirName := range backupRefsDirs {
+		refsPath := filepath.Join(r.Path(), "refs", dirName)
+		if _, err := os.Stat(refsPath); os.IsNotExist(err) {
+			continue
+		}
+
+		err := filepath.Walk(refsPath, func(path string, info os.FileInfo, err error) error {
+			if err != nil {
+				return err
+			}
+
+			relPath, err := filepath.Rel(r.Path(), path)
+			if err != nil {
+				return err
+			}
+
+			if info.IsDir() {
+				return os.MkdirAll(filepath.Join(destPath, relPath), 0755)
+			}
+			return copyFile(path, filepath.Join(destPath, relPath))
+		})
+		if err != nil {
+			return fmt.Errorf("failed to backup refs: %v", err)
+		}
+	}
+
+	// Copy summary, a repository might not have one yet
+	for _, fileName := range backupSummaryFiles {
+		path := filepath.Join(r.Path(), fileName)
+		if _, err := os.Stat(path); os.IsNotExist(err) {
+			continue
+		}
+		if err := copyFile(path, filepath.Join(destPath, fileName)); err != nil {
+			return fmt.Errorf("failed to backup %s: %v", fileName, err)
+		}
+	}
+
+	logger.Debugf("Saved refs and summary to %s", destPath)
+
+	return pruneBackups(backupDir, retention)
+}
+
+// pruneBackups removes the oldest backups, leaving only the latest retention
+func pruneBackups(backupDir string, retention int) error {
+	if retention <= 0 {
+		return nil
+	}
+
+	infos, err := ioutil.ReadDir(backupDir)
+	if err != nil {
+		return err
+	}
+
+	// Timestamps sort lexically
+	backups := []string{}
+	for _, info := range infos {
+		if info.IsDir() {
+			backups = append(backups, info.Name())
+		}
+	}
+	sort.Strings(backups)
+
+	for len(backups) > retention {
+		path := filepath.Join(backupDir, backups[0])
+		logger.Debugf("Removing old backup %s", path)
+		if err := os.RemoveAll(path); err != nil {
+			return err
+		}
+		backups = backups[1:]
+	}
+
+	return nil
+}
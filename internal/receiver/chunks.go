@@ -0,0 +1,239 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"io"
+	"net/http"
+	"os"
+	"path/filepath"
+
+	"github.com/go-chi/chi"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// lockChunks marks a chunk of the object of the entry as being received,
+// it returns false if another chunk of the same object is
+func (q *Queue) lockChunks(ID, objectName string) bool {
+	q.chunksMutex.Lock()
+	defer q.chunksMutex.Unlock()
+
+	key := ID + "/" + objectName
+	if q.chunks[key] {
+		return false
+	}
+	q.chunks[key] = true
+	return true
+}
+
+// unlockChunks allows receiving the next chunk of the object of the entry
+func (q *Queue) unlockChunks(ID, objectName string) {
+	q.chunksMutex.Lock()
+	defer q.chunksMutex.Unlock()
+
+	delete(q.chunks, ID+"/"+objectName)
+}
+
+// PartialHandler tells how many bytes of an object uploaded in chunks
+// the server holds, so that an interrupted upload is resumed from there
+func PartialHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+	if service.Journal == nil {
+		http.Error(w, "uploads in chunks require the journal", http.StatusNotImplemented)
+		return
+	}
+
+	objectName := chi.URLParam(r, "objectName")
+	entry, err := service.sessionObject(chi.URLParam(r, "queueID"), objectName)
+	if err != nil {
+		replyServiceError(w, err)
+		return
+	}
+
+	// The last chunk might have been received, but not the reply
+	receipts, err := service.Journal.Receipts(entry.ID)
+	if err != nil {
+		logger.Errorf("Failed to read the receipts of %s: %v", entry.ID, err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+	if receipt, ok := receipts[objectName]; ok {
+		EncodeJSONReply(w, r, common.ChunkResponse{Offset: receipt.Size, Receipt: &receipt})
+		return
+	}
+
+	var offset int64
+	if info, err := os.Stat(service.Journal.PartialPath(entry.ID, objectName)); err == nil {
+		offset = info.Size()
+	}
+	EncodeJSONReply(w, r, common.ChunkResponse{Offset: offset})
+}
+
+// ChunkHandler receives a range of bytes of an object, as told by the
+// Content-Range header ("bytes <FIRST>-<LAST>/<SIZE>"). Chunks are appended,
+// so the first byte must be the offset returned by PartialHandler, one at a
+// time, and must all declare the size of the first one. Bytes received
+// before an interruption are kept. Once the whole object is received it's
+// verified against the checksum in common.ChecksumHeader.
+func ChunkHandler(w http.ResponseWriter, r *http.Request) {
+	defer r.Body.Close()
+
+	// Get from context
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+	if service.Journal == nil {
+		http.Error(w, "uploads in chunks require the journal", http.StatusNotImplemented)
+		return
+	}
+
+	objectName := chi.URLParam(r, "objectName")
+	entry, err := service.sessionObject(chi.URLParam(r, "queueID"), objectName)
+	if err != nil {
+		replyServiceError(w, err)
+		return
+	}
+
+	var first, last, size int64
+	if n, err := fmt.Sscanf(r.Header.Get("Content-Range"), "bytes %d-%d/%d", &first, &last, &size); err != nil || n != 3 || first < 0 || last < first || last >= size {
+		http.Error(w, "invalid Content-Range", http.StatusBadRequest)
+		return
+	}
+	checksum := r.Header.Get(common.ChecksumHeader)
+	if last+1 == size {
+		if err := common.ValidateChecksum(checksum); err != nil {
+			http.Error(w, fmt.Sprintf("the last chunk needs a valid %s header: %v", common.ChecksumHeader, err), http.StatusBadRequest)
+			return
+		}
+	}
+
+	object, err := service.receiveChunk(entry, objectName, first, last, size, checksum, r.Body)
+	if err != nil {
+		replyServiceError(w, err)
+		return
+	}
+
+	EncodeJSONReply(w, r, object)
+}
+
+// receiveChunk appends the bytes from first to last of an object of size
+// bytes, read from reader, to what was received before; once the whole
+// object is there it's verified and accepted with the checksum of the client.
+// It returns how much of the object was received and, at the end, the receipt.
+func (s *ReceiveService) receiveChunk(entry *QueueEntry, objectName string, first, last, size int64, checksum string, reader io.Reader) (*common.ChunkResponse, error) {
+	if _, err := checkReferenced(s.Repo, s.Queue, entry, objectName); err != nil {
+		return nil, err
+	}
+
+	limits, err := s.newObjectLimits(entry)
+	if err != nil {
+		logger.Errorf("Failed to calculate the upload limits: %v", err)
+		return nil, err
+	}
+	if err := limits.check(entry, objectName, size, 0); err != nil {
+		return nil, err
+	}
+
+	// Chunks of the same object would be appended to each other
+	if !s.Queue.lockChunks(entry.ID, objectName) {
+		return nil, newServiceError(ErrorConflict, "another chunk of \"%s\" is being received", redact(objectName))
+	}
+	defer s.Queue.unlockChunks(entry.ID, objectName)
+
+	// Chunks are appended in order
+	partialPath := s.Journal.PartialPath(entry.ID, objectName)
+	if err := os.MkdirAll(filepath.Dir(partialPath), 0755); err != nil {
+		logger.Errorf("Unable to create %s: %v", redact(objectName), err)
+		return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	partialFile, err := os.OpenFile(partialPath, os.O_WRONLY|os.O_CREATE, 0644)
+	if err != nil {
+		logger.Errorf("Unable to create %s: %v", redact(objectName), err)
+		return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	defer partialFile.Close()
+	offset, err := partialFile.Seek(0, io.SeekEnd)
+	if err != nil {
+		return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	if offset != first {
+		return nil, newServiceError(ErrorConflict, "chunk starts at %d, the server has %d bytes", first, offset)
+	}
+
+	// The first chunk declares the size of the object, the others must agree
+	if first == 0 {
+		if err := s.Journal.SetPartialSize(entry.ID, objectName, size); err != nil {
+			logger.Errorf("Unable to record the size of %s: %v", redact(objectName), err)
+			return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+		}
+	} else if declared, ok := s.Journal.PartialSize(entry.ID, objectName); !ok {
+		// Without the size the bytes received can't be trusted, start again
+		partialFile.Truncate(0)
+		return nil, newServiceError(ErrorConflict, "chunk starts at %d, the server has 0 bytes", first)
+	} else if declared != size {
+		return nil, newServiceError(ErrorConflict, "chunk declares a size of %d bytes, the first chunk declared %d", size, declared)
+	}
+
+	// Whatever arrives is kept, even if the connection drops
+	logger.Debugf("Receiving bytes %d-%d of \"%s\"...", first, last, redact(objectName))
+	length := last - first + 1
+	written, copyErr := io.Copy(partialFile, io.LimitReader(limits.reader(reader, first), length+1))
+	if err := partialFile.Sync(); err != nil {
+		partialFile.Truncate(first)
+		logger.Errorf("Failed to flush \"%s\": %v", redact(objectName), err)
+		return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	if err := limits.check(entry, objectName, first+written, written); err != nil {
+		partialFile.Truncate(first)
+		return nil, err
+	}
+	if written > length {
+		partialFile.Truncate(first)
+		return nil, newServiceError(ErrorInvalid, "chunk is longer than its range")
+	}
+	s.Queue.AddWritten(entry.ID, written)
+	if copyErr != nil {
+		logger.Errorf("Failed to copy chunk to \"%s\": %v", redact(objectName), copyErr)
+		return nil, &ServiceError{Kind: ErrorInternal, Err: copyErr}
+	}
+	if first+written < size {
+		return &common.ChunkResponse{Offset: first + written}, nil
+	}
+	partialFile.Close()
+
+	// The whole object is here, it's treated as if it was uploaded at once
+	objectPath := GetTempObjectPath(s.Repo, entry.ID, objectName)
+	if err := os.MkdirAll(filepath.Dir(objectPath), 0755); err != nil {
+		logger.Errorf("Unable to create the temporary directory of queue entry %s: %v", entry.ID, err)
+		return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	if err := os.Rename(partialPath, objectPath); err != nil {
+		logger.Errorf("Unable to move \"%s\" to the temporary directory: %v", redact(objectName), err)
+		return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	s.Journal.RemovePartialSize(entry.ID, objectName)
+	serverChecksum, err := s.verifyObject(entry, objectName, objectPath)
+	if err != nil {
+		return nil, err
+	}
+	receipt, err := s.acceptObject(entry, objectName, serverChecksum, checksum, size)
+	if err != nil {
+		return nil, err
+	}
+
+	return &common.ChunkResponse{Offset: size, Receipt: receipt}, nil
+}
@@ -0,0 +1,109 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"os"
+	"path/filepath"
+	"strings"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// What to do when an uploaded object already exists with a different content
+const (
+	// CollisionReject discards the uploaded object and fails the upload
+	CollisionReject = "reject"
+
+	// CollisionQuarantine moves the existing object aside and publishes the uploaded one
+	CollisionQuarantine = "quarantine"
+
+	// CollisionOverwrite publishes the uploaded object over the existing one
+	CollisionOverwrite = "overwrite"
+)
+
+// Name of the directory inside the OSTree repository where colliding objects are saved
+const quarantineDirName = "tmp/ostree-upload-quarantine"
+
+// ErrObjectCollision is returned when an object is rejected by the collision policy
+type ErrObjectCollision struct {
+	ObjectName string
+}
+
+func (e *ErrObjectCollision) Error() string {
+	return fmt.Sprintf("object %s already exists with a different content", e.ObjectName)
+}
+
+// CheckCollision compares the uploaded object with the published one, if any,
+// and applies the collision policy when they differ; checksum is calculated
+// with the algorithm of the session queueID.
+// The temporary object is removed when it's identical to the published one,
+// published objects are only replaced by publishBranches.
+func CheckCollision(r *ostree.Repo, policy, algorithm, queueID, objectName, checksum string) error {
+	objectPath := r.GetObjectPath(objectName)
+	if _, err := os.Stat(objectPath); os.IsNotExist(err) {
+		return nil
+	}
+
+	// Archive file objects are compressed, the same content might be
+	// stored with different bytes
+	if strings.HasSuffix(objectName, ".filez") {
+		return nil
+	}
+
+	existingChecksum, err := common.CalculateChecksumWith(objectPath, algorithm)
+	if err != nil {
+		return err
+	}
+	if existingChecksum == checksum {
+		return os.Remove(GetTempObjectPath(r, queueID, objectName))
+	}
+
+	logger.Errorf("COLLISION: object \"%s\" already exists with a different content (%s vs %s), applying \"%s\" policy",
+		redact(objectName), existingChecksum, checksum, policy)
+
+	switch policy {
+	case CollisionQuarantine, CollisionOverwrite:
+		return nil
+	}
+
+	os.Remove(GetTempObjectPath(r, queueID, objectName))
+	return &ErrObjectCollision{ObjectName: objectName}
+}
+
+// replacesPublished returns true if an uploaded object can replace the
+// published one with the same name; archive file objects are not compared
+// by CheckCollision, so they are only replaced when overwriting is allowed
+func replacesPublished(policy, objectName string) bool {
+	switch policy {
+	case CollisionOverwrite:
+		return true
+	case CollisionQuarantine:
+		return !strings.HasSuffix(objectName, ".filez")
+	}
+
+	return false
+}
+
+// quarantineObject moves the published object out of the way, into the
+// quarantine directory of the repository
+func quarantineObject(r *ostree.Repo, objectName string) error {
+	quarantinePath := filepath.Join(r.Path(), quarantineDirName)
+	if err := os.MkdirAll(quarantinePath, 0755); err != nil {
+		return err
+	}
+
+	destPath := filepath.Join(quarantinePath, fmt.Sprintf("%s.%d", objectName, time.Now().Unix()))
+	if err := os.Rename(r.GetObjectPath(objectName), destPath); err != nil {
+		return err
+	}
+	logger.Errorf("COLLISION: existing object \"%s\" moved to %s", redact(objectName), destPath)
+
+	return nil
+}
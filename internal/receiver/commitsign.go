@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"errors"
+	"fmt"
+	"io/ioutil"
+	"strings"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// Signature types for the commits signed by the server
+const (
+	CommitSignGPG     = "gpg"
+	CommitSignEd25519 = "ed25519"
+)
+
+// CommitSigningConfig makes the server sign the commits it publishes,
+// so that the release key never leaves the server
+type CommitSigningConfig struct {
+	Type       string `yaml:"type,omitempty"`
+	Key        string `yaml:"key"`
+	GPGHomedir string `yaml:"gpg_homedir,omitempty"`
+
+	secretKey string
+}
+
+// validate checks the values, fills in the defaults and loads the ed25519 secret key
+func (c *CommitSigningConfig) validate() error {
+	if c.Key == "" {
+		return errors.New("commit signing requires key")
+	}
+
+	switch c.Type {
+	case "":
+		c.Type = CommitSignGPG
+	case CommitSignGPG:
+	case CommitSignEd25519:
+		// The secret key is read from a file, so that it's not in the configuration
+		data, err := ioutil.ReadFile(c.Key)
+		if err != nil {
+			return fmt.Errorf("cannot read commit signing key: %v", err)
+		}
+		c.secretKey = strings.TrimSpace(string(data))
+	default:
+		return fmt.Errorf("unsupported commit signature type \"%s\"", c.Type)
+	}
+
+	return nil
+}
+
+// signCommit signs a single commit with the configured key
+func (c *CommitSigningConfig) signCommit(repo *ostree.Repo, rev string) error {
+	if c.Type == CommitSignEd25519 {
+		return repo.SignCommitEd25519(rev, c.secretKey)
+	}
+	return repo.SignCommitGPG(rev, c.Key, c.GPGHomedir)
+}
+
+// signPublishedCommits signs the commits that are going to be published,
+// from the new revision of each branch back to the first commit that is
+// already published, by any branch, or that is not in the repository
+// because the push was shallow; published history is never signed again
+func signPublishedCommits(repo *ostree.Repo, config *Config, entry *QueueEntry) error {
+	if config.CommitSigning == nil {
+		return nil
+	}
+
+	// Commits signed along the way are not signed twice either
+	done, err := publishedCommits(repo)
+	if err != nil {
+		return err
+	}
+	for _, branch := range common.SortedBranches(entry.UpdateRefs) {
+		revPair := entry.UpdateRefs[branch]
+		for rev := revPair.Client; rev != "" && !done[rev] && repo.HasCommit(rev); {
+			logger.Debugf("Queue %s: signing commit %s", entry.ID, rev)
+			if err := config.CommitSigning.signCommit(repo, rev); err != nil {
+				return fmt.Errorf("failed to sign commit %s: %v", rev, err)
+			}
+			done[rev] = true
+
+			if rev, err = repo.GetParentRev(rev); err != nil {
+				return err
+			}
+		}
+	}
+
+	return nil
+}
+
+// publishedCommits returns the commits reachable from the current revision
+// of any branch, mirrors included, as far as their history is in the
+// repository; refsMutex must be held
+func publishedCommits(repo *ostree.Repo) (map[string]bool, error) {
+	revs, err := publishedRevisions(repo)
+	if err != nil {
+		return nil, err
+	}
+
+	published := map[string]bool{}
+	for _, head := range revs {
+		for rev := head; rev != "" && !published[rev] && repo.HasCommit(rev); {
+			published[rev] = true
+
+			if rev, err = repo.GetParentRev(rev); err != nil {
+				return nil, err
+			}
+		}
+	}
+
+	return published, nil
+}
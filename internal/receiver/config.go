@@ -5,16 +5,78 @@
 package receiver
 
 import (
+	"crypto/ed25519"
+	"errors"
+	"fmt"
 	"io/ioutil"
+	"net"
 	"os"
+	"strconv"
+	"sync"
+	"time"
 
 	"gopkg.in/yaml.v2"
 )
 
 // Config represents the configuration file
 type Config struct {
-	path   string
-	Tokens []*Token `yaml:"tokens"`
+	path                     string
+	Tokens                   []*Token              `yaml:"tokens"`
+	Users                    []*User               `yaml:"users,omitempty"`
+	ACL                      []*ACLRule            `yaml:"acl,omitempty"`
+	SigningKey               string                `yaml:"signing_key,omitempty"`
+	TempQuota                int64                 `yaml:"temp_quota,omitempty"`
+	SubjectQuota             int64                 `yaml:"subject_quota,omitempty"`
+	MaxObjectSize            int64                 `yaml:"max_object_size,omitempty"`
+	CommitMetaMaxSize        int64                 `yaml:"commitmeta_max_size,omitempty"`
+	CommitMetaKeys           []string              `yaml:"commitmeta_keys,omitempty"`
+	MaxSessions              int                   `yaml:"max_sessions,omitempty"`
+	MaxSessionObjects        int                   `yaml:"max_session_objects,omitempty"`
+	MaxSessionBytes          int64                 `yaml:"max_session_bytes,omitempty"`
+	BackupDir                string                `yaml:"backup_dir,omitempty"`
+	BackupRetention          int                   `yaml:"backup_retention,omitempty"`
+	HistoryFile              string                `yaml:"history_file,omitempty"`
+	HistoryRotateSize        int64                 `yaml:"history_rotate_size,omitempty"`
+	HistoryMaxAge            string                `yaml:"history_max_age,omitempty"`
+	HistoryCompression       string                `yaml:"history_compression,omitempty"`
+	LogRedaction             string                `yaml:"log_redaction,omitempty"`
+	CollisionPolicy          string                `yaml:"collision_policy,omitempty"`
+	PublishStrategy          string                `yaml:"publish_strategy,omitempty"`
+	RefuseOlderVersions      bool                  `yaml:"refuse_older_versions,omitempty"`
+	RefuseOlderTimestamps    bool                  `yaml:"refuse_older_timestamps,omitempty"`
+	SkipChecksumVerification bool                  `yaml:"skip_checksum_verification,omitempty"`
+	VerificationLevel        string                `yaml:"verification_level,omitempty"`
+	ApprovalRefs             []string              `yaml:"approval_refs,omitempty"`
+	ApprovalExpiry           string                `yaml:"approval_expiry,omitempty"`
+	IdempotencyWindow        string                `yaml:"idempotency_window,omitempty"`
+	CommitMessageRules       []*CommitMessageRule  `yaml:"commit_message_rules,omitempty"`
+	Aliases                  []*AliasRule          `yaml:"aliases,omitempty"`
+	TLSCert                  string                `yaml:"tls_cert,omitempty"`
+	TLSKey                   string                `yaml:"tls_key,omitempty"`
+	UnixSocket               string                `yaml:"unix_socket,omitempty"`
+	UnixSocketMode           string                `yaml:"unix_socket_mode,omitempty"`
+	RunAsUser                string                `yaml:"user,omitempty"`
+	RunAsGroup               string                `yaml:"group,omitempty"`
+	Sandbox                  bool                  `yaml:"sandbox,omitempty"`
+	EphemeralRepo            bool                  `yaml:"ephemeral_repo,omitempty"`
+	OIDC                     *OIDCConfig           `yaml:"oidc,omitempty"`
+	JWT                      *JWTConfig            `yaml:"jwt,omitempty"`
+	Upstream                 *UpstreamConfig       `yaml:"upstream,omitempty"`
+	UploadRedirect           *UploadRedirectConfig `yaml:"upload_redirect,omitempty"`
+	CommitSigning            *CommitSigningConfig  `yaml:"commit_signing,omitempty"`
+	SummarySigning           *SummarySigningConfig `yaml:"summary_signing,omitempty"`
+	ManifestKeys             []string              `yaml:"manifest_keys,omitempty"`
+	AllowedNetworks          []string              `yaml:"allowed_networks,omitempty"`
+	DeniedNetworks           []string              `yaml:"denied_networks,omitempty"`
+	TrustedProxies           []string              `yaml:"trusted_proxies,omitempty"`
+	RateLimit                *RateLimitConfig      `yaml:"rate_limit,omitempty"`
+	FrozenRefs               []string              `yaml:"frozen_refs,omitempty"`
+
+	manifestKeys    []ed25519.PublicKey
+	allowedNetworks []*net.IPNet
+	deniedNetworks  []*net.IPNet
+	trustedProxies  []*net.IPNet
+	mutex           sync.Mutex
 }
 
 // CreateConfig creates the configuration file
@@ -51,9 +113,218 @@ func OpenConfig(path string) (*Config, error) {
 
 	config.path = path
 
+	if err := config.validate(); err != nil {
+		return nil, err
+	}
+
 	return &config, nil
 }
 
+// validate checks the values and fills in the defaults
+func (c *Config) validate() error {
+	switch c.CollisionPolicy {
+	case "":
+		c.CollisionPolicy = CollisionReject
+	case CollisionReject, CollisionQuarantine, CollisionOverwrite:
+	default:
+		return fmt.Errorf("unknown collision policy \"%s\"", c.CollisionPolicy)
+	}
+
+	switch c.PublishStrategy {
+	case "":
+		c.PublishStrategy = PublishRename
+	case PublishRename, PublishClone:
+	default:
+		return fmt.Errorf("unknown publish strategy \"%s\"", c.PublishStrategy)
+	}
+
+	if err := c.validateVerificationLevel(); err != nil {
+		return err
+	}
+	if c.CommitMetaMaxSize < 0 {
+		return errors.New("commitmeta_max_size cannot be negative")
+	}
+
+	if (c.TLSCert == "") != (c.TLSKey == "") {
+		return errors.New("both tls_cert and tls_key are required for TLS")
+	}
+
+	if c.UnixSocketMode != "" {
+		if _, err := strconv.ParseUint(c.UnixSocketMode, 8, 32); err != nil {
+			return fmt.Errorf("invalid unix socket mode \"%s\"", c.UnixSocketMode)
+		}
+	}
+
+	if c.OIDC != nil && c.OIDC.Issuer == "" && c.OIDC.IntrospectionURL == "" {
+		return errors.New("oidc requires either issuer or introspection_url")
+	}
+	if c.OIDC != nil && c.OIDC.ClientID == "" && c.OIDC.Audience == "" {
+		return errors.New("oidc requires client_id or audience")
+	}
+
+	if c.JWT != nil {
+		if err := c.JWT.validate(); err != nil {
+			return err
+		}
+	}
+
+	if c.CommitSigning != nil {
+		if err := c.CommitSigning.validate(); err != nil {
+			return err
+		}
+	}
+
+	if c.SummarySigning != nil {
+		if err := c.SummarySigning.validate(); err != nil {
+			return err
+		}
+	}
+
+	manifestKeys, err := parseManifestKeys(c.ManifestKeys)
+	if err != nil {
+		return err
+	}
+	c.manifestKeys = manifestKeys
+
+	if c.allowedNetworks, err = parseNetworks(c.AllowedNetworks); err != nil {
+		return fmt.Errorf("invalid allowed network: %v", err)
+	}
+	if c.deniedNetworks, err = parseNetworks(c.DeniedNetworks); err != nil {
+		return fmt.Errorf("invalid denied network: %v", err)
+	}
+	if c.trustedProxies, err = parseNetworks(c.TrustedProxies); err != nil {
+		return fmt.Errorf("invalid trusted proxy: %v", err)
+	}
+
+	if c.RateLimit != nil {
+		if err := c.RateLimit.validate(); err != nil {
+			return err
+		}
+	}
+
+	if c.Upstream != nil {
+		if err := c.Upstream.validate(); err != nil {
+			return err
+		}
+	}
+
+	if c.UploadRedirect != nil {
+		if err := c.UploadRedirect.validate(); err != nil {
+			return err
+		}
+	}
+
+	if c.ApprovalExpiry != "" {
+		if _, err := time.ParseDuration(c.ApprovalExpiry); err != nil {
+			return fmt.Errorf("invalid approval expiry: %v", err)
+		}
+	}
+
+	if c.IdempotencyWindow != "" {
+		if _, err := time.ParseDuration(c.IdempotencyWindow); err != nil {
+			return fmt.Errorf("invalid idempotency window: %v", err)
+		}
+	}
+
+	switch c.HistoryCompression {
+	case "":
+		c.HistoryCompression = HistoryCompressionGzip
+	case HistoryCompressionGzip, HistoryCompressionNone:
+	default:
+		return fmt.Errorf("unknown history compression \"%s\"", c.HistoryCompression)
+	}
+	switch c.LogRedaction {
+	case "":
+		c.LogRedaction = LogRedactionNone
+	case LogRedactionNone, LogRedactionHash, LogRedactionTruncate:
+	default:
+		return fmt.Errorf("unknown log redaction \"%s\"", c.LogRedaction)
+	}
+	if c.HistoryMaxAge != "" {
+		if _, err := time.ParseDuration(c.HistoryMaxAge); err != nil {
+			return fmt.Errorf("invalid history max age: %v", err)
+		}
+	}
+
+	for _, rule := range c.CommitMessageRules {
+		if err := rule.compile(); err != nil {
+			return err
+		}
+	}
+
+	for _, rule := range c.Aliases {
+		if err := rule.compile(); err != nil {
+			return err
+		}
+	}
+	if err := validateAliases(c.Aliases); err != nil {
+		return err
+	}
+
+	for _, rule := range c.ACL {
+		if err := rule.validate(); err != nil {
+			return err
+		}
+	}
+
+	for _, token := range c.Tokens {
+		if err := ValidateScopes(token.Scopes); err != nil {
+			return err
+		}
+	}
+	for _, user := range c.Users {
+		if err := ValidateScopes(user.Scopes); err != nil {
+			return fmt.Errorf("user \"%s\": %v", user.Name, err)
+		}
+		if user.Password == "" {
+			continue
+		}
+		if _, err := parsePasswordHash(user.Password); err != nil {
+			return fmt.Errorf("user \"%s\": %v", user.Name, err)
+		}
+	}
+
+	return nil
+}
+
+// ApprovalExpiryDuration returns how long an approval request is valid
+func (c *Config) ApprovalExpiryDuration() time.Duration {
+	if expiry, err := time.ParseDuration(c.ApprovalExpiry); err == nil {
+		return expiry
+	}
+
+	return defaultApprovalExpiry
+}
+
+// IdempotencyWindowDuration returns how long the outcome of requests with an
+// idempotency key is replayed, zero disables it
+func (c *Config) IdempotencyWindowDuration() time.Duration {
+	if window, err := time.ParseDuration(c.IdempotencyWindow); err == nil {
+		return window
+	}
+
+	return defaultIdempotencyWindow
+}
+
+// UnixSocketPermissions returns the permissions of the unix socket,
+// read and write for the owner and the group by default
+func (c *Config) UnixSocketPermissions() os.FileMode {
+	if mode, err := strconv.ParseUint(c.UnixSocketMode, 8, 32); err == nil {
+		return os.FileMode(mode) & os.ModePerm
+	}
+
+	return 0660
+}
+
+// HistoryMaxAgeDuration returns how long the rotated history is kept, forever when zero
+func (c *Config) HistoryMaxAgeDuration() time.Duration {
+	if maxAge, err := time.ParseDuration(c.HistoryMaxAge); err == nil {
+		return maxAge
+	}
+
+	return 0
+}
+
 // Save saves the configuration file
 func (c *Config) Save() error {
 	data, err := yaml.Marshal(c)
@@ -0,0 +1,50 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"os"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// PruneStaticDeltas removes the static deltas referencing commits that
+// are no longer in the repository, for example after pruning, and
+// returns how many were removed
+func PruneStaticDeltas(repo *ostree.Repo, config *Config) (int, error) {
+	deltas, err := repo.ListStaticDeltas()
+	if err != nil {
+		return 0, fmt.Errorf("failed to list static deltas: %v", err)
+	}
+
+	commitExists := func(rev string) bool {
+		_, err := os.Stat(repo.GetObjectPath(rev + ".commit"))
+		return err == nil
+	}
+
+	removed := 0
+	for _, delta := range deltas {
+		if commitExists(delta.To) && (delta.From == "" || commitExists(delta.From)) {
+			continue
+		}
+
+		logger.Debugf("Removing static delta %s", delta.Name())
+		if err := repo.DeleteStaticDelta(delta); err != nil {
+			return removed, fmt.Errorf("failed to remove static delta %s: %v", delta.Name(), err)
+		}
+		removed++
+	}
+
+	// The summary must not advertise the deltas we removed
+	if removed > 0 {
+		if err := updateSummary(repo, config); err != nil {
+			return removed, err
+		}
+	}
+
+	return removed, nil
+}
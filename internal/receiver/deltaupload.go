@@ -0,0 +1,132 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"io"
+	"io/ioutil"
+	"net/http"
+	"os"
+	"path/filepath"
+	"sync"
+
+	"github.com/go-chi/chi"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// Static deltas are applied one at a time, each in its own transaction
+var deltaMutex sync.Mutex
+
+// StaticDeltaUploadHandler receives a static delta, generated by the client
+// as a single file, between the published revision of a branch of the queue
+// entry ("from" parameter) and the new one ("to" parameter), and applies it
+// so that the objects don't need to be uploaded one by one.
+// The objects are verified by libostree and go straight to the repository,
+// where publishing the entry finds them.
+func StaticDeltaUploadHandler(w http.ResponseWriter, r *http.Request) {
+	defer r.Body.Close()
+
+	// Get from context
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+	repo := service.Repo
+
+	// Get the entry from the queue
+	entry, err := service.getEntry(chi.URLParam(r, "queueID"))
+	if err != nil {
+		logger.Errorf("Unable to retrieve queue entry: %v", err)
+		replyServiceError(w, err)
+		return
+	}
+
+	// Objects of a delta can't be compared with a signed push manifest
+	if entry.Checksums != nil {
+		http.Error(w, "static deltas can't be uploaded with a signed push manifest", http.StatusUnprocessableEntity)
+		return
+	}
+
+	// The delta must move a branch of the entry from a published commit
+	from := r.URL.Query().Get("from")
+	to := r.URL.Query().Get("to")
+	if err := common.ValidateChecksum(from); err != nil {
+		http.Error(w, fmt.Sprintf("invalid from: %v", err), http.StatusBadRequest)
+		return
+	}
+	if err := common.ValidateChecksum(to); err != nil {
+		http.Error(w, fmt.Sprintf("invalid to: %v", err), http.StatusBadRequest)
+		return
+	}
+	found := false
+	for _, revPair := range entry.UpdateRefs {
+		if revPair.Server == from && revPair.Client == to {
+			found = true
+		}
+	}
+	if !found {
+		http.Error(w, "the delta doesn't update a branch of the queue entry", http.StatusUnprocessableEntity)
+		return
+	}
+	if !repo.HasCommit(from) {
+		http.Error(w, fmt.Sprintf("commit %s is not in the repository", from), http.StatusUnprocessableEntity)
+		return
+	}
+
+	// Receive the delta within the same limits of the objects
+	limits, err := service.newObjectLimits(entry)
+	if err != nil {
+		logger.Errorf("Failed to calculate the upload limits: %v", err)
+		replyServiceError(w, err)
+		return
+	}
+	deltaFile, err := ioutil.TempFile(filepath.Join(repo.Path(), "tmp"), "ostree-upload-delta-")
+	if err != nil {
+		logger.Errorf("Unable to create the static delta file: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+	defer os.Remove(deltaFile.Name())
+	written, err := io.Copy(deltaFile, limits.reader(r.Body, 0))
+	deltaFile.Close()
+	if err != nil {
+		logger.Errorf("Failed to receive the static delta %s-%s: %v", from, to, err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+	if err := limits.check(entry, "static delta", written, written); err != nil {
+		replyServiceError(w, err)
+		return
+	}
+	service.Queue.AddWritten(entry.ID, written)
+
+	logger.Infof("Queue %s: applying static delta %s-%s (%d bytes)", entry.ID, from, to, written)
+	deltaMutex.Lock()
+	err = repo.ApplyStaticDeltaFile(deltaFile.Name())
+	deltaMutex.Unlock()
+	if err != nil {
+		logger.Errorf("Queue %s: failed to apply static delta %s-%s: %v", entry.ID, from, to, err)
+		if ostree.IsNotFound(err) || ostree.IsCorrupted(err) {
+			http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		} else {
+			http.Error(w, err.Error(), http.StatusInternalServerError)
+		}
+		return
+	}
+	if !repo.HasCommit(to) {
+		http.Error(w, fmt.Sprintf("the delta doesn't lead to commit %s", to), http.StatusUnprocessableEntity)
+		return
+	}
+
+	// Reply with what is still missing, such as intermediate commits
+	object := common.ObjectsResponse{Objects: missingObjects(repo, entry)}
+	EncodeJSONReply(w, r, object)
+}
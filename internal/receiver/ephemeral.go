@@ -0,0 +1,49 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"os"
+	"path/filepath"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// Shared memory file system, used for ephemeral repositories when available
+const ephemeralBaseDir = "/dev/shm"
+
+// EphemeralRepoPath returns where the ephemeral repository of this process
+// is created: the path stays the same when the process executes itself again,
+// as it does to enter the sandbox
+func EphemeralRepoPath() string {
+	baseDir := ephemeralBaseDir
+	if info, err := os.Stat(baseDir); err != nil || !info.IsDir() {
+		baseDir = os.TempDir()
+	}
+	return filepath.Join(baseDir, fmt.Sprintf("ostree-upload-%d", os.Getpid()))
+}
+
+// CreateEphemeralRepo creates an empty repository in memory, unless it
+// already exists, and returns its path
+func CreateEphemeralRepo(configPath string) (string, error) {
+	repoPath := EphemeralRepoPath()
+	if _, err := os.Stat(repoPath); err == nil {
+		return repoPath, nil
+	}
+
+	logger.Actionf("Creating ephemeral repository %s...", repoPath)
+	if _, err := InitRepository(repoPath, configPath, InitOptions{}); err != nil {
+		os.RemoveAll(repoPath)
+		return "", err
+	}
+	return repoPath, nil
+}
+
+// RemoveEphemeralRepo deletes the ephemeral repository and what it contains
+func RemoveEphemeralRepo(repoPath string) error {
+	logger.Actionf("Removing ephemeral repository %s...", repoPath)
+	return os.RemoveAll(repoPath)
+}
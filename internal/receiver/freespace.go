@@ -0,0 +1,91 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"strconv"
+	"strings"
+	"syscall"
+
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// Default of core.min-free-space-percent, as in libostree
+const defaultMinFreeSpacePercent = 3
+
+// Units accepted by core.min-free-space-size
+var minFreeSpaceUnits = map[string]uint{"MB": 20, "GB": 30, "TB": 40}
+
+// parseMinFreeSpaceSize parses a size such as "500MB"
+func parseMinFreeSpaceSize(value string) (uint64, error) {
+	value = strings.TrimSpace(value)
+	if len(value) < 3 {
+		return 0, fmt.Errorf("invalid min-free-space-size \"%s\"", value)
+	}
+
+	shift, ok := minFreeSpaceUnits[value[len(value)-2:]]
+	if !ok {
+		return 0, fmt.Errorf("invalid unit in min-free-space-size \"%s\", use MB, GB or TB", value)
+	}
+	size, err := strconv.ParseUint(value[:len(value)-2], 10, 64)
+	if err != nil {
+		return 0, fmt.Errorf("invalid min-free-space-size \"%s\": %v", value, err)
+	}
+
+	return size << shift, nil
+}
+
+// reservedSpace returns the bytes that must be left free on the file system
+// of the repository, according to core.min-free-space-size or, if not set,
+// core.min-free-space-percent
+func reservedSpace(repo *ostree.Repo, total uint64) (uint64, error) {
+	if value := repo.GetConfigValue("core", "min-free-space-size"); value != "" {
+		return parseMinFreeSpaceSize(value)
+	}
+
+	percent := uint64(defaultMinFreeSpacePercent)
+	if value := repo.GetConfigValue("core", "min-free-space-percent"); value != "" {
+		parsed, err := strconv.ParseUint(value, 10, 64)
+		if err != nil || parsed > 99 {
+			return 0, fmt.Errorf("invalid min-free-space-percent \"%s\"", value)
+		}
+		percent = parsed
+	}
+
+	return total / 100 * percent, nil
+}
+
+// SpaceUsage describes the free space on the file system of the repository
+type SpaceUsage struct {
+	// Bytes available to unprivileged users
+	Free uint64
+	// Bytes that must be left free
+	Reserved uint64
+}
+
+// Headroom returns how many bytes can still be written
+func (u SpaceUsage) Headroom() int64 {
+	if u.Free <= u.Reserved {
+		return 0
+	}
+	return int64(u.Free - u.Reserved)
+}
+
+// GetSpaceUsage returns free and reserved space on the file system of the repository
+func GetSpaceUsage(repo *ostree.Repo) (SpaceUsage, error) {
+	var stat syscall.Statfs_t
+	if err := syscall.Statfs(repo.Path(), &stat); err != nil {
+		return SpaceUsage{}, err
+	}
+
+	free := stat.Bavail * uint64(stat.Bsize)
+	reserved, err := reservedSpace(repo, stat.Blocks*uint64(stat.Bsize))
+	if err != nil {
+		return SpaceUsage{}, err
+	}
+
+	return SpaceUsage{Free: free, Reserved: reserved}, nil
+}
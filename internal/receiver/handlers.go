@@ -6,15 +6,15 @@ package receiver
 
 import (
 	"bytes"
+	"context"
+	"crypto/sha256"
+	"encoding/json"
 	"fmt"
 	"io"
 	"mime/multipart"
 	"net/http"
-	"os"
-	"path/filepath"
 	"strings"
 
-	"github.com/chilts/sid"
 	"github.com/go-chi/chi"
 
 	"github.com/lirios/ostree-upload/internal/common"
@@ -56,51 +56,69 @@ func InfoHandler(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
-	object := common.InfoResponse{Mode: mode, Revs: refs}
-	EncodeJSONReply(w, r, object)
+	// Refs mirrored from other collections are listed separately
+	mirrors, err := repo.ListMirrorRevisions()
+	if err != nil {
+		logger.Errorf("Failed to list mirrored revisions: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+
+	object := common.InfoResponse{
+		Mode:               mode,
+		Revs:               refs,
+		Mirrors:            mirrors,
+		OstreeVersion:      ostree.Version(),
+		Capabilities:       ostree.Capabilities(),
+		CollectionID:       repo.GetCollectionID(),
+		Options:            repo.GetConfigValues(ostree.ConfigGroup),
+		ServerVersion:      common.Version,
+		ProtocolVersion:    common.ProtocolVersion,
+		Features:           serverFeatures(ctx),
+		ChecksumAlgorithms: common.ChecksumAlgorithms,
+	}
+	if config, ok := ctx.Value(KeyConfig).(*Config); ok {
+		object.VerificationLevel = config.VerificationLevel
+	}
+
+	// Let clients skip the body when nothing changed
+	js, err := json.Marshal(object)
+	if err != nil {
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+	etag := fmt.Sprintf("\"%x\"", sha256.Sum256(js))
+	w.Header().Set("ETag", etag)
+	if r.Header.Get("If-None-Match") == etag {
+		w.WriteHeader(http.StatusNotModified)
+		return
+	}
+
+	EncodeSignedJSONReply(w, r, object)
 }
 
 // CreateEntryHandler creates a new queue entry ready for the upload
 func CreateEntryHandler(w http.ResponseWriter, r *http.Request) {
 	// Get from context
-	ctx := r.Context()
-	queue, ok := ctx.Value(KeyQueue).(*Queue)
-	if !ok {
-		logger.Error("Unable to retrieve queue object from context")
-		http.Error(w, "no queue found", http.StatusUnprocessableEntity)
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
 		return
 	}
 
 	// Decode request
 	var req common.QueueRequest
-	err := DecodeJSONBody(w, r, &req)
+	err = DecodeJSONBody(w, r, &req)
 	if err != nil {
 		HandleDecodeError(w, err)
 		return
 	}
 
-	// Forbid an update of the same branches
-	err = queue.Walk(func(entry *QueueEntry) error {
-		for branch := range entry.UpdateRefs {
-			if _, ok := req.Refs[branch]; ok {
-				return fmt.Errorf("branch \"%s\" is already being updated", branch)
-			}
-		}
-
-		return nil
-	})
+	queueID, err := service.CreateSession(r.Context(), &req)
 	if err != nil {
-		logger.Errorf("Failed to walk the queue: %v", err)
-		http.Error(w, err.Error(), http.StatusInternalServerError)
-		return
-	}
-
-	// New queue entry
-	queueID := sid.IdBase64()
-	queueEntry := &QueueEntry{ID: queueID, UpdateRefs: req.Refs, Objects: req.Objects}
-	if err := queue.AddEntry(queueEntry); err != nil {
-		logger.Errorf("Failed to add entry \"%s\" to the queue: %v", queueID, err)
-		http.Error(w, err.Error(), http.StatusInternalServerError)
+		logger.Errorf("Refusing queue entry: %v", err)
+		replyServiceError(w, err)
 		return
 	}
 
@@ -108,35 +126,21 @@ func CreateEntryHandler(w http.ResponseWriter, r *http.Request) {
 	EncodeJSONReply(w, r, object)
 }
 
-// DeleteEntryHandler deletes the entry from the queue
+// DeleteEntryHandler aborts the session, deleting the entry from the
+// queue and the objects it staged
 func DeleteEntryHandler(w http.ResponseWriter, r *http.Request) {
 	// Get from context
-	ctx := r.Context()
-	queue, ok := ctx.Value(KeyQueue).(*Queue)
-	if !ok {
-		logger.Error("Unable to retrieve queue object from context")
-		http.Error(w, "no queue found", http.StatusUnprocessableEntity)
-		return
-	}
-
-	// Get the entry from the queue
-	queueID := chi.URLParam(r, "queueID")
-	entry, err := queue.GetEntry(queueID)
+	service, err := serviceFromContext(r.Context())
 	if err != nil {
-		logger.Errorf("Unable to retrieve queue entry: %v", err)
-		http.Error(w, fmt.Sprintf("failed to get entry from queue: %v", err), http.StatusNotFound)
-		return
-	}
-	if entry == nil {
-		logger.Error("Unable to find queue entry")
-		http.Error(w, "queue entry not found", http.StatusNotFound)
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
 		return
 	}
 
 	// Delete
-	if err := queue.RemoveEntry(entry); err != nil {
-		logger.Errorf("Unable to remove entry from queue: %v")
-		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+	if err := service.DeleteSession(r.Context(), chi.URLParam(r, "queueID")); err != nil {
+		logger.Errorf("Unable to remove entry from queue: %v", err)
+		replyServiceError(w, err)
 		return
 	}
 }
@@ -145,89 +149,89 @@ func DeleteEntryHandler(w http.ResponseWriter, r *http.Request) {
 // and returns the list of objects that were not previously upload
 func ObjectsHandler(w http.ResponseWriter, r *http.Request) {
 	// Get from context
-	ctx := r.Context()
-	queue, ok := ctx.Value(KeyQueue).(*Queue)
-	if !ok {
-		logger.Error("Unable to retrieve queue object from context")
-		http.Error(w, "no queue found", http.StatusUnprocessableEntity)
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
 		return
 	}
-	repo, ok := ctx.Value(KeyRepository).(*ostree.Repo)
-	if !ok {
-		logger.Error("Unable to retrieve repository object from context")
-		http.Error(w, "no repository found", http.StatusUnprocessableEntity)
+
+	// Decode request
+	err = DecodeJSONBody(w, r, nil)
+	if err != nil {
+		HandleDecodeError(w, err)
 		return
 	}
 
-	// Get the entry from the queue
+	// List of missing objects we will receive from the client
 	queueID := chi.URLParam(r, "queueID")
-	entry, err := queue.GetEntry(queueID)
+	missingObjects, err := service.MissingObjects(queueID, nil)
 	if err != nil {
 		logger.Errorf("Unable to retrieve queue entry: %v", err)
-		http.Error(w, fmt.Sprintf("failed to get entry from queue: %v", err), http.StatusNotFound)
+		replyServiceError(w, err)
 		return
 	}
-	if entry == nil {
-		logger.Error("Unable to find queue entry")
-		http.Error(w, "queue entry not found", http.StatusNotFound)
+
+	// Reply, with where to upload the objects when uploads are redirected
+	redirect, _ := r.Context().Value(KeyUploadRedirect).(*UploadRedirect)
+	object := common.ObjectsResponse{Objects: missingObjects, UploadTargets: redirect.Targets(queueID, missingObjects)}
+	EncodeJSONReply(w, r, object)
+}
+
+// MissingObjectsHandler returns which objects of the list, possibly
+// compressed, were not previously uploaded; it replaces ObjectsHandler
+// because proxies might drop GET requests with a body
+func MissingObjectsHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
 		return
 	}
 
 	// Decode request
-	err = DecodeJSONBody(w, r, nil)
+	var req common.ObjectsRequest
+	err = DecodeJSONBody(w, r, &req)
 	if err != nil {
 		HandleDecodeError(w, err)
 		return
 	}
 
 	// List of missing objects we will receive from the client
-	missingObjects := []string{}
-	for _, objectName := range entry.Objects {
-		tempPath := GetTempObjectPath(repo, objectName)
-		objectPath := repo.GetObjectPath(objectName)
-
-		if _, err := os.Stat(tempPath); os.IsNotExist(err) {
-			if _, err := os.Stat(objectPath); os.IsNotExist(err) {
-				missingObjects = append(missingObjects, objectName)
-			}
-		}
+	queueID := chi.URLParam(r, "queueID")
+	missingObjects, err := service.MissingObjects(queueID, req.Objects)
+	if err != nil {
+		logger.Errorf("Unable to list missing objects: %v", err)
+		replyServiceError(w, err)
+		return
 	}
 
-	// Reply
-	object := common.ObjectsResponse{Objects: missingObjects}
+	// Reply, with where to upload the objects when uploads are redirected
+	redirect, _ := r.Context().Value(KeyUploadRedirect).(*UploadRedirect)
+	object := common.ObjectsResponse{Objects: missingObjects, UploadTargets: redirect.Targets(queueID, missingObjects)}
 	EncodeJSONReply(w, r, object)
 }
 
-// UploadHandler receives objects from the client
+// UploadHandler receives objects from the client, any number of them in
+// a multipart request: each "file" part is an object, followed by a
+// "checksum" part with "<OBJECT>:<CHECKSUM>"
 func UploadHandler(w http.ResponseWriter, r *http.Request) {
 	defer r.Body.Close()
 
 	// Get from context
-	ctx := r.Context()
-	queue, ok := ctx.Value(KeyQueue).(*Queue)
-	if !ok {
-		logger.Error("Unable to retrieve queue object from context")
-		http.Error(w, "no queue found", http.StatusUnprocessableEntity)
-		return
-	}
-	repo, ok := ctx.Value(KeyRepository).(*ostree.Repo)
-	if !ok {
-		logger.Error("Unable to retrieve repository object from context")
-		http.Error(w, "no repository found", http.StatusUnprocessableEntity)
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
 		return
 	}
 
 	// Get the entry from the queue
-	queueID := chi.URLParam(r, "queueID")
-	entry, err := queue.GetEntry(queueID)
+	entry, err := service.getEntry(chi.URLParam(r, "queueID"))
 	if err != nil {
 		logger.Errorf("Unable to retrieve queue entry: %v", err)
-		http.Error(w, fmt.Sprintf("failed to get entry from queue: %v", err), http.StatusNotFound)
-		return
-	}
-	if entry == nil {
-		logger.Error("Unable to find queue entry")
-		http.Error(w, "queue entry not found", http.StatusNotFound)
+		replyServiceError(w, err)
 		return
 	}
 
@@ -240,8 +244,19 @@ func UploadHandler(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
-	// Save checksums here for later comparison
+	// Limit what we accept to the space left in the temporary directory,
+	// calculated once for all the objects of the request
+	limits, err := service.newObjectLimits(entry)
+	if err != nil {
+		logger.Errorf("Failed to calculate the upload limits: %v", err)
+		replyServiceError(w, err)
+		return
+	}
+
+	// Save checksums and sizes here for later comparison
 	checksums := map[string]string{}
+	sizes := map[string]int64{}
+	receipts := []common.ObjectReceipt{}
 
 	// Read all parts
 	for {
@@ -259,38 +274,15 @@ func UploadHandler(w http.ResponseWriter, r *http.Request) {
 		if part.FormName() == "file" {
 			// Receive file
 			objectName := part.FileName()
-			logger.Debugf("Receiving \"%s\"...", objectName)
-
-			// Create the destination file
-			objectPath := GetTempObjectPath(repo, objectName)
-			if _, err := os.Stat(objectPath); os.IsExist(err) {
-				msg := fmt.Sprintf("temporary file for object \"%s\" already exist", objectName)
-				logger.Errorf("Unable to complete upload: %s")
-				http.Error(w, msg, http.StatusUnprocessableEntity)
-				return
-			}
-			objectFile, err := os.Create(objectPath)
+			checksum, written, err := service.receiveObject(entry, limits, objectName, part)
 			if err != nil {
-				logger.Errorf("Unable to create %s: %v", objectName, err)
-				http.Error(w, err.Error(), http.StatusInternalServerError)
+				replyServiceError(w, err)
 				return
 			}
-			defer objectFile.Close()
-
-			// Write file and calculate checksum for a verification later
-			if _, err = io.Copy(objectFile, part); err != nil {
-				logger.Errorf("Failed to copy part to \"%s\": %v", objectName, err)
-				http.Error(w, err.Error(), http.StatusInternalServerError)
-				return
-			}
-			objectFile.Close()
-			checksum, err := common.CalculateChecksum(objectPath)
-			if err != nil {
-				logger.Errorf("Failed to calculate checksum of \"%s\": %v", objectName, err)
-				http.Error(w, err.Error(), http.StatusInternalServerError)
-				return
+			sizes[objectName] = written
+			if checksum != "" {
+				checksums[objectName] = checksum
 			}
-			checksums[objectName] = checksum
 		} else if part.FormName() == "checksum" {
 			// Read checksum calculate by the client
 			value := &bytes.Buffer{}
@@ -307,20 +299,27 @@ func UploadHandler(w http.ResponseWriter, r *http.Request) {
 			}
 			objectName := args[0]
 			checksum := args[1]
-			if objectName == "" || checksum == "" {
-				logger.Error("Failed to receive checksum: empty object name or checksum")
-				http.Error(w, "empty object name or checksum", http.StatusUnprocessableEntity)
+			if err := common.ValidateObjectName(objectName); err != nil {
+				logger.Errorf("Failed to receive checksum: %v", err)
+				http.Error(w, err.Error(), http.StatusBadRequest)
 				return
 			}
+			if err := common.ValidateChecksum(checksum); err != nil {
+				logger.Errorf("Failed to receive checksum: %v", err)
+				http.Error(w, err.Error(), http.StatusBadRequest)
+				return
+			}
+
+			// Only keep the checksums of the objects that are not verified yet
+			serverChecksum := checksums[objectName]
+			delete(checksums, objectName)
 
-			// If the checksum doesn't match we remove the object and report the error,
-			// so that the next time the object will be uploaded again
-			if checksums[objectName] != checksum {
-				os.Remove(GetTempObjectPath(repo, objectName))
-				logger.Errorf("Object \"%s\" has a bad checksum (%s vs %s)", objectName, checksums[objectName], checksum)
-				http.Error(w, fmt.Sprintf("bad checksum for %s", objectName), http.StatusUnprocessableEntity)
+			receipt, err := service.acceptObject(entry, objectName, serverChecksum, checksum, sizes[objectName])
+			if err != nil {
+				replyServiceError(w, err)
 				return
 			}
+			receipts = append(receipts, *receipt)
 		} else {
 			logger.Errorf("Received unsupported form field %s", part.FormName())
 			http.Error(w, fmt.Sprintf("unsupported form field %s", part.FormName()), http.StatusUnprocessableEntity)
@@ -328,43 +327,180 @@ func UploadHandler(w http.ResponseWriter, r *http.Request) {
 		}
 	}
 
-	// Now publish the branches
-	if err = publishBranches(repo, entry); err != nil {
-		logger.Errorf("Cannot publish branches for queue entry %s: %v", queueID, err)
-		http.Error(w, err.Error(), http.StatusInternalServerError)
+	// Clients that predate the done request publish with the upload
+	if r.Header.Get(common.ProtocolHeader) == "" {
+		publishLegacyUpload(w, r, service, entry)
+		return
 	}
 
-	// Remove entry
-	if err := queue.RemoveEntry(entry); err != nil {
-		logger.Errorf("Failed to delete queue entry %s: %v", queueID, err)
-		http.Error(w, err.Error(), http.StatusInternalServerError)
+	object := common.UploadResponse{Receipts: receipts}
+	EncodeJSONReply(w, r, object)
+}
+
+// publishLegacyUpload publishes the entry right after its objects were
+// uploaded, which is what clients without the protocol header expect
+func publishLegacyUpload(w http.ResponseWriter, r *http.Request, service *ReceiveService, entry *QueueEntry) {
+	logger.Warnf("Queue %s: client sent no %s header, publishing with the upload", entry.ID, common.ProtocolHeader)
+
+	if _, err := service.CheckPublish(r.Context(), entry.ID, nil); err != nil {
+		logger.Errorf("Refusing to publish queue entry %s: %v", entry.ID, err)
+		replyServiceError(w, err)
 		return
 	}
+
+	if requiresApproval(service.Config, entry) {
+		requestApproval(w, r, service, entry)
+		return
+	}
+
+	publishEntry(w, r, service, entry)
 }
 
-func publishBranches(repo *ostree.Repo, entry *QueueEntry) error {
-	logger.Infof("Queue %s: publishing %d objects", entry.ID, len(entry.Objects))
-	for _, objectName := range entry.Objects {
-		// Create path where the object will be moved to
-		objectPath := repo.GetObjectPath(objectName)
-		path := filepath.Dir(objectPath)
-		if err := os.MkdirAll(path, 0755); err != nil {
-			return fmt.Errorf("failed to create directory \"%s\" for the objects: %v", path, err)
-		}
+// DoneHandler publishes the branches once all the objects have been uploaded
+func DoneHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+
+	// Decode request, older clients send no body
+	var req common.DoneRequest
+	if r.ContentLength != 0 {
+		err = DecodeJSONBody(w, r, &req)
+	} else {
+		err = DecodeJSONBody(w, r, nil)
+	}
+	if err != nil {
+		HandleDecodeError(w, err)
+		return
+	}
+
+	queueID := chi.URLParam(r, "queueID")
+	entry, err := service.CheckPublish(r.Context(), queueID, &req)
+	if err != nil {
+		logger.Errorf("Refusing to publish queue entry %s: %v", queueID, err)
+		replyServiceError(w, err)
+		return
+	}
 
-		// Move from the temporary location to the proper path only if it wasn't previously moved
-		if _, err := os.Stat(objectPath); os.IsNotExist(err) {
-			tempPath := GetTempObjectPath(repo, objectName)
-			if err := moveFile(tempPath, objectPath); err != nil {
-				return fmt.Errorf("unable to move \"%s\" to \"%s\": %v", tempPath, objectPath, err)
+	// Some branches need to be approved by somebody else
+	if requiresApproval(service.Config, entry) {
+		requestApproval(w, r, service, entry)
+		return
+	}
+
+	publishEntry(w, r, service, entry)
+}
+
+// publishEntry publishes the branches of the entry and replies with a receipt
+func publishEntry(w http.ResponseWriter, r *http.Request, service *ReceiveService, entry *QueueEntry) {
+	object, err := service.Publish(entry)
+	if err != nil {
+		replyServiceError(w, err)
+		return
+	}
+
+	EncodeSignedJSONReply(w, r, object)
+}
+
+// validateQueueRequest makes sure branch names, revisions and object names are valid
+func validateQueueRequest(req *common.QueueRequest) error {
+	for _, branch := range common.SortedBranches(req.Refs) {
+		if err := ostree.ValidateBranch(branch); err != nil {
+			return fmt.Errorf("invalid branch name %q: %v", redactRef(branch), err)
+		}
+		revPair := req.Refs[branch]
+		if revPair.Server != "" {
+			if err := common.ValidateChecksum(revPair.Server); err != nil {
+				return fmt.Errorf("branch \"%s\": %v", redactRef(branch), err)
 			}
 		}
+		if err := common.ValidateChecksum(revPair.Client); err != nil {
+			return fmt.Errorf("branch \"%s\": %v", redactRef(branch), err)
+		}
 	}
 
-	// Update refs
-	if err := UpdateRefs(repo, entry.UpdateRefs); err != nil {
+	for _, objectName := range req.Objects {
+		if err := common.ValidateObjectName(objectName); err != nil {
+			return err
+		}
+	}
+
+	if _, err := common.NewChecksumHasher(req.ChecksumAlgorithm); err != nil {
 		return err
 	}
 
 	return nil
 }
+
+// checkRefsAllowed returns an error if whoever authenticated the request
+// is not allowed to update any of the branches, either by the refs of the
+// token or user or by the ACL
+func checkRefsAllowed(ctx context.Context, refs map[string]common.RevisionPair) error {
+	config, _ := ctx.Value(KeyConfig).(*Config)
+	for _, branch := range common.SortedBranches(refs) {
+		if !canUpdate(ctx, branch) {
+			return fmt.Errorf("not allowed to update branch \"%s\"", redactRef(branch))
+		}
+		if config != nil {
+			if err := checkACL(ctx, config, branch, refs[branch]); err != nil {
+				return err
+			}
+		}
+	}
+
+	return nil
+}
+
+// checkRefsNotFrozen returns an error if any of the branches is frozen
+func checkRefsNotFrozen(ctx context.Context, refs map[string]common.RevisionPair) error {
+	config, ok := ctx.Value(KeyConfig).(*Config)
+	if !ok {
+		return nil
+	}
+	for _, branch := range common.SortedBranches(refs) {
+		if config.IsFrozen(branch) {
+			return fmt.Errorf("branch \"%s\" is frozen", redactRef(branch))
+		}
+	}
+
+	return nil
+}
+
+// countMissingObjects returns how many objects of the entry were not uploaded
+func countMissingObjects(repo *ostree.Repo, entry *QueueEntry) int {
+	return len(missingObjects(repo, entry))
+}
+
+// SummaryDiffHandler returns the branches whose revision changed
+// after the time passed with the "from" parameter
+func SummaryDiffHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	history, ok := ctx.Value(KeyHistory).(*History)
+	if !ok || history == nil {
+		logger.Error("Unable to retrieve history object from context")
+		http.Error(w, "history is disabled", http.StatusNotFound)
+		return
+	}
+
+	// Parse the time, either RFC 3339 or seconds since the epoch
+	since, err := ParseTime(r.URL.Query().Get("from"))
+	if err != nil {
+		http.Error(w, err.Error(), http.StatusBadRequest)
+		return
+	}
+
+	changes, err := history.ChangesSince(since)
+	if err != nil {
+		logger.Errorf("Failed to read history: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+
+	object := common.SummaryDiffResponse{Refs: changes}
+	EncodeSignedJSONReply(w, r, object)
+}
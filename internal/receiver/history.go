@@ -0,0 +1,290 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"bufio"
+	"compress/gzip"
+	"encoding/json"
+	"fmt"
+	"io"
+	"os"
+	"path/filepath"
+	"sort"
+	"strconv"
+	"strings"
+	"sync"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// How rotated history segments are compressed
+const (
+	HistoryCompressionGzip = "gzip"
+	HistoryCompressionNone = "none"
+)
+
+// Timestamp appended to the name of rotated segments, sorts lexically
+const historySegmentTimeFormat = "20060102T150405.000000000Z"
+
+// HistoryEntry records the branches updated by a publish
+type HistoryEntry struct {
+	Time    time.Time                      `json:"time"`
+	QueueID string                         `json:"id"`
+	Refs    map[string]common.RevisionPair `json:"refs"`
+	KeyID   string                         `json:"key_id,omitempty"`
+	Objects int                            `json:"objects,omitempty"`
+	Bytes   int64                          `json:"bytes,omitempty"`
+
+	// Published objects replaced because of the collision policy
+	Replaced []string `json:"replaced,omitempty"`
+}
+
+// History is an append-only log of the publishes, one JSON object per line,
+// rotated to compressed segments when it grows too large
+type History struct {
+	path        string
+	rotateSize  int64
+	maxAge      time.Duration
+	compression string
+	mutex       sync.Mutex
+}
+
+// HistoryWalkFn is a function prototype for Walk()
+type HistoryWalkFn func(entry *HistoryEntry) error
+
+// OpenHistory opens the history log at path, the file is created
+// with the first entry
+func OpenHistory(path string) *History {
+	return &History{path: path, compression: HistoryCompressionGzip}
+}
+
+// SetRotation rotates the log when it reaches rotateSize bytes, compressing
+// the segment, and removes the segments older than maxAge, zero disables them
+func (h *History) SetRotation(rotateSize int64, maxAge time.Duration, compression string) {
+	h.mutex.Lock()
+	defer h.mutex.Unlock()
+
+	h.rotateSize = rotateSize
+	h.maxAge = maxAge
+	h.compression = compression
+}
+
+// ParseTime parses a time, either RFC 3339 or seconds since the epoch
+func ParseTime(value string) (time.Time, error) {
+	t, err := time.Parse(time.RFC3339, value)
+	if err != nil {
+		seconds, err := strconv.ParseInt(value, 10, 64)
+		if err != nil {
+			return time.Time{}, fmt.Errorf("invalid time \"%s\"", value)
+		}
+		t = time.Unix(seconds, 0)
+	}
+
+	return t, nil
+}
+
+// segments returns the paths of the rotated segments, oldest first
+func (h *History) segments() ([]string, error) {
+	matches, err := filepath.Glob(h.path + ".*")
+	if err != nil {
+		return nil, err
+	}
+
+	segments := []string{}
+	for _, path := range matches {
+		timestamp := strings.TrimSuffix(strings.TrimPrefix(path, h.path+"."), ".gz")
+		if _, err := time.Parse(historySegmentTimeFormat, timestamp); err == nil {
+			segments = append(segments, path)
+		}
+	}
+	sort.Strings(segments)
+
+	return segments, nil
+}
+
+// rotate moves the log to a new segment, compressed if configured
+func (h *History) rotate() error {
+	segmentPath := h.path + "." + time.Now().UTC().Format(historySegmentTimeFormat)
+	if h.compression != HistoryCompressionGzip {
+		return os.Rename(h.path, segmentPath)
+	}
+
+	source, err := os.Open(h.path)
+	if err != nil {
+		return err
+	}
+	defer source.Close()
+
+	segmentPath += ".gz"
+	dest, err := os.OpenFile(segmentPath, os.O_WRONLY|os.O_CREATE|os.O_EXCL, 0644)
+	if err != nil {
+		return err
+	}
+	defer dest.Close()
+
+	writer := gzip.NewWriter(dest)
+	if _, err := io.Copy(writer, source); err != nil {
+		os.Remove(segmentPath)
+		return err
+	}
+	if err := writer.Close(); err != nil {
+		os.Remove(segmentPath)
+		return err
+	}
+	if err := dest.Sync(); err != nil {
+		os.Remove(segmentPath)
+		return err
+	}
+
+	return os.Remove(h.path)
+}
+
+// expire removes the segments last written before maxAge
+func (h *History) expire() error {
+	segments, err := h.segments()
+	if err != nil {
+		return err
+	}
+
+	for _, path := range segments {
+		info, err := os.Stat(path)
+		if err != nil {
+			return err
+		}
+		if time.Since(info.ModTime()) > h.maxAge {
+			logger.Debugf("Removing expired history segment %s", path)
+			if err := os.Remove(path); err != nil {
+				return err
+			}
+		}
+	}
+
+	return nil
+}
+
+// Append adds an entry to the history
+func (h *History) Append(entry *HistoryEntry) error {
+	h.mutex.Lock()
+	defer h.mutex.Unlock()
+
+	data, err := json.Marshal(entry)
+	if err != nil {
+		return err
+	}
+
+	file, err := os.OpenFile(h.path, os.O_WRONLY|os.O_CREATE|os.O_APPEND, 0644)
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+
+	if _, err := file.Write(append(data, '\n')); err != nil {
+		return err
+	}
+	if err := file.Sync(); err != nil {
+		return err
+	}
+
+	// Rotation failures are not fatal, the entry is already saved
+	if h.rotateSize > 0 {
+		if info, err := file.Stat(); err == nil && info.Size() >= h.rotateSize {
+			if err := h.rotate(); err != nil {
+				logger.Warnf("Failed to rotate history: %v", err)
+			}
+		}
+	}
+	if h.maxAge > 0 {
+		if err := h.expire(); err != nil {
+			logger.Warnf("Failed to remove expired history: %v", err)
+		}
+	}
+
+	return nil
+}
+
+// Walk walks through the history entries, oldest first, and execute walkFn for each of them
+func (h *History) Walk(walkFn HistoryWalkFn) error {
+	h.mutex.Lock()
+	defer h.mutex.Unlock()
+
+	segments, err := h.segments()
+	if err != nil {
+		return err
+	}
+
+	for _, path := range append(segments, h.path) {
+		if err := walkHistoryFile(path, walkFn); err != nil {
+			return fmt.Errorf("failed to read %s: %v", path, err)
+		}
+	}
+
+	return nil
+}
+
+// walkHistoryFile executes walkFn for each entry of a segment or the log
+func walkHistoryFile(path string, walkFn HistoryWalkFn) error {
+	file, err := os.Open(path)
+	if os.IsNotExist(err) {
+		return nil
+	} else if err != nil {
+		return err
+	}
+	defer file.Close()
+
+	var reader io.Reader = file
+	if strings.HasSuffix(path, ".gz") {
+		gzipReader, err := gzip.NewReader(file)
+		if err != nil {
+			return err
+		}
+		defer gzipReader.Close()
+		reader = gzipReader
+	}
+
+	scanner := bufio.NewScanner(reader)
+	scanner.Buffer(make([]byte, 64*1024), 10*1024*1024)
+	for scanner.Scan() {
+		var entry HistoryEntry
+		if err := json.Unmarshal(scanner.Bytes(), &entry); err != nil {
+			return err
+		}
+		if err := walkFn(&entry); err != nil {
+			return err
+		}
+	}
+
+	return scanner.Err()
+}
+
+// ChangesSince returns the branches that were updated after since
+func (h *History) ChangesSince(since time.Time) (map[string]common.RefChange, error) {
+	changes := map[string]common.RefChange{}
+
+	err := h.Walk(func(entry *HistoryEntry) error {
+		if !entry.Time.After(since) {
+			return nil
+		}
+
+		for branch, revPair := range entry.Refs {
+			change, ok := changes[branch]
+			if !ok {
+				change.From = revPair.Server
+			}
+			change.To = revPair.Client
+			change.Time = entry.Time
+			changes[branch] = change
+		}
+
+		return nil
+	})
+	if err != nil {
+		return nil, err
+	}
+
+	return changes, nil
+}
@@ -0,0 +1,74 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"encoding/csv"
+	"encoding/json"
+	"fmt"
+	"io"
+	"strconv"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+)
+
+// Formats supported by ExportHistory()
+const (
+	HistoryExportJSON = "json"
+	HistoryExportCSV  = "csv"
+)
+
+// ExportHistory writes the history entries published after since to w,
+// either as JSON lines or as CSV with a row per branch, and returns
+// how many entries were exported
+func ExportHistory(history *History, since time.Time, format string, w io.Writer) (int, error) {
+	var writeFn HistoryWalkFn
+	var csvWriter *csv.Writer
+
+	switch format {
+	case HistoryExportJSON:
+		encoder := json.NewEncoder(w)
+		writeFn = func(entry *HistoryEntry) error {
+			return encoder.Encode(entry)
+		}
+	case HistoryExportCSV:
+		csvWriter = csv.NewWriter(w)
+		if err := csvWriter.Write([]string{"time", "id", "branch", "from", "to", "key_id", "objects", "bytes"}); err != nil {
+			return 0, err
+		}
+		writeFn = func(entry *HistoryEntry) error {
+			for _, branch := range common.SortedBranches(entry.Refs) {
+				revPair := entry.Refs[branch]
+				record := []string{entry.Time.UTC().Format(time.RFC3339), entry.QueueID, branch, revPair.Server, revPair.Client, entry.KeyID, strconv.Itoa(entry.Objects), strconv.FormatInt(entry.Bytes, 10)}
+				if err := csvWriter.Write(record); err != nil {
+					return err
+				}
+			}
+			return nil
+		}
+	default:
+		return 0, fmt.Errorf("unknown export format \"%s\"", format)
+	}
+
+	count := 0
+	err := history.Walk(func(entry *HistoryEntry) error {
+		if !entry.Time.After(since) {
+			return nil
+		}
+		count++
+		return writeFn(entry)
+	})
+	if err != nil {
+		return count, err
+	}
+
+	if csvWriter != nil {
+		csvWriter.Flush()
+		return count, csvWriter.Error()
+	}
+
+	return count, nil
+}
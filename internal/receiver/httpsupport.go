@@ -5,14 +5,19 @@
 package receiver
 
 import (
+	"compress/gzip"
+	"crypto/ed25519"
 	"encoding/json"
 	"errors"
 	"fmt"
 	"io"
 	"net/http"
+	"strconv"
 	"strings"
+	"time"
 
 	"github.com/golang/gddo/httputil/header"
+	"github.com/lirios/ostree-upload/internal/common"
 	"github.com/lirios/ostree-upload/internal/logger"
 )
 
@@ -45,8 +50,25 @@ func DecodeJSONBody(w http.ResponseWriter, r *http.Request, dst interface{}) err
 		}
 	}
 
+	// Long lists of objects are sent compressed
+	switch r.Header.Get("Content-Encoding") {
+	case "", "identity":
+	case "gzip":
+		gzipReader, err := gzip.NewReader(r.Body)
+		if err != nil {
+			msg := "Request body is not gzip compressed"
+			return &MalformedRequest{Status: http.StatusBadRequest, Message: msg}
+		}
+		defer r.Body.Close()
+		r.Body = gzipReader
+	default:
+		msg := fmt.Sprintf("Content-Encoding %q is not supported", r.Header.Get("Content-Encoding"))
+		return &MalformedRequest{Status: http.StatusUnsupportedMediaType, Message: msg}
+	}
+
 	// Enforce a maximum read from the response body: a body larger
-	// than that will now result in Decode() returning a "http: request body too large" error
+	// than that will now result in Decode() returning a "http: request body too large" error,
+	// for compressed bodies the limit applies to the uncompressed size
 	r.Body = http.MaxBytesReader(w, r.Body, 10*1024*1024)
 	defer r.Body.Close()
 
@@ -118,6 +140,29 @@ func EncodeJSONReply(w http.ResponseWriter, r *http.Request, object interface{})
 	w.Write(js)
 }
 
+// EncodeSignedJSONReply encodes a JSON reply and, when the server has a
+// signing key, adds the signature of the body, the request and the nonce
+// of the client (see common.SignedReply) to the reply headers.
+// The encoding is canonical: struct fields keep their declaration order
+// and map keys are sorted by encoding/json.
+func EncodeSignedJSONReply(w http.ResponseWriter, r *http.Request, object interface{}) {
+	js, err := json.Marshal(object)
+	if err != nil {
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+
+	if key, ok := r.Context().Value(KeySigningKey).(ed25519.PrivateKey); ok && key != nil {
+		timestamp := strconv.FormatInt(time.Now().Unix(), 10)
+		payload := common.SignedReply(r.Method, r.URL.Path, r.Header.Get(common.NonceHeader), timestamp, js)
+		w.Header().Set(common.SignatureTimeHeader, timestamp)
+		w.Header().Set(common.SignatureHeader, SignPayload(key, payload))
+	}
+
+	w.Header().Set("Content-Type", "application/json")
+	w.Write(js)
+}
+
 // HandleDecodeError sends the error to the client
 func HandleDecodeError(w http.ResponseWriter, err error) {
 	var mr *MalformedRequest
@@ -0,0 +1,268 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"bytes"
+	"crypto/sha256"
+	"encoding/hex"
+	"errors"
+	"fmt"
+	"io"
+	"io/ioutil"
+	"net/http"
+	"strings"
+	"sync"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+const (
+	// How long outcomes are replayed when idempotency_window is not set
+	defaultIdempotencyWindow = time.Hour
+	// Longest idempotency key accepted
+	maxIdempotencyKeyLength = 255
+	// Largest reply body remembered, bigger replies are not replayed
+	maxIdempotentReplySize = 1024 * 1024
+	// How often expired outcomes are forgotten
+	idempotencyPurgeInterval = time.Minute
+	// Most keys remembered for a subject, and for everybody
+	maxIdempotencyKeysPerSubject = 1000
+	maxIdempotencyKeys           = 100000
+)
+
+// Headers that depend on how the reply is encoded, which is done
+// again when it is replayed
+var idempotencyEncodingHeaders = []string{"Content-Encoding", "Content-Length", "Vary"}
+
+// Headers of the request that must be the same when the key is used again,
+// Content-Type is not because the boundary of multipart bodies is random
+var idempotencyFingerprintHeaders = []string{"Content-Encoding", "Content-Range", common.ChecksumHeader}
+
+// errIdempotencyMismatch is returned when a key is used again for another request
+var errIdempotencyMismatch = errors.New("idempotency key was used for a different request")
+
+// idempotentReply is the outcome of a request with an idempotency key,
+// done is closed once the request that owns the key completes
+type idempotentReply struct {
+	done        chan struct{}
+	subject     string
+	fingerprint string
+	stored      bool
+	status      int
+	header      http.Header
+	body        []byte
+	expires     time.Time
+}
+
+// replyRecorder passes the reply through, remembering it
+type replyRecorder struct {
+	http.ResponseWriter
+	status   int
+	body     bytes.Buffer
+	overflow bool
+}
+
+// WriteHeader remembers the status
+func (r *replyRecorder) WriteHeader(status int) {
+	if r.status == 0 {
+		r.status = status
+	}
+	r.ResponseWriter.WriteHeader(status)
+}
+
+// Write remembers the body, unless it is too big
+func (r *replyRecorder) Write(data []byte) (int, error) {
+	if r.status == 0 {
+		r.status = http.StatusOK
+	}
+	if !r.overflow {
+		if r.body.Len()+len(data) > maxIdempotentReplySize {
+			r.overflow = true
+			r.body.Reset()
+		} else {
+			r.body.Write(data)
+		}
+	}
+	return r.ResponseWriter.Write(data)
+}
+
+// IdempotencyCache remembers the successful outcome of the requests with
+// an Idempotency-Key header, so that a request retried because its reply
+// was lost is answered with the same reply instead of being applied again
+type IdempotencyCache struct {
+	window    time.Duration
+	mutex     sync.Mutex
+	replies   map[string]*idempotentReply
+	keys      map[string]int
+	lastPurge time.Time
+}
+
+// NewIdempotencyCache creates a cache replaying outcomes for window
+func NewIdempotencyCache(window time.Duration) *IdempotencyCache {
+	return &IdempotencyCache{window: window, replies: map[string]*idempotentReply{}, keys: map[string]int{}, lastPurge: time.Now()}
+}
+
+// requestFingerprint identifies what a request does besides its method and
+// path: its query, the length of its body and some headers
+func requestFingerprint(r *http.Request) string {
+	h := sha256.New()
+	fmt.Fprintf(h, "%s\x00%d", r.URL.RawQuery, r.ContentLength)
+	for _, name := range idempotencyFingerprintHeaders {
+		fmt.Fprintf(h, "\x00%s", strings.Join(r.Header.Values(name), ","))
+	}
+	return hex.EncodeToString(h.Sum(nil))
+}
+
+// forget removes the outcome of key, the mutex must be locked
+func (c *IdempotencyCache) forget(key string) {
+	reply, ok := c.replies[key]
+	if !ok {
+		return
+	}
+	delete(c.replies, key)
+	c.keys[reply.subject]--
+	if c.keys[reply.subject] <= 0 {
+		delete(c.keys, reply.subject)
+	}
+}
+
+// purge forgets the expired outcomes, the mutex must be locked
+func (c *IdempotencyCache) purge(now time.Time) {
+	if now.Sub(c.lastPurge) < idempotencyPurgeInterval {
+		return
+	}
+	for key, reply := range c.replies {
+		if reply.stored && now.After(reply.expires) {
+			c.forget(key)
+		}
+	}
+	c.lastPurge = now
+}
+
+// acquire returns the outcome stored for key, or nil when the caller
+// owns the key and has to run the request, which is not remembered when
+// there are too many keys; requests with the same key in progress are
+// waited for. The error is errIdempotencyMismatch if the key was used
+// for a request with another fingerprint, or the one of the context.
+func (c *IdempotencyCache) acquire(r *http.Request, key, subject, fingerprint string) (*idempotentReply, bool, error) {
+	for {
+		c.mutex.Lock()
+		now := time.Now()
+		c.purge(now)
+		reply, ok := c.replies[key]
+		if ok && reply.stored && now.After(reply.expires) {
+			c.forget(key)
+			ok = false
+		}
+		if !ok {
+			if c.keys[subject] >= maxIdempotencyKeysPerSubject || len(c.replies) >= maxIdempotencyKeys {
+				c.mutex.Unlock()
+				return nil, false, nil
+			}
+			c.replies[key] = &idempotentReply{done: make(chan struct{}), subject: subject, fingerprint: fingerprint}
+			c.keys[subject]++
+			c.mutex.Unlock()
+			return nil, true, nil
+		}
+		c.mutex.Unlock()
+
+		if reply.fingerprint != fingerprint {
+			return nil, false, errIdempotencyMismatch
+		}
+
+		select {
+		case <-reply.done:
+		case <-r.Context().Done():
+			return nil, false, r.Context().Err()
+		}
+		if reply.stored {
+			return reply, false, nil
+		}
+
+		// The other request failed, run it again
+	}
+}
+
+// release stores the outcome of the request owning key, when it succeeded,
+// and wakes up the requests waiting for it
+func (c *IdempotencyCache) release(key string, recorder *replyRecorder) {
+	c.mutex.Lock()
+	defer c.mutex.Unlock()
+
+	reply := c.replies[key]
+	if recorder.status >= 200 && recorder.status < 300 && !recorder.overflow {
+		reply.stored = true
+		reply.status = recorder.status
+		reply.header = recorder.Header().Clone()
+		for _, name := range idempotencyEncodingHeaders {
+			reply.header.Del(name)
+		}
+		reply.body = recorder.body.Bytes()
+		reply.expires = time.Now().Add(c.window)
+	} else {
+		c.forget(key)
+	}
+	close(reply.done)
+}
+
+// Replay HTTP middleware handler answers a request whose Idempotency-Key
+// was already used by the same subject for the same method and path with
+// the reply of the first one; keys of failed requests can be used again,
+// a key used again with another query, body length or headers is refused
+func (c *IdempotencyCache) Replay(next http.Handler) http.Handler {
+	if c == nil {
+		return next
+	}
+
+	fn := func(w http.ResponseWriter, r *http.Request) {
+		value := r.Header.Get(common.IdempotencyKeyHeader)
+		if value == "" {
+			next.ServeHTTP(w, r)
+			return
+		}
+		if len(value) > maxIdempotencyKeyLength {
+			http.Error(w, "idempotency key is too long", http.StatusBadRequest)
+			return
+		}
+
+		subject := rateLimitKey(r.Context())
+		key := subject + "\x00" + r.Method + "\x00" + r.URL.Path + "\x00" + value
+		reply, owned, err := c.acquire(r, key, subject, requestFingerprint(r))
+		if err == errIdempotencyMismatch {
+			logger.Errorf("Idempotency key %s of %s %s was used for a different request", value, r.Method, r.URL.Path)
+			http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+			return
+		}
+		if err != nil {
+			return
+		}
+		if reply != nil {
+			// The client expects the body to be read, as the first time
+			logger.Debugf("Replaying %s %s with idempotency key %s", r.Method, r.URL.Path, value)
+			io.Copy(ioutil.Discard, r.Body)
+			for name, values := range reply.header {
+				w.Header()[name] = append([]string(nil), values...)
+			}
+			w.Header().Set(common.IdempotentReplayHeader, "true")
+			w.WriteHeader(reply.status)
+			w.Write(reply.body)
+			return
+		}
+
+		if !owned {
+			logger.Warnf("Too many idempotency keys, %s %s is not remembered", r.Method, r.URL.Path)
+			next.ServeHTTP(w, r)
+			return
+		}
+
+		recorder := &replyRecorder{ResponseWriter: w}
+		defer c.release(key, recorder)
+		next.ServeHTTP(recorder, r)
+	}
+	return http.HandlerFunc(fn)
+}
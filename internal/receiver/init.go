@@ -0,0 +1,97 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"errors"
+	"fmt"
+	"os"
+	"strconv"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// Keys of the repository configuration where the init options are recorded
+const (
+	repoConfigGenerateDeltas = "generate-deltas"
+	repoConfigSigningKey     = "signing-key"
+)
+
+// InitOptions contains the settings of a new repository
+type InitOptions struct {
+	// Repository mode, "archive" by default
+	Mode string
+	// Collection ID, none when empty
+	CollectionID string
+	// Generate static deltas when branches are updated
+	GenerateDeltas bool
+	// Generate a key to sign the server replies, if the configuration doesn't have one
+	GenerateSigningKey bool
+}
+
+// InitRepository creates a new repository with the options and records
+// them in the repository configuration, returning the public signing key
+// when one was generated
+func InitRepository(repoPath, configPath string, opts InitOptions) (string, error) {
+	if _, err := os.Stat(repoPath); err == nil {
+		return "", fmt.Errorf("repository \"%s\" already exists", repoPath)
+	}
+
+	mode := opts.Mode
+	if mode == "" {
+		mode = "archive"
+	}
+
+	repo, err := ostree.CreateRepoWithMode(repoPath, mode)
+	if err != nil {
+		return "", fmt.Errorf("failed to create OSTree repository: %v", err)
+	}
+
+	if opts.CollectionID != "" {
+		logger.Actionf("Setting collection ID to \"%s\"...", opts.CollectionID)
+		if err := repo.SetCollectionID(opts.CollectionID); err != nil {
+			return "", fmt.Errorf("failed to set collection ID: %v", err)
+		}
+	}
+
+	if err := repo.SetConfigValue(ostree.ConfigGroup, repoConfigGenerateDeltas, strconv.FormatBool(opts.GenerateDeltas)); err != nil {
+		return "", fmt.Errorf("failed to save repository configuration: %v", err)
+	}
+
+	if !opts.GenerateSigningKey {
+		return "", nil
+	}
+
+	config, err := CreateConfig(configPath)
+	if err != nil {
+		return "", fmt.Errorf("cannot open configuration file: %v", err)
+	}
+	if config.SigningKey != "" {
+		return "", errors.New("configuration file already has a signing key")
+	}
+
+	privateKey, publicKey, err := GenerateSigningKey()
+	if err != nil {
+		return "", fmt.Errorf("failed to generate signing key: %v", err)
+	}
+	config.SigningKey = privateKey
+	if err := config.Save(); err != nil {
+		return "", fmt.Errorf("cannot save configuration file: %v", err)
+	}
+
+	if err := repo.SetConfigValue(ostree.ConfigGroup, repoConfigSigningKey, publicKey); err != nil {
+		return "", fmt.Errorf("failed to save repository configuration: %v", err)
+	}
+
+	return publicKey, nil
+}
+
+// generateDeltasEnabled returns whether static deltas are generated
+// when branches are updated
+func generateDeltasEnabled(r *ostree.Repo) bool {
+	enabled, _ := strconv.ParseBool(r.GetConfigValue(ostree.ConfigGroup, repoConfigGenerateDeltas))
+	return enabled
+}
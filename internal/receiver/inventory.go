@@ -0,0 +1,70 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"net/http"
+	"strconv"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// False positive rate of the Bloom filter when the client doesn't ask for one
+const defaultInventoryFPRate = 0.01
+
+// InventoryHandler returns the objects the server already has, so that
+// clients can tell which objects are missing without sending their list.
+// Objects uploaded by unfinished sessions are not included, each session
+// has its own.
+// The "format" parameter is either "list" (default) or "bloom", in
+// which case "fp_rate" is the false positive rate of the filter.
+func InventoryHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	repo, ok := ctx.Value(KeyRepository).(*ostree.Repo)
+	if !ok {
+		logger.Error("Unable to retrieve repository object from context")
+		http.Error(w, "no repository found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	format := r.URL.Query().Get("format")
+	if format == "" {
+		format = common.InventoryList
+	}
+	if format != common.InventoryList && format != common.InventoryBloom {
+		http.Error(w, "invalid format", http.StatusBadRequest)
+		return
+	}
+	fpRate := defaultInventoryFPRate
+	if value := r.URL.Query().Get("fp_rate"); value != "" {
+		var err error
+		if fpRate, err = strconv.ParseFloat(value, 64); err != nil || fpRate <= 0 || fpRate >= 1 {
+			http.Error(w, "invalid false positive rate", http.StatusBadRequest)
+			return
+		}
+	}
+
+	objectNames, err := repo.ListObjects()
+	if err != nil {
+		logger.Errorf("Failed to list objects: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+
+	// Reply
+	object := common.InventoryResponse{Count: len(objectNames)}
+	if format == common.InventoryBloom {
+		object.Bloom = common.NewBloomFilter(len(objectNames), fpRate)
+		for _, objectName := range objectNames {
+			object.Bloom.Add(objectName)
+		}
+	} else {
+		object.Objects = objectNames
+	}
+	EncodeJSONReply(w, r, object)
+}
@@ -0,0 +1,358 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"bufio"
+	"encoding/json"
+	"fmt"
+	"io/ioutil"
+	"os"
+	"path/filepath"
+	"strconv"
+	"strings"
+	"sync"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// Name of the journal directory inside the OSTree repository
+const journalDirName = "tmp/ostree-upload-journal"
+
+// Suffix of the file with the size of an object uploaded in chunks
+const partialSizeSuffix = ".size"
+
+// Journal record types
+const (
+	journalSession  = "session"
+	journalObject   = "object"
+	journalApproval = "approval"
+)
+
+// journalRecord is a line of the journal
+type journalRecord struct {
+	Type      string                         `json:"type"`
+	ID        string                         `json:"id,omitempty"`
+	Refs      map[string]common.RevisionPair `json:"refs,omitempty"`
+	Objects   []string                       `json:"objects,omitempty"`
+	Created   time.Time                      `json:"created,omitempty"`
+	Name      string                         `json:"name,omitempty"`
+	Checksum  string                         `json:"checksum,omitempty"`
+	Size      int64                          `json:"size,omitempty"`
+	Checksums map[string]string              `json:"checksums,omitempty"`
+	KeyID     string                         `json:"key_id,omitempty"`
+	Force     bool                           `json:"force,omitempty"`
+	Subject   string                         `json:"subject,omitempty"`
+	Requester string                         `json:"requested_by,omitempty"`
+	Algorithm string                         `json:"checksum_algorithm,omitempty"`
+}
+
+// Journal is a write-ahead log with a file for each queue entry, recording
+// the objects received and verified and the approval requests, so that the
+// queue can be rebuilt after a crash. A nil Journal records nothing.
+type Journal struct {
+	path  string
+	mutex sync.Mutex
+}
+
+// OpenJournal opens the journal of the repository, creating the directory if needed
+func OpenJournal(r *ostree.Repo) (*Journal, error) {
+	path := filepath.Join(r.Path(), journalDirName)
+	if err := os.MkdirAll(path, 0755); err != nil {
+		return nil, err
+	}
+
+	return &Journal{path: path}, nil
+}
+
+func (j *Journal) entryPath(ID string) string {
+	return filepath.Join(j.path, ID+".json")
+}
+
+func (j *Journal) partialDir(ID string) string {
+	return filepath.Join(j.path, ID+".partial")
+}
+
+// PartialPath returns where the beginning of an object uploaded in
+// chunks is kept until all of it is received
+func (j *Journal) PartialPath(ID, objectName string) string {
+	return filepath.Join(j.partialDir(ID), objectName)
+}
+
+// partialSizePath returns where the size declared by the first chunk
+// of an object is kept, next to the partial object
+func (j *Journal) partialSizePath(ID, objectName string) string {
+	return j.PartialPath(ID, objectName) + partialSizeSuffix
+}
+
+// SetPartialSize records the size of an object declared by its first chunk
+func (j *Journal) SetPartialSize(ID, objectName string, size int64) error {
+	return ioutil.WriteFile(j.partialSizePath(ID, objectName), []byte(strconv.FormatInt(size, 10)), 0644)
+}
+
+// PartialSize returns the size of an object declared by its first chunk,
+// or false if it's unknown
+func (j *Journal) PartialSize(ID, objectName string) (int64, bool) {
+	data, err := ioutil.ReadFile(j.partialSizePath(ID, objectName))
+	if err != nil {
+		return 0, false
+	}
+	size, err := strconv.ParseInt(string(data), 10, 64)
+	if err != nil {
+		return 0, false
+	}
+
+	return size, true
+}
+
+// RemovePartialSize forgets the size of an object that was received
+func (j *Journal) RemovePartialSize(ID, objectName string) {
+	os.Remove(j.partialSizePath(ID, objectName))
+}
+
+func (j *Journal) append(ID string, record *journalRecord) error {
+	if j == nil {
+		return nil
+	}
+
+	data, err := json.Marshal(record)
+	if err != nil {
+		return err
+	}
+
+	j.mutex.Lock()
+	defer j.mutex.Unlock()
+
+	file, err := os.OpenFile(j.entryPath(ID), os.O_WRONLY|os.O_CREATE|os.O_APPEND, 0644)
+	if err != nil {
+		return err
+	}
+	defer file.Close()
+
+	if _, err := file.Write(append(data, '\n')); err != nil {
+		return err
+	}
+
+	return file.Sync()
+}
+
+// AddEntry starts the journal of a queue entry
+func (j *Journal) AddEntry(entry *QueueEntry) error {
+	record := &journalRecord{
+		Type:      journalSession,
+		ID:        entry.ID,
+		Refs:      entry.UpdateRefs,
+		Objects:   entry.Objects,
+		Created:   entry.Created,
+		Checksums: entry.Checksums,
+		KeyID:     entry.KeyID,
+		Force:     entry.Force,
+		Subject:   entry.Subject,
+		Algorithm: entry.ChecksumAlgorithm,
+	}
+	return j.append(entry.ID, record)
+}
+
+// RecordObject records that the object was received, written to disk and verified
+func (j *Journal) RecordObject(ID, objectName, checksum string, size int64) error {
+	record := &journalRecord{Type: journalObject, Name: objectName, Checksum: checksum, Size: size}
+	return j.append(ID, record)
+}
+
+// RecordApproval records that the queue entry waits for approval,
+// a nil approval records that it doesn't wait anymore
+func (j *Journal) RecordApproval(ID string, approval *Approval) error {
+	record := &journalRecord{Type: journalApproval}
+	if approval != nil {
+		record.Requester = approval.RequestedBy
+		record.Created = approval.Requested
+	}
+	return j.append(ID, record)
+}
+
+// Receipts returns the receipts of the objects received for a queue entry
+func (j *Journal) Receipts(ID string) (map[string]common.ObjectReceipt, error) {
+	if j == nil {
+		return nil, nil
+	}
+
+	j.mutex.Lock()
+	defer j.mutex.Unlock()
+
+	_, objects, _, err := j.readEntry(j.entryPath(ID))
+	if err != nil {
+		return nil, err
+	}
+
+	receipts := map[string]common.ObjectReceipt{}
+	for objectName, record := range objects {
+		receipts[objectName] = newObjectReceipt(ID, objectName, record.Checksum, record.Size)
+	}
+
+	return receipts, nil
+}
+
+// RemoveEntry removes the journal of a queue entry and its partial uploads
+func (j *Journal) RemoveEntry(ID string) error {
+	if j == nil {
+		return nil
+	}
+
+	j.mutex.Lock()
+	defer j.mutex.Unlock()
+
+	if err := os.RemoveAll(j.partialDir(ID)); err != nil {
+		return err
+	}
+	err := os.Remove(j.entryPath(ID))
+	if os.IsNotExist(err) {
+		return nil
+	}
+	return err
+}
+
+// readEntry reads the journal of a queue entry and returns the entry,
+// the records of the objects that were received and the approval request
+func (j *Journal) readEntry(path string) (*QueueEntry, map[string]*journalRecord, *Approval, error) {
+	file, err := os.Open(path)
+	if err != nil {
+		return nil, nil, nil, err
+	}
+	defer file.Close()
+
+	var entry *QueueEntry
+	var approval *Approval
+	objects := map[string]*journalRecord{}
+
+	scanner := bufio.NewScanner(file)
+	scanner.Buffer(make([]byte, 64*1024), 64*1024*1024)
+	for scanner.Scan() {
+		var record journalRecord
+		if err := json.Unmarshal(scanner.Bytes(), &record); err != nil {
+			// The last line might be incomplete if we crashed while writing it
+			logger.Warnf("Ignoring bad journal record in %s: %v", path, err)
+			continue
+		}
+
+		switch record.Type {
+		case journalSession:
+			entry = &QueueEntry{ID: record.ID, UpdateRefs: record.Refs, Objects: record.Objects, Created: record.Created, Checksums: record.Checksums, KeyID: record.KeyID, Force: record.Force, Subject: record.Subject, ChecksumAlgorithm: record.Algorithm}
+		case journalObject:
+			objects[record.Name] = &record
+		case journalApproval:
+			approval = nil
+			if record.Requester != "" {
+				approval = &Approval{RequestedBy: record.Requester, Requested: record.Created}
+			}
+		}
+	}
+	if err := scanner.Err(); err != nil {
+		return nil, nil, nil, err
+	}
+
+	if entry == nil {
+		return nil, nil, nil, fmt.Errorf("journal %s has no session record", path)
+	}
+
+	// Object names are used to build paths, never trust them
+	for _, objectName := range entry.Objects {
+		if err := common.ValidateObjectName(objectName); err != nil {
+			return nil, nil, nil, err
+		}
+	}
+	for objectName := range objects {
+		if err := common.ValidateObjectName(objectName); err != nil {
+			return nil, nil, nil, err
+		}
+	}
+
+	return entry, objects, approval, nil
+}
+
+// Recover rebuilds the queue from the journal and removes temporary objects
+// that were not completely received and verified, partial uploads are kept
+// for the sessions that are recovered
+func (j *Journal) Recover(queue *Queue, r *ostree.Repo) error {
+	infos, err := ioutil.ReadDir(j.path)
+	if err != nil {
+		return err
+	}
+
+	verified := map[string]bool{}
+	recovered := map[string]bool{}
+	for _, info := range infos {
+		if !strings.HasSuffix(info.Name(), ".json") {
+			continue
+		}
+
+		path := filepath.Join(j.path, info.Name())
+		entry, objects, approval, err := j.readEntry(path)
+		if err != nil {
+			logger.Warnf("Removing unreadable journal %s: %v", path, err)
+			os.Remove(path)
+			continue
+		}
+
+		if err := queue.AddEntry(entry); err != nil {
+			return err
+		}
+		if approval != nil {
+			queue.restoreApproval(entry.ID, approval)
+			logger.Infof("Queue %s: waiting for approval, requested by %s", entry.ID, approval.RequestedBy)
+		}
+		for objectName, record := range objects {
+			verified[filepath.Join(entry.ID, objectName)] = true
+			queue.AddWritten(entry.ID, record.Size)
+		}
+		partials, _ := ioutil.ReadDir(j.partialDir(entry.ID))
+		for _, partial := range partials {
+			if strings.HasSuffix(partial.Name(), partialSizeSuffix) {
+				continue
+			}
+			queue.AddWritten(entry.ID, partial.Size())
+		}
+		recovered[entry.ID] = true
+		logger.Infof("Queue %s: recovered with %d/%d objects received", entry.ID, len(objects), len(entry.Objects))
+	}
+
+	// Partial uploads of sessions that are gone
+	for _, info := range infos {
+		if queueID := strings.TrimSuffix(info.Name(), ".partial"); info.IsDir() && queueID != info.Name() && !recovered[queueID] {
+			logger.Debugf("Removing partial uploads of %s", queueID)
+			os.RemoveAll(filepath.Join(j.path, info.Name()))
+		}
+	}
+
+	// Anything else in the temporary directory can't be trusted
+	tempPath := filepath.Join(r.Path(), tempDirName)
+	return filepath.Walk(tempPath, func(path string, info os.FileInfo, err error) error {
+		if err != nil {
+			return err
+		}
+		name, err := filepath.Rel(tempPath, path)
+		if err != nil {
+			return err
+		}
+		if info.IsDir() {
+			// Objects of sessions that are gone
+			if path != tempPath && !recovered[name] {
+				logger.Debugf("Removing temporary objects of %s", name)
+				if err := os.RemoveAll(path); err != nil {
+					return err
+				}
+				return filepath.SkipDir
+			}
+			return nil
+		}
+		if verified[name] {
+			return nil
+		}
+		logger.Debugf("Removing unverified temporary object %s", name)
+		return os.Remove(path)
+	})
+}
@@ -0,0 +1,285 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"crypto"
+	"crypto/ecdsa"
+	"crypto/elliptic"
+	"crypto/hmac"
+	"crypto/rsa"
+	"crypto/sha256"
+	"crypto/x509"
+	"encoding/base64"
+	"encoding/json"
+	"encoding/pem"
+	"errors"
+	"fmt"
+	"io/ioutil"
+	"math/big"
+	"strings"
+	"time"
+)
+
+// Signature algorithms supported for JSON Web Tokens
+const (
+	JWTAlgorithmHS256 = "HS256"
+	JWTAlgorithmRS256 = "RS256"
+	JWTAlgorithmES256 = "ES256"
+)
+
+// How much the clocks of issuer and receiver can differ
+const jwtLeeway = time.Minute
+
+// JWTConfig validates self-contained JSON Web Tokens, signed by the
+// configured keys which are picked by the "kid" header
+type JWTConfig struct {
+	Issuer      string    `yaml:"issuer,omitempty"`
+	Audience    string    `yaml:"audience,omitempty"`
+	ScopePrefix string    `yaml:"scope_prefix,omitempty"`
+	RefsClaim   string    `yaml:"refs_claim,omitempty"`
+	Keys        []*JWTKey `yaml:"keys"`
+}
+
+// JWTKey is a key tokens can be signed with: the base64 encoded shared
+// secret for HS256, a PEM encoded public key or the path to one otherwise
+type JWTKey struct {
+	ID        string `yaml:"kid,omitempty"`
+	Algorithm string `yaml:"algorithm"`
+	Secret    string `yaml:"secret,omitempty"`
+	PublicKey string `yaml:"public_key,omitempty"`
+
+	key interface{}
+}
+
+// jwtHeader is the header of a token
+type jwtHeader struct {
+	Algorithm string `json:"alg"`
+	KeyID     string `json:"kid"`
+}
+
+// jwtClaims are the registered claims we check
+type jwtClaims struct {
+	Issuer    string          `json:"iss"`
+	Subject   string          `json:"sub"`
+	Audience  json.RawMessage `json:"aud"`
+	Expires   int64           `json:"exp"`
+	NotBefore int64           `json:"nbf"`
+	Scope     string          `json:"scope"`
+}
+
+// validate checks the configuration and loads the keys
+func (c *JWTConfig) validate() error {
+	if len(c.Keys) == 0 {
+		return errors.New("jwt requires at least one key")
+	}
+
+	// Tokens minted for other services must not be accepted
+	if c.Issuer == "" {
+		return errors.New("jwt requires issuer")
+	}
+	if c.Audience == "" {
+		return errors.New("jwt requires audience")
+	}
+
+	ids := map[string]bool{}
+	for _, key := range c.Keys {
+		if err := key.load(); err != nil {
+			return fmt.Errorf("jwt key \"%s\": %v", key.ID, err)
+		}
+		if key.ID != "" {
+			if ids[key.ID] {
+				return fmt.Errorf("jwt key \"%s\" is listed twice", key.ID)
+			}
+			ids[key.ID] = true
+		}
+	}
+
+	return nil
+}
+
+// load decodes the secret or the public key
+func (k *JWTKey) load() error {
+	if k.Algorithm == JWTAlgorithmHS256 {
+		secret, err := base64.StdEncoding.DecodeString(k.Secret)
+		if err != nil {
+			return fmt.Errorf("invalid secret: %v", err)
+		}
+		if len(secret) < sha256.Size {
+			return fmt.Errorf("secret must be at least %d bytes", sha256.Size)
+		}
+		k.key = secret
+		return nil
+	}
+
+	data := []byte(k.PublicKey)
+	if !strings.Contains(k.PublicKey, "-----BEGIN") {
+		var err error
+		if data, err = ioutil.ReadFile(k.PublicKey); err != nil {
+			return err
+		}
+	}
+	block, _ := pem.Decode(data)
+	if block == nil {
+		return errors.New("public key is not PEM encoded")
+	}
+	publicKey, err := x509.ParsePKIXPublicKey(block.Bytes)
+	if err != nil {
+		return err
+	}
+
+	switch k.Algorithm {
+	case JWTAlgorithmRS256:
+		if _, ok := publicKey.(*rsa.PublicKey); !ok {
+			return errors.New("RS256 requires an RSA public key")
+		}
+	case JWTAlgorithmES256:
+		if ecKey, ok := publicKey.(*ecdsa.PublicKey); !ok || ecKey.Curve != elliptic.P256() {
+			return errors.New("ES256 requires a P-256 public key")
+		}
+	default:
+		return fmt.Errorf("unsupported algorithm \"%s\"", k.Algorithm)
+	}
+	k.key = publicKey
+
+	return nil
+}
+
+// verify checks the signature of input with the key
+func (k *JWTKey) verify(input string, signature []byte) bool {
+	digest := sha256.Sum256([]byte(input))
+
+	switch key := k.key.(type) {
+	case []byte:
+		mac := hmac.New(sha256.New, key)
+		mac.Write([]byte(input))
+		return hmac.Equal(mac.Sum(nil), signature)
+	case *rsa.PublicKey:
+		return rsa.VerifyPKCS1v15(key, crypto.SHA256, digest[:], signature) == nil
+	case *ecdsa.PublicKey:
+		if len(signature) != 64 {
+			return false
+		}
+		r := new(big.Int).SetBytes(signature[:32])
+		s := new(big.Int).SetBytes(signature[32:])
+		return ecdsa.Verify(key, digest[:], r, s)
+	}
+
+	return false
+}
+
+// isJWT returns true if the token looks like a JSON Web Token
+func isJWT(tokenString string) bool {
+	return strings.Count(tokenString, ".") == 2
+}
+
+// decodeJWTPart decodes a base64url encoded JSON part of a token
+func decodeJWTPart(part string, v interface{}) error {
+	data, err := base64.RawURLEncoding.DecodeString(part)
+	if err != nil {
+		return err
+	}
+	return json.Unmarshal(data, v)
+}
+
+// hasAudience returns true if the audience claim, a string
+// or a list, contains audience
+func hasAudience(claim json.RawMessage, audience string) bool {
+	var audiences []string
+	if err := json.Unmarshal(claim, &audiences); err != nil {
+		var value string
+		if json.Unmarshal(claim, &value) != nil {
+			return false
+		}
+		audiences = []string{value}
+	}
+
+	for _, value := range audiences {
+		if value == audience {
+			return true
+		}
+	}
+
+	return false
+}
+
+// Verify checks the signature and the claims of the token and returns it
+// with the scopes and branches it grants
+func (c *JWTConfig) Verify(tokenString string) (*Token, error) {
+	parts := strings.Split(tokenString, ".")
+	if len(parts) != 3 {
+		return nil, errors.New("malformed token")
+	}
+
+	var header jwtHeader
+	if err := decodeJWTPart(parts[0], &header); err != nil {
+		return nil, fmt.Errorf("bad header: %v", err)
+	}
+	signature, err := base64.RawURLEncoding.DecodeString(parts[2])
+	if err != nil {
+		return nil, fmt.Errorf("bad signature: %v", err)
+	}
+
+	// The algorithm must be the one of the key, so that a public
+	// key is never used as an HMAC secret
+	verified := false
+	for _, key := range c.Keys {
+		if header.KeyID != "" && key.ID != header.KeyID {
+			continue
+		}
+		if key.Algorithm != header.Algorithm {
+			continue
+		}
+		if key.verify(parts[0]+"."+parts[1], signature) {
+			verified = true
+			break
+		}
+	}
+	if !verified {
+		return nil, fmt.Errorf("signature not valid for key \"%s\" (%s)", header.KeyID, header.Algorithm)
+	}
+
+	var claims jwtClaims
+	if err := decodeJWTPart(parts[1], &claims); err != nil {
+		return nil, fmt.Errorf("bad claims: %v", err)
+	}
+	var rawClaims map[string]json.RawMessage
+	if err := decodeJWTPart(parts[1], &rawClaims); err != nil {
+		return nil, fmt.Errorf("bad claims: %v", err)
+	}
+
+	// Tokens that never expire cannot be revoked
+	now := time.Now()
+	if claims.Expires <= 0 {
+		return nil, errors.New("token has no expiry")
+	}
+	if now.After(time.Unix(claims.Expires, 0).Add(jwtLeeway)) {
+		return nil, errors.New("token expired")
+	}
+	if claims.NotBefore > 0 && now.Add(jwtLeeway).Before(time.Unix(claims.NotBefore, 0)) {
+		return nil, errors.New("token not valid yet")
+	}
+	if claims.Issuer != c.Issuer {
+		return nil, fmt.Errorf("unexpected issuer \"%s\"", claims.Issuer)
+	}
+	if !hasAudience(claims.Audience, c.Audience) {
+		return nil, errors.New("token is not meant for us")
+	}
+
+	// Tokens without scope claim can't do anything
+	token := &Token{Token: tokenString, Subject: claims.Subject, Scopes: mapScopes(claims.Scope, c.ScopePrefix)}
+	token.NoScopes = len(token.Scopes) == 0
+	token.Expires = time.Unix(claims.Expires, 0).UTC().Format(time.RFC3339)
+
+	// Tokens without branch patterns are refused when the claim is configured
+	if c.RefsClaim != "" {
+		token.Refs = refsFromClaim(rawClaims[c.RefsClaim])
+		if len(token.Refs) == 0 {
+			return nil, fmt.Errorf("token of \"%s\" has no \"%s\" claim", claims.Subject, c.RefsClaim)
+		}
+	}
+
+	return token, nil
+}
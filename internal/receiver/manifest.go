@@ -0,0 +1,89 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"crypto/ed25519"
+	"encoding/base64"
+	"errors"
+	"fmt"
+
+	"github.com/lirios/ostree-upload/internal/common"
+)
+
+// parseManifestKeys decodes the base64 encoded ed25519 public keys
+// allowed to sign push manifests
+func parseManifestKeys(values []string) ([]ed25519.PublicKey, error) {
+	keys := []ed25519.PublicKey{}
+
+	for _, value := range values {
+		key, err := base64.StdEncoding.DecodeString(value)
+		if err != nil {
+			return nil, fmt.Errorf("bad manifest key \"%s\": %v", value, err)
+		}
+		if len(key) != ed25519.PublicKeySize {
+			return nil, fmt.Errorf("bad manifest key size %d", len(key))
+		}
+		keys = append(keys, ed25519.PublicKey(key))
+	}
+
+	return keys, nil
+}
+
+// verifyManifest makes sure the request comes with a manifest signed by
+// one of the keys, describing exactly the branches and objects requested,
+// and returns the ID of the key that signed it
+func verifyManifest(keys []ed25519.PublicKey, req *common.QueueRequest) (string, error) {
+	if req.Manifest == nil || req.ManifestSignature == "" {
+		return "", errors.New("a signed push manifest is required")
+	}
+
+	payload, err := req.Manifest.Payload()
+	if err != nil {
+		return "", err
+	}
+	signature, err := base64.StdEncoding.DecodeString(req.ManifestSignature)
+	if err != nil {
+		return "", fmt.Errorf("bad manifest signature: %v", err)
+	}
+
+	keyID := ""
+	for _, key := range keys {
+		if ed25519.Verify(key, payload, signature) {
+			keyID = common.KeyID(key)
+			break
+		}
+	}
+	if keyID == "" {
+		return "", errors.New("push manifest is not signed by a trusted key")
+	}
+
+	// The manifest must describe the request, nothing more and nothing less
+	if len(req.Manifest.Refs) != len(req.Refs) {
+		return "", errors.New("push manifest doesn't match the branches")
+	}
+	for branch, revPair := range req.Refs {
+		if signed, ok := req.Manifest.Refs[branch]; !ok || signed != revPair {
+			return "", fmt.Errorf("push manifest doesn't match branch \"%s\"", redactRef(branch))
+		}
+	}
+	if req.Manifest.ChecksumAlgorithm != req.ChecksumAlgorithm {
+		return "", errors.New("push manifest doesn't match the checksum algorithm")
+	}
+	if len(req.Manifest.Objects) != len(req.Objects) {
+		return "", errors.New("push manifest doesn't match the objects")
+	}
+	for _, objectName := range req.Objects {
+		checksum, ok := req.Manifest.Objects[objectName]
+		if !ok {
+			return "", fmt.Errorf("object %s is not in the push manifest", redact(objectName))
+		}
+		if err := common.ValidateChecksum(checksum); err != nil {
+			return "", fmt.Errorf("object %s: %v", redact(objectName), err)
+		}
+	}
+
+	return keyID, nil
+}
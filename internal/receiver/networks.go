@@ -0,0 +1,163 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"net"
+	"net/http"
+	"strings"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// parseNetworks parses a list of networks in CIDR notation,
+// a plain address is a network with just that address
+func parseNetworks(values []string) ([]*net.IPNet, error) {
+	networks := []*net.IPNet{}
+
+	for _, value := range values {
+		_, network, err := net.ParseCIDR(value)
+		if err != nil {
+			ip := net.ParseIP(value)
+			if ip == nil {
+				return nil, fmt.Errorf("invalid network \"%s\"", value)
+			}
+			bits := 8 * net.IPv4len
+			if ip.To4() == nil {
+				bits = 8 * net.IPv6len
+			}
+			network = &net.IPNet{IP: ip, Mask: net.CIDRMask(bits, bits)}
+		}
+		networks = append(networks, network)
+	}
+
+	return networks, nil
+}
+
+// containsIP returns true if one of the networks contains ip
+func containsIP(networks []*net.IPNet, ip net.IP) bool {
+	for _, network := range networks {
+		if network.Contains(ip) {
+			return true
+		}
+	}
+
+	return false
+}
+
+// allowsAddress returns true if requests from ip are accepted:
+// denied networks win over allowed ones and no allowed networks means all
+func (c *Config) allowsAddress(ip net.IP) bool {
+	if containsIP(c.deniedNetworks, ip) {
+		return false
+	}
+
+	return len(c.allowedNetworks) == 0 || containsIP(c.allowedNetworks, ip)
+}
+
+// peerIP returns the address of the peer, clients of the unix socket
+// are on the same host so they have the loopback address
+func peerIP(r *http.Request) net.IP {
+	if addr, ok := r.Context().Value(http.LocalAddrContextKey).(net.Addr); ok && addr.Network() == "unix" {
+		return net.IPv4(127, 0, 0, 1)
+	}
+
+	host, _, err := net.SplitHostPort(r.RemoteAddr)
+	if err != nil {
+		host = r.RemoteAddr
+	}
+	return net.ParseIP(host)
+}
+
+// NetworkFilter HTTP middleware handler will refuse requests coming from
+// addresses that are not allowed, it looks at the address of the peer
+// so it must be used before anything that trusts forwarding headers
+func NetworkFilter(config *Config) func(next http.Handler) http.Handler {
+	return func(next http.Handler) http.Handler {
+		fn := func(w http.ResponseWriter, r *http.Request) {
+			ip := peerIP(r)
+			if ip == nil || !config.allowsAddress(ip) {
+				logger.Errorf("Refusing request from %s", r.RemoteAddr)
+				http.Error(w, http.StatusText(http.StatusForbidden), http.StatusForbidden)
+				return
+			}
+
+			next.ServeHTTP(w, r)
+		}
+		return http.HandlerFunc(fn)
+	}
+}
+
+// forwardedAddresses returns the addresses of the chain of clients and
+// proxies a request went through, from the Forwarded header or the
+// X-Forwarded-For and X-Real-IP headers, the client first
+func forwardedAddresses(r *http.Request) []string {
+	addresses := []string{}
+
+	for _, header := range r.Header.Values("Forwarded") {
+		for _, element := range strings.Split(header, ",") {
+			for _, pair := range strings.Split(element, ";") {
+				pair = strings.TrimSpace(pair)
+				if len(pair) > 4 && strings.EqualFold(pair[:4], "for=") {
+					addresses = append(addresses, pair[4:])
+				}
+			}
+		}
+	}
+	if len(addresses) > 0 {
+		return addresses
+	}
+
+	for _, header := range r.Header.Values("X-Forwarded-For") {
+		addresses = append(addresses, strings.Split(header, ",")...)
+	}
+	if len(addresses) == 0 && r.Header.Get("X-Real-IP") != "" {
+		addresses = append(addresses, r.Header.Get("X-Real-IP"))
+	}
+
+	return addresses
+}
+
+// parseForwardedAddress parses an address of the forwarding headers, which
+// can be quoted and have a port, returning nil for obfuscated identifiers
+func parseForwardedAddress(value string) net.IP {
+	value = strings.Trim(strings.TrimSpace(value), "\"")
+	if host, _, err := net.SplitHostPort(value); err == nil {
+		value = host
+	}
+	return net.ParseIP(strings.Trim(value, "[]"))
+}
+
+// ForwardedFor HTTP middleware handler replaces the address of the peer
+// with the one of the client, when the peer is a trusted proxy: the client
+// is the last address of the forwarding headers that is not a trusted proxy,
+// because whatever comes before it could have been forged by the client
+func ForwardedFor(config *Config) func(next http.Handler) http.Handler {
+	return func(next http.Handler) http.Handler {
+		fn := func(w http.ResponseWriter, r *http.Request) {
+			ip := peerIP(r)
+			if ip == nil || !containsIP(config.trustedProxies, ip) {
+				next.ServeHTTP(w, r)
+				return
+			}
+
+			addresses := forwardedAddresses(r)
+			for i := len(addresses) - 1; i >= 0; i-- {
+				client := parseForwardedAddress(addresses[i])
+				if client == nil {
+					break
+				}
+				r.RemoteAddr = client.String()
+				if !containsIP(config.trustedProxies, client) {
+					break
+				}
+			}
+
+			next.ServeHTTP(w, r)
+		}
+		return http.HandlerFunc(fn)
+	}
+}
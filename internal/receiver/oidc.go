@@ -0,0 +1,273 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"encoding/json"
+	"fmt"
+	"io/ioutil"
+	"net/http"
+	"net/url"
+	"strings"
+	"sync"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// How long the result of an introspection is reused
+const oidcCacheDuration = time.Minute
+
+// How many introspection results are kept, others are not cached
+const oidcCacheSize = 10000
+
+// OIDCConfig configures the validation of bearer tokens with the
+// introspection endpoint (RFC 7662) of an OpenID Connect provider
+type OIDCConfig struct {
+	Issuer           string `yaml:"issuer"`
+	IntrospectionURL string `yaml:"introspection_url,omitempty"`
+	ClientID         string `yaml:"client_id"`
+	ClientSecret     string `yaml:"client_secret"`
+	Audience         string `yaml:"audience,omitempty"`
+	ScopePrefix      string `yaml:"scope_prefix,omitempty"`
+	RefsClaim        string `yaml:"refs_claim,omitempty"`
+}
+
+// introspectionResponse is the reply of the introspection endpoint,
+// the claim with the branch patterns is read separately
+type introspectionResponse struct {
+	Active  bool   `json:"active"`
+	Subject string `json:"sub"`
+	Scope   string `json:"scope"`
+	Expires int64  `json:"exp"`
+}
+
+// oidcCacheEntry is a token validated recently
+type oidcCacheEntry struct {
+	token   *Token
+	checked time.Time
+}
+
+// OIDCVerifier validates bearer tokens with the OpenID Connect provider
+type OIDCVerifier struct {
+	config     *OIDCConfig
+	httpClient *http.Client
+
+	mutex            sync.Mutex
+	introspectionURL string
+	cache            map[string]oidcCacheEntry
+	purged           time.Time
+}
+
+// NewOIDCVerifier creates a verifier for the provider
+func NewOIDCVerifier(config *OIDCConfig) *OIDCVerifier {
+	return &OIDCVerifier{
+		config:           config,
+		httpClient:       &http.Client{Timeout: 10 * time.Second},
+		introspectionURL: config.IntrospectionURL,
+		cache:            map[string]oidcCacheEntry{},
+	}
+}
+
+// endpoint returns the introspection endpoint, discovering it
+// from the issuer the first time if not configured
+func (v *OIDCVerifier) endpoint() (string, error) {
+	v.mutex.Lock()
+	defer v.mutex.Unlock()
+
+	if v.introspectionURL != "" {
+		return v.introspectionURL, nil
+	}
+
+	discoveryURL := strings.TrimSuffix(v.config.Issuer, "/") + "/.well-known/openid-configuration"
+	response, err := v.httpClient.Get(discoveryURL)
+	if err != nil {
+		return "", err
+	}
+	defer response.Body.Close()
+	if response.StatusCode != http.StatusOK {
+		return "", fmt.Errorf("discovery failed with status %d", response.StatusCode)
+	}
+
+	var discovery struct {
+		IntrospectionEndpoint string `json:"introspection_endpoint"`
+	}
+	if err := json.NewDecoder(response.Body).Decode(&discovery); err != nil {
+		return "", err
+	}
+	if discovery.IntrospectionEndpoint == "" {
+		return "", fmt.Errorf("provider %s has no introspection endpoint", v.config.Issuer)
+	}
+
+	v.introspectionURL = discovery.IntrospectionEndpoint
+	return v.introspectionURL, nil
+}
+
+// Verify returns the token with the scopes and branches granted by the
+// provider, or nil if the provider says it's not active
+func (v *OIDCVerifier) Verify(tokenString string) (*Token, error) {
+	// Reuse recent results
+	v.mutex.Lock()
+	entry, ok := v.cache[tokenString]
+	v.mutex.Unlock()
+	if ok && time.Since(entry.checked) <= oidcCacheDuration {
+		return entry.token, nil
+	}
+
+	endpoint, err := v.endpoint()
+	if err != nil {
+		return nil, fmt.Errorf("cannot find introspection endpoint: %v", err)
+	}
+
+	form := url.Values{}
+	form.Set("token", tokenString)
+	form.Set("token_type_hint", "access_token")
+	request, err := http.NewRequest("POST", endpoint, strings.NewReader(form.Encode()))
+	if err != nil {
+		return nil, err
+	}
+	request.Header.Set("Content-Type", "application/x-www-form-urlencoded")
+	request.Header.Set("Accept", "application/json")
+	request.SetBasicAuth(v.config.ClientID, v.config.ClientSecret)
+
+	response, err := v.httpClient.Do(request)
+	if err != nil {
+		return nil, err
+	}
+	defer response.Body.Close()
+	if response.StatusCode != http.StatusOK {
+		return nil, fmt.Errorf("introspection failed with status %d", response.StatusCode)
+	}
+
+	data, err := ioutil.ReadAll(response.Body)
+	if err != nil {
+		return nil, err
+	}
+	var result introspectionResponse
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, err
+	}
+	var claims map[string]json.RawMessage
+	if err := json.Unmarshal(data, &claims); err != nil {
+		return nil, err
+	}
+
+	var token *Token
+	if result.Active {
+		token = v.mapClaims(tokenString, &result, claims)
+	}
+
+	v.remember(tokenString, token)
+
+	return token, nil
+}
+
+// remember caches the result of an introspection, expired results are
+// purged at most once per cache duration and nothing is added when full
+func (v *OIDCVerifier) remember(tokenString string, token *Token) {
+	v.mutex.Lock()
+	defer v.mutex.Unlock()
+
+	now := time.Now()
+	if now.Sub(v.purged) > oidcCacheDuration || len(v.cache) >= oidcCacheSize {
+		for key, entry := range v.cache {
+			if now.Sub(entry.checked) > oidcCacheDuration {
+				delete(v.cache, key)
+			}
+		}
+		v.purged = now
+	}
+	if len(v.cache) >= oidcCacheSize {
+		return
+	}
+
+	v.cache[tokenString] = oidcCacheEntry{token: token, checked: now}
+}
+
+// mapClaims converts the claims of an active token to a Token,
+// or returns nil if the claims don't grant anything
+func (v *OIDCVerifier) mapClaims(tokenString string, result *introspectionResponse, claims map[string]json.RawMessage) *Token {
+	// Tokens issued to other clients are not for us
+	if !v.forUs(claims) {
+		logger.Warnf("Token of \"%s\" was not issued for this service", result.Subject)
+		return nil
+	}
+
+	token := &Token{Token: tokenString, Subject: result.Subject}
+	if result.Expires > 0 {
+		token.Expires = time.Unix(result.Expires, 0).UTC().Format(time.RFC3339)
+	}
+
+	// A token without any of our scopes, or without a scope claim at all,
+	// can't do anything, while no scopes would allow it to do everything
+	token.Scopes = mapScopes(result.Scope, v.config.ScopePrefix)
+	token.NoScopes = len(token.Scopes) == 0
+
+	// Tokens without branch patterns are refused when the claim is configured
+	if v.config.RefsClaim != "" {
+		raw, ok := claims[v.config.RefsClaim]
+		if !ok {
+			logger.Warnf("Token of \"%s\" has no \"%s\" claim", result.Subject, v.config.RefsClaim)
+			return nil
+		}
+		token.Refs = refsFromClaim(raw)
+		if len(token.Refs) == 0 {
+			logger.Warnf("Token of \"%s\" has an empty \"%s\" claim", result.Subject, v.config.RefsClaim)
+			return nil
+		}
+	}
+
+	return token
+}
+
+// forUs returns true if the audience of the token is the configured
+// audience or, without one, if the token was issued to our client
+func (v *OIDCVerifier) forUs(claims map[string]json.RawMessage) bool {
+	if v.config.Audience != "" {
+		return hasAudience(claims["aud"], v.config.Audience)
+	}
+
+	if hasAudience(claims["aud"], v.config.ClientID) {
+		return true
+	}
+	var clientID string
+	if err := json.Unmarshal(claims["client_id"], &clientID); err != nil {
+		return false
+	}
+	return clientID == v.config.ClientID
+}
+
+// mapScopes converts a space separated scope claim to our scopes,
+// only those starting with prefix are considered
+func mapScopes(claim, prefix string) []string {
+	var scopes []string
+	for _, scope := range strings.Fields(claim) {
+		if prefix != "" {
+			if !strings.HasPrefix(scope, prefix) {
+				continue
+			}
+			scope = strings.TrimPrefix(scope, prefix)
+		}
+		if ValidateScopes([]string{scope}) == nil {
+			scopes = append(scopes, scope)
+		}
+	}
+
+	return scopes
+}
+
+// refsFromClaim returns the branch patterns of a claim that is
+// either a list or a space separated string
+func refsFromClaim(raw json.RawMessage) []string {
+	var refs []string
+	if err := json.Unmarshal(raw, &refs); err != nil {
+		var value string
+		if json.Unmarshal(raw, &value) == nil {
+			refs = strings.Fields(value)
+		}
+	}
+
+	return refs
+}
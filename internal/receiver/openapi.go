@@ -0,0 +1,345 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"encoding/json"
+	"net/http"
+	"reflect"
+	"regexp"
+	"sort"
+	"strconv"
+	"strings"
+	"sync"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+)
+
+// apiParameter is a query or header parameter of an operation
+type apiParameter struct {
+	in          string
+	name        string
+	description string
+	required    bool
+}
+
+// apiOperation describes a route of v1Router for the OpenAPI document:
+// request and response are values of the JSON types of the bodies, nil
+// when there is none, and body is the content type of other bodies
+type apiOperation struct {
+	method      string
+	path        string
+	scope       string
+	summary     string
+	parameters  []apiParameter
+	request     interface{}
+	body        string
+	response    interface{}
+	status      int
+	idempotent  bool
+	description string
+}
+
+// Parameters shared by several operations
+var (
+	chunkRangeParameter    = apiParameter{"header", "Content-Range", "Bytes of the object in the chunk, as \"bytes <FIRST>-<LAST>/<SIZE>\"", true}
+	chunkChecksumParameter = apiParameter{"header", common.ChecksumHeader, "Checksum of the object calculated by the client, required with the last chunk", false}
+	ifNoneMatchParameter   = apiParameter{"header", "If-None-Match", "ETag of a previous reply, the server replies 304 if nothing changed", false}
+)
+
+// apiOperations lists the routes of the API, keep it in sync with v1Router
+var apiOperations = []apiOperation{
+	{method: "GET", path: "/token", summary: "Describe the credentials of the request", response: common.TokenInfoResponse{}},
+	{method: "GET", path: "/info", scope: ScopeRead, summary: "Describe the repository and the features of the server", parameters: []apiParameter{ifNoneMatchParameter}, response: common.InfoResponse{}},
+	{method: "POST", path: "/preflight", scope: ScopeRead, summary: "Check whether a push would be accepted", request: common.PreflightRequest{}, response: common.PreflightResponse{}},
+	{method: "GET", path: "/summary/diff", scope: ScopeRead, summary: "List the branches changed since a time", parameters: []apiParameter{{"query", "from", "RFC 3339 time or Unix timestamp", false}}, response: common.SummaryDiffResponse{}},
+	{method: "GET", path: "/push-socket", scope: ScopeRead, summary: "Serve the API over a WebSocket", status: http.StatusSwitchingProtocols,
+		description: "Each request is sent as a SocketRequest message followed by the body, and each reply as a SocketResponse message followed by the body."},
+	{method: "POST", path: "/queue", scope: ScopeUpload, summary: "Start a session", request: common.QueueRequest{}, response: common.UpdateResponse{}},
+	{method: "DELETE", path: "/queue/{queueID}", scope: ScopeUpload, summary: "Remove a session"},
+	{method: "DELETE", path: "/session/{queueID}", scope: ScopeUpload, summary: "Abort a session, removing its staged objects"},
+	{method: "GET", path: "/queue/{queueID}", scope: ScopeUpload, summary: "List the objects of the session still missing", response: common.ObjectsResponse{}},
+	{method: "POST", path: "/queue/{queueID}/missing_objects", scope: ScopeUpload, summary: "Tell which objects of the list are missing", request: common.ObjectsRequest{}, response: common.ObjectsResponse{},
+		description: "The body can be gzip compressed with Content-Encoding. When uploads are redirected, the reply tells where to upload each object."},
+	{method: "GET", path: "/objects", scope: ScopeUpload, summary: "List the objects of the server", response: common.InventoryResponse{},
+		parameters: []apiParameter{{"query", "format", "\"list\" (default) or \"bloom\"", false}, {"query", "fp_rate", "False positive rate of the Bloom filter", false}}},
+	{method: "PUT", path: "/queue/{queueID}", scope: ScopeUpload, summary: "Upload objects", body: "multipart/form-data", response: common.UploadResponse{}, idempotent: true,
+		description: "Each \"file\" part, named after the object, is followed by a \"checksum\" part with \"<OBJECT>:<CHECKSUM>\"."},
+	{method: "PUT", path: "/queue/{queueID}/upload_pack", scope: ScopeUpload, summary: "Upload objects as a tar stream", body: "application/x-tar", response: common.UploadResponse{}, idempotent: true,
+		description: "Each entry is named after the object, the last one is " + common.PackIndexName + " with the PackIndex of the checksums."},
+	{method: "POST", path: "/queue/{queueID}/fetch", scope: ScopeUpload, summary: "Fetch objects uploaded to their targets", request: common.FetchRequest{}, response: common.UploadResponse{}, idempotent: true},
+	{method: "PUT", path: "/queue/{queueID}/delta", scope: ScopeUpload, summary: "Upload a static delta", body: "application/octet-stream", response: common.ObjectsResponse{},
+		parameters: []apiParameter{{"query", "from", "Published revision of the branch", true}, {"query", "to", "New revision of the branch", true}}},
+	{method: "GET", path: "/queue/{queueID}/objects/{objectName}", scope: ScopeUpload, summary: "Tell how much of an object uploaded in chunks was received", response: common.ChunkResponse{}},
+	{method: "PUT", path: "/queue/{queueID}/objects/{objectName}", scope: ScopeUpload, summary: "Upload a chunk of an object", body: "application/octet-stream", response: common.ChunkResponse{}, idempotent: true,
+		parameters: []apiParameter{chunkRangeParameter, chunkChecksumParameter}},
+	{method: "POST", path: "/queue/{queueID}/done", scope: ScopePublish, summary: "Publish the branches of the session", request: common.DoneRequest{}, response: common.DoneResponse{}, idempotent: true},
+	{method: "POST", path: "/queue/{queueID}/approve", scope: ScopePublish, summary: "Approve the publish of a session", response: common.DoneResponse{}},
+	{method: "GET", path: "/status", scope: ScopeAdmin, summary: "Report the disk usage and the sessions", response: common.StatusResponse{}},
+	{method: "GET", path: "/sessions", scope: ScopeAdmin, summary: "List the sessions", response: common.SessionsResponse{}},
+	{method: "DELETE", path: "/sessions/{sessionID}", scope: ScopeAdmin, summary: "Cancel a session"},
+	{method: "GET", path: "/admin/frozen", scope: ScopeAdmin, summary: "List the frozen branches", response: common.FrozenResponse{}},
+	{method: "POST", path: "/admin/frozen", scope: ScopeAdmin, summary: "Freeze or thaw branches", request: common.FreezeRequest{}, response: common.FrozenResponse{}},
+	{method: "POST", path: "/admin/revoke", scope: ScopeAdmin, summary: "Revoke the tokens of a subject", request: common.RevokeRequest{}, response: common.RevokeResponse{}},
+	{method: "GET", path: "/admin/jobs", scope: ScopeAdmin, summary: "List the maintenance jobs", response: common.JobsResponse{}},
+	{method: "POST", path: "/admin/jobs/{kind}", scope: ScopeAdmin, summary: "Start a maintenance job", response: common.JobInfo{}},
+	{method: "GET", path: "/admin/history", scope: ScopeAdmin, summary: "List the last publishes", response: common.HistoryResponse{},
+		parameters: []apiParameter{{"query", "since", "Only the publishes after this time", false}, {"query", "limit", "Maximum number of publishes", false}}},
+}
+
+// Types of the messages of the push socket and of the index of upload
+// packs, which are not the body of a route
+var apiMessages = []interface{}{common.SocketRequest{}, common.SocketResponse{}, common.PackIndex{}}
+
+var pathParameterRegexp = regexp.MustCompile(`\{([^}]+)\}`)
+
+// schemaBuilder turns the Go types of the API into JSON schemas, named
+// structs are added to the components and referenced
+type schemaBuilder struct {
+	schemas map[string]interface{}
+}
+
+// schema returns the JSON schema of values of type t encoded by encoding/json
+func (b *schemaBuilder) schema(t reflect.Type) map[string]interface{} {
+	if t == reflect.TypeOf(time.Time{}) {
+		return map[string]interface{}{"type": "string", "format": "date-time"}
+	}
+
+	switch t.Kind() {
+	case reflect.Ptr:
+		return b.schema(t.Elem())
+	case reflect.Bool:
+		return map[string]interface{}{"type": "boolean"}
+	case reflect.Int, reflect.Int8, reflect.Int16, reflect.Int32, reflect.Int64:
+		return map[string]interface{}{"type": "integer", "format": "int64"}
+	case reflect.Uint, reflect.Uint8, reflect.Uint16, reflect.Uint32, reflect.Uint64:
+		return map[string]interface{}{"type": "integer", "format": "int64", "minimum": 0}
+	case reflect.Float32, reflect.Float64:
+		return map[string]interface{}{"type": "number"}
+	case reflect.String:
+		return map[string]interface{}{"type": "string"}
+	case reflect.Slice, reflect.Array:
+		if t.Elem().Kind() == reflect.Uint8 {
+			return map[string]interface{}{"type": "string", "format": "byte"}
+		}
+		return map[string]interface{}{"type": "array", "items": b.schema(t.Elem())}
+	case reflect.Map:
+		return map[string]interface{}{"type": "object", "additionalProperties": b.schema(t.Elem())}
+	case reflect.Struct:
+		if t.Name() == "" {
+			return b.object(t)
+		}
+		if _, ok := b.schemas[t.Name()]; !ok {
+			// Reserve the name first, types can refer to themselves
+			b.schemas[t.Name()] = nil
+			b.schemas[t.Name()] = b.object(t)
+		}
+		return map[string]interface{}{"$ref": "#/components/schemas/" + t.Name()}
+	}
+
+	return map[string]interface{}{}
+}
+
+// object returns the JSON schema of a struct, fields without omitempty
+// are always encoded so they are required
+func (b *schemaBuilder) object(t reflect.Type) map[string]interface{} {
+	properties := map[string]interface{}{}
+	required := []string{}
+	b.fields(t, properties, &required)
+
+	schema := map[string]interface{}{"type": "object", "properties": properties}
+	if len(required) > 0 {
+		sort.Strings(required)
+		schema["required"] = required
+	}
+	return schema
+}
+
+// fields adds the fields of a struct to properties, the fields of
+// embedded structs included
+func (b *schemaBuilder) fields(t reflect.Type, properties map[string]interface{}, required *[]string) {
+	for i := 0; i < t.NumField(); i++ {
+		field := t.Field(i)
+		tag := field.Tag.Get("json")
+		if tag == "-" {
+			continue
+		}
+		options := strings.Split(tag, ",")
+		if field.Anonymous && options[0] == "" && field.Type.Kind() == reflect.Struct {
+			b.fields(field.Type, properties, required)
+			continue
+		}
+		if field.PkgPath != "" {
+			continue
+		}
+
+		name := options[0]
+		if name == "" {
+			name = field.Name
+		}
+		properties[name] = b.schema(field.Type)
+
+		omitEmpty := false
+		for _, option := range options[1:] {
+			if option == "omitempty" {
+				omitEmpty = true
+			}
+		}
+		if !omitEmpty {
+			*required = append(*required, name)
+		}
+	}
+}
+
+// content returns the content of a request or reply with a JSON body
+func (b *schemaBuilder) content(value interface{}) map[string]interface{} {
+	return map[string]interface{}{
+		"application/json": map[string]interface{}{"schema": b.schema(reflect.TypeOf(value))},
+	}
+}
+
+// operation returns the OpenAPI operation object of op
+func (b *schemaBuilder) operation(op apiOperation) map[string]interface{} {
+	parameters := []interface{}{}
+	for _, match := range pathParameterRegexp.FindAllStringSubmatch(op.path, -1) {
+		parameters = append(parameters, map[string]interface{}{
+			"in":       "path",
+			"name":     match[1],
+			"required": true,
+			"schema":   map[string]interface{}{"type": "string"},
+		})
+	}
+	all := op.parameters
+	if op.idempotent {
+		all = append(all, apiParameter{"header", common.IdempotencyKeyHeader, "Retrying with the same key replays the reply of the first request", false})
+	}
+	for _, parameter := range all {
+		parameters = append(parameters, map[string]interface{}{
+			"in":          parameter.in,
+			"name":        parameter.name,
+			"description": parameter.description,
+			"required":    parameter.required,
+			"schema":      map[string]interface{}{"type": "string"},
+		})
+	}
+
+	status := op.status
+	if status == 0 {
+		status = http.StatusOK
+	}
+	reply := map[string]interface{}{"description": http.StatusText(status)}
+	if op.response != nil {
+		reply["content"] = b.content(op.response)
+	}
+
+	operation := map[string]interface{}{
+		"operationId": operationID(op),
+		"summary":     op.summary,
+		"responses": map[string]interface{}{
+			strconv.Itoa(status): reply,
+			"default":            map[string]interface{}{"$ref": "#/components/responses/Error"},
+		},
+	}
+	description := op.description
+	if op.scope != "" {
+		description = strings.TrimSpace("Requires the \"" + op.scope + "\" scope. " + description)
+	}
+	if description != "" {
+		operation["description"] = description
+	}
+	if len(parameters) > 0 {
+		operation["parameters"] = parameters
+	}
+	if op.request != nil {
+		operation["requestBody"] = map[string]interface{}{"required": true, "content": b.content(op.request)}
+	} else if op.body != "" {
+		operation["requestBody"] = map[string]interface{}{
+			"required": true,
+			"content": map[string]interface{}{
+				op.body: map[string]interface{}{"schema": map[string]interface{}{"type": "string", "format": "binary"}},
+			},
+		}
+	}
+
+	return operation
+}
+
+// operationID names an operation after its method and path,
+// such as "postQueueQueueIDDone"
+func operationID(op apiOperation) string {
+	id := strings.ToLower(op.method)
+	for _, word := range strings.FieldsFunc(op.path, func(r rune) bool {
+		return r == '/' || r == '{' || r == '}' || r == '_' || r == '-'
+	}) {
+		id += strings.ToUpper(word[:1]) + word[1:]
+	}
+	return id
+}
+
+// OpenAPIDocument describes the API in OpenAPI 3 format
+func OpenAPIDocument() map[string]interface{} {
+	b := &schemaBuilder{schemas: map[string]interface{}{}}
+
+	paths := map[string]interface{}{}
+	for _, op := range apiOperations {
+		item, ok := paths[op.path].(map[string]interface{})
+		if !ok {
+			item = map[string]interface{}{}
+			paths[op.path] = item
+		}
+		item[strings.ToLower(op.method)] = b.operation(op)
+	}
+	for _, message := range apiMessages {
+		b.schema(reflect.TypeOf(message))
+	}
+
+	return map[string]interface{}{
+		"openapi": "3.0.3",
+		"info": map[string]interface{}{
+			"title":              "ostree-upload",
+			"description":        "Push OSTree commits to a remote repository.",
+			"version":            common.Version,
+			"x-protocol-version": common.ProtocolVersion,
+		},
+		"servers": []interface{}{map[string]interface{}{"url": "/api/v1"}},
+		"paths":   paths,
+		"components": map[string]interface{}{
+			"schemas": b.schemas,
+			"responses": map[string]interface{}{
+				"Error": map[string]interface{}{
+					"description": "The request failed, the body is the error message",
+					"content": map[string]interface{}{
+						"text/plain": map[string]interface{}{"schema": map[string]interface{}{"type": "string"}},
+					},
+				},
+			},
+			"securitySchemes": map[string]interface{}{
+				"token": map[string]interface{}{"type": "http", "scheme": "bearer"},
+				"user":  map[string]interface{}{"type": "http", "scheme": "basic"},
+			},
+		},
+		"security": []interface{}{
+			map[string]interface{}{"token": []string{}},
+			map[string]interface{}{"user": []string{}},
+		},
+	}
+}
+
+var (
+	openAPIOnce sync.Once
+	openAPIJSON []byte
+)
+
+// OpenAPIHandler serves the OpenAPI document of the API, so that
+// clients can be written without reading the source
+func OpenAPIHandler(w http.ResponseWriter, r *http.Request) {
+	openAPIOnce.Do(func() {
+		openAPIJSON, _ = json.Marshal(OpenAPIDocument())
+	})
+
+	w.Header().Set("Content-Type", "application/json")
+	w.Write(openAPIJSON)
+}
@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"archive/tar"
+	"encoding/json"
+	"io"
+	"net/http"
+
+	"github.com/go-chi/chi"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// Biggest index accepted at the end of an upload pack
+const maxPackIndexSize = 10 * 1024 * 1024
+
+// UploadPackHandler receives the objects of a queue entry as a single tar
+// stream, so that a whole push is one request: each entry is an object
+// named after it, the last one is common.PackIndexName with the checksums
+// calculated by the client, which are then verified like those of
+// UploadHandler
+func UploadPackHandler(w http.ResponseWriter, r *http.Request) {
+	defer r.Body.Close()
+
+	// Get from context
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+
+	// Get the entry from the queue
+	entry, err := service.getEntry(chi.URLParam(r, "queueID"))
+	if err != nil {
+		logger.Errorf("Unable to retrieve queue entry: %v", err)
+		replyServiceError(w, err)
+		return
+	}
+
+	receipts, err := service.receivePack(entry, r.Body)
+	if err != nil {
+		replyServiceError(w, err)
+		return
+	}
+
+	object := common.UploadResponse{Receipts: receipts}
+	EncodeJSONReply(w, r, object)
+}
+
+// receivePack receives the objects of the entry from an upload pack read
+// from reader and returns their receipts
+func (s *ReceiveService) receivePack(entry *QueueEntry, reader io.Reader) ([]common.ObjectReceipt, error) {
+	limits, err := s.newObjectLimits(entry)
+	if err != nil {
+		logger.Errorf("Failed to calculate the upload limits: %v", err)
+		return nil, err
+	}
+
+	// Objects are verified as they arrive, the checksums of the client come last
+	checksums := map[string]string{}
+	sizes := map[string]int64{}
+	objectNames := []string{}
+	var index *common.PackIndex
+
+	tr := tar.NewReader(reader)
+	for {
+		header, err := tr.Next()
+		if err == io.EOF {
+			break
+		}
+		if err != nil {
+			logger.Errorf("Error reading upload pack: %v", err)
+			return nil, &ServiceError{Kind: ErrorInvalid, Err: err}
+		}
+		if index != nil {
+			return nil, newServiceError(ErrorInvalid, "the index must be the last entry of the pack")
+		}
+		if header.Typeflag != tar.TypeReg {
+			return nil, newServiceError(ErrorInvalid, "unsupported entry \"%s\" in the pack", header.Name)
+		}
+
+		if header.Name == common.PackIndexName {
+			index = &common.PackIndex{}
+			if err := json.NewDecoder(io.LimitReader(tr, maxPackIndexSize)).Decode(index); err != nil {
+				return nil, newServiceError(ErrorInvalid, "invalid pack index: %v", err)
+			}
+			continue
+		}
+
+		if _, ok := sizes[header.Name]; ok {
+			return nil, newServiceError(ErrorInvalid, "object \"%s\" is twice in the pack", redact(header.Name))
+		}
+		checksum, written, err := s.receiveObject(entry, limits, header.Name, tr)
+		if err != nil {
+			return nil, err
+		}
+		sizes[header.Name] = written
+		checksums[header.Name] = checksum
+		objectNames = append(objectNames, header.Name)
+	}
+	if index == nil {
+		return nil, newServiceError(ErrorInvalid, "the pack has no index")
+	}
+
+	receipts := []common.ObjectReceipt{}
+	for _, objectName := range objectNames {
+		checksum, ok := index.Checksums[objectName]
+		if !ok {
+			return nil, newServiceError(ErrorUnprocessable, "the pack index has no checksum for \"%s\"", redact(objectName))
+		}
+		if err := common.ValidateChecksum(checksum); err != nil {
+			return nil, &ServiceError{Kind: ErrorInvalid, Err: err}
+		}
+		receipt, err := s.acceptObject(entry, objectName, checksums[objectName], checksum, sizes[objectName])
+		if err != nil {
+			return nil, err
+		}
+		receipts = append(receipts, *receipt)
+	}
+	logger.Debugf("Received %d objects in a pack for queue entry %s", len(receipts), entry.ID)
+
+	return receipts, nil
+}
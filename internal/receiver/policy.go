@@ -0,0 +1,218 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"regexp"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// ErrPolicyViolation is returned when publishing a branch is refused by a policy
+type ErrPolicyViolation struct {
+	Branch  string
+	Message string
+}
+
+func (e *ErrPolicyViolation) Error() string {
+	return fmt.Sprintf("branch \"%s\": %s", redactRef(e.Branch), e.Message)
+}
+
+// publishPolicyFn checks whether a branch can be moved to the new revision,
+// at this point all the objects can be read from the repository
+type publishPolicyFn func(repo *ostree.Repo, config *Config, branch string, revPair common.RevisionPair) error
+
+// Policies applied before the refs are updated
+var publishPolicies = []publishPolicyFn{
+	checkVersionNotOlder,
+	checkTimestampNotOlder,
+	checkCommitMessages,
+}
+
+// CommitMessageRule requires the commits on the matching branches
+// to have subject and body matching regular expressions
+type CommitMessageRule struct {
+	Refs    []string `yaml:"refs"`
+	Subject string   `yaml:"subject,omitempty"`
+	Body    string   `yaml:"body,omitempty"`
+
+	subjectRe *regexp.Regexp
+	bodyRe    *regexp.Regexp
+}
+
+// compile compiles the regular expressions of the rule
+func (r *CommitMessageRule) compile() error {
+	var err error
+	if r.Subject != "" {
+		if r.subjectRe, err = regexp.Compile(r.Subject); err != nil {
+			return fmt.Errorf("invalid subject regular expression: %v", err)
+		}
+	}
+	if r.Body != "" {
+		if r.bodyRe, err = regexp.Compile(r.Body); err != nil {
+			return fmt.Errorf("invalid body regular expression: %v", err)
+		}
+	}
+
+	return nil
+}
+
+// checkVersionNotOlder refuses commits whose version is lower than the published one
+func checkVersionNotOlder(repo *ostree.Repo, config *Config, branch string, revPair common.RevisionPair) error {
+	if !config.RefuseOlderVersions || revPair.Server == "" {
+		return nil
+	}
+
+	publishedVersion, err := repo.GetCommitVersion(revPair.Server)
+	if err != nil {
+		return err
+	}
+	version, err := repo.GetCommitVersion(revPair.Client)
+	if err != nil {
+		return err
+	}
+
+	if publishedVersion != "" && common.CompareVersions(version, publishedVersion) < 0 {
+		msg := fmt.Sprintf("version \"%s\" is older than the published version \"%s\"", version, publishedVersion)
+		return &ErrPolicyViolation{Branch: branch, Message: msg}
+	}
+
+	return nil
+}
+
+// checkTimestampNotOlder refuses commits created before the published one,
+// which protects from downgrades even when commits have no version
+func checkTimestampNotOlder(repo *ostree.Repo, config *Config, branch string, revPair common.RevisionPair) error {
+	if !config.RefuseOlderTimestamps || revPair.Server == "" {
+		return nil
+	}
+
+	publishedTimestamp, err := repo.GetCommitTimestamp(revPair.Server)
+	if err != nil {
+		return err
+	}
+	timestamp, err := repo.GetCommitTimestamp(revPair.Client)
+	if err != nil {
+		return err
+	}
+
+	if timestamp.Before(publishedTimestamp) {
+		msg := fmt.Sprintf("commit %s was created on %s, before the published commit (%s)", revPair.Client, timestamp.Format(time.RFC3339), publishedTimestamp.Format(time.RFC3339))
+		return &ErrPolicyViolation{Branch: branch, Message: msg}
+	}
+
+	return nil
+}
+
+// checkCommitMessages refuses commits whose message doesn't match the rules,
+// all the commits that are pushed are checked and not only the last one
+func checkCommitMessages(repo *ostree.Repo, config *Config, branch string, revPair common.RevisionPair) error {
+	var rules []*CommitMessageRule
+	for _, rule := range config.CommitMessageRules {
+		if matchRefs(rule.Refs, branch) {
+			rules = append(rules, rule)
+		}
+	}
+	if len(rules) == 0 {
+		return nil
+	}
+
+	for rev := revPair.Client; rev != "" && rev != revPair.Server; {
+		subject, body, err := repo.GetCommitMessage(rev)
+		if err != nil {
+			return err
+		}
+
+		for _, rule := range rules {
+			if rule.subjectRe != nil && !rule.subjectRe.MatchString(subject) {
+				msg := fmt.Sprintf("commit %s: subject \"%s\" doesn't match \"%s\"", rev, subject, rule.Subject)
+				return &ErrPolicyViolation{Branch: branch, Message: msg}
+			}
+			if rule.bodyRe != nil && !rule.bodyRe.MatchString(body) {
+				msg := fmt.Sprintf("commit %s: body doesn't match \"%s\"", rev, rule.Body)
+				return &ErrPolicyViolation{Branch: branch, Message: msg}
+			}
+		}
+
+		// Stop at the published commit
+		if rev, err = repo.GetParentRev(rev); err != nil {
+			return err
+		}
+	}
+
+	return nil
+}
+
+// checkFastForward refuses to move a branch to a commit that doesn't descend
+// from the published one, walking back the history of the new commit
+func checkFastForward(repo *ostree.Repo, branch string, revPair common.RevisionPair, published string) error {
+	if published == "" {
+		return nil
+	}
+
+	for rev := revPair.Client; rev != "" && repo.HasCommit(rev); {
+		if rev == published {
+			return nil
+		}
+
+		var err error
+		if rev, err = repo.GetParentRev(rev); err != nil {
+			return err
+		}
+	}
+
+	msg := fmt.Sprintf("commit %s doesn't descend from the published commit %s, a force push is required", revPair.Client, published)
+	return &ErrPolicyViolation{Branch: branch, Message: msg}
+}
+
+// publishedRevisions returns the current revisions of all the branches, mirrors included
+func publishedRevisions(repo *ostree.Repo) (map[string]string, error) {
+	revs, err := repo.ListRevisions()
+	if err != nil {
+		return nil, err
+	}
+	mirrors, err := repo.ListMirrorRevisions()
+	if err != nil {
+		return nil, err
+	}
+	for branch, rev := range mirrors {
+		revs[branch] = rev
+	}
+
+	return revs, nil
+}
+
+// checkPublishPolicies applies all the policies to the branches of the entry,
+// the commits are read from objects which might be a staging repository.
+// Branches are compared with the revisions published in repo, not with those
+// the pusher claims to replace, so refsMutex must be held.
+func checkPublishPolicies(repo, objects *ostree.Repo, config *Config, entry *QueueEntry) error {
+	published, err := publishedRevisions(repo)
+	if err != nil {
+		return err
+	}
+
+	for _, branch := range common.SortedBranches(entry.UpdateRefs) {
+		revPair := common.RevisionPair{Server: published[branch], Client: entry.UpdateRefs[branch].Client}
+
+		// Only fast-forwards, unless the pusher forced the update
+		if !entry.Force {
+			if err := checkFastForward(objects, branch, revPair, published[branch]); err != nil {
+				return err
+			}
+		}
+
+		for _, policy := range publishPolicies {
+			if err := policy(objects, config, branch, revPair); err != nil {
+				return err
+			}
+		}
+	}
+
+	return nil
+}
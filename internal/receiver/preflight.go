@@ -0,0 +1,220 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"context"
+	"errors"
+	"fmt"
+	"net/http"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// refCheckFn checks whether a branch can be updated, returning the reason if it can't
+type refCheckFn func(ctx *preflightContext, branch string, revPair common.RevisionPair) string
+
+// preflightContext holds what the checks need to know
+type preflightContext struct {
+	request context.Context
+	queue   *Queue
+	repo    *ostree.Repo
+	config  *Config
+	revs    map[string]string
+	force   bool
+}
+
+// Checks applied to each branch
+var refChecks = []refCheckFn{
+	checkRefNotBusy,
+	checkRefNotStale,
+	checkRefAllowed,
+	checkRefNotFrozen,
+	checkRefNotOwnMirror,
+	checkRefPolicies,
+}
+
+// checkRefNotBusy fails when another queue entry is updating the branch
+func checkRefNotBusy(ctx *preflightContext, branch string, revPair common.RevisionPair) string {
+	reason := ""
+	ctx.queue.Walk(func(entry *QueueEntry) error {
+		if _, ok := entry.UpdateRefs[branch]; ok {
+			reason = fmt.Sprintf("branch \"%s\" is already being updated", branch)
+		}
+		return nil
+	})
+	return reason
+}
+
+// checkRefNotStale fails when the branch was moved since the client looked at it
+func checkRefNotStale(ctx *preflightContext, branch string, revPair common.RevisionPair) string {
+	if ctx.revs[branch] != revPair.Server {
+		return fmt.Sprintf("branch \"%s\" is at %s on the server, not %s", branch, ctx.revs[branch], revPair.Server)
+	}
+	return ""
+}
+
+// checkRefAllowed fails when the token, the user or the ACL don't allow the update
+func checkRefAllowed(ctx *preflightContext, branch string, revPair common.RevisionPair) string {
+	if err := checkRefsAllowed(ctx.request, map[string]common.RevisionPair{branch: revPair}); err != nil {
+		return err.Error()
+	}
+	return ""
+}
+
+// checkRefNotFrozen fails when the branch is frozen
+func checkRefNotFrozen(ctx *preflightContext, branch string, revPair common.RevisionPair) string {
+	if err := checkRefsNotFrozen(ctx.request, map[string]common.RevisionPair{branch: revPair}); err != nil {
+		return err.Error()
+	}
+	return ""
+}
+
+// checkRefPolicies fails when the publish policies refuse the update, they
+// can only be applied when the new commit is already on the server, otherwise
+// they are applied when publishing
+func checkRefPolicies(ctx *preflightContext, branch string, revPair common.RevisionPair) string {
+	if !ctx.repo.HasCommit(revPair.Client) {
+		return ""
+	}
+
+	refsMutex.Lock()
+	defer refsMutex.Unlock()
+	entry := &QueueEntry{UpdateRefs: map[string]common.RevisionPair{branch: revPair}, Force: ctx.force}
+	if err := checkPublishPolicies(ctx.repo, ctx.repo, ctx.config, entry); err != nil {
+		var policyErr *ErrPolicyViolation
+		if errors.As(err, &policyErr) {
+			return policyErr.Message
+		}
+		return fmt.Sprintf("cannot apply the publish policies: %v", err)
+	}
+	return ""
+}
+
+// checkRefNotOwnMirror fails when a mirrored ref belongs to the collection
+// of the repository, which must be pushed as a plain branch instead
+func checkRefNotOwnMirror(ctx *preflightContext, branch string, revPair common.RevisionPair) string {
+	if collectionID, ref, ok := ostree.ParseMirrorRef(branch); ok && collectionID == ctx.repo.GetCollectionID() {
+		return fmt.Sprintf("branch \"%s\" is in the collection of the repository, push \"%s\" instead", branch, ref)
+	}
+	return ""
+}
+
+// runPreflight evaluates the branch transitions without creating a queue entry
+func runPreflight(ctx *preflightContext, refs map[string]common.RevisionPair) (*common.PreflightResponse, error) {
+	response := &common.PreflightResponse{Pass: true, Refs: map[string]common.PreflightResult{}}
+
+	// The push needs to upload and publish
+	scopes := []string{ScopeUpload, ScopePublish}
+	if ctx.force {
+		scopes = append(scopes, ScopeForcePush)
+	}
+	for _, scope := range scopes {
+		if !requestHasScope(ctx.request, scope) {
+			response.Pass = false
+			response.Reasons = append(response.Reasons, fmt.Sprintf("not enough permissions, scope \"%s\" is required", scope))
+		}
+	}
+
+	// Repository-wide checks
+	if ctx.config.SubjectQuota > 0 {
+		written, err := ctx.queue.SubjectWritten(subject(ctx.request))
+		if err != nil {
+			return nil, err
+		}
+		if written >= ctx.config.SubjectQuota {
+			response.Pass = false
+			response.Reasons = append(response.Reasons, ErrQuotaExceeded.Error())
+		}
+	}
+	if ctx.config.TempQuota > 0 {
+		size, err := GetTempDirectorySize(ctx.repo)
+		if err != nil {
+			return nil, err
+		}
+		if size >= ctx.config.TempQuota {
+			response.Pass = false
+			response.Reasons = append(response.Reasons, "temporary storage quota exceeded")
+		}
+	}
+	usage, err := GetSpaceUsage(ctx.repo)
+	if err != nil {
+		return nil, err
+	}
+	if usage.Headroom() == 0 {
+		response.Pass = false
+		response.Reasons = append(response.Reasons, "repository min-free-space reached")
+	}
+	response.FreeSpace = usage.Free
+
+	// Branch checks
+	for _, branch := range common.SortedBranches(refs) {
+		result := common.PreflightResult{Pass: true}
+		for _, check := range refChecks {
+			if reason := check(ctx, branch, refs[branch]); reason != "" {
+				result.Pass = false
+				result.Reasons = append(result.Reasons, reason)
+			}
+		}
+		if !result.Pass {
+			response.Pass = false
+		}
+		response.Refs[branch] = result
+	}
+
+	return response, nil
+}
+
+// PreflightHandler tells whether the branches can be updated, without creating a queue entry
+func PreflightHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	queue, ok := ctx.Value(KeyQueue).(*Queue)
+	if !ok {
+		logger.Error("Unable to retrieve queue object from context")
+		http.Error(w, "no queue found", http.StatusUnprocessableEntity)
+		return
+	}
+	repo, ok := ctx.Value(KeyRepository).(*ostree.Repo)
+	if !ok {
+		logger.Error("Unable to retrieve repository object from context")
+		http.Error(w, "no repository found", http.StatusUnprocessableEntity)
+		return
+	}
+	config, ok := ctx.Value(KeyConfig).(*Config)
+	if !ok {
+		logger.Error("Unable to retrieve configuration from context")
+		http.Error(w, "no configuration found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	// Decode request
+	var req common.PreflightRequest
+	err := DecodeJSONBody(w, r, &req)
+	if err != nil {
+		HandleDecodeError(w, err)
+		return
+	}
+
+	// Current server-side revisions
+	revs, err := publishedRevisions(repo)
+	if err != nil {
+		logger.Errorf("Failed to list revisions: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+
+	preflightCtx := &preflightContext{request: ctx, queue: queue, repo: repo, config: config, revs: revs, force: req.Force}
+	object, err := runPreflight(preflightCtx, req.Refs)
+	if err != nil {
+		logger.Errorf("Failed to run preflight checks: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+
+	EncodeJSONReply(w, r, object)
+}
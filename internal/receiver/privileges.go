@@ -0,0 +1,69 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"errors"
+	"fmt"
+	"os/user"
+	"strconv"
+	"syscall"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// dropPrivileges switches to the user and group, so that the receiver can be
+// started as root to bind a privileged port and then serve unprivileged
+func dropPrivileges(userName, groupName string) error {
+	if userName == "" && groupName == "" {
+		return nil
+	}
+
+	uid, gid := -1, -1
+	if userName != "" {
+		account, err := user.Lookup(userName)
+		if err != nil {
+			return err
+		}
+		if uid, err = strconv.Atoi(account.Uid); err != nil {
+			return err
+		}
+		if gid, err = strconv.Atoi(account.Gid); err != nil {
+			return err
+		}
+	}
+	if groupName != "" {
+		group, err := user.LookupGroup(groupName)
+		if err != nil {
+			return err
+		}
+		if gid, err = strconv.Atoi(group.Gid); err != nil {
+			return err
+		}
+	}
+
+	// The group goes first, we can't change it anymore after the user,
+	// and the supplementary groups of root must not be kept
+	if err := syscall.Setgroups([]int{gid}); err != nil {
+		return fmt.Errorf("setgroups: %v", err)
+	}
+	if err := syscall.Setgid(gid); err != nil {
+		return fmt.Errorf("setgid: %v", err)
+	}
+	if uid >= 0 {
+		if err := syscall.Setuid(uid); err != nil {
+			return fmt.Errorf("setuid: %v", err)
+		}
+
+		// Make sure there's no way back
+		if uid != 0 && syscall.Setuid(0) == nil {
+			return errors.New("root privileges could be regained")
+		}
+	}
+
+	logger.Infof("Running as uid %d and gid %d", syscall.Getuid(), syscall.Getgid())
+
+	return nil
+}
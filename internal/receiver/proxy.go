@@ -0,0 +1,270 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"bytes"
+	"encoding/json"
+	"errors"
+	"fmt"
+	"io/ioutil"
+	"net/http"
+	"net/http/httputil"
+	"net/url"
+	"sync"
+	"time"
+
+	"github.com/go-chi/chi"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// How long replies of the upstream receiver are cached by default
+const defaultUpstreamCacheTTL = time.Minute
+
+// Headers of the upstream replies that are cached along with the body
+var cachedHeaders = []string{"Content-Type", "ETag", common.SignatureHeader, common.SignatureTimeHeader}
+
+// UpstreamConfig makes the receiver forward the API to another receiver
+type UpstreamConfig struct {
+	URL      string `yaml:"url"`
+	Token    string `yaml:"token"`
+	CacheTTL string `yaml:"cache_ttl,omitempty"`
+}
+
+// validate checks the values
+func (c *UpstreamConfig) validate() error {
+	if c.URL == "" {
+		return errors.New("upstream requires url")
+	}
+	if _, err := url.Parse(c.URL); err != nil {
+		return fmt.Errorf("invalid upstream url: %v", err)
+	}
+	if c.Token == "" {
+		return errors.New("upstream requires token")
+	}
+	if c.CacheTTL != "" {
+		if _, err := time.ParseDuration(c.CacheTTL); err != nil {
+			return fmt.Errorf("invalid upstream cache ttl: %v", err)
+		}
+	}
+
+	return nil
+}
+
+// cachedReply is a reply of the upstream receiver
+type cachedReply struct {
+	fetched time.Time
+	header  http.Header
+	body    []byte
+}
+
+// Upstream forwards requests to another receiver, with the credentials
+// of the clients, and caches the replies that don't change often with its
+// own token
+type Upstream struct {
+	url        *url.URL
+	token      string
+	ttl        time.Duration
+	proxy      *httputil.ReverseProxy
+	httpClient *http.Client
+
+	mutex sync.Mutex
+	cache map[string]*cachedReply
+}
+
+// NewUpstream creates the upstream from the configuration
+func NewUpstream(config *UpstreamConfig) (*Upstream, error) {
+	u, err := url.Parse(config.URL)
+	if err != nil {
+		return nil, err
+	}
+
+	ttl := defaultUpstreamCacheTTL
+	if config.CacheTTL != "" {
+		if ttl, err = time.ParseDuration(config.CacheTTL); err != nil {
+			return nil, err
+		}
+	}
+
+	return &Upstream{
+		url:        u,
+		token:      config.Token,
+		ttl:        ttl,
+		proxy:      httputil.NewSingleHostReverseProxy(u),
+		httpClient: &http.Client{Timeout: 30 * time.Second},
+		cache:      map[string]*cachedReply{},
+	}, nil
+}
+
+// Forward sends the request to the upstream receiver with the credentials
+// of the client, so that the upstream receiver checks them as well and
+// records who made the request rather than the edge receiver
+func (u *Upstream) Forward(w http.ResponseWriter, r *http.Request) {
+	if r.Header.Get("Authorization") == "" {
+		http.Error(w, "requests forwarded upstream need credentials", http.StatusUnauthorized)
+		return
+	}
+	r.Host = u.url.Host
+	u.proxy.ServeHTTP(w, r)
+}
+
+// fetch returns the cached reply for the path, asking the upstream
+// receiver again when it's older than the TTL
+func (u *Upstream) fetch(path string) (*cachedReply, error) {
+	u.mutex.Lock()
+	defer u.mutex.Unlock()
+
+	if reply, ok := u.cache[path]; ok && time.Since(reply.fetched) < u.ttl {
+		return reply, nil
+	}
+
+	target := *u.url
+	target.Path = singleJoiningSlash(u.url.Path, path)
+	request, err := http.NewRequest("GET", target.String(), nil)
+	if err != nil {
+		return nil, err
+	}
+	request.Header.Set("Accept", "application/json")
+	request.Header.Set("Authorization", fmt.Sprintf("BEARER %s", u.token))
+
+	response, err := u.httpClient.Do(request)
+	if err != nil {
+		return nil, err
+	}
+	defer response.Body.Close()
+
+	body, err := ioutil.ReadAll(response.Body)
+	if err != nil {
+		return nil, err
+	}
+	if response.StatusCode != http.StatusOK {
+		return nil, fmt.Errorf("upstream replied %s", response.Status)
+	}
+
+	reply := &cachedReply{fetched: time.Now(), header: http.Header{}, body: body}
+	for _, name := range cachedHeaders {
+		if value := response.Header.Get(name); value != "" {
+			reply.header.Set(name, value)
+		}
+	}
+	u.cache[path] = reply
+
+	return reply, nil
+}
+
+// Cached replies with the cached upstream reply, signature included;
+// requests with a nonce need a reply signed for them and are forwarded
+func (u *Upstream) Cached(w http.ResponseWriter, r *http.Request) {
+	if r.Header.Get(common.NonceHeader) != "" {
+		u.Forward(w, r)
+		return
+	}
+
+	reply, err := u.fetch(r.URL.Path)
+	if err != nil {
+		logger.Errorf("Failed to fetch %s from upstream: %v", r.URL.Path, err)
+		http.Error(w, err.Error(), http.StatusBadGateway)
+		return
+	}
+
+	for name, values := range reply.header {
+		w.Header()[name] = values
+	}
+	if etag := reply.header.Get("ETag"); etag != "" && r.Header.Get("If-None-Match") == etag {
+		w.WriteHeader(http.StatusNotModified)
+		return
+	}
+	w.Write(reply.body)
+}
+
+// checkRefs makes sure the credentials of the client can update the
+// branches before a queue entry is created upstream, which checks
+// them again
+func (u *Upstream) checkRefs(next http.Handler) http.Handler {
+	fn := func(w http.ResponseWriter, r *http.Request) {
+		data, err := ioutil.ReadAll(http.MaxBytesReader(w, r.Body, 10*1024*1024))
+		if err != nil {
+			http.Error(w, err.Error(), http.StatusBadRequest)
+			return
+		}
+		r.Body.Close()
+
+		var req common.QueueRequest
+		if err := json.Unmarshal(data, &req); err != nil {
+			http.Error(w, fmt.Sprintf("Request body contains badly-formed JSON: %v", err), http.StatusBadRequest)
+			return
+		}
+		if err := checkRefsAllowed(r.Context(), req.Refs); err != nil {
+			logger.Errorf("Refusing queue entry: %v", err)
+			http.Error(w, err.Error(), http.StatusForbidden)
+			return
+		}
+
+		r.Body = ioutil.NopCloser(bytes.NewReader(data))
+		r.ContentLength = int64(len(data))
+		next.ServeHTTP(w, r)
+	}
+	return http.HandlerFunc(fn)
+}
+
+func singleJoiningSlash(a, b string) string {
+	switch {
+	case len(a) > 0 && a[len(a)-1] == '/' && len(b) > 0 && b[0] == '/':
+		return a + b[1:]
+	case (len(a) == 0 || a[len(a)-1] != '/') && (len(b) == 0 || b[0] != '/'):
+		return a + "/" + b
+	}
+	return a + b
+}
+
+// proxyRouter is the API of a receiver that forwards everything to
+// the upstream receiver, the credentials and scopes of the clients are
+// still checked locally
+func proxyRouter(appState *AppState) http.Handler {
+	upstream := appState.Upstream
+	r := chi.NewRouter()
+
+	r.Use(receiverContext(appState))
+
+	// Any valid credentials can ask who they belong to
+	r.Get("/token", TokenInfoHandler)
+
+	r.Group(func(r chi.Router) {
+		r.Use(RequireScope(ScopeRead))
+		r.Get("/info", upstream.Cached)
+		r.Post("/preflight", upstream.Forward)
+		r.Get("/summary/diff", upstream.Forward)
+	})
+	r.Group(func(r chi.Router) {
+		r.Use(RequireScope(ScopeUpload))
+		r.With(upstream.checkRefs).Post("/queue", upstream.Forward)
+		r.Get("/objects", upstream.Forward)
+		r.Delete("/queue/{queueID}", upstream.Forward)
+		r.Delete("/session/{queueID}", upstream.Forward)
+		r.Get("/queue/{queueID}", upstream.Forward)
+		r.Post("/queue/{queueID}/missing_objects", upstream.Forward)
+		r.Put("/queue/{queueID}", upstream.Forward)
+		r.Put("/queue/{queueID}/upload_pack", upstream.Forward)
+		r.Post("/queue/{queueID}/fetch", upstream.Forward)
+		r.Put("/queue/{queueID}/delta", upstream.Forward)
+		r.Get("/queue/{queueID}/objects/{objectName}", upstream.Forward)
+		r.Put("/queue/{queueID}/objects/{objectName}", upstream.Forward)
+	})
+	r.Group(func(r chi.Router) {
+		r.Use(RequireScope(ScopePublish))
+		r.Post("/queue/{queueID}/done", upstream.Forward)
+		r.Post("/queue/{queueID}/approve", upstream.Forward)
+	})
+	r.Group(func(r chi.Router) {
+		r.Use(RequireScope(ScopeAdmin))
+		r.Get("/status", upstream.Cached)
+		r.Get("/sessions", upstream.Forward)
+		r.Delete("/sessions/{sessionID}", upstream.Forward)
+	})
+
+	return r
+}
@@ -0,0 +1,270 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"io"
+	"os"
+	"path/filepath"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// #define _GNU_SOURCE
+// #include <sys/ioctl.h>
+// #include <sys/types.h>
+// #include <unistd.h>
+// #include <linux/fs.h>
+//
+// static int _clone_file(int dst, int src)
+// {
+//   return ioctl(dst, FICLONE, src);
+// }
+import "C"
+
+// How objects are moved from the temporary directory to the repository
+const (
+	// PublishRename renames the objects, falling back to cloning them
+	// when the temporary directory is on another file system
+	PublishRename = "rename"
+
+	// PublishClone always creates new files in the repository, so that
+	// they get the ownership and labels of the objects directory
+	PublishClone = "clone"
+)
+
+// How an object was transferred, reported after publishing
+const (
+	transferRename        = "rename"
+	transferReflink       = "reflink"
+	transferCopyFileRange = "copy_file_range"
+	transferCopy          = "copy"
+)
+
+// reflinkFile makes dst share the data blocks of src,
+// only btrfs and XFS support this
+func reflinkFile(dst, src *os.File) error {
+	if ret, err := C._clone_file(C.int(dst.Fd()), C.int(src.Fd())); ret < 0 {
+		return err
+	}
+	return nil
+}
+
+// copyFileRange copies size bytes from src to dst in the kernel, which
+// might also share the data blocks when the file system supports it
+func copyFileRange(dst, src *os.File, size int64) error {
+	for size > 0 {
+		n, err := C.copy_file_range(C.int(src.Fd()), nil, C.int(dst.Fd()), nil, C.size_t(size), 0)
+		if n < 0 {
+			return err
+		}
+		if n == 0 {
+			return io.ErrUnexpectedEOF
+		}
+		size -= int64(n)
+	}
+	return nil
+}
+
+// cloneFile creates destination with the content of source, using the
+// fastest way supported by the file systems, and returns what was used
+func cloneFile(source, destination string) (string, error) {
+	src, err := os.Open(source)
+	if err != nil {
+		return "", err
+	}
+	defer src.Close()
+
+	fi, err := src.Stat()
+	if err != nil {
+		return "", err
+	}
+
+	perm := fi.Mode() & os.ModePerm
+	dst, err := os.OpenFile(destination, os.O_WRONLY|os.O_CREATE|os.O_TRUNC, perm)
+	if err != nil {
+		return "", err
+	}
+	defer dst.Close()
+
+	strategy, err := func() (string, error) {
+		if err := reflinkFile(dst, src); err == nil {
+			return transferReflink, nil
+		}
+		if err := copyFileRange(dst, src, fi.Size()); err == nil {
+			return transferCopyFileRange, nil
+		}
+
+		// Start over with a plain copy, copy_file_range might
+		// have failed half way through
+		if _, err := src.Seek(0, io.SeekStart); err != nil {
+			return "", err
+		}
+		if _, err := dst.Seek(0, io.SeekStart); err != nil {
+			return "", err
+		}
+		if err := dst.Truncate(0); err != nil {
+			return "", err
+		}
+		if _, err := io.Copy(dst, src); err != nil {
+			return "", err
+		}
+		return transferCopy, nil
+	}()
+	if err != nil {
+		dst.Close()
+		os.Remove(destination)
+		return "", err
+	}
+
+	return strategy, dst.Close()
+}
+
+// transferFile moves source to destination according to the publish
+// strategy and returns how the file was transferred
+func transferFile(source, destination, publishStrategy string) (string, error) {
+	// Renaming is atomic and doesn't read the file, but it only
+	// works on the same file system
+	if publishStrategy != PublishClone {
+		if err := os.Rename(source, destination); err == nil {
+			return transferRename, nil
+		}
+	}
+
+	strategy, err := cloneFile(source, destination)
+	if err != nil {
+		return "", err
+	}
+
+	return strategy, os.Remove(source)
+}
+
+// How often the publish progress is logged, in objects
+const publishProgressInterval = 10000
+
+// publishBranches checks the objects and the policies, moves the objects into
+// the repository and updates the branches; it returns how many objects were
+// transferred with each strategy, the published objects that were replaced and
+// the problems found after the branches were updated
+func publishBranches(repo *ostree.Repo, config *Config, entry *QueueEntry) (map[string]int, []string, []string, error) {
+	// Nothing is moved into the repository before it's checked
+	staged, cleanup, err := stageEntry(repo, config, entry)
+	if err != nil {
+		return nil, nil, nil, fmt.Errorf("failed to stage the objects: %v", err)
+	}
+	defer cleanup()
+
+	// Check everything with libostree, together with the published objects;
+	// objects that were not verified when received are always checked
+	if config.verifies(VerifyFull) || !config.verifies(VerifyChecksum) {
+		logger.Infof("Queue %s: checking %d objects", entry.ID, len(entry.Objects))
+		if err := fsckEntry(staged, entry); err != nil {
+			return nil, nil, nil, err
+		}
+	}
+
+	// Nothing else can move the branches until they are updated,
+	// compare and set them atomically
+	refsMutex.Lock()
+	defer refsMutex.Unlock()
+	if err := checkRefsUnchanged(repo, entry.UpdateRefs); err != nil {
+		return nil, nil, nil, err
+	}
+	if err := checkRefsNotAliases(repo, config, entry.UpdateRefs); err != nil {
+		return nil, nil, nil, err
+	}
+
+	// Make sure the branches can be moved
+	if err := checkPublishPolicies(repo, staged, config, entry); err != nil {
+		return nil, nil, nil, err
+	}
+
+	transfers, replaced, err := moveEntryObjects(repo, config, entry)
+	if err != nil {
+		return nil, nil, nil, err
+	}
+
+	// Sign what is going to be published with the release key
+	if err := signPublishedCommits(repo, config, entry); err != nil {
+		return nil, nil, nil, err
+	}
+
+	// Save the current state of refs before changing them
+	if config.BackupDir != "" {
+		if err := BackupRefs(repo, config.BackupDir, config.BackupRetention); err != nil {
+			return nil, nil, nil, err
+		}
+	}
+
+	// Update refs
+	warnings, err := UpdateRefs(repo, config, entry.UpdateRefs)
+	if err != nil {
+		return nil, nil, nil, err
+	}
+
+	return transfers, replaced, warnings, nil
+}
+
+// moveEntryObjects moves the received objects of the entry into the
+// repository; published objects are only replaced when the collision policy
+// allows it, the others are kept and the received copy discarded.
+// It returns how many objects were transferred with each strategy and the
+// published objects that were replaced.
+func moveEntryObjects(repo *ostree.Repo, config *Config, entry *QueueEntry) (map[string]int, []string, error) {
+	transfers := map[string]int{}
+	replaced := []string{}
+	logger.Infof("Queue %s: publishing %d objects", entry.ID, len(entry.Objects))
+	for i, objectName := range entry.Objects {
+		if i > 0 && i%publishProgressInterval == 0 {
+			logger.Infof("Queue %s: published %d/%d objects", entry.ID, i, len(entry.Objects))
+		}
+
+		// Objects are not received again when they were already moved
+		tempPath := GetTempObjectPath(repo, entry.ID, objectName)
+		if _, err := os.Stat(tempPath); err != nil {
+			continue
+		}
+
+		// Create path where the object will be moved to
+		objectPath := repo.GetObjectPath(objectName)
+		path := filepath.Dir(objectPath)
+		if err := os.MkdirAll(path, 0755); err != nil {
+			return nil, nil, fmt.Errorf("failed to create directory \"%s\" for the objects: %v", path, err)
+		}
+
+		// Something else might have published the object meanwhile
+		if _, err := os.Stat(objectPath); err == nil {
+			if !replacesPublished(config.CollisionPolicy, objectName) {
+				os.Remove(tempPath)
+				continue
+			}
+			if config.CollisionPolicy == CollisionQuarantine {
+				if err := quarantineObject(repo, objectName); err != nil {
+					return nil, nil, fmt.Errorf("unable to quarantine \"%s\": %v", objectPath, err)
+				}
+			}
+			logger.Errorf("COLLISION: queue %s replaces published object \"%s\"", entry.ID, redact(objectName))
+			replaced = append(replaced, objectName)
+		}
+
+		strategy, err := transferFile(tempPath, objectPath, config.PublishStrategy)
+		if err != nil {
+			return nil, nil, fmt.Errorf("unable to move \"%s\" to \"%s\": %v", tempPath, objectPath, err)
+		}
+		logger.Debugf("Queue %s: published %s with %s", entry.ID, redact(objectName), strategy)
+		transfers[strategy]++
+	}
+
+	for _, strategy := range []string{transferRename, transferReflink, transferCopyFileRange, transferCopy} {
+		if transfers[strategy] == 0 {
+			continue
+		}
+		logger.Infof("Queue %s: %d objects transferred with %s", entry.ID, transfers[strategy], strategy)
+	}
+
+	return transfers, replaced, nil
+}
@@ -5,22 +5,71 @@
 package receiver
 
 import (
+	"errors"
+	"sync"
+	"time"
+
 	"github.com/hashicorp/go-memdb"
 
 	"github.com/lirios/ostree-upload/internal/common"
 )
 
+// How long we remember published entries
+const finishedRetention = time.Hour
+
+var (
+	// ErrPublishing is returned when the entry is already being published
+	ErrPublishing = errors.New("queue entry is already being published")
+
+	// ErrPublished is returned when the entry was already published
+	ErrPublished = errors.New("queue entry was already published")
+
+	// ErrRemoved is returned when the entry was removed from the queue meanwhile
+	ErrRemoved = errors.New("queue entry was removed")
+
+	// ErrQuotaExceeded is returned when a subject wrote more than its disk quota
+	ErrQuotaExceeded = errors.New("disk quota exceeded")
+
+	// ErrQueueFull is returned when the queue has as many entries as allowed
+	ErrQueueFull = errors.New("too many sessions in progress")
+)
+
 // QueueEntry represents an entry in the update queue
 type QueueEntry struct {
 	ID         string
 	UpdateRefs map[string]common.RevisionPair
 	Objects    []string
+	Created    time.Time
+	// Checksums from the signed push manifest, if any
+	Checksums map[string]string
+	// ID of the key that signed the push manifest
+	KeyID string
+	// Branches can be moved to commits that don't descend from the published ones
+	Force bool
+	// User name or token subject that created the entry
+	Subject string
+	// Algorithm of the checksums of the objects, SHA-256 when empty
+	ChecksumAlgorithm string
 }
 
 // Queue represents the update queue
 type Queue struct {
 	schema *memdb.DBSchema
 	db     *memdb.MemDB
+
+	mutex      sync.Mutex
+	publishing map[string]bool
+	finished   map[string]time.Time
+	approvals  map[string]*Approval
+
+	writtenMutex sync.Mutex
+	written      map[string]int64
+
+	reachableMutex sync.Mutex
+	reachable      map[string]*reachableObjects
+
+	chunksMutex sync.Mutex
+	chunks      map[string]bool
 }
 
 // QueueWalkFn is a function prototype for Walk()
@@ -49,7 +98,89 @@ func NewQueue() (*Queue, error) {
 		return nil, err
 	}
 
-	return &Queue{schema, db}, nil
+	return &Queue{schema: schema, db: db, publishing: map[string]bool{}, finished: map[string]time.Time{}, approvals: map[string]*Approval{}, written: map[string]int64{}, reachable: map[string]*reachableObjects{}, chunks: map[string]bool{}}, nil
+}
+
+// StartPublishing marks the entry as being published, so that it
+// cannot be published twice nor aborted
+func (q *Queue) StartPublishing(entry *QueueEntry) error {
+	q.mutex.Lock()
+	defer q.mutex.Unlock()
+
+	if q.publishing[entry.ID] {
+		return ErrPublishing
+	}
+	if !q.contains(entry.ID) {
+		return ErrRemoved
+	}
+	q.publishing[entry.ID] = true
+
+	return nil
+}
+
+// Abort removes the entry from the queue unless it's being published,
+// which is decided under the same lock as StartPublishing
+func (q *Queue) Abort(entry *QueueEntry) error {
+	q.mutex.Lock()
+	defer q.mutex.Unlock()
+
+	if q.publishing[entry.ID] {
+		return ErrPublishing
+	}
+	if !q.contains(entry.ID) {
+		return ErrRemoved
+	}
+	delete(q.approvals, entry.ID)
+
+	return q.RemoveEntry(entry)
+}
+
+// contains returns true if the entry with the specified ID is in the queue
+func (q *Queue) contains(ID string) bool {
+	entry, err := q.GetEntry(ID)
+	return err == nil && entry != nil
+}
+
+// FinishPublishing clears the publishing mark and, if the entry was
+// published successfully, removes it from the queue
+func (q *Queue) FinishPublishing(entry *QueueEntry, published bool) error {
+	q.mutex.Lock()
+	defer q.mutex.Unlock()
+
+	delete(q.publishing, entry.ID)
+	if !published {
+		return nil
+	}
+
+	// Forget about old entries
+	now := time.Now()
+	for id, finishedTime := range q.finished {
+		if now.Sub(finishedTime) > finishedRetention {
+			delete(q.finished, id)
+		}
+	}
+	q.finished[entry.ID] = now
+	delete(q.approvals, entry.ID)
+
+	return q.RemoveEntry(entry)
+}
+
+// IsPublishing returns true if the entry with the specified ID is being published
+func (q *Queue) IsPublishing(ID string) bool {
+	q.mutex.Lock()
+	defer q.mutex.Unlock()
+
+	return q.publishing[ID]
+}
+
+// IsPublished returns true if the entry with the specified ID was
+// published recently
+func (q *Queue) IsPublished(ID string) bool {
+	q.mutex.Lock()
+	defer q.mutex.Unlock()
+
+	_, ok := q.finished[ID]
+	return ok
 }
 
 // AddEntry adds an entry to the queue
@@ -63,6 +194,36 @@ func (q *Queue) AddEntry(entry *QueueEntry) error {
 	return nil
 }
 
+// AddEntryWithin adds an entry to the queue unless it has max entries
+// already, 0 means no limit, or check returns an error for any of them;
+// the entries are counted and checked in the same transaction that
+// adds the new one, so concurrent calls can't exceed the limit
+func (q *Queue) AddEntryWithin(entry *QueueEntry, max int, check QueueWalkFn) error {
+	txn := q.db.Txn(true)
+	defer txn.Abort()
+
+	it, err := txn.Get("entry", "id")
+	if err != nil {
+		return err
+	}
+	count := 0
+	for object := it.Next(); object != nil; object = it.Next() {
+		if err := check(object.(*QueueEntry)); err != nil {
+			return err
+		}
+		count++
+	}
+	if max > 0 && count >= max {
+		return ErrQueueFull
+	}
+
+	if err := txn.Insert("entry", entry); err != nil {
+		return err
+	}
+	txn.Commit()
+	return nil
+}
+
 // RemoveEntry removes the entry from the queue
 func (q *Queue) RemoveEntry(entry *QueueEntry) error {
 	txn := q.db.Txn(true)
@@ -71,9 +232,61 @@ func (q *Queue) RemoveEntry(entry *QueueEntry) error {
 		return err
 	}
 	txn.Commit()
+
+	q.writtenMutex.Lock()
+	delete(q.written, entry.ID)
+	q.writtenMutex.Unlock()
+
+	q.reachableMutex.Lock()
+	delete(q.reachable, entry.ID)
+	q.reachableMutex.Unlock()
+
 	return nil
 }
 
+// AddWritten records that size bytes were received for the entry
+func (q *Queue) AddWritten(ID string, size int64) {
+	q.writtenMutex.Lock()
+	defer q.writtenMutex.Unlock()
+
+	q.written[ID] += size
+}
+
+// Written returns how many bytes were received for the entry
+func (q *Queue) Written(ID string) int64 {
+	q.writtenMutex.Lock()
+	defer q.writtenMutex.Unlock()
+
+	return q.written[ID]
+}
+
+// Len returns the number of entries in the queue
+func (q *Queue) Len() (int, error) {
+	count := 0
+	err := q.Walk(func(entry *QueueEntry) error {
+		count++
+		return nil
+	})
+
+	return count, err
+}
+
+// SubjectWritten returns how many bytes were received for the
+// entries created by subject that are still in the queue
+func (q *Queue) SubjectWritten(subject string) (int64, error) {
+	var total int64
+	err := q.Walk(func(entry *QueueEntry) error {
+		if entry.Subject == subject {
+			q.writtenMutex.Lock()
+			total += q.written[entry.ID]
+			q.writtenMutex.Unlock()
+		}
+		return nil
+	})
+
+	return total, err
+}
+
 // GetEntry returns the entry corresponding to the specified ID
 func (q *Queue) GetEntry(ID string) (*QueueEntry, error) {
 	txn := q.db.Txn(false)
@@ -0,0 +1,251 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"context"
+	"errors"
+	"fmt"
+	"math"
+	"net/http"
+	"sync"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// How long clients are asked to wait when they have too many uploads
+const uploadRetryAfter = 5 * time.Second
+
+// How long clients are asked to wait when there are too many sessions
+const busyRetryAfter = 30 * time.Second
+
+// RateLimitConfig limits what each token subject or user can do,
+// so that one runaway CI job can't starve the receiver
+type RateLimitConfig struct {
+	RequestsPerSecond float64 `yaml:"requests_per_second,omitempty"`
+	Burst             int     `yaml:"burst,omitempty"`
+	ConcurrentUploads int     `yaml:"concurrent_uploads,omitempty"`
+}
+
+// validate checks the values and fills in the defaults
+func (c *RateLimitConfig) validate() error {
+	if c.RequestsPerSecond < 0 || c.Burst < 0 || c.ConcurrentUploads < 0 {
+		return errors.New("rate limits cannot be negative")
+	}
+	if c.Burst == 0 {
+		c.Burst = int(math.Max(1, math.Ceil(c.RequestsPerSecond)))
+	}
+
+	return nil
+}
+
+// tokenBucket holds the requests that can be made right away
+type tokenBucket struct {
+	tokens float64
+	last   time.Time
+}
+
+// RateLimiter keeps track of the requests and uploads of each subject
+type RateLimiter struct {
+	config  *RateLimitConfig
+	mutex   sync.Mutex
+	buckets map[string]*tokenBucket
+	uploads map[string]int
+}
+
+// NewRateLimiter creates a rate limiter with the configured limits
+func NewRateLimiter(config *RateLimitConfig) *RateLimiter {
+	return &RateLimiter{config: config, buckets: map[string]*tokenBucket{}, uploads: map[string]int{}}
+}
+
+// rateLimitKey identifies who made the request, tokens without
+// a subject are told apart by a fingerprint of the token
+func rateLimitKey(ctx context.Context) string {
+	if who := subject(ctx); who != "" {
+		return who
+	}
+	if token, ok := ctx.Value(KeyToken).(*Token); ok {
+		return "token:" + common.KeyID([]byte(token.Token))
+	}
+
+	return ""
+}
+
+// allow takes a request from the bucket of key, if it's empty
+// it returns how long to wait before the next request
+func (l *RateLimiter) allow(key string) (bool, time.Duration) {
+	l.mutex.Lock()
+	defer l.mutex.Unlock()
+
+	now := time.Now()
+	bucket, ok := l.buckets[key]
+	if !ok {
+		bucket = &tokenBucket{tokens: float64(l.config.Burst), last: now}
+		l.buckets[key] = bucket
+	}
+
+	// Refill according to the time passed since the last request
+	bucket.tokens = math.Min(float64(l.config.Burst), bucket.tokens+now.Sub(bucket.last).Seconds()*l.config.RequestsPerSecond)
+	bucket.last = now
+
+	if bucket.tokens < 1 {
+		wait := time.Duration((1 - bucket.tokens) / l.config.RequestsPerSecond * float64(time.Second))
+		return false, wait
+	}
+	bucket.tokens--
+
+	return true, 0
+}
+
+// acquireUpload returns false if key has already too many uploads in progress
+func (l *RateLimiter) acquireUpload(key string) bool {
+	l.mutex.Lock()
+	defer l.mutex.Unlock()
+
+	if l.uploads[key] >= l.config.ConcurrentUploads {
+		return false
+	}
+	l.uploads[key]++
+
+	return true
+}
+
+// releaseUpload marks an upload of key as finished
+func (l *RateLimiter) releaseUpload(key string) {
+	l.mutex.Lock()
+	defer l.mutex.Unlock()
+
+	if l.uploads[key]--; l.uploads[key] <= 0 {
+		delete(l.uploads, key)
+	}
+}
+
+// retryLater replies with status and tells the client when to retry
+func retryLater(w http.ResponseWriter, status int, message string, wait time.Duration) {
+	w.Header().Set("Retry-After", fmt.Sprintf("%d", int(math.Ceil(wait.Seconds()))))
+	http.Error(w, message, status)
+}
+
+// Limit HTTP middleware handler will refuse requests exceeding the
+// requests per second, it does nothing when the limiter is nil
+func (l *RateLimiter) Limit(next http.Handler) http.Handler {
+	if l == nil || l.config.RequestsPerSecond == 0 {
+		return next
+	}
+
+	fn := func(w http.ResponseWriter, r *http.Request) {
+		key := rateLimitKey(r.Context())
+		if ok, wait := l.allow(key); !ok {
+			logger.Errorf("Rate limit exceeded by \"%s\"", key)
+			retryLater(w, http.StatusTooManyRequests, http.StatusText(http.StatusTooManyRequests), wait)
+			return
+		}
+
+		next.ServeHTTP(w, r)
+	}
+	return http.HandlerFunc(fn)
+}
+
+// LimitUploads HTTP middleware handler will refuse uploads exceeding the
+// concurrent uploads, it does nothing when the limiter is nil
+func (l *RateLimiter) LimitUploads(next http.Handler) http.Handler {
+	if l == nil || l.config.ConcurrentUploads == 0 {
+		return next
+	}
+
+	fn := func(w http.ResponseWriter, r *http.Request) {
+		key := rateLimitKey(r.Context())
+		if !l.acquireUpload(key) {
+			logger.Errorf("Too many concurrent uploads by \"%s\"", key)
+			retryLater(w, http.StatusTooManyRequests, http.StatusText(http.StatusTooManyRequests), uploadRetryAfter)
+			return
+		}
+		defer l.releaseUpload(key)
+
+		next.ServeHTTP(w, r)
+	}
+	return http.HandlerFunc(fn)
+}
+
+// How many times a peer can fail to authenticate in authFailureWindow
+// before its requests are refused without looking at the credentials
+const maxAuthFailures = 10
+
+// How long failed authentications are counted
+const authFailureWindow = time.Minute
+
+// How many peers are tracked at most
+const maxAuthFailurePeers = 10000
+
+// authFailure counts the failures of a peer since start
+type authFailure struct {
+	count int
+	start time.Time
+}
+
+// AuthFailures keeps track of the failed authentications of each peer,
+// so that passwords and tokens can't be guessed at full speed
+type AuthFailures struct {
+	mutex sync.Mutex
+	peers map[string]*authFailure
+}
+
+// NewAuthFailures creates an empty tracker
+func NewAuthFailures() *AuthFailures {
+	return &AuthFailures{peers: map[string]*authFailure{}}
+}
+
+// blocked returns how long peer has to wait before trying again,
+// zero if it can try now; nil trackers never block
+func (f *AuthFailures) blocked(peer string) time.Duration {
+	if f == nil {
+		return 0
+	}
+
+	f.mutex.Lock()
+	defer f.mutex.Unlock()
+
+	failure, ok := f.peers[peer]
+	if !ok || failure.count < maxAuthFailures {
+		return 0
+	}
+	wait := time.Until(failure.start.Add(authFailureWindow))
+	if wait <= 0 {
+		delete(f.peers, peer)
+		return 0
+	}
+
+	return wait
+}
+
+// record counts a failed authentication of peer
+func (f *AuthFailures) record(peer string) {
+	if f == nil {
+		return
+	}
+
+	f.mutex.Lock()
+	defer f.mutex.Unlock()
+
+	now := time.Now()
+	failure, ok := f.peers[peer]
+	if ok && now.Sub(failure.start) < authFailureWindow {
+		failure.count++
+		return
+	}
+
+	if len(f.peers) >= maxAuthFailurePeers {
+		for key, failure := range f.peers {
+			if now.Sub(failure.start) >= authFailureWindow {
+				delete(f.peers, key)
+			}
+		}
+	}
+	if len(f.peers) < maxAuthFailurePeers {
+		f.peers[peer] = &authFailure{count: 1, start: now}
+	}
+}
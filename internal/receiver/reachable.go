@@ -0,0 +1,164 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"os"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// isReceived returns true if the object is in the temporary directory of the
+// queue entry, objects that are not there were published before along with
+// everything they reference
+func isReceived(repo *ostree.Repo, queueID, objectName string) bool {
+	_, err := os.Stat(GetTempObjectPath(repo, queueID, objectName))
+	return err == nil
+}
+
+// referencedObjects traverses the commits that are going to be published,
+// from the new revision of each branch back to the published one, and
+// returns the names of the objects they reference
+func referencedObjects(repo *ostree.Repo, entry *QueueEntry) (map[string]bool, error) {
+	referenced := map[string]bool{}
+
+	var walkTree func(tree, meta string) error
+	walkTree = func(tree, meta string) error {
+		referenced[meta+".dirmeta"] = true
+
+		objectName := tree + ".dirtree"
+		if referenced[objectName] {
+			return nil
+		}
+		referenced[objectName] = true
+		if !isReceived(repo, entry.ID, objectName) {
+			return nil
+		}
+
+		dirTree, err := ostree.ReadDirTreeObject(GetTempObjectPath(repo, entry.ID, objectName))
+		if err != nil {
+			return err
+		}
+		for _, file := range dirTree.Files {
+			// Archive repositories have compressed files
+			referenced[file+".file"] = true
+			referenced[file+".filez"] = true
+		}
+		for _, dir := range dirTree.Dirs {
+			if err := walkTree(dir.Tree, dir.Meta); err != nil {
+				return err
+			}
+		}
+
+		return nil
+	}
+
+	for _, branch := range common.SortedBranches(entry.UpdateRefs) {
+		revPair := entry.UpdateRefs[branch]
+		for rev := revPair.Client; rev != "" && rev != revPair.Server; {
+			objectName := rev + ".commit"
+			if referenced[objectName] {
+				break
+			}
+			referenced[objectName] = true
+			referenced[rev+".commitmeta"] = true
+			if !isReceived(repo, entry.ID, objectName) {
+				break
+			}
+
+			commit, err := ostree.ReadCommitObject(GetTempObjectPath(repo, entry.ID, objectName))
+			if err != nil {
+				return nil, err
+			}
+			if err := walkTree(commit.RootTree, commit.RootMeta); err != nil {
+				return nil, err
+			}
+			rev = commit.Parent
+		}
+	}
+
+	return referenced, nil
+}
+
+// unreferencedObjects returns the objects received for the entry
+// that are not referenced by its commits
+func unreferencedObjects(repo *ostree.Repo, entry *QueueEntry) ([]string, error) {
+	referenced, err := referencedObjects(repo, entry)
+	if err != nil {
+		return nil, err
+	}
+
+	var unreferenced []string
+	for _, objectName := range entry.Objects {
+		if !referenced[objectName] && isReceived(repo, entry.ID, objectName) {
+			unreferenced = append(unreferenced, objectName)
+		}
+	}
+
+	return unreferenced, nil
+}
+
+// reachableObjects is what the commits of an entry reference, as far
+// as the objects received so far tell
+type reachableObjects struct {
+	objects map[string]bool
+	stale   bool
+}
+
+// isReferenced returns true if the object is referenced by the commits of
+// the entry; with walk they are walked again when metadata was received
+// since the last time, otherwise only the last walk is looked at
+func (q *Queue) isReferenced(repo *ostree.Repo, entry *QueueEntry, objectName string, walk bool) (bool, error) {
+	q.reachableMutex.Lock()
+	defer q.reachableMutex.Unlock()
+
+	reachable, ok := q.reachable[entry.ID]
+	if ok && (reachable.objects[objectName] || !reachable.stale) {
+		return reachable.objects[objectName], nil
+	}
+	if !walk {
+		return false, nil
+	}
+
+	objects, err := referencedObjects(repo, entry)
+	if err != nil {
+		return false, err
+	}
+	q.reachable[entry.ID] = &reachableObjects{objects: objects}
+
+	return objects[objectName], nil
+}
+
+// metadataReceived tells that the commits of the entry reference more
+// objects than the last time they were walked
+func (q *Queue) metadataReceived(ID string) {
+	q.reachableMutex.Lock()
+	defer q.reachableMutex.Unlock()
+
+	if reachable, ok := q.reachable[ID]; ok {
+		reachable.stale = true
+	}
+}
+
+// checkReferenced refuses files that are not referenced by the commits and
+// trees received so far, so that nothing else is stored even temporarily.
+// Metadata can arrive before what references it, it's accepted if well
+// formed and refused later when publishing if it's still unreferenced.
+// It returns whether the object is referenced.
+func checkReferenced(repo *ostree.Repo, queue *Queue, entry *QueueEntry, objectName string) (bool, error) {
+	// Metadata doesn't need a walk, which would happen for every tree
+	referenced, err := queue.isReferenced(repo, entry, objectName, common.IsContentObject(objectName))
+	if err != nil {
+		logger.Warnf("Queue %s: failed to traverse the commits received so far: %v", entry.ID, err)
+	}
+	if referenced || !common.IsContentObject(objectName) {
+		return referenced, nil
+	}
+
+	logger.Errorf("Unable to receive object \"%s\": not referenced by the commits of queue entry %s", redact(objectName), entry.ID)
+	return false, newServiceError(ErrorConflict, "object \"%s\" is not referenced by the commits and trees received so far, upload them first", redact(objectName))
+}
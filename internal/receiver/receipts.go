@@ -0,0 +1,50 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"crypto/sha256"
+	"encoding/hex"
+	"fmt"
+
+	"github.com/lirios/ostree-upload/internal/common"
+)
+
+// newObjectReceipt returns the receipt of an object received for a queue entry,
+// the identifier is derived from what was stored so that it can be issued
+// again from the journal when the client is done
+func newObjectReceipt(queueID, objectName, checksum string, size int64) common.ObjectReceipt {
+	sum := sha256.Sum256([]byte(fmt.Sprintf("%s\x00%s\x00%s\x00%d", queueID, objectName, checksum, size)))
+	return common.ObjectReceipt{
+		ObjectName: objectName,
+		Checksum:   checksum,
+		Size:       size,
+		ReceiptID:  hex.EncodeToString(sum[:16]),
+	}
+}
+
+// checkReceipts returns an error if any of the receipts sent by the client
+// doesn't match what the server recorded
+func checkReceipts(journal *Journal, queueID string, receipts []common.ObjectReceipt) error {
+	if len(receipts) == 0 {
+		return nil
+	}
+
+	recorded, err := journal.Receipts(queueID)
+	if err != nil {
+		return err
+	}
+	if recorded == nil {
+		return nil
+	}
+
+	for _, receipt := range receipts {
+		if recorded[receipt.ObjectName] != receipt {
+			return fmt.Errorf("receipt %s for object %s doesn't match what was received", receipt.ReceiptID, redact(receipt.ObjectName))
+		}
+	}
+
+	return nil
+}
@@ -0,0 +1,294 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"errors"
+	"fmt"
+	"io"
+	"os"
+	"path/filepath"
+	"strings"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// checkObject returns an error unless the object can be uploaded to
+// the entry, only the objects listed when it was created can
+func checkObject(entry *QueueEntry, objectName string) error {
+	if err := common.ValidateObjectName(objectName); err != nil {
+		logger.Errorf("Unable to receive object: %v", err)
+		return &ServiceError{Kind: ErrorInvalid, Err: err}
+	}
+	for _, name := range entry.Objects {
+		if name == objectName {
+			return nil
+		}
+	}
+
+	logger.Errorf("Unable to receive object \"%s\": not part of queue entry %s", redact(objectName), entry.ID)
+	return newServiceError(ErrorUnprocessable, "object \"%s\" is not part of the queue entry", redact(objectName))
+}
+
+// sessionObject returns the queue entry an object is uploaded to,
+// if the object is part of it
+func (s *ReceiveService) sessionObject(queueID, objectName string) (*QueueEntry, error) {
+	entry, err := s.getEntry(queueID)
+	if err != nil {
+		return nil, err
+	}
+	if err := checkObject(entry, objectName); err != nil {
+		return nil, err
+	}
+
+	return entry, nil
+}
+
+// receiveObject writes an object of the entry read from reader to the
+// temporary directory, within the limits, and verifies it; it returns the
+// checksum calculated by the server, if any, and the size
+func (s *ReceiveService) receiveObject(entry *QueueEntry, limits *objectLimits, objectName string, reader io.Reader) (string, int64, error) {
+	if err := checkObject(entry, objectName); err != nil {
+		return "", 0, err
+	}
+	if _, err := checkReferenced(s.Repo, s.Queue, entry, objectName); err != nil {
+		return "", 0, err
+	}
+	logger.Debugf("Receiving \"%s\"...", redact(objectName))
+
+	// Create the destination file
+	objectPath := GetTempObjectPath(s.Repo, entry.ID, objectName)
+	if err := os.MkdirAll(filepath.Dir(objectPath), 0755); err != nil {
+		logger.Errorf("Unable to create the temporary directory of queue entry %s: %v", entry.ID, err)
+		return "", 0, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	if _, err := os.Stat(objectPath); os.IsExist(err) {
+		msg := fmt.Sprintf("temporary file for object \"%s\" already exist", objectName)
+		logger.Errorf("Unable to complete upload: %s", msg)
+		return "", 0, &ServiceError{Kind: ErrorUnprocessable, Err: errors.New(msg)}
+	}
+	objectFile, err := os.Create(objectPath)
+	if err != nil {
+		logger.Errorf("Unable to create %s: %v", redact(objectName), err)
+		return "", 0, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+
+	// Write file and calculate checksum for a verification later
+	written, err := io.Copy(objectFile, limits.reader(reader, 0))
+	if err != nil {
+		objectFile.Close()
+		os.Remove(objectPath)
+		logger.Errorf("Failed to copy part to \"%s\": %v", redact(objectName), err)
+		return "", 0, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	if err := objectFile.Sync(); err != nil {
+		objectFile.Close()
+		os.Remove(objectPath)
+		logger.Errorf("Failed to flush \"%s\": %v", redact(objectName), err)
+		return "", 0, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	objectFile.Close()
+	if err := limits.check(entry, objectName, written, written); err != nil {
+		os.Remove(objectPath)
+		return "", 0, err
+	}
+	limits.consume(written)
+	s.Queue.AddWritten(entry.ID, written)
+
+	// Objects are verified as soon as they are received
+	checksum, err := s.verifyObject(entry, objectName, objectPath)
+	if err != nil {
+		return "", 0, err
+	}
+
+	return checksum, written, nil
+}
+
+// objectLimits is how much can be written for an object before exceeding
+// the configured limits or the free space of the repository
+type objectLimits struct {
+	config   *Config
+	temp     int64
+	quota    int64
+	session  int64
+	headroom int64
+}
+
+// newObjectLimits calculates the limits for an object of the entry
+func (s *ReceiveService) newObjectLimits(entry *QueueEntry) (*objectLimits, error) {
+	limits := &objectLimits{config: s.Config}
+
+	if s.Config.TempQuota > 0 {
+		size, err := GetTempDirectorySize(s.Repo)
+		if err != nil {
+			return nil, newServiceError(ErrorInternal, "failed to calculate temporary directory size: %v", err)
+		}
+		limits.temp = s.Config.TempQuota - size
+	}
+
+	// Each subject can only write so much in its sessions
+	if s.Config.SubjectQuota > 0 {
+		written, err := s.Queue.SubjectWritten(entry.Subject)
+		if err != nil {
+			return nil, newServiceError(ErrorInternal, "failed to calculate the disk usage of \"%s\": %v", entry.Subject, err)
+		}
+		limits.quota = s.Config.SubjectQuota - written
+	}
+
+	// Sessions can only be so big
+	if s.Config.MaxSessionBytes > 0 {
+		limits.session = s.Config.MaxSessionBytes - s.Queue.Written(entry.ID)
+	}
+
+	// Honor the min-free-space settings of the repository
+	usage, err := GetSpaceUsage(s.Repo)
+	if err != nil {
+		return nil, newServiceError(ErrorInternal, "failed to calculate free space: %v", err)
+	}
+	limits.headroom = usage.Headroom()
+
+	return limits, nil
+}
+
+// reader stops reading one byte after the limits, so that exceeding
+// them can be detected, offset is how much of the object was received before
+func (l *objectLimits) reader(reader io.Reader, offset int64) io.Reader {
+	if l.config.MaxObjectSize > 0 {
+		reader = io.LimitReader(reader, l.config.MaxObjectSize-offset+1)
+	}
+	if l.config.TempQuota > 0 {
+		reader = io.LimitReader(reader, l.temp+1)
+	}
+	if l.config.SubjectQuota > 0 {
+		reader = io.LimitReader(reader, l.quota+1)
+	}
+	if l.config.MaxSessionBytes > 0 {
+		reader = io.LimitReader(reader, l.session+1)
+	}
+	return io.LimitReader(reader, l.headroom+1)
+}
+
+// consume subtracts what was written for an object from the limits,
+// so that they apply to the following objects of the same request
+func (l *objectLimits) consume(written int64) {
+	l.temp -= written
+	l.quota -= written
+	l.session -= written
+	l.headroom -= written
+}
+
+// check returns an error if writing written bytes, making the object
+// size bytes, exceeded the limits
+func (l *objectLimits) check(entry *QueueEntry, objectName string, size, written int64) error {
+	if l.config.MaxObjectSize > 0 && size > l.config.MaxObjectSize {
+		logger.Errorf("Object \"%s\" exceeds the maximum object size", redact(objectName))
+		return newServiceError(ErrorTooLarge, "object %s exceeds the maximum size of %d bytes", redact(objectName), l.config.MaxObjectSize)
+	}
+	if l.config.TempQuota > 0 && written > l.temp {
+		logger.Errorf("Object \"%s\" exceeds the temporary storage quota", redact(objectName))
+		return newServiceError(ErrorInsufficientStorage, "temporary storage quota exceeded")
+	}
+	if l.config.SubjectQuota > 0 && written > l.quota {
+		logger.Errorf("Object \"%s\" exceeds the disk quota of \"%s\"", redact(objectName), entry.Subject)
+		return &ServiceError{Kind: ErrorInsufficientStorage, Err: ErrQuotaExceeded}
+	}
+	if l.config.MaxSessionBytes > 0 && written > l.session {
+		logger.Errorf("Object \"%s\" exceeds the size limit of session %s", redact(objectName), entry.ID)
+		return newServiceError(ErrorTooLarge, "session exceeds the limit of %d bytes, split the push", l.config.MaxSessionBytes)
+	}
+	if written > l.headroom {
+		logger.Errorf("Object \"%s\" doesn't fit in the free space of the repository", redact(objectName))
+		return newServiceError(ErrorInsufficientStorage, "not enough free space, the repository min-free-space would be exceeded")
+	}
+
+	return nil
+}
+
+// verifyObject calculates the checksum of an object just received, if
+// needed, and makes sure it's well formed and that it's what the pusher
+// signed, removing it otherwise; it returns the checksum
+func (s *ReceiveService) verifyObject(entry *QueueEntry, objectName, objectPath string) (string, error) {
+	referenced, err := checkReferenced(s.Repo, s.Queue, entry, objectName)
+	if err != nil {
+		os.Remove(objectPath)
+		return "", err
+	}
+
+	var checksum string
+	if s.Config.verifies(VerifyChecksum) || entry.Checksums != nil {
+		var err error
+		checksum, err = common.CalculateChecksumWith(objectPath, entry.ChecksumAlgorithm)
+		if err != nil {
+			logger.Errorf("Failed to calculate checksum of \"%s\": %v", redact(objectName), err)
+			return "", &ServiceError{Kind: ErrorInternal, Err: err}
+		}
+	}
+
+	// Objects must be those the pusher signed, whatever the transport did
+	if entry.Checksums != nil && checksum != entry.Checksums[objectName] {
+		os.Remove(objectPath)
+		logger.Errorf("Object \"%s\" doesn't match the signed push manifest", redact(objectName))
+		return "", newServiceError(ErrorUnprocessable, "object %s doesn't match the signed push manifest", redact(objectName))
+	}
+
+	// Make sure objects are not corrupt before we publish them, metadata
+	// that nothing references yet is always checked
+	if s.Config.verifies(VerifyObjects) || !referenced {
+		if err := validateObject(objectPath, objectName); err != nil {
+			os.Remove(objectPath)
+			logger.Errorf("Object \"%s\" is not valid: %v", redact(objectName), err)
+			return "", newServiceError(ErrorUnprocessable, "object %s is not valid: %v", redact(objectName), err)
+		}
+	}
+
+	// Anything could be planted as the signatures of a commit
+	if strings.HasSuffix(objectName, ".commitmeta") && s.Config.verifies(VerifyChecksum) {
+		if err := validateCommitMeta(s.Repo, s.Config, entry, objectPath, objectName); err != nil {
+			os.Remove(objectPath)
+			logger.Errorf("Object \"%s\" is not valid: %v", redact(objectName), err)
+			return "", newServiceError(ErrorUnprocessable, "object %s is not valid: %v", redact(objectName), err)
+		}
+	}
+
+	// Commits and trees reference more objects
+	if !common.IsContentObject(objectName) {
+		s.Queue.metadataReceived(entry.ID)
+	}
+
+	return checksum, nil
+}
+
+// acceptObject compares the checksum of a verified object with the one
+// calculated by the client and, if they match, journals the object and
+// returns its receipt
+func (s *ReceiveService) acceptObject(entry *QueueEntry, objectName, serverChecksum, clientChecksum string, size int64) (*common.ObjectReceipt, error) {
+	// If the checksum doesn't match we remove the object and report the error,
+	// so that the next time the object will be uploaded again
+	if s.Config.verifies(VerifyChecksum) && serverChecksum != clientChecksum {
+		os.Remove(GetTempObjectPath(s.Repo, entry.ID, objectName))
+		logger.Errorf("Object \"%s\" has a bad checksum (%s vs %s)", redact(objectName), serverChecksum, clientChecksum)
+		return nil, newServiceError(ErrorUnprocessable, "bad checksum for %s", redact(objectName))
+	}
+
+	// The object might have been published already
+	if err := CheckCollision(s.Repo, s.Config.CollisionPolicy, entry.ChecksumAlgorithm, entry.ID, objectName, clientChecksum); err != nil {
+		var collisionErr *ErrObjectCollision
+		if errors.As(err, &collisionErr) {
+			return nil, &ServiceError{Kind: ErrorConflict, Err: err}
+		}
+		logger.Errorf("Failed to check \"%s\" for collisions: %v", redact(objectName), err)
+		return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+
+	// Now the object can be trusted even after a crash
+	if err := s.Journal.RecordObject(entry.ID, objectName, serverChecksum, size); err != nil {
+		logger.Errorf("Failed to journal \"%s\": %v", redact(objectName), err)
+		return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+
+	receipt := newObjectReceipt(entry.ID, objectName, serverChecksum, size)
+	return &receipt, nil
+}
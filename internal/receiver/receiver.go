@@ -8,8 +8,10 @@ import (
 	"fmt"
 	"os"
 	"path/filepath"
+	"sync"
 
 	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
 	"github.com/lirios/ostree-upload/internal/ostree"
 )
 
@@ -22,6 +24,30 @@ const (
 
 	// KeyRepository is the context key for the ostree.Repo instance
 	KeyRepository ContextKey = iota
+
+	// KeySigningKey is the context key for the key used to sign replies
+	KeySigningKey ContextKey = iota
+
+	// KeyConfig is the context key for the Config instance
+	KeyConfig ContextKey = iota
+
+	// KeyHistory is the context key for the History instance
+	KeyHistory ContextKey = iota
+
+	// KeyJournal is the context key for the Journal instance
+	KeyJournal ContextKey = iota
+
+	// KeyUser is the context key for the User authenticated with HTTP Basic authentication
+	KeyUser ContextKey = iota
+
+	// KeyToken is the context key for the Token that authenticated the request
+	KeyToken ContextKey = iota
+
+	// KeyJobs is the context key for the Jobs instance
+	KeyJobs ContextKey = iota
+
+	// KeyUploadRedirect is the context key for the UploadRedirect instance
+	KeyUploadRedirect ContextKey = iota
 )
 
 // Name of the temporary directory inside the OSTree repository
@@ -44,23 +70,123 @@ func CreateTempDirectory(r *ostree.Repo) error {
 	return nil
 }
 
+// GetTempSessionPath returns the directory inside the temporary directory
+// where the objects of the queue entry are stored during the upload
+func GetTempSessionPath(r *ostree.Repo, queueID string) string {
+	return filepath.Join(r.Path(), tempDirName, queueID)
+}
+
 // GetTempObjectPath returns the path to the OSTree object passed as argument
-// from the temporary directory
-func GetTempObjectPath(r *ostree.Repo, objectName string) string {
-	return filepath.Join(r.Path(), tempDirName, objectName)
+// from the temporary directory of the queue entry
+func GetTempObjectPath(r *ostree.Repo, queueID, objectName string) string {
+	return filepath.Join(GetTempSessionPath(r, queueID), objectName)
 }
 
-// UpdateRefs points branches to the new checksum
-func UpdateRefs(r *ostree.Repo, refs map[string]common.RevisionPair) error {
-	for branch, revPair := range refs {
-		if err := r.SetRefImmediate("", branch, revPair.Client); err != nil {
-			return fmt.Errorf("Failed to set branch %s from %s to %s: %v", branch, revPair.Server, revPair.Client, err)
+// GetTempDirectorySize returns the size in bytes of the objects stored in the
+// temporary directory, partial uploads in the journal included
+func GetTempDirectorySize(r *ostree.Repo) (int64, error) {
+	var size int64
+
+	for _, dirName := range []string{tempDirName, journalDirName} {
+		err := filepath.Walk(filepath.Join(r.Path(), dirName), func(path string, info os.FileInfo, err error) error {
+			if os.IsNotExist(err) {
+				return nil
+			}
+			if err != nil {
+				return err
+			}
+			if !info.IsDir() {
+				size += info.Size()
+			}
+			return nil
+		})
+		if err != nil {
+			return size, err
 		}
 	}
 
-	if err := r.RegenerateSummary(); err != nil {
-		return fmt.Errorf("Failed to regenerate summary: %v", err)
+	return size, nil
+}
+
+// ErrRefMoved is returned when a branch is not at the revision the pusher
+// replaces anymore, because something else published meanwhile
+type ErrRefMoved struct {
+	Branch   string
+	Expected string
+	Actual   string
+}
+
+func (e *ErrRefMoved) Error() string {
+	return fmt.Sprintf("branch \"%s\" was moved to %s meanwhile, expected %s", redactRef(e.Branch), e.Actual, e.Expected)
+}
+
+// Serializes the ref updates, so that checking and moving them is atomic
+var refsMutex sync.Mutex
+
+// checkRefsUnchanged returns ErrRefMoved if any of the branches is not
+// at the revision it's going to be moved from
+func checkRefsUnchanged(r *ostree.Repo, refs map[string]common.RevisionPair) error {
+	revs, err := r.ListRevisions()
+	if err != nil {
+		return err
+	}
+	mirrors, err := r.ListMirrorRevisions()
+	if err != nil {
+		return err
+	}
+	for ref, rev := range mirrors {
+		revs[ref] = rev
+	}
+
+	for _, branch := range common.SortedBranches(refs) {
+		if actual := revs[branch]; actual != refs[branch].Server {
+			return &ErrRefMoved{Branch: branch, Expected: refs[branch].Server, Actual: actual}
+		}
 	}
 
 	return nil
 }
+
+// UpdateRefs points branches to the new checksum; once they moved, failures
+// to update the aliases, the static deltas and the summary don't undo the
+// publish and are returned as warnings
+func UpdateRefs(r *ostree.Repo, config *Config, refs map[string]common.RevisionPair) ([]string, error) {
+	for _, branch := range common.SortedBranches(refs) {
+		revPair := refs[branch]
+		var err error
+		if collectionID, ref, ok := ostree.ParseMirrorRef(branch); ok {
+			err = r.SetMirrorRefImmediate(collectionID, ref, revPair.Client)
+		} else {
+			err = r.SetRefImmediate("", branch, revPair.Client)
+		}
+		if err != nil {
+			return nil, fmt.Errorf("Failed to set branch %s from %s to %s: %v", redactRef(branch), revPair.Server, revPair.Client, err)
+		}
+	}
+
+	// The branches moved already, the aliases catch up at the next publish
+	warnings := []string{}
+	if err := updateAliases(r, config); err != nil {
+		logger.Errorf("Failed to update the aliases: %v", err)
+		warnings = append(warnings, fmt.Sprintf("aliases not updated: %v", err))
+	}
+
+	if generateDeltasEnabled(r) {
+		for _, branch := range common.SortedBranches(refs) {
+			revPair := refs[branch]
+			logger.Actionf("Generating static delta for branch \"%s\"...", redactRef(branch))
+			if err := r.GenerateStaticDelta(revPair.Server, revPair.Client); err != nil {
+				logger.Errorf("Failed to generate static delta for branch %s: %v", redactRef(branch), err)
+				warnings = append(warnings, fmt.Sprintf("static delta for branch %s not generated: %v", redactRef(branch), err))
+			}
+		}
+	}
+
+	// The summary is regenerated by the next publish or the summary job
+	if err := updateSummary(r, config); err != nil {
+		logger.Errorf("Failed to update the summary: %v", err)
+		warnings = append(warnings, fmt.Sprintf("summary not updated: %v", err))
+	}
+
+	return warnings, nil
+}
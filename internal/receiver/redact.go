@@ -0,0 +1,86 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"crypto/sha256"
+	"encoding/hex"
+	"strings"
+	"sync"
+
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// How names of branches and objects appear in logs and error messages
+const (
+	LogRedactionNone     = "none"
+	LogRedactionHash     = "hash"
+	LogRedactionTruncate = "truncate"
+)
+
+// Names are shortened to this many characters when truncated
+const redactedLength = 8
+
+var (
+	redactionMutex sync.RWMutex
+	logRedaction   = LogRedactionNone
+)
+
+// SetLogRedaction sets how names are redacted from now on
+func SetLogRedaction(mode string) {
+	redactionMutex.Lock()
+	defer redactionMutex.Unlock()
+	logRedaction = mode
+}
+
+// redact returns name as it should appear in logs and error messages
+func redact(name string) string {
+	redactionMutex.RLock()
+	mode := logRedaction
+	redactionMutex.RUnlock()
+
+	switch mode {
+	case LogRedactionHash:
+		sum := sha256.Sum256([]byte(name))
+		return "#" + hex.EncodeToString(sum[:])[:2*redactedLength]
+	case LogRedactionTruncate:
+		if len(name) > redactedLength {
+			return name[:redactedLength] + "..."
+		}
+	}
+
+	return name
+}
+
+// redactRef returns the name of a branch as it should appear in logs,
+// the collection of mirrored refs is kept
+func redactRef(ref string) string {
+	if strings.HasPrefix(ref, ostree.MirrorRefPrefix) {
+		parts := strings.SplitN(strings.TrimPrefix(ref, ostree.MirrorRefPrefix), "/", 2)
+		if len(parts) == 2 {
+			return ostree.MirrorRefPrefix + parts[0] + "/" + redact(parts[1])
+		}
+	}
+
+	return redact(ref)
+}
+
+// redactRefs is redactRef for a list of branches
+func redactRefs(refs []string) []string {
+	redacted := make([]string, len(refs))
+	for i, ref := range refs {
+		redacted[i] = redactRef(ref)
+	}
+	return redacted
+}
+
+// redactObjects is redact for a list of objects
+func redactObjects(objectNames []string) []string {
+	redacted := make([]string, len(objectNames))
+	for i, objectName := range objectNames {
+		redacted[i] = redact(objectName)
+	}
+	return redacted
+}
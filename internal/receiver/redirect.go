@@ -0,0 +1,359 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"context"
+	"crypto/hmac"
+	"crypto/sha256"
+	"encoding/hex"
+	"errors"
+	"fmt"
+	"net/http"
+	"net/url"
+	"sort"
+	"strconv"
+	"strings"
+	"time"
+
+	"github.com/go-chi/chi"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+const (
+	// How long upload targets are valid when expiry is not set
+	defaultUploadRedirectExpiry = time.Hour
+	// Longest validity of a pre-signed URL accepted by S3
+	maxUploadRedirectExpiry = 7 * 24 * time.Hour
+	// Region assumed when region is not set, as most S3 implementations do
+	defaultUploadRedirectRegion = "us-east-1"
+	// How long fetching an object can take besides the transfer, which
+	// can't be slower than the rate in bytes per second
+	fetchTimeout = time.Minute
+	fetchMinRate = 1024 * 1024
+)
+
+// UploadRedirectConfig makes clients upload the objects to an S3 compatible
+// bucket, with pre-signed URLs, instead of sending them to the receiver
+type UploadRedirectConfig struct {
+	Endpoint  string `yaml:"endpoint"`
+	Bucket    string `yaml:"bucket,omitempty"`
+	Region    string `yaml:"region,omitempty"`
+	AccessKey string `yaml:"access_key"`
+	SecretKey string `yaml:"secret_key"`
+	Prefix    string `yaml:"prefix,omitempty"`
+	Expiry    string `yaml:"expiry,omitempty"`
+}
+
+// validate checks the values
+func (c *UploadRedirectConfig) validate() error {
+	if c.Endpoint == "" {
+		return errors.New("upload redirect requires endpoint")
+	}
+	u, err := url.Parse(c.Endpoint)
+	if err != nil {
+		return fmt.Errorf("invalid upload redirect endpoint: %v", err)
+	}
+	if (u.Scheme != "http" && u.Scheme != "https") || u.Host == "" {
+		return fmt.Errorf("invalid upload redirect endpoint \"%s\": not an HTTP URL", c.Endpoint)
+	}
+	if c.AccessKey == "" || c.SecretKey == "" {
+		return errors.New("upload redirect requires access_key and secret_key")
+	}
+	if c.Expiry != "" {
+		expiry, err := time.ParseDuration(c.Expiry)
+		if err != nil {
+			return fmt.Errorf("invalid upload redirect expiry: %v", err)
+		}
+		if expiry < time.Second || expiry > maxUploadRedirectExpiry {
+			return fmt.Errorf("upload redirect expiry must be between 1s and %s", maxUploadRedirectExpiry)
+		}
+	}
+
+	return nil
+}
+
+// UploadRedirect hands out pre-signed URLs of a bucket where clients
+// upload the objects, which are fetched by the receiver when the client
+// asks for them, so that the bytes of the push don't go through the API
+type UploadRedirect struct {
+	endpoint   *url.URL
+	bucket     string
+	region     string
+	accessKey  string
+	secretKey  string
+	prefix     string
+	expiry     time.Duration
+	httpClient *http.Client
+}
+
+// NewUploadRedirect creates the upload redirect from the configuration
+func NewUploadRedirect(config *UploadRedirectConfig) (*UploadRedirect, error) {
+	u, err := url.Parse(config.Endpoint)
+	if err != nil {
+		return nil, err
+	}
+
+	region := config.Region
+	if region == "" {
+		region = defaultUploadRedirectRegion
+	}
+
+	expiry := defaultUploadRedirectExpiry
+	if config.Expiry != "" {
+		if expiry, err = time.ParseDuration(config.Expiry); err != nil {
+			return nil, err
+		}
+	}
+
+	return &UploadRedirect{
+		endpoint:   u,
+		bucket:     config.Bucket,
+		region:     region,
+		accessKey:  config.AccessKey,
+		secretKey:  config.SecretKey,
+		prefix:     config.Prefix,
+		expiry:     expiry,
+		httpClient: &http.Client{},
+	}, nil
+}
+
+// key returns where an object of the session is stored in the bucket
+func (u *UploadRedirect) key(queueID, objectName string) string {
+	return u.prefix + queueID + "/" + objectName
+}
+
+// Targets returns where the objects of the session are uploaded,
+// or nil when uploads are not redirected
+func (u *UploadRedirect) Targets(queueID string, objectNames []string) map[string]common.UploadTarget {
+	if u == nil || len(objectNames) == 0 {
+		return nil
+	}
+
+	now := time.Now()
+	targets := map[string]common.UploadTarget{}
+	for _, objectName := range objectNames {
+		targets[objectName] = common.UploadTarget{
+			URL:    u.presign("PUT", u.key(queueID, objectName), now),
+			Method: "PUT",
+		}
+	}
+	return targets
+}
+
+// presign returns the URL of the key in the bucket, valid for method until
+// the expiry, signed with AWS Signature Version 4 in the query string
+func (u *UploadRedirect) presign(method, key string, now time.Time) string {
+	now = now.UTC()
+	date := now.Format("20060102")
+	timestamp := now.Format("20060102T150405Z")
+	scope := date + "/" + u.region + "/s3/aws4_request"
+
+	// Every segment of the path is encoded as S3 does
+	segments := []string{""}
+	for _, segment := range strings.Split(u.endpoint.Path, "/") {
+		if segment != "" {
+			segments = append(segments, segment)
+		}
+	}
+	if u.bucket != "" {
+		segments = append(segments, u.bucket)
+	}
+	segments = append(segments, strings.Split(key, "/")...)
+	target := *u.endpoint
+	target.Path = strings.Join(segments, "/")
+	escaped := make([]string, len(segments))
+	for i, segment := range segments {
+		escaped[i] = sigV4Escape(segment)
+	}
+	target.RawPath = strings.Join(escaped, "/")
+
+	query := url.Values{}
+	query.Set("X-Amz-Algorithm", "AWS4-HMAC-SHA256")
+	query.Set("X-Amz-Credential", u.accessKey+"/"+scope)
+	query.Set("X-Amz-Date", timestamp)
+	query.Set("X-Amz-Expires", strconv.Itoa(int(u.expiry/time.Second)))
+	query.Set("X-Amz-SignedHeaders", "host")
+	canonicalQuery := strings.Replace(query.Encode(), "+", "%20", -1)
+
+	canonicalRequest := strings.Join([]string{
+		method,
+		target.RawPath,
+		canonicalQuery,
+		"host:" + target.Host + "\n",
+		"host",
+		"UNSIGNED-PAYLOAD",
+	}, "\n")
+	hash := sha256.Sum256([]byte(canonicalRequest))
+	stringToSign := "AWS4-HMAC-SHA256\n" + timestamp + "\n" + scope + "\n" + hex.EncodeToString(hash[:])
+
+	signingKey := hmacSHA256([]byte("AWS4"+u.secretKey), date)
+	signingKey = hmacSHA256(signingKey, u.region)
+	signingKey = hmacSHA256(signingKey, "s3")
+	signingKey = hmacSHA256(signingKey, "aws4_request")
+	signature := hex.EncodeToString(hmacSHA256(signingKey, stringToSign))
+
+	target.RawQuery = canonicalQuery + "&X-Amz-Signature=" + signature
+	return target.String()
+}
+
+// hmacSHA256 returns the HMAC-SHA256 of data with key
+func hmacSHA256(key []byte, data string) []byte {
+	mac := hmac.New(sha256.New, key)
+	mac.Write([]byte(data))
+	return mac.Sum(nil)
+}
+
+// sigV4Escape percent-encodes everything but the unreserved characters
+func sigV4Escape(value string) string {
+	var b strings.Builder
+	for i := 0; i < len(value); i++ {
+		c := value[i]
+		if (c >= 'A' && c <= 'Z') || (c >= 'a' && c <= 'z') || (c >= '0' && c <= '9') || c == '-' || c == '_' || c == '.' || c == '~' {
+			b.WriteByte(c)
+		} else {
+			fmt.Fprintf(&b, "%%%02X", c)
+		}
+	}
+	return b.String()
+}
+
+// fetch downloads an object the client uploaded to the bucket and
+// receives it as if it was uploaded to the receiver; it returns the
+// checksum calculated by the server, if any, and the size.
+// The request is not bound by the timeout of the API, see requestTimeout,
+// but the download is by the size of the object.
+func (u *UploadRedirect) fetch(r *http.Request, service *ReceiveService, entry *QueueEntry, limits *objectLimits, objectName string) (string, int64, error) {
+	ctx, cancel := context.WithCancel(r.Context())
+	defer cancel()
+	timer := time.AfterFunc(fetchTimeout, cancel)
+	defer timer.Stop()
+
+	request, err := http.NewRequestWithContext(ctx, "GET", u.presign("GET", u.key(entry.ID, objectName), time.Now()), nil)
+	if err != nil {
+		return "", 0, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	response, err := u.httpClient.Do(request)
+	if err != nil {
+		logger.Errorf("Unable to fetch \"%s\": %v", redact(objectName), err)
+		return "", 0, newServiceError(ErrorBadGateway, "cannot fetch object %s: %v", redact(objectName), err)
+	}
+	defer response.Body.Close()
+	if response.ContentLength > 0 {
+		timer.Reset(fetchTimeout + time.Duration(response.ContentLength/fetchMinRate)*time.Second)
+	}
+
+	switch {
+	case response.StatusCode == http.StatusNotFound:
+		logger.Errorf("Unable to fetch \"%s\": not uploaded", redact(objectName))
+		return "", 0, newServiceError(ErrorUnprocessable, "object %s was not uploaded", redact(objectName))
+	case response.StatusCode != http.StatusOK:
+		logger.Errorf("Unable to fetch \"%s\": %s", redact(objectName), response.Status)
+		return "", 0, newServiceError(ErrorBadGateway, "cannot fetch object %s: %s", redact(objectName), response.Status)
+	}
+
+	return service.receiveObject(entry, limits, objectName, response.Body)
+}
+
+// remove deletes an object fetched from the bucket, failures are only
+// logged because a lifecycle rule of the bucket can clean up as well
+func (u *UploadRedirect) remove(queueID, objectName string) {
+	request, err := http.NewRequest("DELETE", u.presign("DELETE", u.key(queueID, objectName), time.Now()), nil)
+	if err != nil {
+		return
+	}
+	response, err := u.httpClient.Do(request)
+	if err != nil {
+		logger.Warnf("Failed to remove \"%s\" from the bucket: %v", redact(objectName), err)
+		return
+	}
+	response.Body.Close()
+	if response.StatusCode >= 300 {
+		logger.Warnf("Failed to remove \"%s\" from the bucket: %s", redact(objectName), response.Status)
+	}
+}
+
+// FetchHandler receives the objects the client uploaded to the upload
+// targets, given with the checksums calculated by the client, and replies
+// with their receipts like an upload does
+func FetchHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	service, err := serviceFromContext(ctx)
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+	redirect, _ := ctx.Value(KeyUploadRedirect).(*UploadRedirect)
+	if redirect == nil {
+		http.Error(w, "uploads are not redirected", http.StatusNotFound)
+		return
+	}
+
+	// Decode request
+	var req common.FetchRequest
+	if err := DecodeJSONBody(w, r, &req); err != nil {
+		HandleDecodeError(w, err)
+		return
+	}
+
+	// Get the entry from the queue
+	entry, err := service.getEntry(chi.URLParam(r, "queueID"))
+	if err != nil {
+		logger.Errorf("Unable to retrieve queue entry: %v", err)
+		replyServiceError(w, err)
+		return
+	}
+
+	// The objects share the limits, like those of an upload
+	limits, err := service.newObjectLimits(entry)
+	if err != nil {
+		logger.Errorf("Failed to calculate the upload limits: %v", err)
+		replyServiceError(w, err)
+		return
+	}
+
+	objectNames := make([]string, 0, len(req.Checksums))
+	for objectName := range req.Checksums {
+		objectNames = append(objectNames, objectName)
+	}
+	sort.Strings(objectNames)
+
+	receipts := []common.ObjectReceipt{}
+	for _, objectName := range objectNames {
+		checksum := req.Checksums[objectName]
+		if err := common.ValidateObjectName(objectName); err != nil {
+			logger.Errorf("Unable to fetch object: %v", err)
+			http.Error(w, err.Error(), http.StatusBadRequest)
+			return
+		}
+		if err := common.ValidateChecksum(checksum); err != nil {
+			logger.Errorf("Failed to receive checksum: %v", err)
+			http.Error(w, err.Error(), http.StatusBadRequest)
+			return
+		}
+
+		serverChecksum, written, err := redirect.fetch(r, service, entry, limits, objectName)
+		if err != nil {
+			replyServiceError(w, err)
+			return
+		}
+		receipt, err := service.acceptObject(entry, objectName, serverChecksum, checksum, written)
+		if err != nil {
+			replyServiceError(w, err)
+			return
+		}
+		receipts = append(receipts, *receipt)
+
+		// The bucket is only a staging area
+		redirect.remove(entry.ID, objectName)
+	}
+
+	object := common.UploadResponse{Receipts: receipts}
+	EncodeJSONReply(w, r, object)
+}
@@ -0,0 +1,240 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// +build sandbox,linux
+
+package receiver
+
+import (
+	"fmt"
+	"os"
+	"path/filepath"
+	"runtime"
+	"strings"
+	"syscall"
+	"unsafe"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// Landlock system calls, numbered the same on all architectures
+const (
+	sysLandlockCreateRuleset = 444
+	sysLandlockAddRule       = 445
+	sysLandlockRestrictSelf  = 446
+)
+
+const (
+	landlockCreateRulesetVersion = 1 << 0
+	landlockRulePathBeneath      = 1
+	prSetNoNewPrivs              = 38
+)
+
+// Filesystem access rights that read something
+const (
+	landlockAccessFSExecute  = 1 << 0
+	landlockAccessFSReadFile = 1 << 2
+	landlockAccessFSReadDir  = 1 << 3
+)
+
+// Filesystem access rights that modify something
+const (
+	landlockAccessFSWriteFile  = 1 << 1
+	landlockAccessFSRemoveDir  = 1 << 4
+	landlockAccessFSRemoveFile = 1 << 5
+	landlockAccessFSMakeChar   = 1 << 6
+	landlockAccessFSMakeDir    = 1 << 7
+	landlockAccessFSMakeReg    = 1 << 8
+	landlockAccessFSMakeSock   = 1 << 9
+	landlockAccessFSMakeFifo   = 1 << 10
+	landlockAccessFSMakeBlock  = 1 << 11
+	landlockAccessFSMakeSym    = 1 << 12
+	landlockAccessFSRefer      = 1 << 13
+	landlockAccessFSTruncate   = 1 << 14
+)
+
+// Access rights a rule for a file, rather than a directory, can allow
+const landlockAccessFile = landlockAccessFSExecute | landlockAccessFSWriteFile | landlockAccessFSReadFile | landlockAccessFSTruncate
+
+// System paths the receiver and the libraries it loads only read, those
+// that don't exist are skipped
+var sandboxSystemPaths = []string{
+	"/usr", "/lib", "/lib64", "/bin", "/sbin", "/proc",
+	"/etc/ld.so.cache", "/etc/localtime", "/etc/passwd", "/etc/group",
+	"/etc/hosts", "/etc/resolv.conf", "/etc/nsswitch.conf", "/etc/gai.conf", "/etc/host.conf",
+	"/etc/ssl", "/etc/pki", "/etc/ca-certificates", "/etc/gnupg",
+	"/sys/kernel/mm/transparent_hugepage",
+}
+
+// The process started again inside the sandbox finds this variable
+const sandboxEnv = "OSTREE_UPLOAD_SANDBOXED"
+
+// landlockRulesetAttr is struct landlock_ruleset_attr of the first ABI
+type landlockRulesetAttr struct {
+	handledAccessFS uint64
+}
+
+// landlockPathBeneathAttr is struct landlock_path_beneath_attr, which
+// is packed: only the first 12 bytes are passed to the kernel
+type landlockPathBeneathAttr struct {
+	allowedAccess uint64
+	parentFd      int32
+}
+
+// EnterSandbox restricts the receiver so that it can only modify files in
+// the repository, in the other paths of the configuration and in extraPaths,
+// and only read those, the files it loads and the system paths.
+// Landlock applies to the calling thread only, so the restricted thread
+// executes the receiver again and this function returns in the new process.
+func EnterSandbox(config *Config, repoPath string, extraPaths ...string) error {
+	if !config.Sandbox {
+		return nil
+	}
+	if os.Getenv(sandboxEnv) != "" {
+		logger.Info("Running in the sandbox")
+		return nil
+	}
+
+	abi, _, errno := syscall.Syscall(sysLandlockCreateRuleset, 0, 0, landlockCreateRulesetVersion)
+	if errno != 0 {
+		return fmt.Errorf("landlock is not available: %v", errno)
+	}
+	readAccess := uint64(landlockAccessFSExecute | landlockAccessFSReadFile | landlockAccessFSReadDir)
+	writeAccess := uint64(landlockAccessFSWriteFile | landlockAccessFSRemoveDir | landlockAccessFSRemoveFile |
+		landlockAccessFSMakeChar | landlockAccessFSMakeDir | landlockAccessFSMakeReg | landlockAccessFSMakeSock |
+		landlockAccessFSMakeFifo | landlockAccessFSMakeBlock | landlockAccessFSMakeSym)
+	if abi >= 2 {
+		writeAccess |= landlockAccessFSRefer
+	}
+	if abi >= 3 {
+		writeAccess |= landlockAccessFSTruncate
+	}
+	handled := readAccess | writeAccess
+
+	// Backups are stored in a directory of their own, which is created now
+	// because its parent is not writable once restricted
+	if config.BackupDir != "" {
+		if err := os.MkdirAll(config.BackupDir, 0755); err != nil {
+			return fmt.Errorf("failed to create backup directory: %v", err)
+		}
+	}
+
+	// The thread is never given back, it is replaced by the new process
+	runtime.LockOSThread()
+
+	attr := landlockRulesetAttr{handledAccessFS: handled}
+	rulesetFd, _, errno := syscall.Syscall(sysLandlockCreateRuleset, uintptr(unsafe.Pointer(&attr)), unsafe.Sizeof(attr), 0)
+	if errno != 0 {
+		runtime.UnlockOSThread()
+		return fmt.Errorf("failed to create ruleset: %v", errno)
+	}
+	defer syscall.Close(int(rulesetFd))
+
+	for _, path := range append(sandboxWritablePaths(config, repoPath), extraPaths...) {
+		if err := landlockAllow(int(rulesetFd), path, handled); err != nil {
+			runtime.UnlockOSThread()
+			return fmt.Errorf("failed to allow writing to \"%s\": %v", path, err)
+		}
+		logger.Debugf("Sandbox allows writing to %s", path)
+	}
+	for _, path := range sandboxReadablePaths(config) {
+		if err := landlockAllow(int(rulesetFd), path, readAccess); err != nil {
+			runtime.UnlockOSThread()
+			return fmt.Errorf("failed to allow reading \"%s\": %v", path, err)
+		}
+		logger.Debugf("Sandbox allows reading %s", path)
+	}
+	for _, path := range sandboxSystemPaths {
+		if err := landlockAllow(int(rulesetFd), path, readAccess); err != nil {
+			if os.IsNotExist(err) {
+				continue
+			}
+			runtime.UnlockOSThread()
+			return fmt.Errorf("failed to allow reading \"%s\": %v", path, err)
+		}
+	}
+
+	if _, _, errno := syscall.RawSyscall(syscall.SYS_PRCTL, prSetNoNewPrivs, 1, 0); errno != 0 {
+		runtime.UnlockOSThread()
+		return fmt.Errorf("failed to set no_new_privs: %v", errno)
+	}
+	if _, _, errno := syscall.RawSyscall(sysLandlockRestrictSelf, rulesetFd, 0, 0); errno != 0 {
+		runtime.UnlockOSThread()
+		return fmt.Errorf("failed to restrict the process: %v", errno)
+	}
+
+	logger.Info("Restarting in the sandbox...")
+	env := append(os.Environ(), sandboxEnv+"=1")
+	return syscall.Exec("/proc/self/exe", os.Args, env)
+}
+
+// sandboxWritablePaths returns the paths the receiver writes to, the
+// configuration file is rewritten in place so its directory is not needed
+func sandboxWritablePaths(config *Config, repoPath string) []string {
+	paths := []string{repoPath, config.path, "/dev/null", "/dev/urandom"}
+	if config.HistoryFile != "" {
+		paths = append(paths, filepath.Dir(config.HistoryFile))
+	}
+	if config.BackupDir != "" {
+		paths = append(paths, config.BackupDir)
+	}
+	if config.UnixSocket != "" {
+		paths = append(paths, filepath.Dir(config.UnixSocket))
+	}
+	if config.CommitSigning != nil && config.CommitSigning.GPGHomedir != "" {
+		paths = append(paths, config.CommitSigning.GPGHomedir)
+	}
+	if config.SummarySigning != nil && config.SummarySigning.GPGHomedir != "" {
+		paths = append(paths, config.SummarySigning.GPGHomedir)
+	}
+	return paths
+}
+
+// sandboxReadablePaths returns the files the receiver reads again when it
+// starts in the sandbox: the executable, the certificate and the keys
+func sandboxReadablePaths(config *Config) []string {
+	var paths []string
+	if executable, err := os.Executable(); err == nil {
+		paths = append(paths, executable)
+	}
+	if config.TLSCert != "" {
+		paths = append(paths, config.TLSCert, config.TLSKey)
+	}
+	if config.JWT != nil {
+		for _, key := range config.JWT.Keys {
+			if key.PublicKey != "" && !strings.Contains(key.PublicKey, "-----BEGIN") {
+				paths = append(paths, key.PublicKey)
+			}
+		}
+	}
+	if config.CommitSigning != nil && config.CommitSigning.Type == CommitSignEd25519 {
+		paths = append(paths, config.CommitSigning.Key)
+	}
+	return paths
+}
+
+// landlockAllow adds a rule allowing the access rights beneath path, which
+// must exist; a file only gets the rights that apply to files
+func landlockAllow(rulesetFd int, path string, access uint64) error {
+	fd, err := syscall.Open(path, syscall.O_PATH|syscall.O_CLOEXEC, 0)
+	if err != nil {
+		return err
+	}
+	defer syscall.Close(fd)
+
+	var stat syscall.Stat_t
+	if err := syscall.Fstat(fd, &stat); err != nil {
+		return err
+	}
+	if stat.Mode&syscall.S_IFMT != syscall.S_IFDIR {
+		access &= landlockAccessFile
+	}
+
+	attr := landlockPathBeneathAttr{allowedAccess: access, parentFd: int32(fd)}
+	if _, _, errno := syscall.Syscall6(sysLandlockAddRule, uintptr(rulesetFd), landlockRulePathBeneath, uintptr(unsafe.Pointer(&attr)), 0, 0, 0); errno != 0 {
+		return errno
+	}
+
+	return nil
+}
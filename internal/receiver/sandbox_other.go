@@ -0,0 +1,20 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// +build !sandbox !linux
+
+package receiver
+
+import (
+	"errors"
+)
+
+// EnterSandbox refuses to start a sandboxed receiver, this build
+// doesn't support it
+func EnterSandbox(config *Config, repoPath string, extraPaths ...string) error {
+	if config.Sandbox {
+		return errors.New("sandbox requires a Linux build with the sandbox tag")
+	}
+	return nil
+}
@@ -6,13 +6,21 @@ package receiver
 
 import (
 	"context"
+	"crypto/ed25519"
+	"crypto/tls"
+	"fmt"
+	"net"
 	"net/http"
+	"os"
+	"strings"
 	"time"
 
 	"github.com/go-chi/chi"
 	"github.com/go-chi/chi/middleware"
 
+	"github.com/lirios/ostree-upload/internal/common"
 	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
 )
 
 func receiverContext(appState *AppState) func(next http.Handler) http.Handler {
@@ -20,47 +28,175 @@ func receiverContext(appState *AppState) func(next http.Handler) http.Handler {
 		fn := func(w http.ResponseWriter, r *http.Request) {
 			ctx := context.WithValue(r.Context(), KeyQueue, appState.Queue)
 			ctx = context.WithValue(ctx, KeyRepository, appState.Repo)
+			ctx = context.WithValue(ctx, KeySigningKey, appState.SigningKey)
+			ctx = context.WithValue(ctx, KeyConfig, appState.Config)
+			ctx = context.WithValue(ctx, KeyHistory, appState.History)
+			ctx = context.WithValue(ctx, KeyJournal, appState.Journal)
+			ctx = context.WithValue(ctx, KeyJobs, appState.Jobs)
+			ctx = context.WithValue(ctx, KeyUploadRedirect, appState.Redirect)
 			next.ServeHTTP(w, r.WithContext(ctx))
 		}
 		return http.HandlerFunc(fn)
 	}
 }
 
-func v1Router(appState *AppState) http.Handler {
+// requestTimeout sets the timeout like middleware.Timeout, except for the
+// requests fetching objects from the upload targets, which take as long
+// as the objects need and time out on their own
+func requestTimeout(timeout time.Duration) func(next http.Handler) http.Handler {
+	return func(next http.Handler) http.Handler {
+		timed := middleware.Timeout(timeout)(next)
+		fn := func(w http.ResponseWriter, r *http.Request) {
+			if r.Method == "POST" && strings.HasSuffix(r.URL.Path, "/fetch") {
+				next.ServeHTTP(w, r)
+				return
+			}
+			timed.ServeHTTP(w, r)
+		}
+		return http.HandlerFunc(fn)
+	}
+}
+
+// serverHeader identifies the server build in every reply
+func serverHeader(next http.Handler) http.Handler {
+	value := fmt.Sprintf("ostree-upload/%s protocol/%d libostree/%s", common.Version, common.ProtocolVersion, ostree.Version())
+	fn := func(w http.ResponseWriter, r *http.Request) {
+		w.Header().Set(common.ServerHeader, value)
+		next.ServeHTTP(w, r)
+	}
+	return http.HandlerFunc(fn)
+}
+
+// serverFeatures returns the optional features enabled on this server
+func serverFeatures(ctx context.Context) []string {
+	features := []string{common.FeatureUploadPack, common.FeatureStaticDeltaUpload, common.FeatureSessionAbort}
+
+	if key, ok := ctx.Value(KeySigningKey).(ed25519.PrivateKey); ok && key != nil {
+		features = append(features, "signed-replies")
+	}
+	if history, ok := ctx.Value(KeyHistory).(*History); ok && history != nil {
+		features = append(features, "history")
+	}
+	if config, ok := ctx.Value(KeyConfig).(*Config); ok {
+		if config.BackupDir != "" {
+			features = append(features, "backups")
+		}
+		if !config.verifies(VerifyChecksum) {
+			features = append(features, "skip-checksum-verification")
+		}
+		if config.CommitSigning != nil {
+			features = append(features, "commit-signing")
+		}
+		if config.SummarySigning != nil {
+			features = append(features, "summary-signing")
+		}
+	}
+	if redirect, ok := ctx.Value(KeyUploadRedirect).(*UploadRedirect); ok && redirect != nil {
+		features = append(features, common.FeatureUploadRedirect)
+	}
+	if repo, ok := ctx.Value(KeyRepository).(*ostree.Repo); ok && generateDeltasEnabled(repo) {
+		features = append(features, "static-deltas")
+	}
+
+	return features
+}
+
+// v1Router returns the API, authenticate is the middleware that
+// authenticated the requests, if any, which the push socket runs again
+func v1Router(appState *AppState, authenticate func(http.Handler) http.Handler) http.Handler {
+	// Edge receivers forward everything to the upstream receiver
+	if appState.Upstream != nil {
+		return proxyRouter(appState)
+	}
+
 	r := chi.NewRouter()
 
+	// Requests sent over the push socket go through the same routes
+	api := r
+
 	r.Use(receiverContext(appState))
-	r.Get("/info", InfoHandler)
-	r.Post("/queue", CreateEntryHandler)
-	r.Delete("/queue/{queueID}", DeleteEntryHandler)
-	r.Get("/queue/{queueID}", ObjectsHandler)
-	r.Put("/queue/{queueID}", UploadHandler)
+	r.Use(appState.RateLimiter.Limit)
+
+	// Any valid credentials can ask who they belong to
+	r.Get("/token", TokenInfoHandler)
+
+	// Every route belongs to the group of the scope it requires,
+	// read-only tokens can never reach the routes that change something
+	r.Group(func(r chi.Router) {
+		r.Use(RequireScope(ScopeRead))
+		r.Get("/info", InfoHandler)
+		r.Post("/preflight", PreflightHandler)
+		r.Get("/summary/diff", SummaryDiffHandler)
+
+		// The whole API over a single connection, each request checks its scope
+		r.Get("/push-socket", PushSocketHandler(api, authenticate))
+	})
+	r.Group(func(r chi.Router) {
+		r.Use(RequireScope(ScopeUpload))
+		r.Post("/queue", CreateEntryHandler)
+		r.Get("/objects", InventoryHandler)
+
+		// Only who created a session can act on it
+		session := r.With(RequireSessionOwner)
+		session.Delete("/queue/{queueID}", DeleteEntryHandler)
+		session.Delete("/session/{queueID}", DeleteEntryHandler)
+		session.Get("/queue/{queueID}", ObjectsHandler)
+		session.Post("/queue/{queueID}/missing_objects", MissingObjectsHandler)
+		session.With(appState.RateLimiter.LimitUploads, appState.Idempotency.Replay).Put("/queue/{queueID}", UploadHandler)
+		session.With(appState.RateLimiter.LimitUploads, appState.Idempotency.Replay).Put("/queue/{queueID}/upload_pack", UploadPackHandler)
+		session.With(appState.RateLimiter.LimitUploads, appState.Idempotency.Replay).Post("/queue/{queueID}/fetch", FetchHandler)
+		session.With(appState.RateLimiter.LimitUploads).Put("/queue/{queueID}/delta", StaticDeltaUploadHandler)
+		session.Get("/queue/{queueID}/objects/{objectName}", PartialHandler)
+		session.With(appState.RateLimiter.LimitUploads, appState.Idempotency.Replay).Put("/queue/{queueID}/objects/{objectName}", ChunkHandler)
+	})
+	r.Group(func(r chi.Router) {
+		r.Use(RequireScope(ScopePublish))
+		r.With(RequireSessionOwner, appState.Idempotency.Replay).Post("/queue/{queueID}/done", DoneHandler)
+
+		// Approvals come from somebody else by definition
+		r.Post("/queue/{queueID}/approve", ApproveHandler)
+	})
+	r.Group(func(r chi.Router) {
+		r.Use(RequireScope(ScopeAdmin))
+		r.Get("/status", StatusHandler)
+		r.Get("/sessions", ListSessionsHandler)
+		r.Delete("/sessions/{sessionID}", CancelSessionHandler)
+		r.Get("/admin/frozen", FrozenHandler)
+		r.Post("/admin/frozen", FreezeHandler)
+		r.Post("/admin/revoke", RevokeHandler)
+		r.Get("/admin/jobs", ListJobsHandler)
+		r.Post("/admin/jobs/{kind}", StartJobHandler)
+		r.Get("/admin/history", HistoryHandler)
+	})
 
 	return r
 }
 
-func router(appState *AppState) http.Handler {
+// router builds the middleware stack, with the filters that come before
+// logging, and mounts the API behind the authenticate middleware
+func router(appState *AppState, authenticate func(http.Handler) http.Handler, filters ...func(http.Handler) http.Handler) http.Handler {
 	r := chi.NewRouter()
 
 	// A good base middleware stack
 	r.Use(middleware.RequestID)
-	r.Use(middleware.RealIP)
+	r.Use(filters...)
 	r.Use(middleware.Logger)
 	r.Use(middleware.Recoverer)
+	r.Use(serverHeader)
 	r.Use(middleware.Compress(5, "gzip"))
 
 	// Set a timeout value on the request context (ctx), that will signal
 	// through ctx.Done() that the request has timed out and further
 	// processing should be stopped.
-	r.Use(middleware.Timeout(60 * time.Second))
+	r.Use(requestTimeout(60 * time.Second))
 
 	// Protected routes
 	r.Group(func(r chi.Router) {
 		// Seek, verify and validate tokens
-		r.Use(TokenVerifier(appState))
+		r.Use(authenticate)
 
 		// API
-		r.Mount("/api/v1", v1Router(appState))
+		r.Mount("/api/v1", v1Router(appState, authenticate))
 	})
 
 	// Public routes
@@ -68,12 +204,77 @@ func router(appState *AppState) http.Handler {
 		w.Header().Set("Content-Type", "application/json")
 		w.Write([]byte("{}"))
 	})
+	r.Get("/api/openapi.json", OpenAPIHandler)
 
 	return r
 }
 
-// StartServer starts the server
+// NewHandler returns the complete receiver handler: middleware stack,
+// token authentication and API mounted on /api/v1
+func NewHandler(appState *AppState) http.Handler {
+	return router(appState, TokenVerifier(appState), NetworkFilter(appState.Config), ForwardedFor(appState.Config))
+}
+
+// NewAPIHandler returns only the API handler, without authentication and
+// middleware, so that it can be mounted by another service
+func NewAPIHandler(appState *AppState) http.Handler {
+	return v1Router(appState, nil)
+}
+
+// listen binds the unix socket of the configuration, replacing the one
+// left behind by a previous instance, or address when there is none
+func listen(address string, config *Config) (net.Listener, error) {
+	if config.UnixSocket == "" {
+		return net.Listen("tcp", address)
+	}
+
+	if info, err := os.Lstat(config.UnixSocket); err == nil && info.Mode()&os.ModeSocket != 0 {
+		if err := os.Remove(config.UnixSocket); err != nil {
+			return nil, err
+		}
+	}
+	listener, err := net.Listen("unix", config.UnixSocket)
+	if err != nil {
+		return nil, err
+	}
+	if err := os.Chmod(config.UnixSocket, config.UnixSocketPermissions()); err != nil {
+		listener.Close()
+		return nil, err
+	}
+
+	return listener, nil
+}
+
+// StartServer starts the server, with TLS when the configuration
+// has both certificate and key, on the unix socket of the configuration
+// instead of address if there is one
 func StartServer(address string, appState *AppState) error {
-	logger.Actionf("Starting server on %v", address)
-	return http.ListenAndServe(address, router(appState))
+	config := appState.Config
+	server := &http.Server{Handler: NewHandler(appState)}
+
+	// Bind and read the certificate while we still have the privileges
+	listener, err := listen(address, config)
+	if err != nil {
+		return err
+	}
+	if config.TLSCert != "" && config.TLSKey != "" {
+		certificate, err := tls.LoadX509KeyPair(config.TLSCert, config.TLSKey)
+		if err != nil {
+			listener.Close()
+			return err
+		}
+		server.TLSConfig = &tls.Config{Certificates: []tls.Certificate{certificate}}
+	}
+	if err := dropPrivileges(config.RunAsUser, config.RunAsGroup); err != nil {
+		listener.Close()
+		return fmt.Errorf("cannot drop privileges: %v", err)
+	}
+
+	if server.TLSConfig != nil {
+		logger.Actionf("Starting HTTPS server on %v", listener.Addr())
+		return server.ServeTLS(listener, "", "")
+	}
+
+	logger.Actionf("Starting server on %v", listener.Addr())
+	return server.Serve(listener)
 }
@@ -0,0 +1,533 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"context"
+	"errors"
+	"fmt"
+	"net/http"
+	"os"
+	"strings"
+	"time"
+
+	"github.com/chilts/sid"
+	"github.com/go-chi/chi"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// ErrorKind tells what went wrong in the ReceiveService,
+// each transport reports it its own way
+type ErrorKind int
+
+// Kinds of ServiceError
+const (
+	ErrorInternal ErrorKind = iota
+	ErrorInvalid
+	ErrorUnprocessable
+	ErrorForbidden
+	ErrorNotFound
+	ErrorLocked
+	ErrorTooLarge
+	ErrorBusy
+	ErrorConflict
+	ErrorInsufficientStorage
+	ErrorBadGateway
+)
+
+// ServiceError is an error returned by the ReceiveService
+type ServiceError struct {
+	Kind ErrorKind
+	Err  error
+}
+
+func (e *ServiceError) Error() string {
+	return e.Err.Error()
+}
+
+// newServiceError creates a ServiceError of the kind
+func newServiceError(kind ErrorKind, format string, v ...interface{}) *ServiceError {
+	return &ServiceError{Kind: kind, Err: fmt.Errorf(format, v...)}
+}
+
+// ReceiveService implements the sessions of the upload protocol without
+// knowing about the transport, the HTTP handlers are adapters to it
+type ReceiveService struct {
+	Queue   *Queue
+	Repo    *ostree.Repo
+	Config  *Config
+	Journal *Journal
+	History *History
+}
+
+// serviceFromContext returns the service for the receiver of the request context
+func serviceFromContext(ctx context.Context) (*ReceiveService, error) {
+	queue, ok := ctx.Value(KeyQueue).(*Queue)
+	if !ok {
+		return nil, errors.New("no queue found")
+	}
+	repo, _ := ctx.Value(KeyRepository).(*ostree.Repo)
+	config, _ := ctx.Value(KeyConfig).(*Config)
+	journal, _ := ctx.Value(KeyJournal).(*Journal)
+	history, _ := ctx.Value(KeyHistory).(*History)
+
+	return &ReceiveService{Queue: queue, Repo: repo, Config: config, Journal: journal, History: history}, nil
+}
+
+// CreateSession checks that whoever authenticated ctx can update the
+// branches of the request and creates the queue entry, returning its ID
+func (s *ReceiveService) CreateSession(ctx context.Context, req *common.QueueRequest) (string, error) {
+	if len(req.Refs) == 0 {
+		return "", newServiceError(ErrorUnprocessable, "no branches to update")
+	}
+
+	// Names end up in paths, so refuse anything unexpected
+	if err := validateQueueRequest(req); err != nil {
+		return "", &ServiceError{Kind: ErrorInvalid, Err: err}
+	}
+
+	// Tokens and users might be restricted to some branches
+	if err := checkRefsAllowed(ctx, req.Refs); err != nil {
+		return "", &ServiceError{Kind: ErrorForbidden, Err: err}
+	}
+
+	// Administrators can freeze branches, for example during a release
+	if err := checkRefsNotFrozen(ctx, req.Refs); err != nil {
+		return "", &ServiceError{Kind: ErrorLocked, Err: err}
+	}
+
+	// Moving branches to unrelated commits must be explicitly allowed
+	if req.Force && !requestHasScope(ctx, ScopeForcePush) {
+		return "", newServiceError(ErrorForbidden, "not enough permissions, scope \"%s\" is required", ScopeForcePush)
+	}
+
+	// The pusher must have signed what it's going to send
+	var checksums map[string]string
+	var keyID string
+	if s.Config != nil && len(s.Config.manifestKeys) > 0 {
+		var err error
+		if keyID, err = verifyManifest(s.Config.manifestKeys, req); err != nil {
+			return "", &ServiceError{Kind: ErrorForbidden, Err: err}
+		}
+		checksums = req.Manifest.Objects
+	}
+
+	// The same push again continues its session, for example after a
+	// restart of the client or the server, even when the server is busy
+	var resumed *QueueEntry
+	err := s.Queue.Walk(func(entry *QueueEntry) error {
+		if resumed == nil && s.canResume(ctx, entry, req, keyID) {
+			resumed = entry
+		}
+		return nil
+	})
+	if err != nil {
+		return "", &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	if resumed != nil {
+		logger.Infof("Queue %s: resumed by %s", resumed.ID, identity(ctx))
+		return resumed.ID, nil
+	}
+
+	// Keep the sessions within the limits, so that bursts of pushes
+	// can't exhaust file descriptors and temporary space
+	maxSessions := 0
+	if s.Config != nil {
+		if s.Config.MaxSessionObjects > 0 && len(req.Objects) > s.Config.MaxSessionObjects {
+			return "", newServiceError(ErrorTooLarge, "too many objects for a session (%d, the limit is %d), split the push", len(req.Objects), s.Config.MaxSessionObjects)
+		}
+		maxSessions = s.Config.MaxSessions
+	}
+
+	// New queue entry
+	queueID := sid.IdBase64()
+	queueEntry := &QueueEntry{ID: queueID, UpdateRefs: req.Refs, Objects: req.Objects, Created: time.Now().UTC(), Checksums: checksums, KeyID: keyID, Force: req.Force, Subject: subject(ctx), ChecksumAlgorithm: req.ChecksumAlgorithm}
+	if err := s.Journal.AddEntry(queueEntry); err != nil {
+		return "", newServiceError(ErrorInternal, "failed to journal entry \"%s\": %v", queueID, err)
+	}
+
+	// Forbid an update of the same branches, checked together with
+	// the limit when the entry is added
+	err = s.Queue.AddEntryWithin(queueEntry, maxSessions, func(entry *QueueEntry) error {
+		for branch := range entry.UpdateRefs {
+			if _, ok := req.Refs[branch]; ok {
+				return newServiceError(ErrorConflict, "branch \"%s\" is already being updated", redactRef(branch))
+			}
+		}
+		return nil
+	})
+	if err != nil {
+		s.Journal.RemoveEntry(queueID)
+		if _, ok := err.(*ServiceError); ok {
+			return "", err
+		}
+		if err == ErrQueueFull {
+			return "", newServiceError(ErrorBusy, "server busy, %v", err)
+		}
+		return "", newServiceError(ErrorInternal, "failed to add entry \"%s\" to the queue: %v", queueID, err)
+	}
+
+	return queueID, nil
+}
+
+// canResume returns true if the entry was created by the same request of
+// the same subject and it's still uploading, so that it can be continued
+func (s *ReceiveService) canResume(ctx context.Context, entry *QueueEntry, req *common.QueueRequest, keyID string) bool {
+	if entry.Subject != subject(ctx) || entry.Force != req.Force || entry.KeyID != keyID || entry.ChecksumAlgorithm != req.ChecksumAlgorithm {
+		return false
+	}
+	if s.Queue.IsPublishing(entry.ID) || s.Queue.IsPublished(entry.ID) || s.Queue.GetApproval(entry.ID) != nil {
+		return false
+	}
+
+	if len(entry.UpdateRefs) != len(req.Refs) || len(entry.Objects) != len(req.Objects) {
+		return false
+	}
+	for branch, revPair := range req.Refs {
+		if entry.UpdateRefs[branch] != revPair {
+			return false
+		}
+	}
+	for i, objectName := range req.Objects {
+		if entry.Objects[i] != objectName {
+			return false
+		}
+	}
+
+	return true
+}
+
+// getEntry returns the queue entry
+func (s *ReceiveService) getEntry(queueID string) (*QueueEntry, error) {
+	entry, err := s.Queue.GetEntry(queueID)
+	if err != nil {
+		return nil, newServiceError(ErrorNotFound, "failed to get entry from queue: %v", err)
+	}
+	if entry == nil {
+		return nil, newServiceError(ErrorNotFound, "queue entry not found")
+	}
+
+	return entry, nil
+}
+
+// checkSessionAccess returns an error unless whoever authenticated ctx
+// created the session and is still allowed to update its branches
+func checkSessionAccess(ctx context.Context, entry *QueueEntry) error {
+	if entry.Subject != subject(ctx) {
+		return newServiceError(ErrorForbidden, "queue entry %s belongs to somebody else", entry.ID)
+	}
+	if err := checkRefsAllowed(ctx, entry.UpdateRefs); err != nil {
+		return &ServiceError{Kind: ErrorForbidden, Err: err}
+	}
+
+	return nil
+}
+
+// RequireSessionOwner HTTP middleware handler will make sure the session of
+// the route belongs to whoever authenticated the request, the handler
+// reports sessions that don't exist
+func RequireSessionOwner(next http.Handler) http.Handler {
+	fn := func(w http.ResponseWriter, r *http.Request) {
+		ctx := r.Context()
+		queue, ok := ctx.Value(KeyQueue).(*Queue)
+		if !ok {
+			logger.Error("Unable to retrieve queue object from context")
+			http.Error(w, "no queue found", http.StatusUnprocessableEntity)
+			return
+		}
+
+		entry, err := queue.GetEntry(chi.URLParam(r, "queueID"))
+		if err == nil && entry != nil {
+			if err := checkSessionAccess(ctx, entry); err != nil {
+				logger.Errorf("Refusing access to queue entry %s: %v", entry.ID, err)
+				replyServiceError(w, err)
+				return
+			}
+		}
+
+		next.ServeHTTP(w, r)
+	}
+	return http.HandlerFunc(fn)
+}
+
+// DeleteSession aborts the session: the queue entry is removed with its
+// journal, partial uploads and temporary objects. Only who created the
+// session can abort it, or the administrators.
+func (s *ReceiveService) DeleteSession(ctx context.Context, queueID string) error {
+	entry, err := s.getEntry(queueID)
+	if err != nil {
+		return err
+	}
+	if !requestHasScope(ctx, ScopeAdmin) {
+		if err := checkSessionAccess(ctx, entry); err != nil {
+			return err
+		}
+	}
+
+	// Too late to abort once it's being published
+	if err := s.Queue.Abort(entry); err == ErrPublishing {
+		return &ServiceError{Kind: ErrorConflict, Err: err}
+	} else if err == ErrRemoved {
+		return &ServiceError{Kind: ErrorNotFound, Err: err}
+	} else if err != nil {
+		return &ServiceError{Kind: ErrorUnprocessable, Err: err}
+	}
+	if err := s.Journal.RemoveEntry(entry.ID); err != nil {
+		logger.Errorf("Unable to remove journal of entry %s: %v", entry.ID, err)
+	}
+
+	removed := removeTempObjects(s.Repo, entry.ID)
+	logger.Infof("Queue %s: aborted, removed %d temporary objects", entry.ID, removed)
+
+	return nil
+}
+
+// MissingObjects returns the objects of the session that are neither
+// uploaded nor in the repository already, only among objectNames
+// if it's not empty
+func (s *ReceiveService) MissingObjects(queueID string, objectNames []string) ([]string, error) {
+	entry, err := s.getEntry(queueID)
+	if err != nil {
+		return nil, err
+	}
+	if len(objectNames) == 0 {
+		return missingObjects(s.Repo, entry), nil
+	}
+
+	// Only objects of the session can be checked
+	objects := make(map[string]bool, len(entry.Objects))
+	for _, objectName := range entry.Objects {
+		objects[objectName] = true
+	}
+	for _, objectName := range objectNames {
+		if !objects[objectName] {
+			return nil, newServiceError(ErrorInvalid, "object \"%s\" is not part of the session", redact(objectName))
+		}
+	}
+
+	return findMissingObjects(s.Repo, entry.ID, objectNames), nil
+}
+
+// CheckPublish returns the entry of the session if whoever authenticated
+// ctx can publish it; req is what the client expects to publish and might
+// be nil for clients that don't tell
+func (s *ReceiveService) CheckPublish(ctx context.Context, queueID string, req *common.DoneRequest) (*QueueEntry, error) {
+	if s.Queue.IsPublished(queueID) {
+		return nil, &ServiceError{Kind: ErrorConflict, Err: ErrPublished}
+	}
+	entry, err := s.getEntry(queueID)
+	if err != nil {
+		return nil, err
+	}
+
+	if !requestHasScope(ctx, ScopePublish) {
+		return nil, newServiceError(ErrorForbidden, "not enough permissions, scope \"%s\" is required", ScopePublish)
+	}
+
+	// Nothing to publish
+	if len(entry.UpdateRefs) == 0 {
+		return nil, newServiceError(ErrorUnprocessable, "no branches to update")
+	}
+
+	// Tokens and users might be restricted to some branches
+	if err := checkRefsAllowed(ctx, entry.UpdateRefs); err != nil {
+		return nil, &ServiceError{Kind: ErrorForbidden, Err: err}
+	}
+	if err := checkRefsNotFrozen(ctx, entry.UpdateRefs); err != nil {
+		return nil, &ServiceError{Kind: ErrorLocked, Err: err}
+	}
+	if req == nil {
+		return entry, nil
+	}
+
+	// The client and the session must agree on what is replaced
+	for branch, rev := range req.Expected {
+		revPair, ok := entry.UpdateRefs[branch]
+		if !ok || revPair.Server != rev {
+			return nil, newServiceError(ErrorConflict, "branch \"%s\" is expected at %s, the session replaces %s", redactRef(branch), rev, revPair.Server)
+		}
+	}
+
+	// Make sure the server received what the client sent
+	if err := checkReceipts(s.Journal, queueID, req.Receipts); err != nil {
+		return nil, &ServiceError{Kind: ErrorConflict, Err: err}
+	}
+
+	return entry, nil
+}
+
+// Publish publishes the branches of the entry, once all its objects were
+// received and checked, and returns the receipt of the published revisions
+func (s *ReceiveService) Publish(entry *QueueEntry) (*common.DoneResponse, error) {
+	queueID := entry.ID
+
+	// Only one request can publish the entry
+	if err := s.Queue.StartPublishing(entry); err != nil {
+		return nil, &ServiceError{Kind: ErrorConflict, Err: err}
+	}
+	published := false
+	defer func() {
+		if err := s.Queue.FinishPublishing(entry, published); err != nil {
+			logger.Errorf("Failed to delete queue entry %s: %v", queueID, err)
+		}
+	}()
+
+	// All objects must be uploaded before we publish anything
+	if missing := countMissingObjects(s.Repo, entry); missing > 0 {
+		logger.Errorf("Queue %s: cannot publish, %d objects were not uploaded", queueID, missing)
+		return nil, newServiceError(ErrorConflict, "%d objects were not uploaded", missing)
+	}
+
+	// Only objects needed by the commits can end up in the repository
+	unreferenced, err := unreferencedObjects(s.Repo, entry)
+	if err != nil {
+		logger.Errorf("Queue %s: failed to traverse the commits: %v", queueID, err)
+		return nil, &ServiceError{Kind: publishErrorKind(err), Err: err}
+	}
+	if len(unreferenced) > 0 {
+		logger.Errorf("Queue %s: cannot publish, objects not referenced by the commits: %s", queueID, strings.Join(redactObjects(unreferenced), ", "))
+		return nil, newServiceError(ErrorUnprocessable, "%d objects are not referenced by the commits", len(unreferenced))
+	}
+
+	// Moving objects might need space, for example when the
+	// temporary directory is on another file system
+	usage, err := GetSpaceUsage(s.Repo)
+	if err != nil {
+		logger.Errorf("Queue %s: failed to calculate free space: %v", queueID, err)
+		return nil, &ServiceError{Kind: ErrorInternal, Err: err}
+	}
+	if usage.Headroom() == 0 {
+		logger.Errorf("Queue %s: cannot publish, the repository min-free-space was reached", queueID)
+		return nil, newServiceError(ErrorInsufficientStorage, "not enough free space, the repository min-free-space was reached")
+	}
+
+	// Now publish the branches
+	transfers, replaced, publishWarnings, err := publishBranches(s.Repo, s.Config, entry)
+	if err != nil {
+		logger.Errorf("Cannot publish branches for queue entry %s: %v", queueID, err)
+		return nil, &ServiceError{Kind: publishErrorKind(err), Err: err}
+	}
+
+	// The entry is removed from the queue when we return
+	published = true
+	if err := s.Journal.RemoveEntry(queueID); err != nil {
+		logger.Errorf("Failed to remove journal of queue entry %s: %v", queueID, err)
+	}
+	removeTempObjects(s.Repo, queueID)
+
+	// Problems from now on don't fail the request, the branches are
+	// already updated, but the pusher is told about them
+	warnings := publishWarnings
+	if !s.Config.verifies(VerifyChecksum) {
+		warnings = append(warnings, "objects were not verified when received, only checked by libostree before publishing, verification_level is none")
+	}
+	if len(replaced) > 0 {
+		warnings = append(warnings, fmt.Sprintf("%d published objects were replaced, collision_policy is %s", len(replaced), s.Config.CollisionPolicy))
+	}
+
+	// Record what was published
+	if s.History != nil {
+		historyEntry := &HistoryEntry{Time: time.Now().UTC(), QueueID: queueID, Refs: entry.UpdateRefs, KeyID: entry.KeyID, Objects: len(entry.Objects), Bytes: s.Queue.Written(queueID), Replaced: replaced}
+		if err := s.History.Append(historyEntry); err != nil {
+			logger.Errorf("Failed to record queue entry %s in history: %v", queueID, err)
+			warnings = append(warnings, fmt.Sprintf("publish not recorded in history: %v", err))
+		}
+	}
+
+	// Later publishes might be refused
+	if usage, err := GetSpaceUsage(s.Repo); err == nil && usage.Headroom() == 0 {
+		warnings = append(warnings, "the repository min-free-space was reached, further publishes will be refused")
+	}
+
+	// Reply with a receipt of the published revisions
+	revs := map[string]string{}
+	for branch, revPair := range entry.UpdateRefs {
+		revs[branch] = revPair.Client
+	}
+	return &common.DoneResponse{QueueID: queueID, Revs: revs, Transfers: transfers, VerificationLevel: s.Config.VerificationLevel, Warnings: warnings}, nil
+}
+
+// publishErrorKind returns the kind of an error that prevented a publish
+func publishErrorKind(err error) ErrorKind {
+	var policyErr *ErrPolicyViolation
+	var movedErr *ErrRefMoved
+	switch {
+	case errors.As(err, &policyErr):
+		return ErrorForbidden
+	case errors.As(err, &movedErr):
+		return ErrorConflict
+	case ostree.IsNotFound(err) || ostree.IsCorrupted(err):
+		// The pushed commits are incomplete or broken, not the server
+		return ErrorUnprocessable
+	}
+
+	return ErrorInternal
+}
+
+// missingObjects returns the objects of the entry that were not uploaded
+func missingObjects(repo *ostree.Repo, entry *QueueEntry) []string {
+	return findMissingObjects(repo, entry.ID, entry.Objects)
+}
+
+// findMissingObjects returns the objects that were not uploaded to the queue entry
+func findMissingObjects(repo *ostree.Repo, queueID string, objectNames []string) []string {
+	missing := []string{}
+	for _, objectName := range objectNames {
+		if _, err := os.Stat(GetTempObjectPath(repo, queueID, objectName)); os.IsNotExist(err) {
+			if _, err := os.Stat(repo.GetObjectPath(objectName)); os.IsNotExist(err) {
+				missing = append(missing, objectName)
+			}
+		}
+	}
+
+	return missing
+}
+
+// serviceErrorStatus returns the HTTP status for an error of the service
+func serviceErrorStatus(err error) int {
+	serviceErr, ok := err.(*ServiceError)
+	if !ok {
+		return http.StatusInternalServerError
+	}
+
+	switch serviceErr.Kind {
+	case ErrorInvalid:
+		return http.StatusBadRequest
+	case ErrorUnprocessable:
+		return http.StatusUnprocessableEntity
+	case ErrorForbidden:
+		return http.StatusForbidden
+	case ErrorNotFound:
+		return http.StatusNotFound
+	case ErrorLocked:
+		return http.StatusLocked
+	case ErrorTooLarge:
+		return http.StatusRequestEntityTooLarge
+	case ErrorBusy:
+		return http.StatusServiceUnavailable
+	case ErrorConflict:
+		return http.StatusConflict
+	case ErrorInsufficientStorage:
+		return http.StatusInsufficientStorage
+	case ErrorBadGateway:
+		return http.StatusBadGateway
+	}
+
+	return http.StatusInternalServerError
+}
+
+// replyServiceError replies with an error of the service
+func replyServiceError(w http.ResponseWriter, err error) {
+	status := serviceErrorStatus(err)
+	if status == http.StatusServiceUnavailable {
+		retryLater(w, status, err.Error(), busyRetryAfter)
+		return
+	}
+
+	http.Error(w, err.Error(), status)
+}
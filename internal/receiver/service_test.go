@@ -0,0 +1,273 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"context"
+	"io/ioutil"
+	"os"
+	"path/filepath"
+	"reflect"
+	"strings"
+	"testing"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// Revisions and objects of the sessions created by the tests
+var (
+	serviceTestServer = strings.Repeat("1", 64)
+	serviceTestClient = strings.Repeat("2", 64)
+	serviceTestOther  = strings.Repeat("3", 64)
+
+	serviceTestObjects = []string{
+		strings.Repeat("2", 64) + ".commit",
+		strings.Repeat("4", 64) + ".dirtree",
+		strings.Repeat("5", 64) + ".filez",
+	}
+)
+
+// newTestService returns a service with an empty queue and a repository
+// in a temporary directory
+func newTestService(t *testing.T) *ReceiveService {
+	t.Helper()
+
+	queue, err := NewQueue()
+	if err != nil {
+		t.Fatalf("failed to create the queue: %v", err)
+	}
+	repo, err := ostree.CreateRepo(filepath.Join(t.TempDir(), "repo"))
+	if err != nil {
+		t.Fatalf("failed to create the repository: %v", err)
+	}
+
+	return &ReceiveService{Queue: queue, Repo: repo, Config: &Config{}}
+}
+
+// tokenContext returns a context authenticated by a token of subject
+// with the scopes, all of them when there are none
+func tokenContext(subject string, scopes ...string) context.Context {
+	token := &Token{Token: "token-" + subject, Subject: subject, Scopes: scopes}
+	return context.WithValue(context.Background(), KeyToken, token)
+}
+
+// newTestRequest returns a request moving branch to client
+func newTestRequest(branch, client string) *common.QueueRequest {
+	refs := map[string]common.RevisionPair{branch: {Server: serviceTestServer, Client: client}}
+	objects := append([]string{}, serviceTestObjects...)
+	return &common.QueueRequest{Refs: refs, Objects: objects}
+}
+
+// errorKind returns the kind of a ServiceError, failing the test otherwise
+func errorKind(t *testing.T, err error) ErrorKind {
+	t.Helper()
+
+	serviceErr, ok := err.(*ServiceError)
+	if !ok {
+		t.Fatalf("got error %v, want a ServiceError", err)
+	}
+	return serviceErr.Kind
+}
+
+// writeTestFile creates path with some content, and its directory
+func writeTestFile(t *testing.T, path string) {
+	t.Helper()
+
+	if err := os.MkdirAll(filepath.Dir(path), 0755); err != nil {
+		t.Fatal(err)
+	}
+	if err := ioutil.WriteFile(path, []byte("object"), 0644); err != nil {
+		t.Fatal(err)
+	}
+}
+
+func TestCreateSessionRefusesBranchesBeingUpdated(t *testing.T) {
+	s := newTestService(t)
+	ctx := tokenContext("alice")
+
+	if _, err := s.CreateSession(ctx, newTestRequest("stable", serviceTestClient)); err != nil {
+		t.Fatalf("failed to create the first session: %v", err)
+	}
+
+	// Another push of the same branch, by the same subject or another one
+	tests := []struct {
+		name string
+		ctx  context.Context
+		req  *common.QueueRequest
+	}{
+		{"other revision", ctx, newTestRequest("stable", serviceTestOther)},
+		{"other subject", tokenContext("bob"), newTestRequest("stable", serviceTestClient)},
+	}
+	for _, test := range tests {
+		_, err := s.CreateSession(test.ctx, test.req)
+		if err == nil {
+			t.Errorf("%s: got no error, want the branch to be refused", test.name)
+			continue
+		}
+		if !strings.Contains(err.Error(), "already being updated") {
+			t.Errorf("%s: got error %q, want the branch to be already being updated", test.name, err)
+		}
+	}
+
+	// Other branches are not affected
+	if _, err := s.CreateSession(ctx, newTestRequest("devel", serviceTestClient)); err != nil {
+		t.Errorf("got error %v for another branch, want none", err)
+	}
+}
+
+func TestCreateSessionResumes(t *testing.T) {
+	s := newTestService(t)
+	ctx := tokenContext("alice")
+
+	queueID, err := s.CreateSession(ctx, newTestRequest("stable", serviceTestClient))
+	if err != nil {
+		t.Fatalf("failed to create the session: %v", err)
+	}
+
+	// The same push again continues the session
+	resumed, err := s.CreateSession(ctx, newTestRequest("stable", serviceTestClient))
+	if err != nil {
+		t.Fatalf("got error %v resuming the session, want none", err)
+	}
+	if resumed != queueID {
+		t.Errorf("got session %s, want %s", resumed, queueID)
+	}
+	if sessions, _ := s.Queue.Len(); sessions != 1 {
+		t.Errorf("got %d sessions, want 1", sessions)
+	}
+
+	// Sessions being published can't be continued
+	entry, _ := s.Queue.GetEntry(queueID)
+	if err := s.Queue.StartPublishing(entry); err != nil {
+		t.Fatal(err)
+	}
+	if _, err := s.CreateSession(ctx, newTestRequest("stable", serviceTestClient)); err == nil {
+		t.Error("got no error for a session being published, want the branch to be refused")
+	}
+}
+
+func TestCreateSessionLimit(t *testing.T) {
+	s := newTestService(t)
+	s.Config.MaxSessions = 1
+	ctx := tokenContext("alice")
+
+	queueID, err := s.CreateSession(ctx, newTestRequest("stable", serviceTestClient))
+	if err != nil {
+		t.Fatalf("failed to create the session: %v", err)
+	}
+
+	// Resuming doesn't create a session
+	if resumed, err := s.CreateSession(ctx, newTestRequest("stable", serviceTestClient)); err != nil {
+		t.Errorf("got error %v resuming the session, want none", err)
+	} else if resumed != queueID {
+		t.Errorf("got session %s, want %s", resumed, queueID)
+	}
+
+	if _, err := s.CreateSession(ctx, newTestRequest("devel", serviceTestClient)); err == nil {
+		t.Error("got no error for a session beyond the limit, want the server busy")
+	} else if kind := errorKind(t, err); kind != ErrorBusy {
+		t.Errorf("got error kind %d, want ErrorBusy", kind)
+	}
+}
+
+func TestDeleteSessionChecksOwner(t *testing.T) {
+	s := newTestService(t)
+	owner := tokenContext("alice", ScopeUpload)
+
+	queueID, err := s.CreateSession(owner, newTestRequest("stable", serviceTestClient))
+	if err != nil {
+		t.Fatalf("failed to create the session: %v", err)
+	}
+
+	if err := s.DeleteSession(tokenContext("bob", ScopeUpload), queueID); err == nil {
+		t.Fatal("got no error deleting the session of somebody else, want it forbidden")
+	} else if kind := errorKind(t, err); kind != ErrorForbidden {
+		t.Errorf("got error kind %d, want ErrorForbidden", kind)
+	}
+	if _, err := s.Queue.GetEntry(queueID); err != nil {
+		t.Fatalf("session was removed by somebody else: %v", err)
+	}
+
+	if err := s.DeleteSession(owner, queueID); err != nil {
+		t.Fatalf("got error %v deleting the session, want none", err)
+	}
+	if _, err := s.Queue.GetEntry(queueID); err == nil {
+		t.Error("session is still in the queue after deleting it")
+	}
+	if err := s.DeleteSession(owner, queueID); err == nil {
+		t.Error("got no error deleting the session again, want it not found")
+	} else if kind := errorKind(t, err); kind != ErrorNotFound {
+		t.Errorf("got error kind %d, want ErrorNotFound", kind)
+	}
+}
+
+func TestDeleteSessionByAdministrator(t *testing.T) {
+	s := newTestService(t)
+
+	queueID, err := s.CreateSession(tokenContext("alice", ScopeUpload), newTestRequest("stable", serviceTestClient))
+	if err != nil {
+		t.Fatalf("failed to create the session: %v", err)
+	}
+	if err := s.DeleteSession(tokenContext("root", ScopeAdmin), queueID); err != nil {
+		t.Errorf("got error %v cancelling the session as administrator, want none", err)
+	}
+}
+
+func TestMissingObjects(t *testing.T) {
+	s := newTestService(t)
+	ctx := tokenContext("alice")
+
+	queueID, err := s.CreateSession(ctx, newTestRequest("stable", serviceTestClient))
+	if err != nil {
+		t.Fatalf("failed to create the session: %v", err)
+	}
+
+	// The commit was uploaded, the tree was published before
+	commit, tree, file := serviceTestObjects[0], serviceTestObjects[1], serviceTestObjects[2]
+	writeTestFile(t, GetTempObjectPath(s.Repo, queueID, commit))
+	writeTestFile(t, s.Repo.GetObjectPath(tree))
+
+	tests := []struct {
+		name    string
+		objects []string
+		want    []string
+	}{
+		{"all objects", nil, []string{file}},
+		{"some objects", []string{commit, file}, []string{file}},
+		{"nothing missing", []string{commit, tree}, []string{}},
+	}
+	for _, test := range tests {
+		missing, err := s.MissingObjects(queueID, test.objects)
+		if err != nil {
+			t.Errorf("%s: got error %v, want none", test.name, err)
+			continue
+		}
+		if !reflect.DeepEqual(missing, test.want) {
+			t.Errorf("%s: got %v, want %v", test.name, missing, test.want)
+		}
+	}
+
+	// Objects of other sessions are not disclosed
+	other := strings.Repeat("6", 64) + ".filez"
+	if _, err := s.MissingObjects(queueID, []string{other}); err == nil {
+		t.Error("got no error for an object of no session, want it refused")
+	} else if kind := errorKind(t, err); kind != ErrorInvalid {
+		t.Errorf("got error kind %d, want ErrorInvalid", kind)
+	}
+
+	// Objects uploaded to another session don't count
+	otherID, err := s.CreateSession(ctx, newTestRequest("devel", serviceTestClient))
+	if err != nil {
+		t.Fatalf("failed to create another session: %v", err)
+	}
+	missing, err := s.MissingObjects(otherID, nil)
+	if err != nil {
+		t.Fatalf("got error %v, want none", err)
+	}
+	if want := []string{commit, file}; !reflect.DeepEqual(missing, want) {
+		t.Errorf("got %v for another session, want %v", missing, want)
+	}
+}
@@ -0,0 +1,143 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"io/ioutil"
+	"net/http"
+	"os"
+
+	"github.com/go-chi/chi"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// ListSessionsHandler lists the queue entries
+func ListSessionsHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	queue, ok := ctx.Value(KeyQueue).(*Queue)
+	if !ok {
+		logger.Error("Unable to retrieve queue object from context")
+		http.Error(w, "no queue found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	sessions := []common.SessionInfo{}
+	err := queue.Walk(func(entry *QueueEntry) error {
+		sessions = append(sessions, common.SessionInfo{
+			ID:              entry.ID,
+			Refs:            entry.UpdateRefs,
+			Objects:         len(entry.Objects),
+			Created:         entry.Created,
+			Publishing:      queue.IsPublishing(entry.ID),
+			PendingApproval: queue.GetApproval(entry.ID) != nil,
+		})
+		return nil
+	})
+	if err != nil {
+		logger.Errorf("Failed to walk the queue: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+
+	object := common.SessionsResponse{Sessions: sessions}
+	EncodeJSONReply(w, r, object)
+}
+
+// StatusHandler reports the space left for uploads and the number of sessions
+func StatusHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	ctx := r.Context()
+	queue, ok := ctx.Value(KeyQueue).(*Queue)
+	if !ok {
+		logger.Error("Unable to retrieve queue object from context")
+		http.Error(w, "no queue found", http.StatusUnprocessableEntity)
+		return
+	}
+	repo, ok := ctx.Value(KeyRepository).(*ostree.Repo)
+	if !ok {
+		logger.Error("Unable to retrieve repository object from context")
+		http.Error(w, "no repository found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	usage, err := GetSpaceUsage(repo)
+	if err != nil {
+		logger.Errorf("Failed to calculate free space: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+	tempSize, err := GetTempDirectorySize(repo)
+	if err != nil {
+		logger.Errorf("Failed to calculate temporary directory size: %v", err)
+		http.Error(w, err.Error(), http.StatusInternalServerError)
+		return
+	}
+	sessions := 0
+	queue.Walk(func(entry *QueueEntry) error {
+		sessions++
+		return nil
+	})
+
+	// Conditions that don't break anything yet, but will
+	warnings := []string{}
+	if usage.Headroom() == 0 {
+		warnings = append(warnings, "the repository min-free-space was reached, publishes are refused")
+	}
+	if config, ok := ctx.Value(KeyConfig).(*Config); ok {
+		if config.TempQuota > 0 && tempSize*10 >= config.TempQuota*9 {
+			warnings = append(warnings, fmt.Sprintf("temporary files use %d%% of temp_quota", tempSize*100/config.TempQuota))
+		}
+		if config.MaxSessions > 0 && sessions >= config.MaxSessions {
+			warnings = append(warnings, fmt.Sprintf("max_sessions reached, new sessions are refused until one of the %d sessions ends", sessions))
+		}
+	}
+
+	object := common.StatusResponse{
+		FreeSpace:     usage.Free,
+		ReservedSpace: usage.Reserved,
+		Headroom:      usage.Headroom(),
+		TempSize:      tempSize,
+		Sessions:      sessions,
+		Warnings:      warnings,
+	}
+	EncodeJSONReply(w, r, object)
+}
+
+// CancelSessionHandler aborts a session like DeleteEntryHandler,
+// for the administrators
+func CancelSessionHandler(w http.ResponseWriter, r *http.Request) {
+	// Get from context
+	service, err := serviceFromContext(r.Context())
+	if err != nil {
+		logger.Errorf("Unable to retrieve receive service from context: %v", err)
+		http.Error(w, err.Error(), http.StatusUnprocessableEntity)
+		return
+	}
+
+	// Delete
+	if err := service.DeleteSession(r.Context(), chi.URLParam(r, "sessionID")); err != nil {
+		logger.Errorf("Unable to remove entry from queue: %v", err)
+		replyServiceError(w, err)
+		return
+	}
+}
+
+// removeTempObjects removes the temporary directory of the queue entry
+// and returns how many objects were in it
+func removeTempObjects(repo *ostree.Repo, queueID string) int {
+	path := GetTempSessionPath(repo, queueID)
+	files, _ := ioutil.ReadDir(path)
+	if err := os.RemoveAll(path); err != nil {
+		logger.Errorf("Failed to remove the temporary objects of queue entry %s: %v", queueID, err)
+		return 0
+	}
+
+	return len(files)
+}
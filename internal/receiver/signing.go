@@ -0,0 +1,45 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"crypto/ed25519"
+	"crypto/rand"
+	"encoding/base64"
+	"fmt"
+)
+
+// GenerateSigningKey generates a new ed25519 key pair and returns the
+// base64 encoded private key seed and public key
+func GenerateSigningKey() (string, string, error) {
+	publicKey, privateKey, err := ed25519.GenerateKey(rand.Reader)
+	if err != nil {
+		return "", "", err
+	}
+
+	seed := base64.StdEncoding.EncodeToString(privateKey.Seed())
+	public := base64.StdEncoding.EncodeToString(publicKey)
+
+	return seed, public, nil
+}
+
+// ParseSigningKey decodes the base64 encoded private key seed
+func ParseSigningKey(value string) (ed25519.PrivateKey, error) {
+	seed, err := base64.StdEncoding.DecodeString(value)
+	if err != nil {
+		return nil, err
+	}
+	if len(seed) != ed25519.SeedSize {
+		return nil, fmt.Errorf("bad signing key size %d", len(seed))
+	}
+
+	return ed25519.NewKeyFromSeed(seed), nil
+}
+
+// SignPayload signs the payload with the private key and returns the
+// base64 encoded signature
+func SignPayload(key ed25519.PrivateKey, payload []byte) string {
+	return base64.StdEncoding.EncodeToString(ed25519.Sign(key, payload))
+}
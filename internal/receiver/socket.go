@@ -0,0 +1,171 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"bytes"
+	"context"
+	"encoding/json"
+	"errors"
+	"fmt"
+	"io"
+	"io/ioutil"
+	"net/http"
+	"strings"
+	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/websocket"
+)
+
+// Largest request header accepted on the push socket
+const maxSocketHeaderSize = 64 * 1024
+
+// How long a request sent over the push socket can take
+const socketRequestTimeout = 60 * time.Second
+
+// How long the push socket waits for the next request, clients
+// calculate checksums between requests
+const socketIdleTimeout = 15 * time.Minute
+
+// How long the push socket waits for more of a request being sent
+const socketReadTimeout = time.Minute
+
+// socketResponseWriter collects the reply to a request sent over the push socket
+type socketResponseWriter struct {
+	header http.Header
+	status int
+	body   bytes.Buffer
+}
+
+func (w *socketResponseWriter) Header() http.Header {
+	return w.header
+}
+
+func (w *socketResponseWriter) Write(p []byte) (int, error) {
+	if w.status == 0 {
+		w.status = http.StatusOK
+	}
+	return w.body.Write(p)
+}
+
+func (w *socketResponseWriter) WriteHeader(status int) {
+	if w.status == 0 {
+		w.status = status
+	}
+}
+
+// PushSocketHandler serves the API over a WebSocket: the client sends each
+// request as a SocketRequest followed by the body, and receives the reply
+// the same way. Each request goes through authenticate, with the credentials
+// the socket was opened with unless it has its own, so that revoked or
+// expired credentials stop working; without authenticate requests inherit
+// the credentials verified when the socket was opened.
+func PushSocketHandler(api http.Handler, authenticate func(http.Handler) http.Handler) http.HandlerFunc {
+	if authenticate != nil {
+		api = authenticate(api)
+	}
+
+	return func(w http.ResponseWriter, r *http.Request) {
+		conn, err := websocket.Upgrade(w, r)
+		if err != nil {
+			logger.Errorf("Unable to open push socket: %v", err)
+			return
+		}
+		defer conn.Close()
+
+		// Requests inherit the credentials of the socket, unless they are
+		// authenticated again
+		ctx := context.Background()
+		for _, key := range []ContextKey{KeyUser, KeyToken} {
+			if value := r.Context().Value(key); value != nil {
+				ctx = context.WithValue(ctx, key, value)
+			}
+		}
+		authorization := ""
+		if authenticate != nil {
+			authorization = r.Header.Get("Authorization")
+		}
+
+		logger.Debugf("Push socket opened by %s", r.RemoteAddr)
+		for {
+			if err := serveSocketRequest(ctx, conn, api, r.RemoteAddr, authorization); err != nil {
+				if err != websocket.ErrClosed && err != io.EOF {
+					logger.Errorf("Push socket error: %v", err)
+				}
+				break
+			}
+		}
+		logger.Debugf("Push socket closed by %s", r.RemoteAddr)
+	}
+}
+
+// serveSocketRequest reads a request from the socket, runs it through api
+// and writes the reply
+func serveSocketRequest(ctx context.Context, conn *websocket.Conn, api http.Handler, remoteAddr, authorization string) error {
+	// Idle clients are disconnected, as are those that stop sending
+	conn.SetReadTimeout(socketIdleTimeout)
+	messageType, data, err := conn.ReadMessage(maxSocketHeaderSize)
+	if err != nil {
+		return err
+	}
+	conn.SetReadTimeout(socketReadTimeout)
+	if messageType != websocket.TextMessage {
+		return errors.New("expected a request header")
+	}
+	var req common.SocketRequest
+	if err := json.Unmarshal(data, &req); err != nil {
+		return fmt.Errorf("bad request header: %v", err)
+	}
+	messageType, body, err := conn.NextReader()
+	if err != nil {
+		return err
+	}
+	if messageType != websocket.BinaryMessage {
+		return errors.New("expected a request body")
+	}
+
+	// Only paths of the API can be requested
+	if !strings.HasPrefix(req.Path, "/") || strings.HasPrefix(req.Path, "//") {
+		return fmt.Errorf("bad request path \"%s\"", req.Path)
+	}
+
+	ctx, cancel := context.WithTimeout(ctx, socketRequestTimeout)
+	defer cancel()
+	request, err := http.NewRequestWithContext(ctx, req.Method, req.Path, body)
+	if err != nil {
+		return err
+	}
+	if req.Header != nil {
+		request.Header = req.Header
+	}
+	if request.Header.Get("Authorization") == "" && authorization != "" {
+		request.Header.Set("Authorization", authorization)
+	}
+	request.ContentLength = -1
+	request.RemoteAddr = remoteAddr
+
+	response := &socketResponseWriter{header: http.Header{}}
+	api.ServeHTTP(response, request)
+	if response.status == 0 {
+		response.status = http.StatusOK
+	}
+	logger.Debugf("Push socket: %s %s %d", req.Method, req.Path, response.status)
+
+	// Skip what the handler didn't read to reach the next request
+	if _, err := io.Copy(ioutil.Discard, body); err != nil {
+		return err
+	}
+
+	header, err := json.Marshal(common.SocketResponse{ID: req.ID, Status: response.status, Header: response.header})
+	if err != nil {
+		return err
+	}
+	if err := conn.WriteMessage(websocket.TextMessage, header); err != nil {
+		return err
+	}
+	return conn.WriteMessage(websocket.BinaryMessage, response.body.Bytes())
+}
@@ -0,0 +1,68 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"os"
+	"path/filepath"
+
+	"github.com/lirios/ostree-upload/internal/logger"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// Name of the directory inside the OSTree repository where the objects
+// of a queue entry are checked before they are published
+const stagingDirName = "tmp/ostree-upload-staging"
+
+// stageEntry links the received objects of the entry into a repository whose
+// parent is repo, so that libostree can check them together with the
+// published ones before anything is moved into repo.
+// The returned function removes the staging repository.
+func stageEntry(repo *ostree.Repo, config *Config, entry *QueueEntry) (*ostree.Repo, func(), error) {
+	path := filepath.Join(repo.Path(), stagingDirName, entry.ID)
+	if err := os.RemoveAll(path); err != nil {
+		return nil, nil, err
+	}
+	if err := os.MkdirAll(filepath.Dir(path), 0755); err != nil {
+		return nil, nil, err
+	}
+	cleanup := func() {
+		if err := os.RemoveAll(path); err != nil {
+			logger.Errorf("Failed to remove the staging repository of queue entry %s: %v", entry.ID, err)
+		}
+	}
+
+	staged, err := ostree.CreateStagingRepo(path, repo)
+	if err != nil {
+		cleanup()
+		return nil, nil, err
+	}
+
+	for _, objectName := range entry.Objects {
+		tempPath := GetTempObjectPath(repo, entry.ID, objectName)
+		if _, err := os.Stat(tempPath); err != nil {
+			continue
+		}
+
+		// Only stage what is going to be published
+		if _, err := os.Stat(repo.GetObjectPath(objectName)); err == nil && !replacesPublished(config.CollisionPolicy, objectName) {
+			continue
+		}
+
+		objectPath := staged.GetObjectPath(objectName)
+		if err := os.MkdirAll(filepath.Dir(objectPath), 0755); err != nil {
+			cleanup()
+			return nil, nil, err
+		}
+		if err := os.Link(tempPath, objectPath); err != nil {
+			if err := copyFile(tempPath, objectPath); err != nil {
+				cleanup()
+				return nil, nil, err
+			}
+		}
+	}
+
+	return staged, cleanup, nil
+}
@@ -0,0 +1,128 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"context"
+	"errors"
+	"fmt"
+	"io"
+	"net"
+	"net/http"
+	"os"
+	"os/user"
+	"strings"
+	"sync"
+
+	"github.com/go-chi/chi/middleware"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// errStdioClosed is returned by the listener once the client went away
+var errStdioClosed = errors.New("connection closed")
+
+// stdioListener accepts a single connection, the one over the
+// standard input and output, and then waits for it to be closed
+type stdioListener struct {
+	conn *common.StreamConn
+	once sync.Once
+}
+
+// Accept returns the connection the first time, then blocks until
+// the connection is closed
+func (l *stdioListener) Accept() (net.Conn, error) {
+	var conn net.Conn
+	l.once.Do(func() {
+		conn = l.conn
+	})
+	if conn != nil {
+		return conn, nil
+	}
+
+	<-l.conn.Closed()
+	return nil, errStdioClosed
+}
+
+// Close does nothing, the connection is closed by the server
+func (l *stdioListener) Close() error {
+	return nil
+}
+
+// Addr returns the address of the listener
+func (l *stdioListener) Addr() net.Addr {
+	return l.conn.LocalAddr()
+}
+
+// stdioRequestLogger logs the requests as debug messages, the standard
+// error reaches the client that would otherwise see one line per object
+type stdioRequestLogger struct{}
+
+// Print writes a debug message
+func (stdioRequestLogger) Print(v ...interface{}) {
+	logger.Debug(v...)
+}
+
+// UserAuthenticator HTTP middleware handler authenticates every request
+// as user, who was already authenticated by someone else such as SSH
+func UserAuthenticator(user *User) func(next http.Handler) http.Handler {
+	return func(next http.Handler) http.Handler {
+		fn := func(w http.ResponseWriter, r *http.Request) {
+			ctx := context.WithValue(r.Context(), KeyUser, user)
+			next.ServeHTTP(w, r.WithContext(ctx))
+		}
+		return http.HandlerFunc(fn)
+	}
+}
+
+// stdioClientAddress returns the address of the SSH client, for the
+// messages, from the environment that sshd sets for the forced command
+func stdioClientAddress() string {
+	fields := strings.Fields(os.Getenv("SSH_CLIENT"))
+	if len(fields) >= 2 {
+		return net.JoinHostPort(fields[0], fields[1])
+	}
+	return "stdio"
+}
+
+// ServeStdio speaks the upload protocol over reader and writer, usually
+// the standard input and output of an SSH forced command, until the
+// client closes the connection; all requests are authenticated as user
+func ServeStdio(appState *AppState, user *User, reader io.ReadCloser, writer io.WriteCloser) error {
+	// The standard output belongs to the protocol
+	middleware.DefaultLogger = middleware.RequestLogger(&middleware.DefaultLogFormatter{Logger: stdioRequestLogger{}, NoColor: true})
+
+	address := stdioClientAddress()
+	listener := &stdioListener{conn: common.NewStreamConn(reader, writer, address, nil)}
+	server := &http.Server{Handler: router(appState, UserAuthenticator(user))}
+
+	logger.Actionf("Serving %s over the standard input and output as \"%s\"", address, user.Name)
+	if err := server.Serve(listener); err != errStdioClosed {
+		return err
+	}
+
+	return nil
+}
+
+// StdioUser returns the user that authenticates the requests served over
+// the standard input and output: the configured user called name or,
+// without a name, a user with all scopes named after the system account
+// the forced command runs as
+func StdioUser(config *Config, name string) (*User, error) {
+	if name != "" {
+		found := config.FindUser(name)
+		if found == nil {
+			return nil, fmt.Errorf("user \"%s\" not found", name)
+		}
+		return found, nil
+	}
+
+	account, err := user.Current()
+	if err != nil {
+		return nil, fmt.Errorf("cannot find the current user: %v", err)
+	}
+	return &User{Name: account.Username}, nil
+}
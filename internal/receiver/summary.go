@@ -0,0 +1,44 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"errors"
+	"fmt"
+
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// SummarySigningConfig makes the server sign the summary every time
+// it's regenerated, so that clients can verify the refs
+type SummarySigningConfig struct {
+	Key        string `yaml:"key"`
+	GPGHomedir string `yaml:"gpg_homedir,omitempty"`
+}
+
+// validate checks the values
+func (c *SummarySigningConfig) validate() error {
+	if c.Key == "" {
+		return errors.New("summary signing requires key")
+	}
+
+	return nil
+}
+
+// updateSummary regenerates the summary, like "ostree summary -u" does,
+// and signs it when configured, because regenerating it drops the signature
+func updateSummary(repo *ostree.Repo, config *Config) error {
+	if err := repo.RegenerateSummary(); err != nil {
+		return fmt.Errorf("failed to regenerate summary: %v", err)
+	}
+
+	if config != nil && config.SummarySigning != nil {
+		if err := repo.SignSummaryGPG(config.SummarySigning.Key, config.SummarySigning.GPGHomedir); err != nil {
+			return fmt.Errorf("failed to sign summary: %v", err)
+		}
+	}
+
+	return nil
+}
@@ -5,17 +5,128 @@
 package receiver
 
 import (
+	"context"
 	"crypto/rand"
 	"encoding/base64"
+	"fmt"
 	"net/http"
+	"path"
 	"strings"
 	"time"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/logger"
+)
+
+// Scopes that can be granted to tokens and users
+const (
+	ScopeRead      = "read"
+	ScopeUpload    = "upload"
+	ScopePublish   = "publish"
+	ScopeAdmin     = "admin"
+	ScopeForcePush = "force-push"
 )
 
 // Token represents an API token
 type Token struct {
-	Token   string `yaml:"token"`
-	Created string `yaml:"created"`
+	Token   string   `yaml:"token"`
+	Created string   `yaml:"created"`
+	Subject string   `yaml:"subject,omitempty"`
+	Expires string   `yaml:"expires,omitempty"`
+	Scopes  []string `yaml:"scopes,omitempty"`
+	Refs    []string `yaml:"refs,omitempty"`
+
+	// NoScopes is set on tokens issued by an identity provider that
+	// didn't grant any of our scopes, they can't do anything
+	NoScopes bool `yaml:"-"`
+}
+
+// hasScope returns true if scope is in scopes, no scopes means all of them
+// and any other scope includes reading
+func hasScope(scopes []string, scope string) bool {
+	if len(scopes) == 0 {
+		return true
+	}
+
+	for _, s := range scopes {
+		if s == scope {
+			return true
+		}
+		if scope == ScopeRead && (s == ScopeUpload || s == ScopePublish || s == ScopeAdmin || s == ScopeForcePush) {
+			return true
+		}
+	}
+
+	return false
+}
+
+// matchRefs returns true if the branch matches one of the glob patterns,
+// a trailing "*" matches everything under the prefix, including slashes
+func matchRefs(patterns []string, branch string) bool {
+	for _, pattern := range patterns {
+		if strings.HasSuffix(pattern, "*") && strings.HasPrefix(branch, strings.TrimSuffix(pattern, "*")) {
+			return true
+		}
+		if matched, _ := path.Match(pattern, branch); matched {
+			return true
+		}
+	}
+
+	return false
+}
+
+// canUpdate returns true if whoever authenticated the request is
+// allowed to update the branch
+func canUpdate(ctx context.Context, branch string) bool {
+	if user, ok := ctx.Value(KeyUser).(*User); ok {
+		return user.CanUpdate(branch)
+	}
+	if token, ok := ctx.Value(KeyToken).(*Token); ok {
+		return token.CanUpdate(branch)
+	}
+
+	return true
+}
+
+// ValidateScopes returns an error if any of the scopes is unknown
+func ValidateScopes(scopes []string) error {
+	for _, scope := range scopes {
+		switch scope {
+		case ScopeRead, ScopeUpload, ScopePublish, ScopeAdmin, ScopeForcePush:
+		default:
+			return fmt.Errorf("unknown scope \"%s\"", scope)
+		}
+	}
+
+	return nil
+}
+
+// HasScope returns true if the token was granted scope
+func (t *Token) HasScope(scope string) bool {
+	if t.NoScopes {
+		return false
+	}
+	return hasScope(t.Scopes, scope)
+}
+
+// IsExpired returns true if the token has an expiry date in the past
+func (t *Token) IsExpired() bool {
+	if t.Expires == "" {
+		return false
+	}
+
+	expires, err := time.Parse(time.RFC3339, t.Expires)
+	if err != nil {
+		return true
+	}
+
+	return time.Now().After(expires)
+}
+
+// CanUpdate returns true if the token is allowed to update the branch,
+// tokens without refs can update all branches
+func (t *Token) CanUpdate(branch string) bool {
+	return len(t.Refs) == 0 || matchRefs(t.Refs, branch)
 }
 
 // GenerateToken generates a new reandom API token
@@ -39,31 +150,127 @@ func tokenFromHeader(r *http.Request) string {
 }
 
 // TokenVerifier HTTP middleware handler will verify token in a HTTP request
-// Checks if the HTTP request has 'Authorization: BEARER T' header.
+// Checks if the HTTP request has 'Authorization: BEARER T' header,
+// or falls back to HTTP Basic authentication against the configured users.
 func TokenVerifier(appState *AppState) func(next http.Handler) http.Handler {
 	return func(next http.Handler) http.Handler {
 		fn := func(w http.ResponseWriter, r *http.Request) {
+			// Peers that keep failing are refused before anything is verified
+			peer := peerIP(r).String()
+			if wait := appState.AuthFailures.blocked(peer); wait > 0 {
+				logger.Errorf("Too many failed authentications from %s", peer)
+				retryLater(w, http.StatusTooManyRequests, http.StatusText(http.StatusTooManyRequests), wait)
+				return
+			}
+
+			// Basic authentication
+			if name, password, ok := r.BasicAuth(); ok {
+				user := appState.Config.FindUser(name)
+				if user == nil || !appState.Passwords.Verify(user, password) {
+					appState.AuthFailures.record(peer)
+					w.Header().Set("WWW-Authenticate", `Basic realm="ostree-upload"`)
+					http.Error(w, http.StatusText(http.StatusUnauthorized), http.StatusUnauthorized)
+					return
+				}
+
+				ctx := context.WithValue(r.Context(), KeyUser, user)
+				next.ServeHTTP(w, r.WithContext(ctx))
+				return
+			}
+
 			tokenString := tokenFromHeader(r)
 			if tokenString == "" {
+				appState.AuthFailures.record(peer)
 				http.Error(w, http.StatusText(http.StatusUnauthorized), http.StatusUnauthorized)
 				return
 			}
 
 			// Check if the token is valid
-			found := false
-			for _, token := range appState.Config.Tokens {
-				if token.Token == tokenString {
-					found = true
-					break
+			found := appState.Config.FindToken(tokenString)
+			if found == nil && appState.Config.JWT != nil && isJWT(tokenString) {
+				token, err := appState.Config.JWT.Verify(tokenString)
+				if err != nil {
+					logger.Errorf("Refusing JSON Web Token: %v", err)
+					appState.AuthFailures.record(peer)
+					http.Error(w, http.StatusText(http.StatusUnauthorized), http.StatusUnauthorized)
+					return
 				}
+				found = token
 			}
-			if !found {
+			if found == nil && appState.OIDC != nil {
+				token, err := appState.OIDC.Verify(tokenString)
+				if err != nil {
+					logger.Errorf("Failed to verify token with the OpenID Connect provider: %v", err)
+					http.Error(w, http.StatusText(http.StatusServiceUnavailable), http.StatusServiceUnavailable)
+					return
+				}
+				found = token
+			}
+			if found == nil || found.IsExpired() {
+				appState.AuthFailures.record(peer)
 				http.Error(w, http.StatusText(http.StatusUnauthorized), http.StatusUnauthorized)
 				return
 			}
 
+			ctx := context.WithValue(r.Context(), KeyToken, found)
+			next.ServeHTTP(w, r.WithContext(ctx))
+		}
+		return http.HandlerFunc(fn)
+	}
+}
+
+// WithUser returns a copy of ctx where user authenticated the request,
+// for services that embed the API and authenticate requests themselves
+func WithUser(ctx context.Context, user *User) context.Context {
+	return context.WithValue(ctx, KeyUser, user)
+}
+
+// requestHasScope returns true if the token or the user that
+// authenticated the request was granted scope
+func requestHasScope(ctx context.Context, scope string) bool {
+	if token, ok := ctx.Value(KeyToken).(*Token); ok {
+		return token.HasScope(scope)
+	} else if user, ok := ctx.Value(KeyUser).(*User); ok {
+		return user.HasScope(scope)
+	}
+
+	return false
+}
+
+// RequireScope HTTP middleware handler will make sure the token or
+// the user that authenticated the request was granted scope
+func RequireScope(scope string) func(next http.Handler) http.Handler {
+	return func(next http.Handler) http.Handler {
+		fn := func(w http.ResponseWriter, r *http.Request) {
+			if !requestHasScope(r.Context(), scope) {
+				http.Error(w, fmt.Sprintf("not enough permissions, scope \"%s\" is required", scope), http.StatusForbidden)
+				return
+			}
+
 			next.ServeHTTP(w, r)
 		}
 		return http.HandlerFunc(fn)
 	}
 }
+
+// TokenInfoHandler tells the client who it is authenticated as,
+// so that it can diagnose problems with its credentials
+func TokenInfoHandler(w http.ResponseWriter, r *http.Request) {
+	ctx := r.Context()
+
+	var object common.TokenInfoResponse
+	if user, ok := ctx.Value(KeyUser).(*User); ok {
+		object = common.TokenInfoResponse{Kind: "user", Subject: user.Name, Scopes: user.Scopes, Refs: user.Refs}
+	} else if token, ok := ctx.Value(KeyToken).(*Token); ok {
+		object = common.TokenInfoResponse{Kind: "token", Subject: token.Subject, Scopes: token.Scopes, NoScopes: token.NoScopes, Refs: token.Refs}
+		if expires, err := time.Parse(time.RFC3339, token.Expires); err == nil {
+			object.Expires = &expires
+		}
+	} else {
+		logger.Error("Unable to retrieve credentials from context")
+		http.Error(w, "no credentials found", http.StatusUnprocessableEntity)
+		return
+	}
+
+	EncodeJSONReply(w, r, object)
+}
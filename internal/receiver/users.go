@@ -0,0 +1,217 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"crypto/hmac"
+	"crypto/rand"
+	"crypto/sha256"
+	"crypto/subtle"
+	"encoding/base64"
+	"errors"
+	"fmt"
+	"strings"
+	"sync"
+	"time"
+
+	"golang.org/x/crypto/argon2"
+)
+
+// Argon2id parameters used to hash new passwords
+const (
+	argon2Time    = 1
+	argon2Memory  = 64 * 1024
+	argon2Threads = 4
+	argon2KeyLen  = 32
+	argon2SaltLen = 16
+)
+
+// User represents a user authenticated with HTTP Basic authentication
+type User struct {
+	Name     string   `yaml:"name"`
+	Password string   `yaml:"password"`
+	Refs     []string `yaml:"refs,omitempty"`
+	Scopes   []string `yaml:"scopes,omitempty"`
+}
+
+// HashPassword hashes the password with argon2id, the result is encoded
+// in the PHC string format
+func HashPassword(password string) (string, error) {
+	salt := make([]byte, argon2SaltLen)
+	if _, err := rand.Read(salt); err != nil {
+		return "", err
+	}
+
+	key := argon2.IDKey([]byte(password), salt, argon2Time, argon2Memory, argon2Threads, argon2KeyLen)
+
+	return fmt.Sprintf("$argon2id$v=%d$m=%d,t=%d,p=%d$%s$%s",
+		argon2.Version, argon2Memory, argon2Time, argon2Threads,
+		base64.RawStdEncoding.EncodeToString(salt),
+		base64.RawStdEncoding.EncodeToString(key)), nil
+}
+
+// Limits of the argon2id parameters accepted from the configuration,
+// anything else is either weaker than the defaults or too expensive
+const (
+	argon2MaxTime    = 16
+	argon2MaxMemory  = 1024 * 1024
+	argon2MinKeyLen  = 16
+	argon2MaxKeyLen  = 64
+	argon2MinSaltLen = 8
+)
+
+// passwordHash is a decoded argon2id hash in the PHC string format
+type passwordHash struct {
+	memory  uint32
+	time    uint32
+	threads uint8
+	salt    []byte
+	key     []byte
+}
+
+// parsePasswordHash decodes and validates an argon2id hash
+func parsePasswordHash(encoded string) (*passwordHash, error) {
+	parts := strings.Split(encoded, "$")
+	if len(parts) != 6 || parts[1] != "argon2id" {
+		return nil, errors.New("password is not an argon2id hash")
+	}
+
+	var version int
+	if _, err := fmt.Sscanf(parts[2], "v=%d", &version); err != nil || version != argon2.Version {
+		return nil, fmt.Errorf("unsupported argon2 version \"%s\"", parts[2])
+	}
+
+	hash := &passwordHash{}
+	if _, err := fmt.Sscanf(parts[3], "m=%d,t=%d,p=%d", &hash.memory, &hash.time, &hash.threads); err != nil {
+		return nil, fmt.Errorf("invalid argon2 parameters \"%s\"", parts[3])
+	}
+	if hash.time < 1 || hash.time > argon2MaxTime {
+		return nil, fmt.Errorf("argon2 time must be between 1 and %d", argon2MaxTime)
+	}
+	if hash.threads < 1 {
+		return nil, errors.New("argon2 parallelism must be at least 1")
+	}
+	if hash.memory < 8*uint32(hash.threads) || hash.memory > argon2MaxMemory {
+		return nil, fmt.Errorf("argon2 memory must be between %d and %d KiB", 8*uint32(hash.threads), argon2MaxMemory)
+	}
+
+	var err error
+	if hash.salt, err = base64.RawStdEncoding.DecodeString(parts[4]); err != nil || len(hash.salt) < argon2MinSaltLen {
+		return nil, errors.New("invalid argon2 salt")
+	}
+	if hash.key, err = base64.RawStdEncoding.DecodeString(parts[5]); err != nil || len(hash.key) < argon2MinKeyLen || len(hash.key) > argon2MaxKeyLen {
+		return nil, errors.New("invalid argon2 key")
+	}
+
+	return hash, nil
+}
+
+// VerifyPassword returns true if password matches the user password hash
+func (u *User) VerifyPassword(password string) bool {
+	hash, err := parsePasswordHash(u.Password)
+	if err != nil {
+		return false
+	}
+
+	key := argon2.IDKey([]byte(password), hash.salt, hash.time, hash.memory, hash.threads, uint32(len(hash.key)))
+
+	return subtle.ConstantTimeCompare(key, hash.key) == 1
+}
+
+// How long verified credentials are remembered, clients send them with
+// every request and argon2id is expensive on purpose
+const passwordCacheTTL = 5 * time.Minute
+
+// How many verified credentials are remembered at most
+const passwordCacheSize = 1024
+
+// PasswordCache remembers the credentials verified recently, identified by
+// a keyed hash so that the passwords are not kept in memory
+type PasswordCache struct {
+	secret  []byte
+	mutex   sync.Mutex
+	entries map[string]time.Time
+}
+
+// NewPasswordCache creates an empty cache with a random key
+func NewPasswordCache() (*PasswordCache, error) {
+	secret := make([]byte, 32)
+	if _, err := rand.Read(secret); err != nil {
+		return nil, err
+	}
+
+	return &PasswordCache{secret: secret, entries: map[string]time.Time{}}, nil
+}
+
+// key identifies the credentials, the hash is included so that
+// changing the password forgets the old one
+func (c *PasswordCache) key(user *User, password string) string {
+	mac := hmac.New(sha256.New, c.secret)
+	for _, value := range []string{user.Name, user.Password, password} {
+		mac.Write([]byte(value))
+		mac.Write([]byte{0})
+	}
+
+	return string(mac.Sum(nil))
+}
+
+// Verify returns true if password matches the user password hash, like
+// VerifyPassword, but credentials verified recently are not hashed again;
+// it always verifies when the cache is nil
+func (c *PasswordCache) Verify(user *User, password string) bool {
+	if c == nil {
+		return user.VerifyPassword(password)
+	}
+
+	key := c.key(user, password)
+	now := time.Now()
+	c.mutex.Lock()
+	expires, ok := c.entries[key]
+	c.mutex.Unlock()
+	if ok && now.Before(expires) {
+		return true
+	}
+
+	if !user.VerifyPassword(password) {
+		return false
+	}
+
+	c.mutex.Lock()
+	defer c.mutex.Unlock()
+	if len(c.entries) >= passwordCacheSize {
+		for k, expires := range c.entries {
+			if !now.Before(expires) {
+				delete(c.entries, k)
+			}
+		}
+	}
+	if len(c.entries) < passwordCacheSize {
+		c.entries[key] = now.Add(passwordCacheTTL)
+	}
+
+	return true
+}
+
+// HasScope returns true if the user was granted scope
+func (u *User) HasScope(scope string) bool {
+	return hasScope(u.Scopes, scope)
+}
+
+// CanUpdate returns true if the user is allowed to update the branch,
+// users without refs can update all branches
+func (u *User) CanUpdate(branch string) bool {
+	return len(u.Refs) == 0 || matchRefs(u.Refs, branch)
+}
+
+// FindUser returns the user called name, or nil if it doesn't exist
+func (c *Config) FindUser(name string) *User {
+	for _, user := range c.Users {
+		if user.Name == name {
+			return user
+		}
+	}
+
+	return nil
+}
@@ -9,7 +9,7 @@ import (
 	"os"
 )
 
-func moveFile(source, destination string) error {
+func copyFile(source, destination string) error {
 	src, err := os.Open(source)
 	if err != nil {
 		return err
@@ -34,17 +34,5 @@ func moveFile(source, destination string) error {
 		return err
 	}
 
-	if err = dst.Close(); err != nil {
-		return err
-	}
-
-	if err = src.Close(); err != nil {
-		return err
-	}
-
-	if err = os.Remove(source); err != nil {
-		return err
-	}
-
-	return nil
+	return dst.Close()
 }
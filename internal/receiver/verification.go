@@ -0,0 +1,151 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package receiver
+
+import (
+	"fmt"
+	"os"
+	"strings"
+
+	"github.com/lirios/ostree-upload/internal/common"
+	"github.com/lirios/ostree-upload/internal/ostree"
+)
+
+// How thoroughly the received objects are verified, each level
+// includes the previous ones
+const (
+	// VerifyNone doesn't verify objects as they are received, they are
+	// checked with libostree before publishing
+	VerifyNone = "none"
+
+	// VerifyChecksum compares the checksum of the received objects
+	// with the one calculated by the client
+	VerifyChecksum = "checksum"
+
+	// VerifyObjects also makes sure the objects are well formed
+	VerifyObjects = "objects"
+
+	// VerifyFull also checks the new commits with libostree before publishing
+	VerifyFull = "full"
+)
+
+// Maximum size of a detached metadata object when not configured
+const defaultCommitMetaMaxSize = 1024 * 1024
+
+// Keys of the detached metadata allowed when not configured, those
+// of the signatures libostree knows about
+var defaultCommitMetaKeys = []string{"ostree.gpgsigs", "ostree.sign.ed25519", "ostree.sign.spki"}
+
+// verificationLevels in increasing order
+var verificationLevels = []string{VerifyNone, VerifyChecksum, VerifyObjects, VerifyFull}
+
+// verifies returns true if the configured verification level includes level
+func (c *Config) verifies(level string) bool {
+	for _, l := range verificationLevels {
+		if l == level {
+			return true
+		}
+		if l == c.VerificationLevel {
+			return false
+		}
+	}
+
+	return false
+}
+
+// validateVerificationLevel fills in the default, which depends
+// on the deprecated skip_checksum_verification
+func (c *Config) validateVerificationLevel() error {
+	if c.VerificationLevel == "" {
+		if c.SkipChecksumVerification {
+			c.VerificationLevel = VerifyNone
+		} else {
+			c.VerificationLevel = VerifyObjects
+		}
+		return nil
+	}
+
+	for _, level := range verificationLevels {
+		if level == c.VerificationLevel {
+			return nil
+		}
+	}
+
+	return fmt.Errorf("unknown verification level \"%s\", use one of: %s", c.VerificationLevel, strings.Join(verificationLevels, ", "))
+}
+
+// validateObject makes sure a received object is well formed
+func validateObject(objectPath, objectName string) error {
+	if strings.HasSuffix(objectName, ".filez") {
+		return ostree.ValidateArchiveObject(objectPath)
+	}
+	return ostree.ValidateMetadataObject(objectPath)
+}
+
+// validateCommitMeta makes sure a received detached metadata object is
+// small, only has the allowed keys and belongs to a commit that is either
+// part of the queue entry or already in the repository
+func validateCommitMeta(repo *ostree.Repo, config *Config, entry *QueueEntry, objectPath, objectName string) error {
+	info, err := os.Stat(objectPath)
+	if err != nil {
+		return err
+	}
+	maxSize := config.CommitMetaMaxSize
+	if maxSize == 0 {
+		maxSize = defaultCommitMetaMaxSize
+	}
+	if info.Size() > maxSize {
+		return fmt.Errorf("detached metadata is bigger than %d bytes", maxSize)
+	}
+
+	keys, err := ostree.ReadCommitMetaKeys(objectPath)
+	if err != nil {
+		return err
+	}
+	allowed := config.CommitMetaKeys
+	if len(allowed) == 0 {
+		allowed = defaultCommitMetaKeys
+	}
+	for _, key := range keys {
+		if !containsString(allowed, key) {
+			return fmt.Errorf("detached metadata key \"%s\" is not allowed", key)
+		}
+	}
+
+	rev := strings.TrimSuffix(objectName, ".commitmeta")
+	if !containsString(entry.Objects, rev+".commit") && !repo.HasCommit(rev) {
+		return fmt.Errorf("detached metadata belongs to the unknown commit %s", rev)
+	}
+
+	return nil
+}
+
+// containsString returns true if value is one of values
+func containsString(values []string, value string) bool {
+	for _, v := range values {
+		if v == value {
+			return true
+		}
+	}
+	return false
+}
+
+// fsckEntry verifies the objects of the entry, staged or published,
+// and makes sure the new commits are complete
+func fsckEntry(repo *ostree.Repo, entry *QueueEntry) error {
+	for _, objectName := range entry.Objects {
+		if err := repo.FsckObject(objectName); err != nil {
+			return fmt.Errorf("object %s is corrupted: %w", redact(objectName), err)
+		}
+	}
+
+	for _, branch := range common.SortedBranches(entry.UpdateRefs) {
+		if _, err := repo.TraverseCommit(entry.UpdateRefs[branch].Client, 0); err != nil {
+			return fmt.Errorf("commit %s of branch \"%s\" is incomplete: %w", entry.UpdateRefs[branch].Client, redactRef(branch), err)
+		}
+	}
+
+	return nil
+}
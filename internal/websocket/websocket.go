@@ -0,0 +1,492 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Package websocket implements the subset of RFC 6455 used by the push
+// socket: binary and text messages of any size, fragmented while they are
+// written so that they can be streamed, ping, pong and close. Extensions
+// are not supported, frames with reserved bits or opcodes are refused.
+package websocket
+
+import (
+	"bufio"
+	"crypto/rand"
+	"crypto/sha1"
+	"crypto/tls"
+	"encoding/base64"
+	"encoding/binary"
+	"errors"
+	"fmt"
+	"io"
+	"io/ioutil"
+	"net"
+	"net/http"
+	"net/url"
+	"strings"
+	"sync"
+	"time"
+)
+
+// Message types
+const (
+	TextMessage   = 1
+	BinaryMessage = 2
+)
+
+// Control and continuation opcodes
+const (
+	continuationFrame = 0
+	closeFrame        = 8
+	pingFrame         = 9
+	pongFrame         = 10
+)
+
+// Size of the frames written by NextWriter
+const writeFrameSize = 64 * 1024
+
+// Appended to the key of the client to calculate the accept header
+const acceptGUID = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11"
+
+// ErrClosed is returned when the peer closed the connection
+var ErrClosed = errors.New("websocket connection closed")
+
+// Conn is a WebSocket connection
+type Conn struct {
+	conn        net.Conn
+	reader      *bufio.Reader
+	client      bool
+	writeMutex  sync.Mutex
+	readTimeout time.Duration
+}
+
+// acceptKey returns the value of Sec-WebSocket-Accept for key
+func acceptKey(key string) string {
+	hash := sha1.Sum([]byte(key + acceptGUID))
+	return base64.StdEncoding.EncodeToString(hash[:])
+}
+
+// headerContains returns true if the comma separated header has token
+func headerContains(header http.Header, name, token string) bool {
+	for _, value := range header[http.CanonicalHeaderKey(name)] {
+		for _, field := range strings.Split(value, ",") {
+			if strings.EqualFold(strings.TrimSpace(field), token) {
+				return true
+			}
+		}
+	}
+
+	return false
+}
+
+// Upgrade takes over the connection of the request and completes
+// the handshake, replying with an error when it's not a WebSocket request
+func Upgrade(w http.ResponseWriter, r *http.Request) (*Conn, error) {
+	if r.Method != "GET" || !headerContains(r.Header, "Connection", "upgrade") || !headerContains(r.Header, "Upgrade", "websocket") {
+		http.Error(w, "websocket upgrade required", http.StatusUpgradeRequired)
+		return nil, errors.New("not a websocket request")
+	}
+	if r.Header.Get("Sec-WebSocket-Version") != "13" {
+		w.Header().Set("Sec-WebSocket-Version", "13")
+		http.Error(w, "unsupported websocket version", http.StatusBadRequest)
+		return nil, errors.New("unsupported websocket version")
+	}
+	key := r.Header.Get("Sec-WebSocket-Key")
+	if key == "" {
+		http.Error(w, "missing websocket key", http.StatusBadRequest)
+		return nil, errors.New("missing websocket key")
+	}
+
+	hijacker, ok := w.(http.Hijacker)
+	if !ok {
+		http.Error(w, "websocket not supported", http.StatusInternalServerError)
+		return nil, errors.New("connection cannot be hijacked")
+	}
+	conn, rw, err := hijacker.Hijack()
+	if err != nil {
+		return nil, err
+	}
+
+	response := "HTTP/1.1 101 Switching Protocols\r\n" +
+		"Upgrade: websocket\r\n" +
+		"Connection: Upgrade\r\n" +
+		"Sec-WebSocket-Accept: " + acceptKey(key) + "\r\n\r\n"
+	if _, err := conn.Write([]byte(response)); err != nil {
+		conn.Close()
+		return nil, err
+	}
+
+	return &Conn{conn: conn, reader: rw.Reader}, nil
+}
+
+// Dial opens a WebSocket connection to u, whose scheme is either ws or wss,
+// sending header with the handshake
+func Dial(u *url.URL, header http.Header, tlsConfig *tls.Config) (*Conn, error) {
+	host := u.Host
+	var conn net.Conn
+	var err error
+	switch u.Scheme {
+	case "ws":
+		if u.Port() == "" {
+			host += ":80"
+		}
+		conn, err = net.Dial("tcp", host)
+	case "wss":
+		if u.Port() == "" {
+			host += ":443"
+		}
+		if tlsConfig == nil {
+			tlsConfig = &tls.Config{}
+		}
+		if tlsConfig.ServerName == "" {
+			tlsConfig = tlsConfig.Clone()
+			tlsConfig.ServerName = u.Hostname()
+		}
+		conn, err = tls.Dial("tcp", host, tlsConfig)
+	default:
+		return nil, fmt.Errorf("unsupported scheme \"%s\"", u.Scheme)
+	}
+	if err != nil {
+		return nil, err
+	}
+
+	nonce := make([]byte, 16)
+	if _, err := rand.Read(nonce); err != nil {
+		conn.Close()
+		return nil, err
+	}
+	key := base64.StdEncoding.EncodeToString(nonce)
+
+	request, err := http.NewRequest("GET", u.String(), nil)
+	if err != nil {
+		conn.Close()
+		return nil, err
+	}
+	for name, values := range header {
+		request.Header[name] = values
+	}
+	request.Header.Set("Connection", "Upgrade")
+	request.Header.Set("Upgrade", "websocket")
+	request.Header.Set("Sec-WebSocket-Version", "13")
+	request.Header.Set("Sec-WebSocket-Key", key)
+	if err := request.Write(conn); err != nil {
+		conn.Close()
+		return nil, err
+	}
+
+	reader := bufio.NewReader(conn)
+	response, err := http.ReadResponse(reader, request)
+	if err != nil {
+		conn.Close()
+		return nil, err
+	}
+	if response.StatusCode != http.StatusSwitchingProtocols {
+		body, _ := ioutil.ReadAll(io.LimitReader(response.Body, 4096))
+		response.Body.Close()
+		conn.Close()
+		return nil, fmt.Errorf("websocket handshake failed: %s: %s", response.Status, strings.TrimSpace(string(body)))
+	}
+	if response.Header.Get("Sec-WebSocket-Accept") != acceptKey(key) {
+		conn.Close()
+		return nil, errors.New("websocket handshake failed: bad accept key")
+	}
+
+	return &Conn{conn: conn, reader: reader, client: true}, nil
+}
+
+// SetReadTimeout sets how long a read can wait for data from the peer,
+// zero waits forever
+func (c *Conn) SetReadTimeout(timeout time.Duration) {
+	c.readTimeout = timeout
+}
+
+// extendDeadline moves the read deadline before reading from the peer
+func (c *Conn) extendDeadline() error {
+	if c.readTimeout <= 0 {
+		return nil
+	}
+	return c.conn.SetReadDeadline(time.Now().Add(c.readTimeout))
+}
+
+// Close closes the connection, telling the peer
+func (c *Conn) Close() error {
+	c.writeFrame(closeFrame, true, []byte{0x03, 0xe8})
+	return c.conn.Close()
+}
+
+// writeFrame writes a single frame, masked if this is the client side
+func (c *Conn) writeFrame(opcode int, fin bool, payload []byte) error {
+	c.writeMutex.Lock()
+	defer c.writeMutex.Unlock()
+
+	header := make([]byte, 2, 14)
+	header[0] = byte(opcode)
+	if fin {
+		header[0] |= 0x80
+	}
+	length := len(payload)
+	switch {
+	case length < 126:
+		header[1] = byte(length)
+	case length <= 0xffff:
+		header[1] = 126
+		header = append(header, 0, 0)
+		binary.BigEndian.PutUint16(header[2:], uint16(length))
+	default:
+		header[1] = 127
+		header = append(header, 0, 0, 0, 0, 0, 0, 0, 0)
+		binary.BigEndian.PutUint64(header[2:], uint64(length))
+	}
+
+	// Frames sent by clients must be masked
+	if c.client {
+		header[1] |= 0x80
+		mask := make([]byte, 4)
+		if _, err := rand.Read(mask); err != nil {
+			return err
+		}
+		header = append(header, mask...)
+		masked := make([]byte, length)
+		for i := range payload {
+			masked[i] = payload[i] ^ mask[i%4]
+		}
+		payload = masked
+	}
+
+	if _, err := c.conn.Write(header); err != nil {
+		return err
+	}
+	_, err := c.conn.Write(payload)
+	return err
+}
+
+// WriteMessage writes a whole message in a single frame
+func (c *Conn) WriteMessage(messageType int, data []byte) error {
+	return c.writeFrame(messageType, true, data)
+}
+
+// messageWriter fragments a message into frames
+type messageWriter struct {
+	conn    *Conn
+	opcode  int
+	buffer  []byte
+	written bool
+}
+
+func (w *messageWriter) flush(fin bool) error {
+	if err := w.conn.writeFrame(w.opcode, fin, w.buffer); err != nil {
+		return err
+	}
+	w.opcode = continuationFrame
+	w.buffer = w.buffer[:0]
+	return nil
+}
+
+func (w *messageWriter) Write(p []byte) (int, error) {
+	total := len(p)
+	for len(p) > 0 {
+		n := writeFrameSize - len(w.buffer)
+		if n > len(p) {
+			n = len(p)
+		}
+		w.buffer = append(w.buffer, p[:n]...)
+		p = p[n:]
+		if len(w.buffer) == writeFrameSize {
+			if err := w.flush(false); err != nil {
+				return total - len(p), err
+			}
+		}
+	}
+
+	return total, nil
+}
+
+func (w *messageWriter) Close() error {
+	return w.flush(true)
+}
+
+// NextWriter returns a writer for a message that is streamed in frames,
+// the message is complete when the writer is closed
+func (c *Conn) NextWriter(messageType int) io.WriteCloser {
+	return &messageWriter{conn: c, opcode: messageType, buffer: make([]byte, 0, writeFrameSize)}
+}
+
+// frameHeader is the header of a received frame
+type frameHeader struct {
+	fin    bool
+	opcode int
+	length int64
+	masked bool
+	mask   [4]byte
+}
+
+// readFrameHeader reads the header of the next frame
+func (c *Conn) readFrameHeader() (*frameHeader, error) {
+	if err := c.extendDeadline(); err != nil {
+		return nil, err
+	}
+
+	var b [8]byte
+	if _, err := io.ReadFull(c.reader, b[:2]); err != nil {
+		return nil, err
+	}
+
+	// No extension was negotiated
+	if b[0]&0x70 != 0 {
+		return nil, errors.New("websocket frame with reserved bits set")
+	}
+
+	header := &frameHeader{fin: b[0]&0x80 != 0, opcode: int(b[0] & 0x0f), masked: b[1]&0x80 != 0}
+	switch header.opcode {
+	case continuationFrame, TextMessage, BinaryMessage, closeFrame, pingFrame, pongFrame:
+	default:
+		return nil, fmt.Errorf("reserved websocket opcode %d", header.opcode)
+	}
+	switch length := b[1] & 0x7f; length {
+	case 126:
+		if _, err := io.ReadFull(c.reader, b[:2]); err != nil {
+			return nil, err
+		}
+		header.length = int64(binary.BigEndian.Uint16(b[:2]))
+	case 127:
+		if _, err := io.ReadFull(c.reader, b[:8]); err != nil {
+			return nil, err
+		}
+		header.length = int64(binary.BigEndian.Uint64(b[:8]))
+		if header.length < 0 {
+			return nil, errors.New("websocket frame too large")
+		}
+	default:
+		header.length = int64(length)
+	}
+
+	// Clients must mask their frames, servers must not
+	if header.masked == c.client {
+		return nil, errors.New("websocket frame with bad masking")
+	}
+	if header.masked {
+		if _, err := io.ReadFull(c.reader, header.mask[:]); err != nil {
+			return nil, err
+		}
+	}
+
+	return header, nil
+}
+
+// handleControl replies to the control frames, it returns ErrClosed
+// when the peer closed the connection
+func (c *Conn) handleControl(header *frameHeader) error {
+	if header.length > 125 || !header.fin {
+		return errors.New("websocket control frame too large")
+	}
+	payload := make([]byte, header.length)
+	if _, err := io.ReadFull(c.reader, payload); err != nil {
+		return err
+	}
+	for i := range payload {
+		payload[i] ^= header.mask[i%4]
+	}
+
+	switch header.opcode {
+	case pingFrame:
+		return c.writeFrame(pongFrame, true, payload)
+	case pongFrame:
+		return nil
+	case closeFrame:
+		c.writeFrame(closeFrame, true, payload)
+		return ErrClosed
+	default:
+		return fmt.Errorf("unknown websocket opcode %d", header.opcode)
+	}
+}
+
+// nextDataFrame skips the control frames and returns the next data frame
+func (c *Conn) nextDataFrame() (*frameHeader, error) {
+	for {
+		header, err := c.readFrameHeader()
+		if err != nil {
+			return nil, err
+		}
+		if header.opcode < closeFrame {
+			return header, nil
+		}
+		if err := c.handleControl(header); err != nil {
+			return nil, err
+		}
+	}
+}
+
+// messageReader reads the payload of a message across its frames
+type messageReader struct {
+	conn      *Conn
+	header    *frameHeader
+	remaining int64
+	position  int64
+}
+
+func (r *messageReader) Read(p []byte) (int, error) {
+	for r.remaining == 0 {
+		if r.header.fin {
+			return 0, io.EOF
+		}
+		header, err := r.conn.nextDataFrame()
+		if err != nil {
+			return 0, err
+		}
+		if header.opcode != continuationFrame {
+			return 0, errors.New("websocket message interrupted by another message")
+		}
+		r.header = header
+		r.remaining = header.length
+		r.position = 0
+	}
+
+	if int64(len(p)) > r.remaining {
+		p = p[:r.remaining]
+	}
+	if err := r.conn.extendDeadline(); err != nil {
+		return 0, err
+	}
+	n, err := r.conn.reader.Read(p)
+	for i := 0; i < n; i++ {
+		p[i] ^= r.header.mask[(r.position+int64(i))%4]
+	}
+	r.remaining -= int64(n)
+	r.position += int64(n)
+	if err == io.EOF {
+		err = io.ErrUnexpectedEOF
+	}
+
+	return n, err
+}
+
+// NextReader returns the type of the next message and a reader for its
+// payload, which must be read completely before asking for another message
+func (c *Conn) NextReader() (int, io.Reader, error) {
+	header, err := c.nextDataFrame()
+	if err != nil {
+		return 0, nil, err
+	}
+	if header.opcode == continuationFrame {
+		return 0, nil, errors.New("websocket continuation frame without a message")
+	}
+
+	return header.opcode, &messageReader{conn: c, header: header, remaining: header.length}, nil
+}
+
+// ReadMessage reads a whole message of at most maxSize bytes
+func (c *Conn) ReadMessage(maxSize int64) (int, []byte, error) {
+	messageType, reader, err := c.NextReader()
+	if err != nil {
+		return 0, nil, err
+	}
+
+	data, err := ioutil.ReadAll(io.LimitReader(reader, maxSize+1))
+	if err != nil {
+		return 0, nil, err
+	}
+	if int64(len(data)) > maxSize {
+		return 0, nil, errors.New("websocket message too large")
+	}
+
+	return messageType, data, nil
+}
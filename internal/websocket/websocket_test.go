@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+package websocket
+
+import (
+	"bufio"
+	"bytes"
+	"io"
+	"net"
+	"testing"
+	"time"
+)
+
+// newServerConn returns the server side of a connection and the raw
+// client side, where the test writes frames
+func newServerConn(t *testing.T) (*Conn, net.Conn) {
+	server, client := net.Pipe()
+	t.Cleanup(func() {
+		server.Close()
+		client.Close()
+	})
+
+	return &Conn{conn: server, reader: bufio.NewReader(server)}, client
+}
+
+// maskedFrame encodes a frame as sent by a client, first is the
+// byte with the FIN bit, the reserved bits and the opcode
+func maskedFrame(first byte, payload []byte) []byte {
+	mask := [4]byte{0x12, 0x34, 0x56, 0x78}
+
+	var data []byte
+	if len(payload) < 126 {
+		data = []byte{first, 0x80 | byte(len(payload))}
+	} else {
+		data = []byte{first, 0x80 | 126, byte(len(payload) >> 8), byte(len(payload))}
+	}
+	data = append(data, mask[:]...)
+	for i, b := range payload {
+		data = append(data, b^mask[i%4])
+	}
+
+	return data
+}
+
+// send writes the frames to the connection in the background, stopping
+// at the first error as the server doesn't read what it refuses
+func send(conn net.Conn, frames ...[]byte) {
+	go func() {
+		for _, frame := range frames {
+			if _, err := conn.Write(frame); err != nil {
+				return
+			}
+		}
+	}()
+}
+
+func TestReadMessage(t *testing.T) {
+	server, client := newServerConn(t)
+	send(client, maskedFrame(0x80|TextMessage, []byte("hello")))
+
+	messageType, data, err := server.ReadMessage(1024)
+	if err != nil {
+		t.Fatalf("ReadMessage failed: %v", err)
+	}
+	if messageType != TextMessage || string(data) != "hello" {
+		t.Errorf("got message %d %q, want %d \"hello\"", messageType, data, TextMessage)
+	}
+}
+
+func TestReadExtendedLength(t *testing.T) {
+	server, client := newServerConn(t)
+	payload := bytes.Repeat([]byte("0123456789"), 30)
+	send(client, maskedFrame(0x80|BinaryMessage, payload))
+
+	messageType, data, err := server.ReadMessage(1024)
+	if err != nil {
+		t.Fatalf("ReadMessage failed: %v", err)
+	}
+	if messageType != BinaryMessage || !bytes.Equal(data, payload) {
+		t.Errorf("got message %d of %d bytes, want %d of %d bytes", messageType, len(data), BinaryMessage, len(payload))
+	}
+}
+
+func TestReadFragmentedMessage(t *testing.T) {
+	server, client := newServerConn(t)
+	send(client,
+		maskedFrame(BinaryMessage, []byte("hel")),
+		maskedFrame(continuationFrame, []byte("l")),
+		maskedFrame(0x80|continuationFrame, []byte("o")))
+
+	messageType, data, err := server.ReadMessage(1024)
+	if err != nil {
+		t.Fatalf("ReadMessage failed: %v", err)
+	}
+	if messageType != BinaryMessage || string(data) != "hello" {
+		t.Errorf("got message %d %q, want %d \"hello\"", messageType, data, BinaryMessage)
+	}
+}
+
+func TestReadMessageTooLarge(t *testing.T) {
+	server, client := newServerConn(t)
+	send(client, maskedFrame(0x80|TextMessage, []byte("hello")))
+
+	if _, _, err := server.ReadMessage(4); err == nil {
+		t.Error("ReadMessage accepted a message larger than the limit")
+	}
+}
+
+func TestPingIsAnswered(t *testing.T) {
+	server, client := newServerConn(t)
+
+	done := make(chan []byte, 1)
+	go func() {
+		defer close(done)
+		if _, err := client.Write(maskedFrame(0x80|pingFrame, []byte("x"))); err != nil {
+			return
+		}
+		pong := make([]byte, 3)
+		if _, err := io.ReadFull(client, pong); err != nil {
+			return
+		}
+		done <- pong
+		client.Write(maskedFrame(0x80|TextMessage, []byte("a")))
+	}()
+
+	_, data, err := server.ReadMessage(1024)
+	if err != nil {
+		t.Fatalf("ReadMessage failed: %v", err)
+	}
+	if string(data) != "a" {
+		t.Errorf("got %q, want \"a\"", data)
+	}
+	if pong := <-done; !bytes.Equal(pong, []byte{0x80 | pongFrame, 1, 'x'}) {
+		t.Errorf("got pong %x, want %x", pong, []byte{0x80 | pongFrame, 1, 'x'})
+	}
+}
+
+func TestRejectReservedOpcodes(t *testing.T) {
+	for _, opcode := range []byte{3, 4, 5, 6, 7, 11, 12, 13, 14, 15} {
+		server, client := newServerConn(t)
+		send(client, maskedFrame(0x80|opcode, []byte("x")))
+
+		if _, _, err := server.ReadMessage(1024); err == nil {
+			t.Errorf("ReadMessage accepted reserved opcode %d", opcode)
+		}
+	}
+}
+
+func TestRejectReservedBits(t *testing.T) {
+	for _, bit := range []byte{0x40, 0x20, 0x10} {
+		server, client := newServerConn(t)
+		send(client, maskedFrame(0x80|bit|TextMessage, []byte("x")))
+
+		if _, _, err := server.ReadMessage(1024); err == nil {
+			t.Errorf("ReadMessage accepted reserved bit %#x", bit)
+		}
+	}
+}
+
+func TestRejectUnmaskedClientFrame(t *testing.T) {
+	server, client := newServerConn(t)
+	send(client, []byte{0x80 | TextMessage, 1, 'x'})
+
+	if _, _, err := server.ReadMessage(1024); err == nil {
+		t.Error("ReadMessage accepted an unmasked frame from the client")
+	}
+}
+
+func TestRejectFragmentedControlFrame(t *testing.T) {
+	server, client := newServerConn(t)
+	send(client, maskedFrame(pingFrame, []byte("x")))
+
+	if _, _, err := server.ReadMessage(1024); err == nil {
+		t.Error("ReadMessage accepted a fragmented ping")
+	}
+}
+
+func TestRejectContinuationWithoutMessage(t *testing.T) {
+	server, client := newServerConn(t)
+	send(client, maskedFrame(0x80|continuationFrame, []byte("x")))
+
+	if _, _, err := server.ReadMessage(1024); err == nil {
+		t.Error("ReadMessage accepted a continuation frame without a message")
+	}
+}
+
+func TestRejectInterleavedMessages(t *testing.T) {
+	server, client := newServerConn(t)
+	send(client,
+		maskedFrame(TextMessage, []byte("a")),
+		maskedFrame(0x80|TextMessage, []byte("b")))
+
+	if _, _, err := server.ReadMessage(1024); err == nil {
+		t.Error("ReadMessage accepted a message interrupted by another one")
+	}
+}
+
+func TestReadTimeout(t *testing.T) {
+	server, _ := newServerConn(t)
+	server.SetReadTimeout(10 * time.Millisecond)
+
+	if _, _, err := server.ReadMessage(1024); err == nil {
+		t.Error("ReadMessage didn't time out")
+	}
+}
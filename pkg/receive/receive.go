@@ -0,0 +1,97 @@
+// SPDX-FileCopyrightText: 2020 Pier Luigi Fiorini <pierluigi.fiorini@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+// Package receive lets other services embed the ostree-upload receiver
+// instead of running a separate process.
+//
+// Mount the API under your own router, middleware and authentication,
+// which tells the API who made each request with WithPrincipal:
+//
+//	server, err := receive.New("/var/repo", "/etc/ostree-upload.yaml")
+//	if err != nil {
+//		return err
+//	}
+//	router.With(func(next http.Handler) http.Handler {
+//		return http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+//			principal := receive.Principal{Name: currentUser(r), Scopes: []string{receive.ScopeUpload, receive.ScopePublish}}
+//			next.ServeHTTP(w, r.WithContext(receive.WithPrincipal(r.Context(), principal)))
+//		})
+//	}).Mount("/ostree/api/v1", server.APIHandler())
+package receive
+
+import (
+	"context"
+	"net/http"
+
+	"github.com/lirios/ostree-upload/internal/receiver"
+)
+
+// Scopes that can be granted to a principal
+const (
+	ScopeRead      = receiver.ScopeRead
+	ScopeUpload    = receiver.ScopeUpload
+	ScopePublish   = receiver.ScopePublish
+	ScopeAdmin     = receiver.ScopeAdmin
+	ScopeForcePush = receiver.ScopeForcePush
+)
+
+// Principal is who made a request, as authenticated by the embedding service
+type Principal struct {
+	// Name identifies the principal in sessions, logs and the history
+	Name string
+
+	// Scopes granted to the principal, all of them when empty
+	Scopes []string
+
+	// Branch patterns the principal can update, all of them when empty
+	Refs []string
+}
+
+// WithPrincipal returns a copy of ctx carrying the principal, requests to
+// APIHandler without one are refused as their scopes are unknown
+func WithPrincipal(ctx context.Context, principal Principal) context.Context {
+	return receiver.WithUser(ctx, &receiver.User{Name: principal.Name, Scopes: principal.Scopes, Refs: principal.Refs})
+}
+
+// Server is an embeddable receiver
+type Server struct {
+	appState *receiver.AppState
+}
+
+// New opens the OSTree repository at repoPath, creating it if it doesn't
+// exist, and the configuration file at configPath
+func New(repoPath, configPath string) (*Server, error) {
+	appState, err := receiver.NewAppState(repoPath, configPath)
+	if err != nil {
+		return nil, err
+	}
+
+	return &Server{appState}, nil
+}
+
+// Handler returns the same handler served by "ostree-upload receive",
+// which authenticates requests with the tokens from the configuration
+// file and serves the API on /api/v1
+func (s *Server) Handler() http.Handler {
+	return receiver.NewHandler(s.appState)
+}
+
+// APIHandler returns the API handler without authentication or middleware,
+// the caller is responsible for both and passes the authenticated principal
+// with WithPrincipal
+func (s *Server) APIHandler() http.Handler {
+	return receiver.NewAPIHandler(s.appState)
+}
+
+// Prune prunes the repository, returning the total number of objects,
+// the number of objects deleted and the bytes freed
+func (s *Server) Prune() (int, int, uint64, error) {
+	return s.appState.Repo.Prune(false, false)
+}
+
+// PruneStaticDeltas removes the static deltas referencing commits that
+// are no longer in the repository, returning how many were removed
+func (s *Server) PruneStaticDeltas() (int, error) {
+	return receiver.PruneStaticDeltas(s.appState.Repo)
+}